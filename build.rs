@@ -0,0 +1,30 @@
+use std::env;
+use std::path::Path;
+use std::process::Command;
+
+/// Embeds the metadata of the build returned by the `buildinfo` command.
+fn main() {
+    let target = env::var("TARGET").expect("cargo sets TARGET for build scripts");
+    let profile = env::var("PROFILE").expect("cargo sets PROFILE for build scripts");
+    println!("cargo:rustc-env=BUILD_TARGET={target}");
+    println!("cargo:rustc-env=BUILD_PROFILE={profile}");
+
+    // the commit is optional, since the sources may be built outside of a git repository
+    let commit = Command::new("git")
+        .args(["rev-parse", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok());
+    if let Some(commit) = commit {
+        println!("cargo:rustc-env=BUILD_GIT_COMMIT={}", commit.trim());
+    }
+
+    println!("cargo:rerun-if-changed=build.rs");
+    // a missing path would make cargo rerun the script on every build
+    for path in [".git/HEAD", ".git/refs"] {
+        if Path::new(path).exists() {
+            println!("cargo:rerun-if-changed={path}");
+        }
+    }
+}
@@ -0,0 +1,691 @@
+use serde::Serialize;
+
+use crate::types::{
+    CommandKind, EchoTransform, MathFn, NumOp, Operation, PROTOCOL_VERSION, TimeUnit, VectorOp,
+};
+
+/// The machine-readable description of the server's API, returned by `Command::ApiDescribe`.
+///
+/// The structure follows OpenRPC loosely: the `methods` are the commands, their `params`
+/// are the fields of the request's `payload`, and the `result` is the shape of the `response`
+/// field of a successful response.
+#[derive(Serialize)]
+pub struct ApiDescription {
+    /// The name of the server.
+    pub title: &'static str,
+
+    /// The version of the server.
+    pub version: &'static str,
+
+    /// The version of the wire protocol (see `PROTOCOL_VERSION`).
+    pub protocol_version: u32,
+
+    /// The descriptions of all of the commands, in the order of `CommandKind::ALL`.
+    pub methods: Vec<MethodDescription>,
+}
+
+/// The description of a single command.
+#[derive(Serialize)]
+pub struct MethodDescription {
+    /// The name of the command as it appears in the `command` field of a request.
+    pub name: String,
+
+    /// A short description of what the command does.
+    pub summary: &'static str,
+
+    /// Whether this is an administrative command (see `Config::admin_token`).
+    pub admin: bool,
+
+    /// The type of the `payload` field, or `"none"` if the command requires no payload.
+    pub payload: &'static str,
+
+    /// The fields of the `payload`, if it's an object.
+    pub params: Vec<ParamDescription>,
+
+    /// The shape of the result, in the same notation as the types of the params.
+    pub result: String,
+}
+
+/// The description of a single field of a command's payload.
+///
+/// The types are the JSON ones, along with `integer`, `uuid`, `command` (a command in the same
+/// format as in a request, e.g. `{"command": "ping"}`), `array<T>` and the lists of allowed
+/// string values, e.g. `"gcd" | "lcm"`.
+#[derive(Serialize)]
+pub struct ParamDescription {
+    pub name: &'static str,
+
+    #[serde(rename = "type")]
+    pub ty: String,
+
+    pub required: bool,
+}
+
+/// Builds the description of all of the commands supported by the server.
+pub fn describe_api() -> ApiDescription {
+    ApiDescription {
+        title: env!("CARGO_PKG_NAME"),
+        version: env!("CARGO_PKG_VERSION"),
+        protocol_version: PROTOCOL_VERSION,
+        methods: CommandKind::ALL
+            .iter()
+            .map(|&kind| describe_command(kind))
+            .collect(),
+    }
+}
+
+/// A required field of a payload.
+fn required(name: &'static str, ty: impl Into<String>) -> ParamDescription {
+    ParamDescription {
+        name,
+        ty: ty.into(),
+        required: true,
+    }
+}
+
+/// An optional field of a payload.
+fn optional(name: &'static str, ty: impl Into<String>) -> ParamDescription {
+    ParamDescription {
+        name,
+        ty: ty.into(),
+        required: false,
+    }
+}
+
+/// Lists the serialized values of an enum, e.g. `"gcd" | "lcm"`.
+fn one_of<T: Serialize>(values: &[T]) -> String {
+    values
+        .iter()
+        .map(|value| {
+            format!(
+                "{:?}",
+                serde_plain::to_string(value).expect("enums are serializable")
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(" | ")
+}
+
+/// Describes a single command.
+///
+/// The match is exhaustive, so that a new command can't be added without its description.
+fn describe_command(kind: CommandKind) -> MethodDescription {
+    let operations = one_of(&[
+        Operation::Add,
+        Operation::Subtract,
+        Operation::Multiply,
+        Operation::Divide,
+    ]);
+    let (summary, admin, payload, params, result): (_, _, _, Vec<ParamDescription>, String) =
+        match kind {
+            CommandKind::Ping => ("Returns \"pong\".", false, "none", vec![], "string".into()),
+            CommandKind::Echo => (
+                "Returns the payload as is, or applies a transform to its `value`.",
+                false,
+                "any",
+                vec![
+                    optional("value", "any"),
+                    optional(
+                        "transform",
+                        one_of(&[
+                            EchoTransform::Stringify,
+                            EchoTransform::Keys,
+                            EchoTransform::Type,
+                        ]),
+                    ),
+                ],
+                "any".into(),
+            ),
+            CommandKind::Time => (
+                "Returns the current UTC time in RFC 3339 format.",
+                false,
+                "none",
+                vec![],
+                "string".into(),
+            ),
+            CommandKind::Calculate => (
+                "Performs an arithmetic operation on two operands.",
+                false,
+                "object",
+                vec![
+                    required("operation", operations),
+                    required("a", "number | string"),
+                    required("b", "number | string"),
+                    optional("precision", "integer"),
+                ],
+                "{\"result\": number}".into(),
+            ),
+            CommandKind::Batch => (
+                "Processes several requests and returns their responses.",
+                false,
+                "array<request> | object",
+                vec![
+                    required("requests", "array<request>"),
+                    optional("stop_on_error", "boolean"),
+                    optional("checksum", "string"),
+                ],
+                "array<response>".into(),
+            ),
+            CommandKind::ServerInfo => (
+                "Returns the non-sensitive effective configuration of the server.",
+                false,
+                "none",
+                vec![],
+                "object".into(),
+            ),
+            CommandKind::Pipe => (
+                "Executes commands one after another, passing each result to the next one.",
+                false,
+                "object",
+                vec![required(
+                    "stages",
+                    "array<{\"command\": string, \"payload\"?: any}>",
+                )],
+                "any".into(),
+            ),
+            CommandKind::Eval => (
+                "Evaluates an arithmetic expression.",
+                false,
+                "object",
+                vec![required("expression", "string")],
+                "{\"result\": number}".into(),
+            ),
+            CommandKind::Stats => (
+                "Returns the performance metrics of each command.",
+                false,
+                "none",
+                vec![],
+                "object".into(),
+            ),
+            CommandKind::ActiveRequests => (
+                "Returns the requests that are currently being processed.",
+                true,
+                "none",
+                vec![],
+                "array<{\"request_id\": uuid, \"command\": string, \"elapsed_ms\": number}>".into(),
+            ),
+            CommandKind::ProcInfo => (
+                "Returns the metrics of the server process and its host.",
+                true,
+                "none",
+                vec![],
+                "object".into(),
+            ),
+            CommandKind::BaseConvert => (
+                "Converts an integer of arbitrary size from one base to another.",
+                false,
+                "object",
+                vec![
+                    required("value", "string"),
+                    required("from_base", "integer"),
+                    required("to_base", "integer"),
+                ],
+                "{\"result\": string}".into(),
+            ),
+            CommandKind::Noop => ("Does nothing.", false, "none", vec![], "object".into()),
+            CommandKind::SelfTest => (
+                "Executes a command several times and returns its execution times.",
+                true,
+                "object",
+                vec![
+                    required("command", "command"),
+                    required("iterations", "integer"),
+                ],
+                "{\"command\": string, \"iterations\": integer, \"total_ms\": number, \
+                 \"min_ms\": number, \"avg_ms\": number, \"max_ms\": number}"
+                    .into(),
+            ),
+            CommandKind::NumericLimits => (
+                "Returns the limits of the numeric types used by the server.",
+                false,
+                "none",
+                vec![],
+                "object".into(),
+            ),
+            CommandKind::NumberTheory => (
+                "Performs a number theory operation on two non-negative integers.",
+                false,
+                "object",
+                vec![
+                    required("operation", one_of(&[NumOp::Gcd, NumOp::Lcm])),
+                    required("a", "integer"),
+                    required("b", "integer"),
+                ],
+                "{\"result\": integer}".into(),
+            ),
+            CommandKind::ForceError => (
+                "Fails with the given error.",
+                true,
+                "object",
+                vec![
+                    required("code", "error_code"),
+                    optional("message", "string"),
+                ],
+                "none".into(),
+            ),
+            CommandKind::LatencyHistogram => (
+                "Returns the histogram of the processing times of a command.",
+                false,
+                "object",
+                vec![
+                    required("command", one_of(&CommandKind::ALL)),
+                    optional("buckets", "integer"),
+                ],
+                "{\"command\": string, \"count\": integer, \
+                 \"buckets\": array<{\"le_ms\": number | null, \"count\": integer}>}"
+                    .into(),
+            ),
+            CommandKind::DefineMacro => (
+                "Stores a sequence of commands under a name.",
+                false,
+                "object",
+                vec![
+                    required("name", "string"),
+                    required("steps", "array<command>"),
+                ],
+                "{\"name\": string, \"steps\": integer}".into(),
+            ),
+            CommandKind::RunMacro => (
+                "Executes the commands of a macro like a batch.",
+                false,
+                "object",
+                vec![required("name", "string")],
+                "array<response>".into(),
+            ),
+            CommandKind::Describe => (
+                "Returns the descriptive statistics of numbers.",
+                false,
+                "object",
+                vec![required("values", "array<number>")],
+                "{\"mean\": number, \"median\": number, \"stddev\": number, \
+                 \"min\": number, \"max\": number}"
+                    .into(),
+            ),
+            CommandKind::StreamRange => (
+                "Sends each integer of a range as a separate response.",
+                false,
+                "object",
+                vec![required("start", "integer"), required("end", "integer")],
+                "integer".into(),
+            ),
+            CommandKind::ProtocolVersion => (
+                "Returns the version of the wire protocol.",
+                false,
+                "none",
+                vec![],
+                "{\"version\": integer}".into(),
+            ),
+            CommandKind::LastError => (
+                "Returns the details of the last failed request on the connection.",
+                false,
+                "none",
+                vec![],
+                "{\"last_error\": object | null}".into(),
+            ),
+            CommandKind::Replay => (
+                "Executes the command of a recently processed request again.",
+                true,
+                "object",
+                vec![required("request_id", "uuid")],
+                "any".into(),
+            ),
+            CommandKind::MatrixMul => (
+                "Multiplies two matrices.",
+                false,
+                "object",
+                vec![
+                    required("a", "array<array<number>>"),
+                    required("b", "array<array<number>>"),
+                ],
+                "{\"result\": array<array<number>>}".into(),
+            ),
+            CommandKind::MetricsSnapshot => (
+                "Returns a snapshot of the lifetime metrics.",
+                false,
+                "none",
+                vec![],
+                "{\"timestamp_ms\": integer, \"total\": integer, \"commands\": object}".into(),
+            ),
+            CommandKind::MetricsSeries => (
+                "Returns the most recent periodic metrics snapshots.",
+                false,
+                "object",
+                vec![required("points", "integer")],
+                "{\"interval_secs\": integer, \"snapshots\": array<object>}".into(),
+            ),
+            CommandKind::RecentRequests => (
+                "Returns the most recently processed requests and their outcomes.",
+                true,
+                "object",
+                vec![required("limit", "integer")],
+                "{\"requests\": array<object>}".into(),
+            ),
+            CommandKind::Percentile => (
+                "Returns a percentile of numbers, interpolated linearly.",
+                false,
+                "object",
+                vec![required("values", "array<number>"), required("q", "number")],
+                "{\"result\": number}".into(),
+            ),
+            CommandKind::Inspect => (
+                "Returns the request received on the connection as the server has parsed it.",
+                false,
+                "none",
+                vec![],
+                "{\"request\": object, \"bytes\": integer, \"encoding\": string, \
+                 \"compression\": string}"
+                    .into(),
+            ),
+            CommandKind::Math => (
+                "Applies a trigonometric, logarithmic or other elementary function to a number.",
+                false,
+                "object",
+                vec![
+                    required(
+                        "function",
+                        one_of(&[
+                            MathFn::Sin,
+                            MathFn::Cos,
+                            MathFn::Tan,
+                            MathFn::Sqrt,
+                            MathFn::Ln,
+                            MathFn::Log10,
+                            MathFn::Exp,
+                        ]),
+                    ),
+                    required("x", "number"),
+                ],
+                "{\"result\": number}".into(),
+            ),
+            CommandKind::SessionSet => (
+                "Stores a value under a key for the rest of the connection.",
+                false,
+                "object",
+                vec![required("key", "string"), required("value", "any")],
+                "{\"key\": string, \"entries\": integer}".into(),
+            ),
+            CommandKind::SessionGet => (
+                "Returns the value stored under a key on the connection.",
+                false,
+                "object",
+                vec![required("key", "string")],
+                "any".into(),
+            ),
+            CommandKind::TimeDiff => (
+                "Returns the signed difference between two RFC 3339 timestamps.",
+                false,
+                "object",
+                vec![
+                    required("from", "string"),
+                    required("to", "string"),
+                    required(
+                        "unit",
+                        one_of(&[
+                            TimeUnit::Millis,
+                            TimeUnit::Seconds,
+                            TimeUnit::Minutes,
+                            TimeUnit::Hours,
+                            TimeUnit::Days,
+                        ]),
+                    ),
+                ],
+                "{\"result\": number}".into(),
+            ),
+            CommandKind::Canonicalize => (
+                "Serializes a value in the canonical form, e.g. for computing a stable hash of it.",
+                false,
+                "object",
+                vec![required("data", "any")],
+                "{\"result\": string}".into(),
+            ),
+            CommandKind::SlowEcho => (
+                "Returns the payload as is, sending the response at the given rate.",
+                false,
+                "object",
+                vec![
+                    required("payload", "any"),
+                    required("bytes_per_sec", "integer"),
+                ],
+                "any".into(),
+            ),
+            CommandKind::CommandStatus => (
+                "Returns whether each command is enabled and its concurrency limit.",
+                false,
+                "none",
+                vec![],
+                "object".into(),
+            ),
+            CommandKind::StringDistance => (
+                "Returns the Levenshtein distance between two strings.",
+                false,
+                "object",
+                vec![required("a", "string"), required("b", "string")],
+                "{\"distance\": integer}".into(),
+            ),
+            CommandKind::SeededRandom => (
+                "Returns a pseudo-random sequence of integers, the same for the same seed.",
+                false,
+                "object",
+                vec![required("seed", "integer"), required("count", "integer")],
+                "{\"values\": array<integer>}".into(),
+            ),
+            CommandKind::DateFormat => (
+                "Parses a date with a strftime pattern and formats it with another one.",
+                false,
+                "object",
+                vec![
+                    required("input", "string"),
+                    required("from", "string"),
+                    required("to", "string"),
+                ],
+                "{\"result\": string}".into(),
+            ),
+            CommandKind::LogLevel => (
+                "Returns the level of the server log, changing it first if given.",
+                true,
+                "object",
+                vec![optional("level", "string")],
+                "{\"level\": string}".into(),
+            ),
+            CommandKind::SessionStats => (
+                "Adds values to the running statistics of the session, optionally persisted.",
+                false,
+                "object",
+                vec![
+                    optional("values", "array<number>"),
+                    optional("session_id", "string"),
+                ],
+                "{\"count\": integer, \"mean\": number | null, \"variance\": number | null}".into(),
+            ),
+            CommandKind::TimeZone => (
+                "Returns the current time in an IANA time zone in RFC 3339 format.",
+                false,
+                "object",
+                vec![required("tz", "string")],
+                "{\"time\": string, \"tz\": string}".into(),
+            ),
+            CommandKind::StatsSince => (
+                "Returns the metrics accumulated since the snapshot preceding a timestamp.",
+                false,
+                "object",
+                vec![required("since", "string")],
+                "{\"since_ms\": integer, \"until_ms\": integer, \"total\": integer, \
+                 \"commands\": object}"
+                    .into(),
+            ),
+            CommandKind::SubscribeMetrics => (
+                "Sends a snapshot of the lifetime metrics periodically until unsubscribed.",
+                false,
+                "object",
+                vec![required("interval_ms", "integer")],
+                "{\"timestamp_ms\": integer, \"total\": integer, \"commands\": object}".into(),
+            ),
+            CommandKind::SchemaValidate => (
+                "Validates a JSON value against a JSON Schema.",
+                false,
+                "object",
+                vec![required("schema", "object"), required("instance", "any")],
+                "{\"valid\": boolean, \"errors\": array<object>}".into(),
+            ),
+            CommandKind::NormalizeRequest => (
+                "Returns the request as the server interprets it, with the defaults filled in.",
+                false,
+                "object",
+                vec![required("request", "object")],
+                "object".into(),
+            ),
+            CommandKind::Pad => (
+                "Returns a string of exactly the given number of bytes.",
+                false,
+                "object",
+                vec![required("size", "integer")],
+                "{\"data\": string}".into(),
+            ),
+            CommandKind::Connections => (
+                "Returns the connections that are currently open.",
+                true,
+                "none",
+                vec![],
+                "array<{\"peer\": string, \"duration_ms\": number, \"requests\": integer}>".into(),
+            ),
+            CommandKind::CloseConnection => (
+                "Closes an open connection.",
+                true,
+                "object",
+                vec![required("peer", "string")],
+                "{\"closed\": string}".into(),
+            ),
+            CommandKind::Flatten => (
+                "Converts a nested object into a single-level one with dot-separated keys.",
+                false,
+                "object",
+                vec![required("data", "object")],
+                "{\"result\": object}".into(),
+            ),
+            CommandKind::Unflatten => (
+                "Converts a single-level object with dot-separated keys into a nested one.",
+                false,
+                "object",
+                vec![required("data", "object")],
+                "{\"result\": object}".into(),
+            ),
+            CommandKind::Sample => (
+                "Returns values picked at random from an array, with or without replacement.",
+                false,
+                "object",
+                vec![
+                    required("values", "array<any>"),
+                    required("n", "integer"),
+                    optional("replacement", "boolean"),
+                    optional("seed", "integer"),
+                ],
+                "{\"values\": array<any>}".into(),
+            ),
+            CommandKind::Crc32 => (
+                "Returns the CRC-32 checksum of the UTF-8 bytes of a string.",
+                false,
+                "object",
+                vec![required("data", "string")],
+                "{\"crc32\": integer}".into(),
+            ),
+            CommandKind::JsonDiff => (
+                "Returns the differences between two JSON values keyed by JSON Pointers.",
+                false,
+                "object",
+                vec![required("left", "any"), required("right", "any")],
+                "{\"added\": object, \"removed\": object, \"changed\": object}".into(),
+            ),
+            CommandKind::ErrorCodes => (
+                "Returns the error codes the server may respond with and their descriptions.",
+                false,
+                "none",
+                vec![],
+                "{\"codes\": [{\"code\": string, \"description\": string}]}".into(),
+            ),
+            CommandKind::Encrypt => (
+                "Encrypts a value with the key of the connection, which never leaves the server.",
+                false,
+                "object",
+                vec![required("data", "any")],
+                "{\"data\": string}".into(),
+            ),
+            CommandKind::Decrypt => (
+                "Decrypts a value encrypted by `encrypt` on the same connection.",
+                false,
+                "object",
+                vec![required("data", "string")],
+                "{\"data\": any}".into(),
+            ),
+            CommandKind::BuildInfo => (
+                "Returns the version, profile, target and git commit of the server's build.",
+                false,
+                "none",
+                vec![],
+                "{\"version\": string, \"profile\": string, \"target\": string, \
+                 \"git_commit\": string | null}"
+                    .into(),
+            ),
+            CommandKind::MapCalculate => (
+                "Performs an arithmetic operation on each pair of numbers, with an error per pair.",
+                false,
+                "object",
+                vec![
+                    required("operation", operations),
+                    required("pairs", "array<[number, number]>"),
+                ],
+                "{\"results\": array<{\"result\": number} \
+                 | {\"code\": string, \"error\": string}>}"
+                    .into(),
+            ),
+            CommandKind::Uptime => (
+                "Returns how long the server has been running and when it has started.",
+                false,
+                "none",
+                vec![],
+                "{\"uptime_ms\": integer, \"started_at\": string}".into(),
+            ),
+            CommandKind::Vector => (
+                "Computes the dot product of two vectors, or the norm or the direction of one.",
+                false,
+                "object",
+                vec![
+                    required(
+                        "operation",
+                        one_of(&[VectorOp::Dot, VectorOp::Norm, VectorOp::Normalize]),
+                    ),
+                    required("a", "array<number>"),
+                    optional("b", "array<number>"),
+                ],
+                "{\"result\": number | array<number>}".into(),
+            ),
+            CommandKind::Typeof => (
+                "Returns the JSON type of a value and the size of an array, object or string.",
+                false,
+                "object",
+                vec![required("data", "any")],
+                "{\"type\": string, \"size\": integer}".into(),
+            ),
+            CommandKind::QueueDepth => (
+                "Returns the number of the requests waiting for a slot of each limited command.",
+                false,
+                "none",
+                vec![],
+                "object".into(),
+            ),
+            CommandKind::ApiDescribe => (
+                "Returns this description of the API.",
+                false,
+                "none",
+                vec![],
+                "object".into(),
+            ),
+        };
+    MethodDescription {
+        name: kind.name(),
+        summary,
+        admin,
+        payload,
+        params,
+        result,
+    }
+}
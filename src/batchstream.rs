@@ -0,0 +1,403 @@
+use serde::Deserialize;
+use serde::de::IgnoredAny;
+use serde_json::{Map, Value};
+
+use crate::types::{Command, CommandError, ErrorCode, Request};
+
+/// The largest start of a request looked through for the payload of a batch.
+///
+/// A request with larger fields before its payload is buffered whole.
+pub const MAX_HEAD_BYTES: usize = 64 * 1024;
+
+/// The result of looking for the start of a batch that can be streamed (see `find_head`).
+pub enum BatchHead {
+    /// The data ends before it's clear whether the request is such a batch.
+    Incomplete,
+
+    /// The request is not a batch that can be streamed, so it's to be buffered whole.
+    Unsupported,
+
+    /// The request is a batch with an array payload. The batch request contains
+    /// the fields preceding its payload and no requests, and the payload array
+    /// starts right after the given offset in the data.
+    Found(Request, usize),
+}
+
+/// Looks for the start of a batch that can be processed while it's still being received.
+///
+/// That's a request object whose `payload` is an array, and whose fields preceding it
+/// make a batch request on their own, i.e. contain the `request_id` and `"command": "batch"`.
+/// The `payload` must also be the last field (see `BatchSplitter`).
+pub fn find_head(data: &[u8]) -> BatchHead {
+    let skip_whitespace = |mut pos: usize| {
+        while data.get(pos).is_some_and(u8::is_ascii_whitespace) {
+            pos += 1;
+        }
+        pos
+    };
+    let mut pos = skip_whitespace(0);
+    match data.get(pos) {
+        None => return BatchHead::Incomplete,
+        Some(b'{') => pos += 1,
+        Some(_) => return BatchHead::Unsupported,
+    }
+    loop {
+        let key_start = skip_whitespace(pos);
+        let mut values = serde_json::Deserializer::from_slice(&data[key_start..]).into_iter();
+        let key: String = match values.next() {
+            Some(Ok(key)) => key,
+            None => return BatchHead::Incomplete,
+            Some(Err(e)) if e.is_eof() => return BatchHead::Incomplete,
+            Some(Err(_)) => return BatchHead::Unsupported,
+        };
+        pos = skip_whitespace(key_start + values.byte_offset());
+        match data.get(pos) {
+            None => return BatchHead::Incomplete,
+            Some(b':') => pos = skip_whitespace(pos + 1),
+            Some(_) => return BatchHead::Unsupported,
+        }
+        if key == "payload" {
+            return match data.get(pos) {
+                None => BatchHead::Incomplete,
+                Some(b'[') => match parse_head(&data[..key_start]) {
+                    Some(request) => BatchHead::Found(request, pos + 1),
+                    None => BatchHead::Unsupported,
+                },
+                Some(_) => BatchHead::Unsupported,
+            };
+        }
+
+        let mut values = serde_json::Deserializer::from_slice(&data[pos..]).into_iter();
+        match values.next() {
+            Some(Ok(IgnoredAny)) => {}
+            None => return BatchHead::Incomplete,
+            Some(Err(e)) if e.is_eof() => return BatchHead::Incomplete,
+            Some(Err(_)) => return BatchHead::Unsupported,
+        }
+        pos = skip_whitespace(pos + values.byte_offset());
+        match data.get(pos) {
+            None => return BatchHead::Incomplete,
+            Some(b',') => pos += 1,
+            Some(_) => return BatchHead::Unsupported,
+        }
+    }
+}
+
+/// Parses the fields preceding the payload, `{"request_id": ..., "command": "batch", `,
+/// into a batch request without requests.
+fn parse_head(head: &[u8]) -> Option<Request> {
+    let head = head.trim_ascii_end();
+    let head = head.strip_suffix(b",").unwrap_or(head);
+    let mut fields: Map<String, Value> = serde_json::from_slice(&[head, b"}"].concat()).ok()?;
+    fields.insert("payload".to_string(), Value::Array(Vec::new()));
+    let request = Request::deserialize(Value::Object(fields)).ok()?;
+    matches!(request.command, Command::Batch(_)).then_some(request)
+}
+
+/// Splits the payload of a batch into its requests as the data arrives,
+/// keeping only the data of the request being received.
+///
+/// The splitter is fed the data following the start of the payload array (see `find_head`).
+/// After the array, only the end of the request object may follow, since the fields after
+/// the payload would arrive too late to affect the processing of the batch.
+pub struct BatchSplitter {
+    /// The received data that hasn't been split into requests yet.
+    data: Vec<u8>,
+
+    /// How much of the data has been scanned.
+    scanned: usize,
+
+    /// The depth of the nesting of the scanned data into the current request.
+    depth: usize,
+
+    /// The maximum nesting depth of the whole batch request (see `Config::max_json_depth`).
+    max_depth: usize,
+
+    in_string: bool,
+    escaped: bool,
+
+    /// The start of the request being received, if it has started.
+    item_start: Option<usize>,
+
+    /// What is expected after the scanned data, unless it ends inside a request.
+    expected: Expected,
+
+    array_ended: bool,
+    finished: bool,
+
+    /// The largest amount of data kept at once, in bytes.
+    peak: usize,
+}
+
+impl BatchSplitter {
+    /// Creates a splitter with the data following the start of the payload array.
+    ///
+    /// The requests nested deeper than `max_depth` into the batch request fail
+    /// with `ErrorCode::NestingTooDeep` as soon as that is detected.
+    pub fn new(data: Vec<u8>, max_depth: usize) -> Self {
+        BatchSplitter {
+            peak: data.len(),
+            data,
+            scanned: 0,
+            depth: 0,
+            max_depth,
+            in_string: false,
+            escaped: false,
+            item_start: None,
+            expected: Expected::FirstItem,
+            array_ended: false,
+            finished: false,
+        }
+    }
+
+    /// Appends the received data.
+    pub fn push(&mut self, chunk: &[u8]) {
+        self.data.extend_from_slice(chunk);
+        self.peak = self.peak.max(self.data.len());
+    }
+
+    /// Whether the whole request object has been received.
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// The largest amount of data kept at once, in bytes.
+    pub fn peak_buffered(&self) -> usize {
+        self.peak
+    }
+
+    /// Returns the next request of the batch if it has been received completely.
+    ///
+    /// # Errors
+    /// Fails with `ErrorCode::InvalidEncoding`, `ErrorCode::ParseError` or
+    /// `ErrorCode::InvalidRequest`, like the request as a whole would, if a request
+    /// of the batch is invalid, or if the payload is followed by another field.
+    pub fn next_item(&mut self) -> Result<Option<Request>, CommandError> {
+        let invalid_json =
+            || CommandError::new(ErrorCode::ParseError, "request is not a valid JSON");
+        while self.scanned < self.data.len() && !self.finished {
+            let pos = self.scanned;
+            let byte = self.data[pos];
+            self.scanned += 1;
+
+            if self.in_string {
+                match byte {
+                    _ if self.escaped => self.escaped = false,
+                    b'\\' => self.escaped = true,
+                    b'"' => self.in_string = false,
+                    _ => {}
+                }
+                continue;
+            }
+            if byte.is_ascii_whitespace() {
+                continue;
+            }
+            if self.array_ended {
+                match byte {
+                    b'}' => self.finished = true,
+                    _ => {
+                        return Err(CommandError::new(
+                            ErrorCode::InvalidRequest,
+                            "`payload` must be the last field of a streamed batch",
+                        ));
+                    }
+                }
+                continue;
+            }
+
+            // between the items of the array, or inside a scalar one
+            if self.depth == 0 && matches!(byte, b',' | b']') {
+                let scalar = self.item_start.take();
+                match (byte, self.expected) {
+                    _ if scalar.is_some() => {}
+                    (b',', Expected::Delimiter) => {}
+                    (b']', Expected::FirstItem | Expected::Delimiter) => {}
+                    _ => return Err(invalid_json()),
+                }
+                self.expected = Expected::Item;
+                self.array_ended = byte == b']';
+                match scalar {
+                    Some(start) => return self.take_item(start, pos).map(Some),
+                    None => continue,
+                }
+            }
+            if self.item_start.is_none() {
+                if self.expected == Expected::Delimiter {
+                    return Err(invalid_json());
+                }
+                self.item_start = Some(pos);
+            }
+            match byte {
+                b'"' => self.in_string = true,
+                b'{' | b'[' => {
+                    self.depth += 1;
+                    // the request object of the batch and its payload array enclose the item
+                    if self.depth + 2 > self.max_depth {
+                        return Err(CommandError::new(
+                            ErrorCode::NestingTooDeep,
+                            format!("request is nested deeper than {} levels", self.max_depth),
+                        ));
+                    }
+                }
+                b'}' | b']' if self.depth == 0 => return Err(invalid_json()),
+                b'}' | b']' => {
+                    self.depth -= 1;
+                    if self.depth == 0 {
+                        let start = self.item_start.take().expect("the item has started");
+                        self.expected = Expected::Delimiter;
+                        return self.take_item(start, pos + 1).map(Some);
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(None)
+    }
+
+    /// Parses the request at `start..end` of the data and drops the data up to its end.
+    fn take_item(&mut self, start: usize, end: usize) -> Result<Request, CommandError> {
+        let item = &self.data[start..end];
+        if let Err(e) = std::str::from_utf8(item) {
+            return Err(CommandError::new(
+                ErrorCode::InvalidEncoding,
+                format!("a request of the batch is not a valid UTF-8: {e}"),
+            ));
+        }
+        let request = serde_json::from_slice(item).map_err(|e| match e.is_data() {
+            true => CommandError::new(ErrorCode::InvalidRequest, e.to_string()),
+            false => CommandError::new(ErrorCode::ParseError, "request is not a valid JSON"),
+        })?;
+        self.data.drain(..end);
+        self.scanned -= end;
+        Ok(request)
+    }
+}
+
+/// What is expected next in the payload array of a streamed batch.
+#[derive(Clone, Copy, PartialEq)]
+enum Expected {
+    /// The first request, or the end of an empty array.
+    FirstItem,
+
+    /// A request following a comma.
+    Item,
+
+    /// A comma or the end of the array following a request.
+    Delimiter,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::MAX_JSON_DEPTH;
+
+    const ID: &str = "67e55044-10b1-426f-9247-bb680e5fe0c8";
+
+    fn item(i: usize) -> String {
+        format!(
+            r#"{{"request_id": "{ID}", "command": "echo", "payload": {{"i": {i}, "s": "]}},\""}}}}"#
+        )
+    }
+
+    #[test]
+    fn test_find_head() {
+        let head = format!(
+            r#" {{"request_id": "{ID}", "command": "batch", "payload": [{}"#,
+            item(0)
+        );
+        let BatchHead::Found(request, offset) = find_head(head.as_bytes()) else {
+            panic!("Expected the head to be found");
+        };
+        assert_eq!(request.request_id.to_string(), ID);
+        assert_eq!(&head[offset..], item(0));
+
+        let incomplete = format!(r#"{{"request_id": "{ID}", "command": "ba"#);
+        assert!(matches!(
+            find_head(incomplete.as_bytes()),
+            BatchHead::Incomplete
+        ));
+        assert!(matches!(find_head(b""), BatchHead::Incomplete));
+
+        let unsupported = [
+            format!(r#"{{"command": "batch", "payload": [], "request_id": "{ID}"}}"#),
+            format!(r#"{{"request_id": "{ID}", "command": "echo", "payload": []}}"#),
+            format!(r#"{{"request_id": "{ID}", "command": "batch", "payload": {{}}}}"#),
+            format!(r#"{{"request_id": "{ID}", "command": "batch"}}"#),
+            "[]".to_string(),
+        ];
+        for data in unsupported {
+            assert!(
+                matches!(find_head(data.as_bytes()), BatchHead::Unsupported),
+                "{data}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_splitter() {
+        let items: Vec<String> = (0..1000).map(item).collect();
+        let data = format!("{} ] \n}}", items.join(" ,\n"));
+
+        let mut splitter = BatchSplitter::new(Vec::new(), MAX_JSON_DEPTH);
+        let mut received = Vec::new();
+        for chunk in data.as_bytes().chunks(7) {
+            splitter.push(chunk);
+            while let Some(request) = splitter.next_item().unwrap() {
+                received.push(request);
+            }
+        }
+        assert!(splitter.is_finished());
+        assert_eq!(received.len(), 1000);
+        for (i, request) in received.iter().enumerate() {
+            let Command::Echo(payload) = &request.command else {
+                panic!("Expected an echo request");
+            };
+            assert_eq!(serde_json::to_value(payload).unwrap()["i"], i);
+        }
+        // only a single request is kept at once, rather than the whole batch
+        assert!(splitter.peak_buffered() < item(999).len() + 16);
+        assert!(data.len() > 100 * splitter.peak_buffered());
+
+        let mut empty = BatchSplitter::new(b" ]}".to_vec(), MAX_JSON_DEPTH);
+        assert!(empty.next_item().unwrap().is_none());
+        assert!(empty.is_finished());
+    }
+
+    #[test]
+    fn test_splitter_errors() {
+        let error = |data: String| {
+            let mut splitter = BatchSplitter::new(data.into_bytes(), MAX_JSON_DEPTH);
+            loop {
+                match splitter.next_item() {
+                    Ok(Some(_)) => continue,
+                    Ok(None) => panic!("Expected an error"),
+                    Err(e) => return e.code,
+                }
+            }
+        };
+        let (first, second) = (item(1), item(2));
+        assert_eq!(
+            error(format!("{first},,{second}]}}")),
+            ErrorCode::ParseError
+        );
+        assert_eq!(error(format!("{first},]}}")), ErrorCode::ParseError);
+        assert_eq!(error(format!("{first} {second}]}}")), ErrorCode::ParseError);
+        assert_eq!(error(format!(",{first}]}}")), ErrorCode::ParseError);
+        assert_eq!(
+            error(r#"{"command": "ping"}]}"#.to_string()),
+            ErrorCode::InvalidRequest
+        );
+        assert_eq!(error("1]}".to_string()), ErrorCode::InvalidRequest);
+        assert_eq!(
+            error(format!(r#"{first}], "metadata": 1}}"#)),
+            ErrorCode::InvalidRequest
+        );
+
+        // the nesting of the batch adds up with the one of the request
+        let mut splitter = BatchSplitter::new(br#"{"a": [[1]]}]}"#.to_vec(), 4);
+        let Err(e) = splitter.next_item() else {
+            panic!("Expected an error");
+        };
+        assert_eq!(e.code, ErrorCode::NestingTooDeep);
+    }
+}
@@ -0,0 +1,80 @@
+use aes_gcm::aead::Aead;
+use aes_gcm::{Aes256Gcm, KeyInit, Nonce};
+use rand::RngCore;
+use serde_json::Value;
+
+use crate::types::{CommandError, ErrorCode};
+
+/// The size of the nonce prepended to each ciphertext, in bytes.
+const NONCE_LEN: usize = 12;
+
+/// The size of the authentication tag appended to each ciphertext, in bytes.
+const TAG_LEN: usize = 16;
+
+/// The AES-256-GCM cipher of a connection, used by `Command::Encrypt` and `Command::Decrypt`.
+///
+/// The key is generated randomly along with the cipher and never leaves the process:
+/// it isn't logged, persisted or sent to the client, and it's dropped with the connection,
+/// so a ciphertext can only be decrypted on the connection that has produced it.
+pub struct SessionCipher {
+    cipher: Aes256Gcm,
+}
+
+impl SessionCipher {
+    pub fn new() -> Self {
+        let mut key = [0; 32];
+        rand::rng().fill_bytes(&mut key);
+        SessionCipher {
+            cipher: Aes256Gcm::new(&key.into()),
+        }
+    }
+
+    /// Encrypts the value serialized into JSON, returning the hex-encoded random nonce
+    /// followed by the ciphertext and its authentication tag.
+    pub fn encrypt(&self, data: &Value) -> String {
+        let mut nonce = [0; NONCE_LEN];
+        rand::rng().fill_bytes(&mut nonce);
+        let plaintext = serde_json::to_vec(data).expect("values are serializable");
+        let ciphertext = self
+            .cipher
+            .encrypt(Nonce::from_slice(&nonce), plaintext.as_slice())
+            .expect("the plaintext is within the limits of AES-GCM");
+        nonce
+            .iter()
+            .chain(&ciphertext)
+            .map(|b| format!("{b:02x}"))
+            .collect()
+    }
+
+    /// Decrypts the value encrypted by `encrypt`.
+    ///
+    /// # Errors
+    /// Fails with `ErrorCode::InvalidArgument` if the data is not a hex string,
+    /// and with `ErrorCode::DecryptionFailed` if it's too short or fails the authentication,
+    /// e.g. if it has been modified or encrypted on another connection.
+    pub fn decrypt(&self, data: &str) -> Result<Value, CommandError> {
+        let bytes = decode_hex(data).ok_or_else(|| {
+            CommandError::new(ErrorCode::InvalidArgument, "data must be a hex string")
+        })?;
+        let failed = || CommandError::new(ErrorCode::DecryptionFailed, "decryption failed");
+        if bytes.len() < NONCE_LEN + TAG_LEN {
+            return Err(failed());
+        }
+        let (nonce, ciphertext) = bytes.split_at(NONCE_LEN);
+        let plaintext = self
+            .cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| failed())?;
+        Ok(serde_json::from_slice(&plaintext).expect("only serialized values are encrypted"))
+    }
+}
+
+fn decode_hex(data: &str) -> Option<Vec<u8>> {
+    if !data.bytes().all(|b| b.is_ascii_hexdigit()) || !data.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..data.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&data[i..i + 2], 16).ok())
+        .collect()
+}
@@ -1,43 +1,158 @@
 use anyhow::{Result, anyhow};
-use chrono::Utc;
-use log::info;
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use log::{LevelFilter, error, info, warn};
 use serde_json::{Value, json};
-use std::sync::{Arc, Mutex};
+use std::cell::RefCell;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use uuid::Uuid;
 
+use crate::apidoc;
+use crate::expression;
+use crate::flatten;
+use crate::jsondiff;
+use crate::numbers;
+use crate::procinfo::ProcInfo;
+use crate::schema;
+use crate::text;
 use crate::types::*;
 
 /// Processes a deserialized request, updates the performance metrics,
 /// and returns a formed response object.
 ///
+/// If the response to a request with the same ID and command from the same client
+/// (see `with_client`) is still in the `ServerState::response_cache`, the cached response
+/// is returned instead, and the request is not processed. The responses to the administrative
+/// commands are never cached.
+///
+/// If the request chaining is enabled for the connection, the request is appended to the chain,
+/// and the response carries the hash of the previous request (see `with_request_chain`).
+///
 /// # Parameters:
 /// - `request`: The deseriazized request to process.
-/// - `metrics`: A shared thread-safe pointer to the global `Metrics` instance.
+/// - `state`: A shared pointer to the global `ServerState` instance.
 ///
 /// # Returns:
 /// A formed `Response` object representing either a successful result or an error.
-pub async fn form_response(request: Request, metrics: Arc<Mutex<Metrics>>) -> Response {
+pub async fn form_response(request: Request, state: Arc<ServerState>) -> Response {
+    // the request is chained before its nested requests are processed
+    let prev_hash = chain_request(&request);
+
+    if let Err(e) = count_request(state.config.max_requests_per_connection) {
+        warn!(
+            "Request {} exceeds the quota of its connection",
+            request.request_id
+        );
+        return Response::Error {
+            request_id: Some(request.request_id),
+            code: e.code,
+            error: e.to_string(),
+            retry_after_ms: None,
+            metadata: request.metadata,
+            prev_hash,
+        };
+    }
+
+    // a repeated request is answered from the cache without being processed again
+    let cache_key = (!request.command.kind().is_admin()
+        && state.response_cache.lock().unwrap().is_enabled())
+    .then(|| {
+        let (command, transform) = (&request.command, request.transform);
+        CacheKey::new(request.request_id, &current_client(), command, transform)
+    });
+    let cached = cache_key.and_then(|key| {
+        let cache = state.response_cache.lock().unwrap();
+        cache.get(&key, Instant::now()).cloned()
+    });
+    if let Some(response) = cached {
+        info!(
+            "Request {} has already been processed, returning the cached response",
+            request.request_id
+        );
+        return check_if_none_match(response, request.if_none_match.as_deref())
+            .with_prev_hash(prev_hash);
+    }
+
+    // replaying a replay would be of no use
+    if !matches!(request.command, Command::Replay { .. }) {
+        let mut recent = state.recent_requests.lock().unwrap();
+        recent.insert(request.request_id, request.command.clone());
+    }
+
     let mut start = None;
-    if !matches!(request.command, Command::Batch(_)) {
-        start = Some(std::time::Instant::now());
+    // the nested requests are measured on their own
+    if !matches!(
+        request.command,
+        Command::Batch(_) | Command::RunMacro { .. } | Command::Replay { .. }
+    ) {
+        start = Some(Instant::now());
     }
 
     let uuid = request.request_id;
+    let metadata = request.metadata;
+    let if_none_match = request.if_none_match;
     let command_kind = request.command.kind();
-    let response = match process_command(request, metrics.clone()).await {
+    let _active = ActiveRequestGuard::new(state.clone(), uuid, command_kind);
+    let started = Instant::now();
+    let result = match (request.transform, request.command) {
+        (None, command) => process_command(command, state.clone()).await,
+        (Some(transform), Command::Echo(value)) => process_command_echo_transform(value, transform),
+        (Some(_), _) => Err(CommandError::new(
+            ErrorCode::InvalidArgument,
+            "the `transform` field is only supported by the `echo` command",
+        )
+        .into()),
+    };
+    let response = match result {
         Ok(v) => Response::Ok {
             request_id: uuid,
+            etag: state.config.content_hash.then(|| content_hash(&v)),
             response: v,
+            metadata,
+            prev_hash: None,
         },
-        Err(e) => Response::Error {
-            request_id: Some(uuid),
-            error: e.to_string(),
+        Err(e) => {
+            let error = e.downcast_ref::<CommandError>();
+            let code = error.map_or(ErrorCode::CommandFailed, |e| e.code);
+            record_last_error(LastError {
+                request_id: uuid,
+                command: command_kind,
+                code,
+                error: e.to_string(),
+            });
+            Response::Error {
+                request_id: Some(uuid),
+                code,
+                error: e.to_string(),
+                retry_after_ms: error.and_then(|e| e.retry_after_ms),
+                metadata,
+                prev_hash: None,
+            }
+        }
+    };
+
+    let record = RequestRecord {
+        request_id: uuid,
+        command: command_kind,
+        ok: matches!(response, Response::Ok { .. }),
+        code: match &response {
+            Response::Ok { .. } => None,
+            Response::Error { code, .. } => Some(*code),
         },
+        duration_ms: started.elapsed().as_micros() as f64 / 1000.0,
     };
+    state.request_log.lock().unwrap().push(record);
 
     if let Some(s) = start {
         let duration = s.elapsed().as_micros() as f64 / 1000.0;
         let count = {
-            let mut guard = metrics.lock().unwrap();
+            let mut guard = state.lock_metrics();
             guard.update(command_kind, duration);
             *guard.command_counts.get(&command_kind).unwrap()
         };
@@ -46,155 +161,1562 @@ pub async fn form_response(request: Request, metrics: Arc<Mutex<Metrics>>) -> Re
             command_kind, duration, count
         );
     };
-    response
+
+    if let Some(key) = cache_key {
+        let mut cache = state.response_cache.lock().unwrap();
+        cache.insert(key, response.clone(), Instant::now());
+    }
+    check_if_none_match(response, if_none_match.as_deref()).with_prev_hash(prev_hash)
+}
+
+/// Replaces a successful response with an `ErrorCode::NotModified` error if its `etag`
+/// is the one the client already has (see `Request::if_none_match`).
+///
+/// This is done after the response is cached, so that a repeated request gets the result
+/// unless it has the matching `if_none_match` itself.
+fn check_if_none_match(response: Response, if_none_match: Option<&str>) -> Response {
+    match response {
+        Response::Ok {
+            request_id,
+            metadata,
+            etag: Some(etag),
+            ..
+        } if if_none_match == Some(etag.as_str()) => Response::Error {
+            request_id: Some(request_id),
+            code: ErrorCode::NotModified,
+            error: format!("the result is not modified since etag {etag}"),
+            retry_after_ms: None,
+            metadata,
+            prev_hash: None,
+        },
+        response => response,
+    }
+}
+
+/// Registers a request in `ServerState::active_requests` for as long as it's alive.
+///
+/// The request is unregistered on drop, so this also covers processing
+/// that ends with an error, a panic, or an abort.
+struct ActiveRequestGuard {
+    state: Arc<ServerState>,
+    request_id: Uuid,
+}
+
+impl ActiveRequestGuard {
+    fn new(state: Arc<ServerState>, request_id: Uuid, command_kind: CommandKind) -> Self {
+        let request = ActiveRequest {
+            command_kind,
+            started: Instant::now(),
+        };
+        state
+            .active_requests
+            .lock()
+            .unwrap()
+            .insert(request_id, request);
+        ActiveRequestGuard { state, request_id }
+    }
+}
+
+impl Drop for ActiveRequestGuard {
+    fn drop(&mut self) {
+        // panicking while already unwinding would abort the whole server
+        if let Ok(mut active) = self.state.active_requests.lock() {
+            active.remove(&self.request_id);
+        }
+    }
+}
+
+tokio::task_local! {
+    /// Whether the request being processed may use the administrative commands.
+    static ADMIN: bool;
+}
+
+/// Runs the given future with the administrative commands allowed or not.
+///
+/// The requests nested into the one processed by the future, such as the items of a batch,
+/// inherit the permission.
+pub async fn with_admin<F: Future>(admin: bool, future: F) -> F::Output {
+    ADMIN.scope(admin, future).await
+}
+
+tokio::task_local! {
+    /// The client whose request is being processed, which its cached responses are scoped to.
+    static CLIENT: ClientIdentity;
+}
+
+/// Runs the given future on behalf of the given client, including the requests nested
+/// into the one processed by the future.
+///
+/// The requests processed outside of it are treated as the ones of an unknown client.
+pub async fn with_client<F: Future>(client: ClientIdentity, future: F) -> F::Output {
+    CLIENT.scope(client, future).await
+}
+
+/// Returns the client whose request is being processed (see `with_client`).
+fn current_client() -> ClientIdentity {
+    CLIENT.try_with(Clone::clone).unwrap_or_default()
+}
+
+tokio::task_local! {
+    /// The `RequestChain` of the connection whose request is being processed,
+    /// if the client has enabled the chaining.
+    static CHAIN: RefCell<Option<RequestChain>>;
+}
+
+/// Runs the given future with a new `RequestChain` if `enabled` is set, which all of the requests
+/// processed in it, including the nested ones, are appended to.
+pub async fn with_request_chain<F: Future>(enabled: bool, future: F) -> F::Output {
+    let chain = enabled.then(RequestChain::default);
+    CHAIN.scope(RefCell::new(chain), future).await
+}
+
+/// Appends the request to the `RequestChain` of the connection, if it's enabled,
+/// and returns the hash of the previous request.
+fn chain_request(request: &Request) -> Option<String> {
+    CHAIN
+        .try_with(|chain| chain.borrow_mut().as_mut()?.push(request))
+        .ok()?
+}
+
+tokio::task_local! {
+    /// The last request that has failed on the connection whose request is being processed.
+    static LAST_ERROR: RefCell<Option<LastError>>;
+}
+
+/// Runs the given future remembering the last request that fails in it, including the nested
+/// ones, for `Command::LastError`.
+pub async fn with_last_error<F: Future>(future: F) -> F::Output {
+    LAST_ERROR.scope(RefCell::new(None), future).await
+}
+
+/// Remembers the failed request, if it's processed within `with_last_error`.
+fn record_last_error(error: LastError) {
+    let _ = LAST_ERROR.try_with(|last| last.replace(Some(error)));
+}
+
+tokio::task_local! {
+    /// The requests of the batch that is still being received on the connection
+    /// whose request is being processed, until the batch takes them.
+    static STREAMED_BATCH: RefCell<Option<mpsc::Receiver<Request>>>;
+}
+
+/// Runs the given future, which processes a batch without requests, with the requests
+/// of the batch being sent to it as soon as they're received.
+///
+/// The batch processes the requests in the order they're sent, until the sender is dropped.
+pub async fn with_streamed_batch<F: Future>(
+    requests: mpsc::Receiver<Request>,
+    future: F,
+) -> F::Output {
+    STREAMED_BATCH
+        .scope(RefCell::new(Some(requests)), future)
+        .await
+}
+
+tokio::task_local! {
+    /// The request received on the connection whose request is being processed.
+    static RAW_REQUEST: RawRequest;
+}
+
+/// Runs the given future with the request received on the connection, as it was parsed,
+/// available to `Command::Inspect`.
+pub async fn with_raw_request<F: Future>(raw: RawRequest, future: F) -> F::Output {
+    RAW_REQUEST.scope(raw, future).await
+}
+
+tokio::task_local! {
+    /// The session of the connection whose request is being processed.
+    static SESSION: RefCell<Session>;
+}
+
+/// Runs the given future with a new `Session`, which all of the requests processed in it,
+/// including the nested ones, share.
+///
+/// Once the future completes, the statistics of the session are persisted,
+/// if `Command::SessionStats` has bound them to a file.
+pub async fn with_new_session<F: Future>(future: F) -> F::Output {
+    let future = async {
+        let output = future.await;
+        let stats = SESSION.with(|session| {
+            let session = session.borrow();
+            session.stats_file.clone().map(|file| (file, session.stats))
+        });
+        if let Some((file, stats)) = stats
+            && let Err(e) = persist_session_stats(&file, &stats).await
+        {
+            let file = file.display();
+            error!("Couldn't persist session statistics to {file}: {e}");
+        }
+        output
+    };
+    SESSION
+        .scope(RefCell::new(Session::default()), future)
+        .await
+}
+
+/// Calls the function with the session of the connection.
+///
+/// # Errors
+/// Fails with `ErrorCode::Internal` if the request is not processed within `with_new_session`.
+fn with_session<T>(
+    f: impl FnOnce(&mut Session) -> Result<T, CommandError>,
+) -> Result<T, CommandError> {
+    SESSION
+        .try_with(|session| f(&mut session.borrow_mut()))
+        .unwrap_or_else(|_| {
+            Err(CommandError::new(
+                ErrorCode::Internal,
+                "the request has no session",
+            ))
+        })
+}
+
+tokio::task_local! {
+    /// The statistics of the compression of the connection whose request is being processed.
+    static CONNECTION_COMPRESSION: Arc<CompressionStats>;
+}
+
+/// Runs the given future with the statistics of the compression of its connection,
+/// which `Command::Stats` reports.
+pub async fn with_connection_compression<F: Future>(
+    stats: Arc<CompressionStats>,
+    future: F,
+) -> F::Output {
+    CONNECTION_COMPRESSION.scope(stats, future).await
+}
+
+tokio::task_local! {
+    /// The number of requests processed on the connection whose request is being processed.
+    static REQUEST_COUNT: Arc<AtomicUsize>;
+}
+
+/// Runs the given future counting the requests processed in it, including the nested ones,
+/// against `Config::max_requests_per_connection`. The count is shared with
+/// `ServerState::connections`, which `Command::Connections` reports.
+pub async fn with_request_count<F: Future>(count: Arc<AtomicUsize>, future: F) -> F::Output {
+    REQUEST_COUNT.scope(count, future).await
+}
+
+/// Counts the request, if it's processed within `with_request_count`.
+///
+/// # Errors
+/// Fails with `ErrorCode::ConnectionQuotaExceeded` if the connection has already processed
+/// `limit` requests. A `limit` of zero means no limit.
+fn count_request(limit: usize) -> Result<(), CommandError> {
+    let Ok(count) = REQUEST_COUNT.try_with(|count| count.fetch_add(1, Ordering::Relaxed)) else {
+        return Ok(());
+    };
+    if limit > 0 && count >= limit {
+        return Err(CommandError::new(
+            ErrorCode::ConnectionQuotaExceeded,
+            format!(
+                "connection has processed its quota of {limit} requests, reconnect to send more"
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Whether the response is the failure of a request exceeding the quota of the connection,
+/// after which the rest of the nested requests, e.g. of a batch, are not processed.
+fn exceeds_quota(response: &Response) -> bool {
+    matches!(
+        response,
+        Response::Error {
+            code: ErrorCode::ConnectionQuotaExceeded,
+            ..
+        }
+    )
+}
+
+/// Checks that the request being processed may use the administrative commands.
+///
+/// # Errors
+/// Fails with `ErrorCode::Unauthorized` if it may not, or if the request is not processed
+/// within `with_admin`.
+fn check_admin() -> Result<(), CommandError> {
+    if !ADMIN.try_with(|admin| *admin).unwrap_or(false) {
+        return Err(CommandError::new(
+            ErrorCode::Unauthorized,
+            "the command requires the administrative token",
+        ));
+    }
+    Ok(())
 }
 
-async fn process_command(request: Request, metrics: Arc<Mutex<Metrics>>) -> Result<Value> {
-    match request.command {
+tokio::task_local! {
+    /// Whether the request being processed is a step of a macro.
+    static IN_MACRO: bool;
+
+    /// Whether the request being processed is executed by a selftest.
+    static IN_SELFTEST: bool;
+}
+
+/// Checks that the given command name is supported by the server.
+///
+/// This is used to report unknown commands with a clean `ErrorCode::UnknownCommand` error
+/// instead of a verbose deserialization failure.
+pub fn check_command_name(name: &str) -> Result<(), CommandError> {
+    name.parse::<CommandKind>().map(|_| ())
+}
+
+async fn process_command(command: Command, state: Arc<ServerState>) -> Result<Value> {
+    // checked here to cover the commands nested into batches, pipes and selftests as well
+    let kind = command.kind();
+    if state.config.disabled_commands.contains(&kind) {
+        return Err(CommandError::new(
+            ErrorCode::CommandDisabled,
+            format!("command `{}` is disabled on this server", kind.name()),
+        )
+        .into());
+    }
+    // held until the command is executed
+    let _permit = (state.concurrency_limits.acquire(kind).await)
+        .map_err(|e| e.with_retry_after(state.busy_retry_after_ms(kind)))?;
+
+    match command {
         Command::Ping => Ok(json!("pong")),
         Command::Echo(payload) => Ok(payload),
         Command::Time => {
             let time = Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
             Ok(json!({"time": time}))
         }
-        Command::Calculate { operation, a, b } => process_command_calculate(operation, a, b).await,
-        Command::Batch(batch) => {
-            let mut result: Vec<Response> = Vec::new();
-            for item in batch {
-                result.push(Box::pin(form_response(item, metrics.clone())).await);
+        Command::TimeZone { tz } => {
+            let zone: Tz = tz.parse().map_err(|_| {
+                CommandError::new(
+                    ErrorCode::InvalidArgument,
+                    format!("unknown time zone `{tz}`"),
+                )
+            })?;
+            let time = Utc::now().with_timezone(&zone);
+            let time = time.to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
+            Ok(json!({"time": time, "tz": zone.name()}))
+        }
+        Command::Calculate {
+            operation,
+            a,
+            b,
+            precision,
+        } => process_command_calculate(operation, a, b, precision).await,
+        Command::Batch(batch) => process_command_batch(batch, state).await,
+        Command::ServerInfo => Ok(process_command_serverinfo(&state.config)),
+        Command::CommandStatus => Ok(process_command_commandstatus(&state.config)),
+        Command::Pipe { stages } => process_command_pipe(stages, state).await,
+        Command::Eval { expression: expr } => {
+            let result = expression::evaluate(&expr)?;
+            warn_non_finite([result]);
+            Ok(json!({"result": result}))
+        }
+        Command::Stats => Ok(process_command_stats(&state)),
+        Command::ActiveRequests => {
+            check_admin()?;
+            Ok(process_command_activerequests(&state))
+        }
+        Command::ProcInfo => {
+            check_admin()?;
+            Ok(json!(ProcInfo::collect()))
+        }
+        Command::BaseConvert {
+            value,
+            from_base,
+            to_base,
+        } => Ok(json!({"result": numbers::convert_base(&value, from_base, to_base)?})),
+        Command::Noop => Ok(json!({})),
+        Command::SelfTest {
+            command,
+            iterations,
+        } => {
+            check_admin()?;
+            process_command_selftest(*command, iterations, state).await
+        }
+        Command::NumericLimits => Ok(process_command_numericlimits()),
+        Command::NumberTheory { operation, a, b } => {
+            let result = match operation {
+                NumOp::Gcd => numbers::gcd(a, b),
+                NumOp::Lcm => numbers::lcm(a, b)?,
+            };
+            Ok(json!({"result": result}))
+        }
+        Command::LatencyHistogram { command, buckets } => {
+            Ok(process_command_latencyhistogram(&state, command, buckets))
+        }
+        Command::ForceError { code, message } => {
+            check_admin()?;
+            let message = message.unwrap_or_else(|| forced_error_message(code));
+            Err(CommandError::new(code, message).into())
+        }
+        Command::DefineMacro { name, steps } => {
+            let count = steps.len();
+            let client = current_client();
+            let mut macros = state.macros.lock().unwrap();
+            macros.define(client.auth(), name.clone(), steps)?;
+            Ok(json!({"name": name, "steps": count}))
+        }
+        Command::RunMacro { name } => process_command_runmacro(&name, state).await,
+        Command::Describe { values } => {
+            let d = numbers::describe(&values)?;
+            warn_non_finite([d.mean, d.median, d.stddev, d.min, d.max]);
+            Ok(json!(d))
+        }
+        Command::StreamRange { start, end } => {
+            // the handler sends the integers one by one unless the command is nested
+            if start > end || end.abs_diff(start) > MAX_STREAM_RANGE {
+                return Err(CommandError::new(
+                    ErrorCode::InvalidArgument,
+                    format!(
+                        "start must not be greater than end, and the range must contain \
+                        at most {MAX_STREAM_RANGE} integers"
+                    ),
+                )
+                .into());
             }
-            Ok(json!(result))
+            Ok(json!((start..end).collect::<Vec<_>>()))
+        }
+        Command::ProtocolVersion => Ok(json!({"version": PROTOCOL_VERSION})),
+        Command::LastError => {
+            let last_error = LAST_ERROR
+                .try_with(|last| last.borrow().clone())
+                .ok()
+                .flatten();
+            Ok(json!({"last_error": last_error}))
+        }
+        Command::Replay { request_id } => {
+            check_admin()?;
+            process_command_replay(request_id, state).await
+        }
+        Command::MatrixMul { a, b } => process_command_matrixmul(a, b).await,
+        Command::SlowEcho {
+            payload,
+            bytes_per_sec,
+        } => process_command_slowecho(payload, bytes_per_sec),
+        Command::SeededRandom { seed, count } => {
+            Ok(json!({"values": numbers::seeded_random(seed, count)?}))
+        }
+        Command::DateFormat { input, from, to } => {
+            Ok(json!({"result": text::reformat_date(&input, &from, &to)?}))
+        }
+        Command::LogLevel { level } => process_command_loglevel(level),
+        Command::SessionStats { values, session_id } => {
+            process_command_sessionstats(values, session_id, &state.config).await
+        }
+        Command::StringDistance { a, b } => Ok(json!({"distance": text::levenshtein(&a, &b)?})),
+        Command::Canonicalize { data } => Ok(json!({"result": canonicalize(data).to_string()})),
+        Command::SchemaValidate { schema, instance } => {
+            Ok(json!(schema::validate(&schema, &instance)?))
+        }
+        Command::NormalizeRequest { request } => Ok(json!(normalize_request(*request))),
+        Command::Pad { size } => process_command_pad(size, &state.config),
+        Command::TimeDiff { from, to, unit } => Ok(json!({"result": unit.between(from, to)})),
+        Command::Math { function, x } => {
+            let result = numbers::math(function, x)?;
+            warn_non_finite([result]);
+            Ok(json!({"result": result}))
+        }
+        Command::SessionSet { key, value } => {
+            let entries = with_session(|session| {
+                session.set(key.clone(), value)?;
+                Ok(session.len())
+            })?;
+            Ok(json!({"key": key, "entries": entries}))
+        }
+        Command::SessionGet { key } => Ok(with_session(|session| session.get(&key).cloned())?),
+        Command::Percentile { values, q } => {
+            Ok(json!({"result": numbers::percentile(&values, q)?}))
+        }
+        Command::ApiDescribe => Ok(json!(apidoc::describe_api())),
+        Command::Inspect => {
+            let raw = RAW_REQUEST.try_with(|raw| json!(raw)).map_err(|_| {
+                CommandError::new(ErrorCode::NotFound, "the raw request is not available")
+            })?;
+            Ok(raw)
+        }
+        Command::MetricsSnapshot => {
+            let snapshot = state.lock_metrics().snapshot(unix_millis());
+            Ok(json!(snapshot))
+        }
+        Command::StatsSince { since } => process_command_statssince(since, &state),
+        Command::SubscribeMetrics { interval_ms } => {
+            // the handler keeps sending the snapshots unless the command is nested
+            let bounds = MIN_SUBSCRIPTION_INTERVAL_MS..=MAX_SUBSCRIPTION_INTERVAL_MS;
+            if !bounds.contains(&interval_ms) {
+                return Err(CommandError::new(
+                    ErrorCode::InvalidArgument,
+                    format!(
+                        "interval_ms must be from {MIN_SUBSCRIPTION_INTERVAL_MS} \
+                        to {MAX_SUBSCRIPTION_INTERVAL_MS}"
+                    ),
+                )
+                .into());
+            }
+            let snapshot = state.lock_metrics().snapshot(unix_millis());
+            Ok(json!(snapshot))
+        }
+        Command::MetricsSeries { points } => {
+            let history = state.metrics_history.lock().unwrap();
+            Ok(json!({
+                "interval_secs": state.config.metrics_snapshot_interval_secs,
+                "snapshots": history.recent(points as usize),
+            }))
+        }
+        Command::RecentRequests { limit } => {
+            check_admin()?;
+            let log = state.request_log.lock().unwrap();
+            Ok(json!({"requests": log.recent(limit as usize)}))
+        }
+        Command::Connections => {
+            check_admin()?;
+            Ok(process_command_connections(&state))
+        }
+        Command::CloseConnection { peer } => {
+            check_admin()?;
+            process_command_closeconnection(&peer, &state)
+        }
+        Command::Flatten { data } => Ok(json!({"result": flatten::flatten(data)?})),
+        Command::Unflatten { data } => {
+            let max_depth = state.config.max_json_depth;
+            Ok(json!({"result": flatten::unflatten(data, max_depth)?}))
+        }
+        Command::Sample {
+            values,
+            n,
+            replacement,
+            seed,
+        } => Ok(json!({"values": numbers::sample(&values, n, replacement, seed)?})),
+        Command::Crc32 { data } => Ok(json!({"crc32": crc32fast::hash(data.as_bytes())})),
+        Command::JsonDiff { left, right } => Ok(json!(jsondiff::diff(left, right))),
+        Command::ErrorCodes => {
+            let codes: Vec<Value> = ErrorCode::ALL
+                .iter()
+                .map(|code| json!({"code": code, "description": code.description()}))
+                .collect();
+            Ok(json!({"codes": codes}))
+        }
+        Command::Encrypt { data } => {
+            let data = with_session(|session| Ok(session.cipher().encrypt(&data)))?;
+            Ok(json!({"data": data}))
+        }
+        Command::Decrypt { data } => {
+            let data = with_session(|session| session.cipher().decrypt(&data))?;
+            Ok(json!({"data": data}))
+        }
+        Command::BuildInfo => Ok(json!(BuildInfo::CURRENT)),
+        Command::MapCalculate { operation, pairs } => {
+            let results: Vec<_> = pairs
+                .into_iter()
+                .map(|(a, b)| calculate(&operation, a, b))
+                .collect();
+            warn_non_finite(results.iter().filter_map(|r| r.as_ref().ok().copied()));
+            let results: Vec<Value> = results
+                .into_iter()
+                .map(|result| match result {
+                    Ok(result) => json!({"result": result}),
+                    Err(e) => json!({"code": e.code, "error": e.message}),
+                })
+                .collect();
+            Ok(json!({"results": results}))
+        }
+        Command::Vector { operation, a, b } => process_command_vector(operation, a, b),
+        Command::Typeof { data } => Ok(process_command_typeof(&data)),
+        Command::QueueDepth => Ok(process_command_queuedepth(&state)),
+        Command::Uptime => {
+            let uptime_ms = state.started.instant.elapsed().as_millis() as u64;
+            let started_at = state.started.time;
+            let started_at = started_at.to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
+            Ok(json!({"uptime_ms": uptime_ms, "started_at": started_at}))
         }
     }
 }
 
-async fn process_command_calculate(operation: Operation, a: f64, b: f64) -> Result<Value> {
-    let result = match operation {
+async fn process_command_calculate(
+    operation: Operation,
+    a: Operand,
+    b: Operand,
+    precision: Option<u32>,
+) -> Result<Value> {
+    let a = a.value("a")?;
+    let b = b.value("b")?;
+    let result = calculate(&operation, a, b)?;
+
+    let result = match precision {
+        Some(p) => numbers::round_decimal(result, p)?,
+        None => result,
+    };
+    warn_non_finite([result]);
+    Ok(json!({"result": result}))
+}
+
+fn process_command_vector(operation: VectorOp, a: Vec<f64>, b: Option<Vec<f64>>) -> Result<Value> {
+    let result = match (operation, b) {
+        (VectorOp::Dot, Some(b)) => {
+            let dot = numbers::dot(&a, &b)?;
+            warn_non_finite([dot]);
+            json!(dot)
+        }
+        (VectorOp::Dot, None) => {
+            return Err(CommandError::new(
+                ErrorCode::InvalidArgument,
+                "the dot product requires vector b",
+            )
+            .into());
+        }
+        (VectorOp::Norm, _) => {
+            let norm = numbers::norm(&a);
+            warn_non_finite([norm]);
+            json!(norm)
+        }
+        (VectorOp::Normalize, _) => {
+            let unit = numbers::normalize(&a)?;
+            warn_non_finite(unit.iter().copied());
+            json!(unit)
+        }
+    };
+    Ok(json!({"result": result}))
+}
+
+/// Performs the arithmetic operation of `Command::Calculate` and `Command::MapCalculate`.
+///
+/// Fails with `ErrorCode::DivisionByZero` if the operation is a division by zero.
+fn calculate(operation: &Operation, a: f64, b: f64) -> Result<f64, CommandError> {
+    Ok(match operation {
         Operation::Add => a + b,
         Operation::Subtract => a - b,
         Operation::Multiply => a * b,
         Operation::Divide => {
             if b == 0.0 {
-                return Err(anyhow!("division by zero"));
+                return Err(CommandError::new(
+                    ErrorCode::DivisionByZero,
+                    "division by zero",
+                ));
             }
             a / b
         }
-    };
+    })
+}
 
-    Ok(json!({"result": result}))
+/// Logs a warning if any of the numbers of a result is NaN or infinite.
+///
+/// JSON has no representation for them, so they are serialized as `null`,
+/// which hides e.g. an overflow from the client.
+fn warn_non_finite(numbers: impl IntoIterator<Item = f64>) {
+    let count = numbers.into_iter().filter(|n| !n.is_finite()).count();
+    if count > 0 {
+        warn!("The result has {count} non-finite number(s), returning null in their place.");
+    }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use chrono::Timelike;
-    use uuid::Uuid;
+fn process_command_slowecho(payload: Value, bytes_per_sec: u64) -> Result<Value> {
+    // the handler slows the response down unless the command is nested
+    let bytes = payload.to_string().len() as u64;
+    // rounded up, since even the last partial second counts against the limit
+    if bytes_per_sec < MIN_SLOWECHO_RATE || bytes.div_ceil(bytes_per_sec) > MAX_SLOWECHO_SECS {
+        return Err(CommandError::new(
+            ErrorCode::InvalidArgument,
+            format!(
+                "bytes_per_sec must be at least {MIN_SLOWECHO_RATE}, and sending the payload \
+                of {bytes} bytes must take at most {MAX_SLOWECHO_SECS} seconds"
+            ),
+        )
+        .into());
+    }
+    Ok(payload)
+}
 
-    fn build_metrics() -> Arc<Mutex<Metrics>> {
-        Arc::new(Mutex::new(Metrics::default()))
+fn process_command_pad(size: usize, config: &Config) -> Result<Value> {
+    // the string alone must fit, though the whole response is larger than that
+    let max = MAX_PAD_BYTES.min(config.max_response_bytes.unwrap_or(usize::MAX));
+    if size > max {
+        return Err(CommandError::new(
+            ErrorCode::InvalidArgument,
+            format!("size must be at most {max} bytes"),
+        )
+        .into());
     }
+    let data: String = (b'a'..=b'z').cycle().take(size).map(char::from).collect();
+    Ok(json!({"data": data}))
+}
 
-    fn build_request(command: Command) -> Request {
-        Request {
-            request_id: Uuid::new_v4(),
-            command: command,
-        }
+fn process_command_echo_transform(value: Value, transform: EchoTransform) -> Result<Value> {
+    match transform {
+        EchoTransform::Stringify => Ok(json!(value.to_string())),
+        EchoTransform::Keys => match value {
+            Value::Object(map) => Ok(json!(map.keys().collect::<Vec<_>>())),
+            _ => Err(CommandError::new(
+                ErrorCode::InvalidArgument,
+                "the `keys` transform requires the value to be an object",
+            )
+            .into()),
+        },
+        EchoTransform::Type => Ok(json!(json_type_name(&value))),
     }
+}
 
-    #[tokio::test]
-    async fn test_command_ping() {
-        let metrics = build_metrics();
-        let req = build_request(Command::Ping);
-        let uuid = req.request_id;
-        let resp = form_response(req, metrics.clone()).await;
-        match resp {
-            Response::Ok {
-                request_id,
-                response,
-            } => {
-                assert_eq!(request_id, uuid);
-                assert_eq!(response, json!("pong"));
-            }
-            Response::Error { .. } => panic!("Expected OK response"),
-        }
+/// Returns the name of the JSON type of the given value, e.g. `"object"`.
+pub fn json_type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
     }
+}
 
-    #[tokio::test]
-    async fn test_command_time() {
-        use chrono::DateTime;
+fn process_command_typeof(data: &Value) -> Value {
+    let size = match data {
+        Value::Array(values) => Some(values.len()),
+        Value::Object(entries) => Some(entries.len()),
+        Value::String(s) => Some(s.chars().count()),
+        _ => None,
+    };
+    // the command names a boolean the short way, unlike the `type` transform of echo
+    let name = match json_type_name(data) {
+        "boolean" => "bool",
+        name => name,
+    };
+    let mut result = json!({"type": name});
+    if let Some(size) = size {
+        result["size"] = json!(size);
+    }
+    result
+}
 
-        let metrics = build_metrics();
-        let time = Utc::now()
-            .with_nanosecond(0)
-            .expect("This shouldn't ever panic");
-        let req = build_request(Command::Time);
-        let uuid = req.request_id;
-        let resp = form_response(req, metrics.clone()).await;
-        match resp {
-            Response::Ok {
-                request_id,
-                response,
-            } => {
-                assert_eq!(request_id, uuid);
-                let resp_str = response
-                    .get("time")
-                    .and_then(|v| v.as_str())
-                    .expect("Missing `time` field in the response");
-                let parsed = DateTime::parse_from_rfc3339(resp_str)
-                    .expect("Invalid format")
-                    .with_timezone(&Utc);
-                println!("{}", parsed);
-                println!("{}", time);
-                assert!((parsed - time).as_seconds_f32() >= 0.0);
-                assert!((parsed - time).as_seconds_f32() < 2.0);
-            }
-            Response::Error { .. } => panic!("Expected OK response"),
-        }
+fn process_command_queuedepth(state: &ServerState) -> Value {
+    let depths: serde_json::Map<String, Value> = CommandKind::ALL
+        .iter()
+        .filter_map(|k| {
+            let depth = state.concurrency_limits.queue_depth(*k)?;
+            Some((k.name(), json!(depth)))
+        })
+        .collect();
+    Value::Object(depths)
+}
+
+fn process_command_commandstatus(config: &Config) -> Value {
+    let commands: serde_json::Map<String, Value> = CommandKind::ALL
+        .iter()
+        .map(|k| {
+            let status = json!({
+                "enabled": !config.disabled_commands.contains(k),
+                "concurrency_limit": config.command_concurrency.get(k),
+            });
+            (k.name(), status)
+        })
+        .collect();
+    Value::Object(commands)
+}
+
+fn process_command_loglevel(level: Option<String>) -> Result<Value> {
+    check_admin()?;
+    if let Some(level) = level {
+        let filter: LevelFilter = level.parse().map_err(|_| {
+            CommandError::new(
+                ErrorCode::InvalidArgument,
+                format!(
+                    "unknown log level `{level}`, expected one of \
+                    `off`, `error`, `warn`, `info`, `debug` and `trace`"
+                ),
+            )
+        })?;
+        // the logger filters the records by the global level only
+        log::set_max_level(filter);
+        info!("Log level changed to {filter}.");
     }
+    Ok(json!({"level": log::max_level().as_str().to_lowercase()}))
+}
 
-    #[tokio::test]
-    async fn test_command_echo() {
-        let metrics = build_metrics();
+/// The longest session ID that `Command::SessionStats` persists the statistics under.
+const MAX_SESSION_ID_LEN: usize = 64;
 
-        let req = build_request(Command::Echo(json!("hello")));
-        let uuid = req.request_id;
-        let resp = form_response(req, metrics.clone()).await;
-        match resp {
-            Response::Ok {
-                request_id,
-                response,
-            } => {
-                assert_eq!(request_id, uuid);
-                assert_eq!(response, json!("hello"));
-            }
-            Response::Error { .. } => panic!("Expected OK response"),
+async fn process_command_sessionstats(
+    values: Vec<f64>,
+    session_id: Option<String>,
+    config: &Config,
+) -> Result<Value> {
+    if let Some(id) = session_id {
+        let file = session_stats_file(&id, config)?;
+        let bound = with_session(|session| match &session.stats_file {
+            None => Ok(false),
+            Some(bound) if *bound == file => Ok(true),
+            Some(_) => Err(CommandError::new(
+                ErrorCode::InvalidArgument,
+                "the session is bound to another session_id already",
+            )),
+        })?;
+        // the statistics gathered before binding are added to the resumed ones
+        if !bound {
+            let stored = load_session_stats(&file).await?;
+            with_session(|session| {
+                session.stats.merge(&stored);
+                session.stats_file = Some(file);
+                Ok(())
+            })?;
         }
     }
+    let stats = with_session(|session| {
+        values.iter().for_each(|&value| session.stats.push(value));
+        Ok(session.stats)
+    })?;
+    let mean = (stats.count > 0).then_some(stats.mean);
+    Ok(json!({"count": stats.count, "mean": mean, "variance": stats.variance()}))
+}
 
-    #[tokio::test]
-    async fn test_command_calculate() {
-        #[rustfmt::skip]
-        let test_data = Vec::from([
-            (Operation::Add, 0.1, 0.2, 0.1 + 0.2),
-            (Operation::Subtract, 21.0, 9.0, 21.0 - 9.0),
-            (Operation::Multiply, 6.0, -8.0, 6.0 * -8.0),
-            (Operation::Divide, 22.0, 7.0, 22.0 / 7.0),
-        ]);
+/// Returns the file the statistics of the session with the given ID are persisted to.
+///
+/// # Errors
+/// Fails with `ErrorCode::InvalidArgument` if the persistence is disabled,
+/// or if the ID is empty, too long, or contains anything but ASCII letters, digits,
+/// `-` and `_`, which keeps it a plain file name.
+fn session_stats_file(id: &str, config: &Config) -> Result<PathBuf, CommandError> {
+    let invalid = |message: String| CommandError::new(ErrorCode::InvalidArgument, message);
+    let Some(dir) = &config.session_stats_dir else {
+        return Err(invalid(
+            "session statistics are not persisted by the server".to_string(),
+        ));
+    };
+    let valid = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+    if id.is_empty() || id.len() > MAX_SESSION_ID_LEN || !id.chars().all(valid) {
+        return Err(invalid(format!(
+            "session_id must consist of 1 to {MAX_SESSION_ID_LEN} ASCII letters, digits, \
+            `-` and `_`"
+        )));
+    }
+    Ok(dir.join(format!("{id}.json")))
+}
+
+/// Reads the statistics persisted to the file, which are empty if there's no such file.
+async fn load_session_stats(file: &Path) -> Result<RunningStats> {
+    let data = match tokio::fs::read(file).await {
+        Ok(v) => v,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(RunningStats::default()),
+        Err(e) => return Err(anyhow!("couldn't read the persisted statistics: {e}")),
+    };
+    serde_json::from_slice(&data)
+        .map_err(|e| anyhow!("the persisted statistics are corrupted: {e}"))
+}
+
+/// Writes the statistics to the file, replacing it at once, so that it's never left
+/// partially written.
+async fn persist_session_stats(file: &Path, stats: &RunningStats) -> io::Result<()> {
+    if let Some(dir) = file.parent() {
+        tokio::fs::create_dir_all(dir).await?;
+    }
+    let temporary = file.with_extension("json.tmp");
+    let data = serde_json::to_vec(stats).expect("statistics are serializable");
+    tokio::fs::write(&temporary, data).await?;
+    tokio::fs::rename(&temporary, file).await
+}
+
+fn process_command_serverinfo(config: &Config) -> Value {
+    let disabled_commands: Vec<String> = CommandKind::ALL
+        .iter()
+        .filter(|k| config.disabled_commands.contains(k))
+        .map(|k| k.name())
+        .collect();
+    let command_concurrency: serde_json::Map<String, Value> = CommandKind::ALL
+        .iter()
+        .filter_map(|k| {
+            let limit = config.command_concurrency.get(k)?;
+            Some((k.name(), json!(limit)))
+        })
+        .collect();
+    // only the settings that are safe to disclose to any client belong here
+    json!({
+        "address": config.address,
+        "debug": config.debug,
+        "metrics_window_secs": config.metrics_window_secs,
+        "artificial_delay_ms": config.artificial_delay_ms,
+        "so_reuseaddr": config.so_reuseaddr,
+        "so_reuseport": config.so_reuseport,
+        "backlog": config.backlog,
+        "systemd_socket": config.systemd_socket,
+        "idempotency_ttl_secs": config.idempotency_ttl_secs,
+        "max_response_bytes": config.max_response_bytes,
+        "max_request_bytes": config.max_request_bytes,
+        "lenient_json": config.lenient_json,
+        "eager_requests": config.eager_requests,
+        "pretty_json": config.pretty_json,
+        "max_batch_size": config.max_batch_size,
+        "max_total_subrequests": config.max_total_subrequests,
+        "stream_batches": config.stream_batches,
+        "disabled_commands": disabled_commands,
+        "protocol_version_header": config.protocol_version_header,
+        "command_concurrency": command_concurrency,
+        "reject_busy": config.reject_busy,
+        "replay_metrics": config.replay_metrics,
+        "metrics_snapshot_interval_secs": config.metrics_snapshot_interval_secs,
+        "metrics_history_size": config.metrics_history_size,
+        "content_hash": config.content_hash,
+        "max_requests_per_connection": config.max_requests_per_connection,
+        "max_json_depth": config.max_json_depth,
+        "max_memory_bytes": config.max_memory_bytes,
+        "write_timeout_secs": config.write_timeout_secs,
+        "framing": "eof",
+        "encoding": "json",
+    })
+}
+
+fn process_command_stats(state: &ServerState) -> Value {
+    let metrics = state.lock_metrics();
+    let recent = metrics.window.stats(unix_secs());
+    let time_stats =
+        |s: &TimeStats| json!({"count": s.count, "min": s.min, "avg": s.avg(), "max": s.max});
+
+    let mut commands = serde_json::Map::new();
+    for kind in CommandKind::ALL {
+        let Some(&count) = metrics.command_counts.get(&kind) else {
+            continue;
+        };
+        let lifetime = json!({
+            "count": count,
+            "min": metrics.processing_time_min[&kind],
+            "avg": metrics.processing_time_avg[&kind],
+            "max": metrics.processing_time_max[&kind],
+        });
+        let window = recent.get(&kind).map(time_stats);
+        commands.insert(kind.name(), json!({"lifetime": lifetime, "window": window}));
+    }
+    let compression_ratio = json!({
+        "server": state.compression.ratio(),
+        "connection": CONNECTION_COMPRESSION.try_with(|stats| stats.ratio()).ok().flatten(),
+    });
+    json!({
+        "window_secs": metrics.window.window_secs(),
+        "commands": commands,
+        "compression_ratio": compression_ratio,
+        "subrequests": {
+            "in_flight": state.subrequests.in_flight(),
+            "peak": state.subrequests.peak(),
+        },
+        "queue_depth": process_command_queuedepth(state),
+    })
+}
+
+fn process_command_statssince(since: DateTime<Utc>, state: &ServerState) -> Result<Value> {
+    let now = unix_millis();
+    // a moment before the epoch precedes any snapshot
+    let since_ms = u64::try_from(since.timestamp_millis()).unwrap_or(0);
+    if since_ms > now {
+        return Err(CommandError::new(
+            ErrorCode::InvalidArgument,
+            format!("since must not be in the future, but it's {since}"),
+        )
+        .into());
+    }
+    let current = state.lock_metrics().snapshot(now);
+    let history = state.metrics_history.lock().unwrap();
+    let checkpoint = history.checkpoint(since_ms).ok_or_else(|| {
+        CommandError::new(
+            ErrorCode::NotFound,
+            format!("no metrics snapshot taken at or before {since} is kept"),
+        )
+    })?;
+    Ok(json!(current.since(checkpoint)))
+}
+
+/// The message of the error forced by `Command::ForceError` without one.
+fn forced_error_message(code: ErrorCode) -> String {
+    let name = serde_plain::to_string(&code).expect("codes are always serializable");
+    format!("forced `{name}` error")
+}
+
+/// Returns the request with the absent optional fields that the server substitutes a value for
+/// during the processing set to that value, including the ones of the nested requests
+/// (see `Command::NormalizeRequest`).
+fn normalize_request(mut request: Request) -> Request {
+    request.command = normalize_command(request.command);
+    request
+}
+
+fn normalize_command(command: Command) -> Command {
+    let normalize_all = |requests: Vec<Request>| requests.into_iter().map(normalize_request);
+    match command {
+        Command::Batch(BatchPayload::Requests(requests)) => {
+            Command::Batch(BatchPayload::Requests(normalize_all(requests).collect()))
+        }
+        Command::Batch(BatchPayload::WithOptions(mut options)) => {
+            options.requests = normalize_all(options.requests).collect();
+            Command::Batch(BatchPayload::WithOptions(options))
+        }
+        Command::SelfTest {
+            command,
+            iterations,
+        } => Command::SelfTest {
+            command: Box::new(normalize_command(*command)),
+            iterations,
+        },
+        Command::NormalizeRequest { request } => Command::NormalizeRequest {
+            request: Box::new(normalize_request(*request)),
+        },
+        Command::ForceError { code, message } => Command::ForceError {
+            code,
+            message: Some(message.unwrap_or_else(|| forced_error_message(code))),
+        },
+        Command::LatencyHistogram { command, buckets } => Command::LatencyHistogram {
+            command,
+            buckets: Some(buckets.unwrap_or(Histogram::BUCKETS as u32)),
+        },
+        command => command,
+    }
+}
+
+fn process_command_latencyhistogram(
+    state: &ServerState,
+    kind: CommandKind,
+    buckets: Option<u32>,
+) -> Value {
+    let metrics = state.lock_metrics();
+    let histogram = metrics.histograms.get(&kind).cloned().unwrap_or_default();
+    let max_buckets = buckets.map_or(Histogram::BUCKETS, |b| b as usize);
+    json!({
+        "command": kind.name(),
+        "count": histogram.count(),
+        "buckets": histogram.buckets(max_buckets),
+    })
+}
+
+fn process_command_activerequests(state: &ServerState) -> Value {
+    let active = state.active_requests.lock().unwrap();
+    let requests: Vec<Value> = active
+        .iter()
+        .map(|(id, r)| {
+            json!({
+                "request_id": id,
+                "command": r.command_kind.name(),
+                "elapsed_ms": r.started.elapsed().as_micros() as f64 / 1000.0,
+            })
+        })
+        .collect();
+    json!(requests)
+}
+
+fn process_command_connections(state: &ServerState) -> Value {
+    let connections = state.connections.lock().unwrap();
+    let mut connections: Vec<_> = connections.iter().collect();
+    connections.sort_by_key(|(_, c)| c.started);
+    let connections: Vec<Value> = connections
+        .into_iter()
+        .map(|(peer, c)| {
+            json!({
+                "peer": peer.to_string(),
+                "duration_ms": c.started.elapsed().as_micros() as f64 / 1000.0,
+                "requests": c.requests.load(Ordering::Relaxed),
+            })
+        })
+        .collect();
+    json!(connections)
+}
+
+fn process_command_closeconnection(peer: &str, state: &ServerState) -> Result<Value> {
+    let addr: SocketAddr = peer.parse().map_err(|_| {
+        CommandError::new(
+            ErrorCode::InvalidArgument,
+            format!("`{peer}` is not a valid address"),
+        )
+    })?;
+    let connections = state.connections.lock().unwrap();
+    let Some(connection) = connections.get(&addr) else {
+        return Err(CommandError::new(
+            ErrorCode::NotFound,
+            format!("there's no open connection from {addr}"),
+        )
+        .into());
+    };
+    // the permit is stored if the handler isn't waiting yet, so the close isn't missed
+    connection.close.notify_one();
+    Ok(json!({"closed": addr.to_string()}))
+}
+
+fn process_command_numericlimits() -> Value {
+    // integers beyond this range can't be represented in an `f64` exactly
+    let max_safe_integer = (1_i64 << f64::MANTISSA_DIGITS) - 1;
+    json!({
+        "f64_max": f64::MAX,
+        "f64_min": f64::MIN,
+        "f64_min_positive": f64::MIN_POSITIVE,
+        "f64_epsilon": f64::EPSILON,
+        "f64_max_safe_integer": max_safe_integer,
+        "f64_min_safe_integer": -max_safe_integer,
+        "i64_max": i64::MAX,
+        "i64_min": i64::MIN,
+        "u64_max": u64::MAX,
+        "u32_max": u32::MAX,
+    })
+}
+
+/// The maximum number of iterations of a `Command::SelfTest`.
+const MAX_SELFTEST_ITERATIONS: u32 = 10_000;
+
+async fn process_command_selftest(
+    command: Command,
+    iterations: u32,
+    state: Arc<ServerState>,
+) -> Result<Value> {
+    if !(1..=MAX_SELFTEST_ITERATIONS).contains(&iterations) {
+        return Err(CommandError::new(
+            ErrorCode::InvalidArgument,
+            format!("iterations must be from 1 to {MAX_SELFTEST_ITERATIONS}"),
+        )
+        .into());
+    }
+    // nesting would multiply the iterations past the limit, even through a batch or a pipe
+    if IN_SELFTEST.try_with(|nested| *nested).unwrap_or(false) {
+        return Err(
+            CommandError::new(ErrorCode::InvalidArgument, "selftest cannot be nested").into(),
+        );
+    }
+
+    let to_ms = |d: Duration| d.as_nanos() as f64 / 1_000_000.0;
+    let mut min = Duration::MAX;
+    let mut max = Duration::ZERO;
+    let start = Instant::now();
+    IN_SELFTEST
+        .scope(true, async {
+            for _ in 0..iterations {
+                let iteration_start = Instant::now();
+                Box::pin(process_command(command.clone(), state.clone())).await?;
+                let elapsed = iteration_start.elapsed();
+                min = min.min(elapsed);
+                max = max.max(elapsed);
+            }
+            Ok::<_, anyhow::Error>(())
+        })
+        .await?;
+    let total = start.elapsed();
+
+    Ok(json!({
+        "command": command.kind().name(),
+        "iterations": iterations,
+        "total_ms": to_ms(total),
+        "min_ms": to_ms(min),
+        "avg_ms": to_ms(total) / iterations as f64,
+        "max_ms": to_ms(max),
+    }))
+}
+
+async fn process_command_batch(batch: BatchPayload, state: Arc<ServerState>) -> Result<Value> {
+    // only the outermost batch is the one being received
+    let streamed = STREAMED_BATCH
+        .try_with(|requests| requests.take())
+        .ok()
+        .flatten();
+    if let Some(requests) = streamed {
+        return process_streamed_batch(requests, state).await;
+    }
+    let len = batch.requests().len();
+    if let Some(max) = state.config.max_batch_size
+        && len > max
+    {
+        return Err(CommandError::new(
+            ErrorCode::BatchTooLarge,
+            format!("batch of {len} requests exceeds the limit of {max}"),
+        )
+        .into());
+    }
+    let (requests, stop_on_error) = match batch {
+        BatchPayload::Requests(requests) => (requests, None),
+        BatchPayload::WithOptions(options) => {
+            if let Some(expected) = options.checksum {
+                let actual = batch_checksum(&options.requests);
+                if !actual.eq_ignore_ascii_case(&expected) {
+                    return Err(CommandError::new(
+                        ErrorCode::ChecksumMismatch,
+                        format!("checksum of the batch is {actual}, expected {expected}"),
+                    )
+                    .into());
+                }
+            }
+            (options.requests, Some(options.stop_on_error))
+        }
+    };
+
+    let mut result: Vec<Response> = Vec::new();
+    let mut by_shutdown = false;
+    for item in requests {
+        if is_draining(&state) {
+            by_shutdown = true;
+            break;
+        }
+        let response = form_subresponse(item, state.clone()).await;
+        let failed = matches!(response, Response::Error { .. });
+        let exhausted = exceeds_quota(&response);
+        result.push(response);
+        if (failed && stop_on_error == Some(true)) || exhausted {
+            break;
+        }
+    }
+    // a plain array of requests gets an array of responses even if some are missing
+    match stop_on_error {
+        None => Ok(json!(result)),
+        Some(_) if by_shutdown => Ok(json!({
+            "responses": result,
+            "truncated": true,
+            "truncated_by_shutdown": true,
+        })),
+        Some(_) => Ok(json!({"responses": result, "truncated": result.len() < len})),
+    }
+}
+
+/// Processes a request of a batch once a slot of `Config::max_total_subrequests` is free
+/// (see `SubrequestLimit`).
+async fn form_subresponse(request: Request, state: Arc<ServerState>) -> Response {
+    let _permit = state.subrequests.acquire(request.command.kind()).await;
+    Box::pin(form_response(request, state.clone())).await
+}
+
+/// Returns whether the server is shutting down, so the batches are to stop early.
+fn is_draining(state: &ServerState) -> bool {
+    state.draining.load(Ordering::Relaxed)
+}
+
+/// Processes the requests of a batch as they're received (see `with_streamed_batch`).
+///
+/// Unlike a buffered batch, the batch exceeding `Config::max_batch_size` is only known
+/// when the extra request arrives, so the preceding ones have been processed by then.
+async fn process_streamed_batch(
+    mut requests: mpsc::Receiver<Request>,
+    state: Arc<ServerState>,
+) -> Result<Value> {
+    let mut result: Vec<Response> = Vec::new();
+    while let Some(item) = requests.recv().await {
+        if is_draining(&state) {
+            break;
+        }
+        if let Some(max) = state.config.max_batch_size
+            && result.len() == max
+        {
+            return Err(CommandError::new(
+                ErrorCode::BatchTooLarge,
+                format!("batch exceeds the limit of {max} requests"),
+            )
+            .into());
+        }
+        let response = form_subresponse(item, state.clone()).await;
+        let exhausted = exceeds_quota(&response);
+        result.push(response);
+        if exhausted {
+            break;
+        }
+    }
+    Ok(json!(result))
+}
+
+async fn process_command_runmacro(name: &str, state: Arc<ServerState>) -> Result<Value> {
+    // a macro running itself, even through a batch or a pipe, would never end
+    if IN_MACRO.try_with(|in_macro| *in_macro).unwrap_or(false) {
+        return Err(CommandError::new(
+            ErrorCode::InvalidArgument,
+            "a macro cannot run another macro",
+        )
+        .into());
+    }
+    let client = current_client();
+    let steps = state
+        .macros
+        .lock()
+        .unwrap()
+        .get(client.auth(), name)?
+        .to_vec();
+
+    IN_MACRO
+        .scope(true, async {
+            let mut result: Vec<Response> = Vec::new();
+            for command in steps {
+                let step = Request {
+                    request_id: Uuid::new_v4(),
+                    command,
+                    metadata: None,
+                    auth: None,
+                    transform: None,
+                    if_none_match: None,
+                };
+                let response = Box::pin(form_response(step, state.clone())).await;
+                let exhausted = exceeds_quota(&response);
+                result.push(response);
+                if exhausted {
+                    break;
+                }
+            }
+            Ok(json!(result))
+        })
+        .await
+}
+
+async fn process_command_replay(request_id: Uuid, state: Arc<ServerState>) -> Result<Value> {
+    let command = state
+        .recent_requests
+        .lock()
+        .unwrap()
+        .get(&request_id)?
+        .clone();
+    info!("Replaying request {request_id}");
+    if !state.config.replay_metrics {
+        return Box::pin(process_command(command, state)).await;
+    }
+
+    // a fresh ID keeps the replay from being answered by the response cache
+    let replay = Request {
+        request_id: Uuid::new_v4(),
+        command,
+        metadata: None,
+        auth: None,
+        transform: None,
+        if_none_match: None,
+    };
+    match Box::pin(form_response(replay, state)).await {
+        Response::Ok { response, .. } => Ok(response),
+        Response::Error { code, error, .. } => Err(CommandError::new(code, error).into()),
+    }
+}
+
+/// The number of multiplications of the elements, above which `Command::MatrixMul`
+/// is executed on the blocking thread pool so as not to stall the other requests.
+const MATRIX_BLOCKING_THRESHOLD: usize = 64 * 64 * 64;
+
+async fn process_command_matrixmul(a: Vec<Vec<f64>>, b: Vec<Vec<f64>>) -> Result<Value> {
+    let cols = b.first().map_or(0, Vec::len);
+    let work = a.len().saturating_mul(b.len()).saturating_mul(cols);
+    let result = match work > MATRIX_BLOCKING_THRESHOLD {
+        true => tokio::task::spawn_blocking(move || numbers::matrix_mul(&a, &b)).await??,
+        false => numbers::matrix_mul(&a, &b)?,
+    };
+    warn_non_finite(result.iter().flatten().copied());
+    Ok(json!({"result": result}))
+}
+
+async fn process_command_pipe(stages: Vec<Command>, state: Arc<ServerState>) -> Result<Value> {
+    let mut result = None;
+    for (i, command) in stages.into_iter().enumerate() {
+        let command = match result.take() {
+            None => command,
+            Some(prev) => pipe_inject(command, prev).map_err(|e| {
+                CommandError::new(
+                    ErrorCode::PipeTypeMismatch,
+                    format!("stage {i} cannot accept the result of the previous stage: {e}"),
+                )
+            })?,
+        };
+        // each stage is processed as a separate request, just like the items of a batch
+        let stage = Request {
+            request_id: Uuid::new_v4(),
+            command,
+            metadata: None,
+            auth: None,
+            transform: None,
+            if_none_match: None,
+        };
+        match Box::pin(form_response(stage, state.clone())).await {
+            Response::Ok { response, .. } => result = Some(response),
+            Response::Error { code, error, .. } => {
+                return Err(CommandError::new(code, format!("stage {i}: {error}")).into());
+            }
+        }
+    }
+    result.ok_or_else(|| anyhow!("pipe must have at least one stage"))
+}
+
+/// Passes the result of the previous stage of a pipe to the given command:
+/// if both are objects, the fields of the result are merged into the payload of the command,
+/// otherwise the result replaces the payload.
+fn pipe_inject(command: Command, result: Value) -> serde_json::Result<Command> {
+    let mut command = serde_json::to_value(command)?;
+    match (&mut command["payload"], result) {
+        (Value::Object(payload), Value::Object(fields)) => payload.extend(fields),
+        (payload, result) => *payload = result,
+    }
+    serde_json::from_value(command)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Timelike;
+    use sha2::{Digest, Sha256};
+    use std::collections::{HashMap, HashSet};
+
+    fn build_state() -> Arc<ServerState> {
+        Arc::new(ServerState::default())
+    }
+
+    fn build_request(command: Command) -> Request {
+        Request {
+            request_id: Uuid::new_v4(),
+            command: command,
+            metadata: None,
+            auth: None,
+            transform: None,
+            if_none_match: None,
+        }
+    }
+
+    /// Forms the response to the request with the administrative commands allowed.
+    async fn form_admin_response(request: Request, state: Arc<ServerState>) -> Response {
+        with_admin(true, form_response(request, state)).await
+    }
+
+    #[tokio::test]
+    async fn test_command_ping() {
+        let state = build_state();
+        let req = build_request(Command::Ping);
+        let uuid = req.request_id;
+        let resp = form_response(req, state.clone()).await;
+        match resp {
+            Response::Ok {
+                request_id,
+                response,
+                ..
+            } => {
+                assert_eq!(request_id, uuid);
+                assert_eq!(response, json!("pong"));
+            }
+            Response::Error { .. } => panic!("Expected OK response"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_command_time() {
+        use chrono::DateTime;
+
+        let state = build_state();
+        let time = Utc::now()
+            .with_nanosecond(0)
+            .expect("This shouldn't ever panic");
+        let req = build_request(Command::Time);
+        let uuid = req.request_id;
+        let resp = form_response(req, state.clone()).await;
+        match resp {
+            Response::Ok {
+                request_id,
+                response,
+                ..
+            } => {
+                assert_eq!(request_id, uuid);
+                let resp_str = response
+                    .get("time")
+                    .and_then(|v| v.as_str())
+                    .expect("Missing `time` field in the response");
+                let parsed = DateTime::parse_from_rfc3339(resp_str)
+                    .expect("Invalid format")
+                    .with_timezone(&Utc);
+                println!("{}", parsed);
+                println!("{}", time);
+                assert!((parsed - time).as_seconds_f32() >= 0.0);
+                assert!((parsed - time).as_seconds_f32() < 2.0);
+            }
+            Response::Error { .. } => panic!("Expected OK response"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_command_timezone() {
+        use chrono::{DateTime, FixedOffset};
+
+        let state = build_state();
+        // neither of the zones observes daylight saving time
+        for (tz, offset) in [
+            ("Asia/Tokyo", 9 * 3600),
+            ("UTC", 0),
+            ("Asia/Kolkata", 19800),
+        ] {
+            let time = Utc::now().with_nanosecond(0).unwrap();
+            let req = build_request(Command::TimeZone { tz: tz.to_string() });
+            match form_response(req, state.clone()).await {
+                Response::Ok { response, .. } => {
+                    assert_eq!(response["tz"], tz);
+                    let parsed = DateTime::parse_from_rfc3339(response["time"].as_str().unwrap())
+                        .expect("Invalid format");
+                    assert_eq!(parsed.offset(), &FixedOffset::east_opt(offset).unwrap());
+                    let elapsed = (parsed.with_timezone(&Utc) - time).as_seconds_f32();
+                    assert!((0.0..2.0).contains(&elapsed), "{elapsed}");
+                }
+                Response::Error { .. } => panic!("Expected OK response"),
+            }
+        }
+
+        for tz in ["Mars/Olympus_Mons", "", "+03:00"] {
+            let req = build_request(Command::TimeZone { tz: tz.to_string() });
+            match form_response(req, state.clone()).await {
+                Response::Error { code, .. } => assert_eq!(code, ErrorCode::InvalidArgument),
+                Response::Ok { .. } => panic!("Expected error response"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_command_timediff() {
+        let state = build_state();
+        for (unit, expected) in [
+            ("millis", -5_400_000.0),
+            ("seconds", -5400.0),
+            ("minutes", -90.0),
+            ("hours", -1.5),
+            ("days", -0.0625),
+        ] {
+            let request: Request = serde_json::from_value(json!({
+                "request_id": Uuid::new_v4(),
+                "command": "timediff",
+                "payload": {
+                    "from": "2025-03-01T12:00:00+03:00",
+                    "to": "2025-03-01T07:30:00Z",
+                    "unit": unit,
+                },
+            }))
+            .unwrap();
+            match form_response(request, state.clone()).await {
+                Response::Ok { response, .. } => {
+                    assert_eq!(response, json!({"result": expected}), "{unit}");
+                }
+                Response::Error { .. } => panic!("Expected OK response"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_command_echo() {
+        let state = build_state();
+
+        let req = build_request(Command::Echo(json!("hello")));
+        let uuid = req.request_id;
+        let resp = form_response(req, state.clone()).await;
+        match resp {
+            Response::Ok {
+                request_id,
+                response,
+                ..
+            } => {
+                assert_eq!(request_id, uuid);
+                assert_eq!(response, json!("hello"));
+            }
+            Response::Error { .. } => panic!("Expected OK response"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_command_calculate() {
+        #[rustfmt::skip]
+        let test_data = Vec::from([
+            (Operation::Add, 0.1, 0.2, 0.1 + 0.2),
+            (Operation::Subtract, 21.0, 9.0, 21.0 - 9.0),
+            (Operation::Multiply, 6.0, -8.0, 6.0 * -8.0),
+            (Operation::Divide, 22.0, 7.0, 22.0 / 7.0),
+        ]);
 
-        let metrics = build_metrics();
+        let state = build_state();
 
         for item in test_data {
             let req = build_request(Command::Calculate {
                 operation: item.0,
-                a: item.1,
-                b: item.2,
+                a: item.1.into(),
+                b: item.2.into(),
+                precision: None,
             });
             let uuid = req.request_id;
-            let resp = form_response(req, metrics.clone()).await;
+            let resp = form_response(req, state.clone()).await;
             match resp {
                 Response::Ok {
                     request_id,
                     response,
+                    ..
                 } => {
                     assert_eq!(request_id, uuid);
                     assert_eq!(response, json!({"result": item.3}));
@@ -205,16 +1727,19 @@ mod tests {
 
         let req = build_request(Command::Calculate {
             operation: Operation::Divide,
-            a: 5.0,
-            b: 0.0,
+            a: 5.0.into(),
+            b: 0.0.into(),
+            precision: None,
         });
         let uuid = Some(req.request_id);
-        let resp = form_response(req, metrics.clone()).await;
+        let resp = form_response(req, state.clone()).await;
         match resp {
             Response::Error {
-                request_id,
-                error: _,
-            } => assert_eq!(request_id, uuid),
+                request_id, code, ..
+            } => {
+                assert_eq!(request_id, uuid);
+                assert_eq!(code, ErrorCode::DivisionByZero);
+            }
             Response::Ok { .. } => panic!("Expected Error response"),
         }
     }
@@ -222,15 +1747,16 @@ mod tests {
     #[tokio::test]
     async fn test_command_batch() {
         use serde::Deserialize;
-        let metrics = build_metrics();
+        let state = build_state();
 
         let test_requests = Vec::from([
             build_request(Command::Ping),
             build_request(Command::Echo(json!({"key": "value"}))),
             build_request(Command::Calculate {
                 operation: Operation::Divide,
-                a: 3.5,
-                b: -1.05,
+                a: 3.5.into(),
+                b: (-1.05).into(),
+                precision: None,
             }),
         ]);
         let uuids: Vec<Uuid> = test_requests.iter().map(|i| i.request_id).collect();
@@ -240,15 +1766,16 @@ mod tests {
             json!({"result": 3.5 / -1.05}),
         ];
 
-        let req = build_request(Command::Batch(test_requests.clone()));
+        let req = build_request(Command::Batch(test_requests.clone().into()));
         let batch_uuid = req.request_id;
-        let resp = form_response(req, metrics.clone()).await;
+        let resp = form_response(req, state.clone()).await;
 
         match resp {
             Response::Error { .. } => panic!("Expected OK response"),
             Response::Ok {
                 request_id,
-                response: batch
+                response: batch,
+                ..
             } => {
                 assert_eq!(request_id, batch_uuid);
                 for (i, item) in batch.as_array().unwrap().iter().enumerate() {
@@ -257,6 +1784,7 @@ mod tests {
                         Response::Ok {
                             request_id,
                             response,
+                            ..
                         } => {
                             assert_eq!(request_id, uuids[i]);
                             assert_eq!(response, expected_responses[i]);
@@ -267,4 +1795,2173 @@ mod tests {
             }
         }
     }
+
+    #[tokio::test]
+    async fn test_command_serverinfo() {
+        let mut state = ServerState::default();
+        state.config.address = "127.0.0.1:9000".to_string();
+        state.config.debug = true;
+        state.config.admin_token = Some("admin-token-value".to_string());
+        state.config.auth_token = Some("auth-token-value".to_string());
+        state.config.session_stats_dir = Some("/srv/session-stats".into());
+        let state = Arc::new(state);
+        let req = build_request(Command::ServerInfo);
+        let uuid = req.request_id;
+        let resp = form_response(req, state.clone()).await;
+        match resp {
+            Response::Ok {
+                request_id,
+                response,
+                ..
+            } => {
+                assert_eq!(request_id, uuid);
+                assert_eq!(response["address"], json!("127.0.0.1:9000"));
+                assert_eq!(response["debug"], json!(true));
+                assert_eq!(response["framing"], json!("eof"));
+                // settings the server doesn't have are not reported at all
+                assert!(response.get("max_connections").is_none());
+                assert!(response.get("read_timeout_ms").is_none());
+                assert!(response.get("tls_enabled").is_none());
+                // neither are the secrets and the paths on the server
+                let text = response.to_string().to_lowercase();
+                assert!(!text.contains("key"));
+                assert!(!text.contains("secret"));
+                assert!(!text.contains("token"));
+                assert!(!text.contains("session-stats"));
+                assert!(response.get("session_stats_dir").is_none());
+            }
+            Response::Error { .. } => panic!("Expected OK response"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_command_pipe() {
+        let state = build_state();
+
+        // echo -> calculate -> echo: the operands are merged into the payload of calculate
+        let req = build_request(Command::Pipe {
+            stages: Vec::from([
+                Command::Echo(json!({"a": 6, "b": 7})),
+                Command::Calculate {
+                    operation: Operation::Multiply,
+                    a: 0.0.into(),
+                    b: 0.0.into(),
+                    precision: None,
+                },
+                Command::Echo(Value::Null),
+            ]),
+        });
+        let uuid = req.request_id;
+        let resp = form_response(req, state.clone()).await;
+        match resp {
+            Response::Ok {
+                request_id,
+                response,
+                ..
+            } => {
+                assert_eq!(request_id, uuid);
+                assert_eq!(response, json!({"result": 42.0}));
+            }
+            Response::Error { .. } => panic!("Expected OK response"),
+        }
+        // the stages go through the same path as batch items, so they are counted in the metrics
+        let count = state.metrics.lock().unwrap().command_counts[&CommandKind::Calculate];
+        assert_eq!(count, 1);
+
+        // a string can't be a payload of calculate
+        let req = build_request(Command::Pipe {
+            stages: Vec::from([
+                Command::Echo(json!("hello")),
+                Command::Calculate {
+                    operation: Operation::Add,
+                    a: 0.0.into(),
+                    b: 0.0.into(),
+                    precision: None,
+                },
+            ]),
+        });
+        let resp = form_response(req, state.clone()).await;
+        match resp {
+            Response::Error { code, .. } => assert_eq!(code, ErrorCode::PipeTypeMismatch),
+            Response::Ok { .. } => panic!("Expected Error response"),
+        }
+    }
+
+    #[test]
+    fn test_check_command_name() {
+        assert!(check_command_name("ping").is_ok());
+        assert!(check_command_name("serverinfo").is_ok());
+
+        let err = check_command_name("frobnicate").unwrap_err();
+        assert_eq!(err.code, ErrorCode::UnknownCommand);
+        assert!(err.message.starts_with("unknown command `frobnicate`"));
+        assert!(err.message.contains("ping"));
+    }
+
+    #[tokio::test]
+    async fn test_metadata() {
+        let state = build_state();
+        let ping_metadata = json!({"trace_id": "abc", "tags": [1, 2]});
+
+        let mut ok_req = build_request(Command::Ping);
+        ok_req.metadata = Some(ping_metadata.clone());
+        let mut err_req = build_request(Command::Calculate {
+            operation: Operation::Divide,
+            a: 1.0.into(),
+            b: 0.0.into(),
+            precision: None,
+        });
+        err_req.metadata = Some(json!("error metadata"));
+        let plain_req = build_request(Command::Ping);
+
+        // metadata must flow through the batch as well
+        let mut req = build_request(Command::Batch(
+            Vec::from([ok_req, err_req, plain_req]).into(),
+        ));
+        req.metadata = Some(json!("batch metadata"));
+        let resp = form_response(req, state.clone()).await;
+
+        let Response::Ok {
+            response: batch,
+            metadata,
+            ..
+        } = resp
+        else {
+            panic!("Expected OK response");
+        };
+        assert_eq!(metadata, Some(json!("batch metadata")));
+
+        let items: Vec<Response> = serde_json::from_value(batch).unwrap();
+        match &items[0] {
+            Response::Ok { metadata: m, .. } => assert_eq!(m, &Some(ping_metadata.clone())),
+            Response::Error { .. } => panic!("Expected OK response"),
+        }
+        match &items[1] {
+            Response::Error { metadata: m, .. } => {
+                assert_eq!(m, &Some(json!("error metadata")))
+            }
+            Response::Ok { .. } => panic!("Expected Error response"),
+        }
+        match &items[2] {
+            Response::Ok { metadata: m, .. } => assert_eq!(m, &None),
+            Response::Error { .. } => panic!("Expected OK response"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_command_eval() {
+        let state = build_state();
+
+        let req = build_request(Command::Eval {
+            expression: "2 * (3 + 4)".to_string(),
+        });
+        let resp = form_response(req, state.clone()).await;
+        match resp {
+            Response::Ok { response, .. } => assert_eq!(response, json!({"result": 14.0})),
+            Response::Error { .. } => panic!("Expected OK response"),
+        }
+
+        let req = build_request(Command::Eval {
+            expression: "2 * (3 +".to_string(),
+        });
+        let resp = form_response(req, state.clone()).await;
+        match resp {
+            Response::Error { code, .. } => assert_eq!(code, ErrorCode::ParseError),
+            Response::Ok { .. } => panic!("Expected Error response"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_command_stats() {
+        let state = build_state();
+        for _ in 0..3 {
+            form_response(build_request(Command::Ping), state.clone()).await;
+        }
+
+        let resp = form_response(build_request(Command::Stats), state.clone()).await;
+        match resp {
+            Response::Ok { response, .. } => {
+                assert_eq!(response["window_secs"], json!(DEFAULT_METRICS_WINDOW_SECS));
+                assert_eq!(response["commands"]["ping"]["lifetime"]["count"], json!(3));
+                assert_eq!(response["commands"]["ping"]["window"]["count"], json!(3));
+                assert!(response["commands"].get("echo").is_none());
+            }
+            Response::Error { .. } => panic!("Expected OK response"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_command_activerequests() {
+        let state = build_state();
+        let pending_id = Uuid::new_v4();
+        let pending = ActiveRequestGuard::new(state.clone(), pending_id, CommandKind::Eval);
+
+        let req = build_request(Command::ActiveRequests);
+        let uuid = req.request_id;
+        let resp = form_admin_response(req, state.clone()).await;
+        match resp {
+            Response::Ok { response, .. } => {
+                let requests = response.as_array().unwrap();
+                assert_eq!(requests.len(), 2);
+                let item = requests
+                    .iter()
+                    .find(|r| r["request_id"] == json!(pending_id))
+                    .expect("Missing the pending request");
+                assert_eq!(item["command"], json!("eval"));
+                assert!(item["elapsed_ms"].as_f64().unwrap() >= 0.0);
+                assert!(requests.iter().any(|r| r["request_id"] == json!(uuid)));
+            }
+            Response::Error { .. } => panic!("Expected OK response"),
+        }
+
+        // the IDs of the requests of other clients are only revealed to the administrators
+        let resp = form_response(build_request(Command::ActiveRequests), state.clone()).await;
+        match resp {
+            Response::Error { code, .. } => assert_eq!(code, ErrorCode::Unauthorized),
+            Response::Ok { .. } => panic!("Expected Error response"),
+        }
+
+        drop(pending);
+        assert!(state.active_requests.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_command_calculate_lenient_operands() {
+        let state = build_state();
+
+        let calculate = |a: Operand, b: Operand| {
+            build_request(Command::Calculate {
+                operation: Operation::Add,
+                a,
+                b,
+                precision: None,
+            })
+        };
+        let test_data = Vec::from([
+            (Operand::String("3.5".to_string()), 1.0.into(), 4.5),
+            (2.0.into(), Operand::String(" -1e1 ".to_string()), -8.0),
+            (0.5.into(), 0.25.into(), 0.75),
+        ]);
+        for (a, b, expected) in test_data {
+            let resp = form_response(calculate(a, b), state.clone()).await;
+            match resp {
+                Response::Ok { response, .. } => assert_eq!(response, json!({"result": expected})),
+                Response::Error { .. } => panic!("Expected OK response"),
+            }
+        }
+
+        for invalid in ["abc", "NaN", "inf", ""] {
+            let req = calculate(1.0.into(), Operand::String(invalid.to_string()));
+            let resp = form_response(req, state.clone()).await;
+            match resp {
+                Response::Error { code, error, .. } => {
+                    assert_eq!(code, ErrorCode::InvalidArgument);
+                    assert!(error.contains("`b`"));
+                }
+                Response::Ok { .. } => panic!("Expected Error response"),
+            }
+        }
+
+        // the operands are deserialized from both numbers and strings
+        let cmd: Command = serde_json::from_value(
+            json!({"command": "calculate", "payload": {"operation": "add", "a": "1", "b": 2}}),
+        )
+        .unwrap();
+        let Command::Calculate { a, b, .. } = cmd else {
+            panic!("Expected Calculate command");
+        };
+        assert_eq!(a, Operand::String("1".to_string()));
+        assert_eq!(b, Operand::Number(2.0));
+    }
+
+    #[tokio::test]
+    async fn test_command_procinfo() {
+        let state = build_state();
+        let resp = form_admin_response(build_request(Command::ProcInfo), state.clone()).await;
+        match resp {
+            Response::Ok { response, .. } => {
+                for field in ["rss_bytes", "cpu_time_ms", "threads", "load_average"] {
+                    assert!(response.get(field).is_some(), "missing `{field}`");
+                }
+            }
+            Response::Error { .. } => panic!("Expected OK response"),
+        }
+
+        // the command requires the administrative token
+        let resp = form_response(build_request(Command::ProcInfo), state.clone()).await;
+        match resp {
+            Response::Error { code, .. } => assert_eq!(code, ErrorCode::Unauthorized),
+            Response::Ok { .. } => panic!("Expected Error response"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_command_loglevel() {
+        let state = build_state();
+        let loglevel = |level: Option<&str>| {
+            build_request(Command::LogLevel {
+                level: level.map(str::to_string),
+            })
+        };
+        for (level, expected) in [(Some("DEBUG"), "debug"), (None, "debug")] {
+            match form_admin_response(loglevel(level), state.clone()).await {
+                Response::Ok { response, .. } => {
+                    assert_eq!(response, json!({"level": expected}));
+                }
+                Response::Error { .. } => panic!("Expected OK response"),
+            }
+        }
+        assert_eq!(log::max_level(), LevelFilter::Debug);
+
+        match form_admin_response(loglevel(Some("verbose")), state.clone()).await {
+            Response::Error { code, .. } => assert_eq!(code, ErrorCode::InvalidArgument),
+            Response::Ok { .. } => panic!("Expected Error response"),
+        }
+        // the command requires the administrative token
+        match form_response(loglevel(None), state.clone()).await {
+            Response::Error { code, .. } => assert_eq!(code, ErrorCode::Unauthorized),
+            Response::Ok { .. } => panic!("Expected Error response"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_command_echo_transform() {
+        let state = build_state();
+        let value = json!({"b": [1, 2], "a": null});
+
+        let echo = |payload: Value, transform: &str| {
+            let request = json!({
+                "request_id": Uuid::new_v4(),
+                "command": "echo",
+                "payload": payload,
+                "transform": transform,
+            });
+            serde_json::from_value::<Request>(request)
+        };
+        let test_data = Vec::from([
+            (value.clone(), "stringify", json!(value.to_string())),
+            (value.clone(), "keys", json!(["a", "b"])),
+            (value.clone(), "type", json!("object")),
+            (json!([]), "type", json!("array")),
+            (json!(true), "type", json!("boolean")),
+            // the payload itself is never interpreted as a transform request
+            (
+                json!({"value": 1, "transform": "type"}),
+                "stringify",
+                json!(r#"{"transform":"type","value":1}"#),
+            ),
+        ]);
+        for (payload, transform, expected) in test_data {
+            let resp = form_response(echo(payload, transform).unwrap(), state.clone()).await;
+            match resp {
+                Response::Ok { response, .. } => assert_eq!(response, expected),
+                Response::Error { .. } => panic!("Expected OK response"),
+            }
+        }
+
+        // without the transform, an object with these fields is echoed as is
+        let payload = json!({"value": 1, "transform": "type"});
+        let resp =
+            form_response(build_request(Command::Echo(payload.clone())), state.clone()).await;
+        match resp {
+            Response::Ok { response, .. } => assert_eq!(response, payload),
+            Response::Error { .. } => panic!("Expected OK response"),
+        }
+
+        // unknown transforms are rejected
+        assert!(echo(json!(1), "upper").is_err());
+
+        let resp = form_response(echo(json!([1]), "keys").unwrap(), state.clone()).await;
+        match resp {
+            Response::Error { code, .. } => assert_eq!(code, ErrorCode::InvalidArgument),
+            Response::Ok { .. } => panic!("Expected Error response"),
+        }
+
+        // the transform is not allowed with other commands
+        let mut req = build_request(Command::Ping);
+        req.transform = Some(EchoTransform::Type);
+        let resp = form_response(req, state.clone()).await;
+        match resp {
+            Response::Error { code, .. } => assert_eq!(code, ErrorCode::InvalidArgument),
+            Response::Ok { .. } => panic!("Expected Error response"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_idempotency() {
+        let state = Arc::new(ServerState::new(Config {
+            idempotency_ttl_secs: 60,
+            ..Default::default()
+        }));
+
+        let req = build_request(Command::Calculate {
+            operation: Operation::Add,
+            a: 1.0.into(),
+            b: 2.0.into(),
+            precision: None,
+        });
+        let client = ClientIdentity::new("127.0.0.1:50000".parse().unwrap(), None);
+        let send = |req: Request, client: ClientIdentity| {
+            with_client(client, form_response(req, state.clone()))
+        };
+        // the client retries on a new connection, from another port
+        let first = send(req.clone(), client.clone()).await;
+        let retry = ClientIdentity::new("127.0.0.1:50001".parse().unwrap(), None);
+        let second = send(req.clone(), retry).await;
+        for resp in [first, second] {
+            match resp {
+                Response::Ok {
+                    request_id,
+                    response,
+                    ..
+                } => {
+                    assert_eq!(request_id, req.request_id);
+                    assert_eq!(response, json!({"result": 3.0}));
+                }
+                Response::Error { .. } => panic!("Expected OK response"),
+            }
+        }
+
+        let count = |kind| {
+            state
+                .metrics
+                .lock()
+                .unwrap()
+                .command_counts
+                .get(&kind)
+                .copied()
+        };
+        assert_eq!(count(CommandKind::Calculate), Some(1));
+
+        // the same ID with a different command is processed
+        let mut repeated = req.clone();
+        repeated.command = Command::Ping;
+        match send(repeated, client.clone()).await {
+            Response::Ok { response, .. } => assert_eq!(response, json!("pong")),
+            Response::Error { .. } => panic!("Expected OK response"),
+        }
+        // as is the same echo with another transform
+        let echo = build_request(Command::Echo(json!({"a": 1})));
+        let mut transformed = echo.clone();
+        transformed.transform = Some(EchoTransform::Keys);
+        send(echo, client.clone()).await;
+        match send(transformed, client.clone()).await {
+            Response::Ok { response, .. } => assert_eq!(response, json!(["a"])),
+            Response::Error { .. } => panic!("Expected OK response"),
+        }
+
+        // and so is the same request sent by another client or with another token
+        let others = [
+            ClientIdentity::new("192.0.2.1:50000".parse().unwrap(), None),
+            ClientIdentity::new("127.0.0.1:50000".parse().unwrap(), Some("t".to_string())),
+        ];
+        for other in others {
+            match send(req.clone(), other).await {
+                Response::Ok { response, .. } => assert_eq!(response, json!({"result": 3.0})),
+                Response::Error { .. } => panic!("Expected OK response"),
+            }
+        }
+        assert_eq!(count(CommandKind::Calculate), Some(3));
+
+        // the responses to the administrative commands are not cached
+        let req = build_request(Command::ProcInfo);
+        for _ in 0..2 {
+            form_admin_response(req.clone(), state.clone()).await;
+        }
+        assert_eq!(count(CommandKind::ProcInfo), Some(2));
+    }
+
+    #[tokio::test]
+    async fn test_command_baseconvert() {
+        let state = build_state();
+        let req = build_request(Command::BaseConvert {
+            value: "ff".to_string(),
+            from_base: 16,
+            to_base: 2,
+        });
+        let resp = form_response(req, state.clone()).await;
+        match resp {
+            Response::Ok { response, .. } => assert_eq!(response, json!({"result": "11111111"})),
+            Response::Error { .. } => panic!("Expected OK response"),
+        }
+
+        let req = build_request(Command::BaseConvert {
+            value: "12".to_string(),
+            from_base: 2,
+            to_base: 10,
+        });
+        let resp = form_response(req, state.clone()).await;
+        match resp {
+            Response::Error { code, .. } => assert_eq!(code, ErrorCode::InvalidArgument),
+            Response::Ok { .. } => panic!("Expected Error response"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_command_noop() {
+        let state = build_state();
+        for _ in 0..2 {
+            let resp = form_response(build_request(Command::Noop), state.clone()).await;
+            match resp {
+                Response::Ok { response, .. } => assert_eq!(response, json!({})),
+                Response::Error { .. } => panic!("Expected OK response"),
+            }
+        }
+
+        let metrics = state.metrics.lock().unwrap();
+        assert_eq!(metrics.command_counts.get(&CommandKind::Noop), Some(&2));
+        assert_eq!(metrics.command_counts.get(&CommandKind::Ping), None);
+    }
+
+    #[tokio::test]
+    async fn test_command_selftest() {
+        let request: Request = serde_json::from_value(json!({
+            "request_id": Uuid::new_v4(),
+            "command": "selftest",
+            "payload": {"command": {"command": "ping"}, "iterations": 5},
+        }))
+        .unwrap();
+        match form_admin_response(request.clone(), build_state()).await {
+            Response::Ok { response, .. } => {
+                assert_eq!(response["command"], "ping");
+                assert_eq!(response["iterations"], 5);
+                assert!(response["total_ms"].as_f64().unwrap() > 0.0);
+                assert!(response["max_ms"].as_f64().unwrap() > 0.0);
+            }
+            Response::Error { .. } => panic!("Expected OK response"),
+        }
+
+        for iterations in [0, MAX_SELFTEST_ITERATIONS + 1] {
+            let command = Command::SelfTest {
+                command: Box::new(Command::Ping),
+                iterations,
+            };
+            match form_admin_response(build_request(command), build_state()).await {
+                Response::Error { code, .. } => assert_eq!(code, ErrorCode::InvalidArgument),
+                Response::Ok { .. } => panic!("Expected error response"),
+            }
+        }
+
+        // the command requires the administrative token
+        match form_response(request, build_state()).await {
+            Response::Error { code, .. } => assert_eq!(code, ErrorCode::Unauthorized),
+            Response::Ok { .. } => panic!("Expected error response"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_command_selftest_nested() {
+        let selftest = |command| Command::SelfTest {
+            command: Box::new(command),
+            iterations: 10,
+        };
+        let state = build_state();
+        match form_admin_response(build_request(selftest(selftest(Command::Ping))), state).await {
+            Response::Error { code, .. } => assert_eq!(code, ErrorCode::InvalidArgument),
+            Response::Ok { .. } => panic!("Expected error response"),
+        }
+
+        // a selftest nested into a batch fails without running its own iterations
+        let state = build_state();
+        let inner = build_request(selftest(Command::Ping));
+        let batch = Command::Batch(BatchPayload::Requests(vec![inner]));
+        match form_admin_response(build_request(selftest(batch)), state.clone()).await {
+            Response::Ok { response, .. } => assert_eq!(response["iterations"], 10),
+            Response::Error { .. } => panic!("Expected OK response"),
+        }
+        let counts = &state.metrics.lock().unwrap().command_counts;
+        assert_eq!(counts.get(&CommandKind::SelfTest), Some(&11));
+        assert_eq!(counts.get(&CommandKind::Ping), None);
+    }
+
+    #[tokio::test]
+    async fn test_max_batch_size() {
+        let state = Arc::new(ServerState::new(Config {
+            max_batch_size: Some(3),
+            ..Default::default()
+        }));
+        let batch = |len| {
+            let requests = (0..len).map(|_| build_request(Command::Ping)).collect();
+            build_request(Command::Batch(BatchPayload::Requests(requests)))
+        };
+
+        match form_response(batch(3), state.clone()).await {
+            Response::Ok { response, .. } => assert_eq!(response.as_array().unwrap().len(), 3),
+            Response::Error { .. } => panic!("Expected OK response"),
+        }
+
+        match form_response(batch(4), state.clone()).await {
+            Response::Error { code, .. } => assert_eq!(code, ErrorCode::BatchTooLarge),
+            Response::Ok { .. } => panic!("Expected error response"),
+        }
+        // the batch was rejected before any of its items was processed
+        let metrics = state.metrics.lock().unwrap();
+        assert_eq!(metrics.command_counts.get(&CommandKind::Ping), Some(&3));
+    }
+
+    #[tokio::test]
+    async fn test_max_total_subrequests() {
+        let state = Arc::new(ServerState::new(Config {
+            max_total_subrequests: Some(2),
+            ..Default::default()
+        }));
+        // large enough to be multiplied on the blocking threads, so that the batches interleave
+        let matrix = vec![vec![1.0; 65]; 65];
+        let batch = || {
+            let requests: Vec<Request> = (0..5)
+                .map(|_| {
+                    build_request(Command::MatrixMul {
+                        a: matrix.clone(),
+                        b: matrix.clone(),
+                    })
+                })
+                .collect();
+            // a nested batch takes no slot, or it would wait for the ones of its own requests
+            let nested = build_request(Command::Batch(BatchPayload::Requests(requests.clone())));
+            let requests = [requests, vec![nested]].concat();
+            build_request(Command::Batch(BatchPayload::Requests(requests)))
+        };
+
+        let batches: Vec<_> = (0..6)
+            .map(|_| tokio::spawn(form_response(batch(), state.clone())))
+            .collect();
+        for batch in batches {
+            match batch.await.unwrap() {
+                Response::Ok { response, .. } => {
+                    assert_eq!(response.as_array().unwrap().len(), 6)
+                }
+                Response::Error { .. } => panic!("Expected OK response"),
+            }
+        }
+        assert_eq!(state.subrequests.peak(), 2);
+        assert_eq!(state.subrequests.in_flight(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_command_numericlimits() {
+        let resp = form_response(build_request(Command::NumericLimits), build_state()).await;
+        match resp {
+            Response::Ok { response, .. } => {
+                // the value must survive the round trip through its JSON text
+                let f64_max = response["f64_max"].to_string();
+                assert_eq!(f64_max.parse::<f64>().unwrap(), f64::MAX);
+                assert_eq!(response["i64_min"].as_i64(), Some(i64::MIN));
+                assert_eq!(response["u64_max"].as_u64(), Some(u64::MAX));
+                assert_eq!(response["f64_max_safe_integer"], 9007199254740991_i64);
+            }
+            Response::Error { .. } => panic!("Expected OK response"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_command_numbertheory() {
+        let state = build_state();
+        for (operation, a, b, expected) in [(NumOp::Gcd, 48, 180, 12), (NumOp::Lcm, 4, 6, 12)] {
+            let req = build_request(Command::NumberTheory { operation, a, b });
+            match form_response(req, state.clone()).await {
+                Response::Ok { response, .. } => assert_eq!(response, json!({"result": expected})),
+                Response::Error { .. } => panic!("Expected OK response"),
+            }
+        }
+
+        let req = build_request(Command::NumberTheory {
+            operation: NumOp::Lcm,
+            a: u64::MAX,
+            b: 2,
+        });
+        match form_response(req, state).await {
+            Response::Error { code, .. } => assert_eq!(code, ErrorCode::Overflow),
+            Response::Ok { .. } => panic!("Expected error response"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_disabled_command() {
+        let state = Arc::new(ServerState::new(Config {
+            disabled_commands: HashSet::from([CommandKind::Ping]),
+            ..Default::default()
+        }));
+
+        match form_response(build_request(Command::Ping), state.clone()).await {
+            Response::Error { code, .. } => assert_eq!(code, ErrorCode::CommandDisabled),
+            Response::Ok { .. } => panic!("Expected error response"),
+        }
+
+        let echo = Command::Echo(json!("still works"));
+        match form_response(build_request(echo), state.clone()).await {
+            Response::Ok { response, .. } => assert_eq!(response, json!("still works")),
+            Response::Error { .. } => panic!("Expected OK response"),
+        }
+
+        // nested commands are disabled too
+        let batch = Command::Batch(vec![build_request(Command::Ping)].into());
+        match form_response(build_request(batch), state).await {
+            Response::Ok { response, .. } => {
+                assert_eq!(response[0]["code"], "command_disabled");
+            }
+            Response::Error { .. } => panic!("Expected OK response"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_command_commandstatus() {
+        let state = Arc::new(ServerState::new(Config {
+            disabled_commands: HashSet::from([CommandKind::Eval]),
+            command_concurrency: HashMap::from([(CommandKind::Calculate, 4)]),
+            ..Default::default()
+        }));
+
+        match form_response(build_request(Command::CommandStatus), state).await {
+            Response::Ok { response, .. } => {
+                let commands = response.as_object().unwrap();
+                assert_eq!(commands.len(), CommandKind::ALL.len());
+                assert_eq!(
+                    response["eval"],
+                    json!({"enabled": false, "concurrency_limit": null})
+                );
+                assert_eq!(
+                    response["ping"],
+                    json!({"enabled": true, "concurrency_limit": null})
+                );
+                assert_eq!(
+                    response["calculate"],
+                    json!({"enabled": true, "concurrency_limit": 4})
+                );
+            }
+            Response::Error { .. } => panic!("Expected OK response"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_command_concurrency() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let state = Arc::new(ServerState::new(Config {
+            command_concurrency: HashMap::from([(CommandKind::Calculate, 3)]),
+            ..Default::default()
+        }));
+        let running = Arc::new(AtomicUsize::new(0));
+        let max_running = Arc::new(AtomicUsize::new(0));
+        let tasks: Vec<_> = (0..20)
+            .map(|_| {
+                let (state, running, max_running) =
+                    (state.clone(), running.clone(), max_running.clone());
+                tokio::spawn(async move {
+                    let _permit = state
+                        .concurrency_limits
+                        .acquire(CommandKind::Calculate)
+                        .await
+                        .unwrap();
+                    let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    max_running.fetch_max(now, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                    running.fetch_sub(1, Ordering::SeqCst);
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+        assert_eq!(max_running.load(Ordering::SeqCst), 3);
+
+        // the limited commands still work once a slot is free
+        let req = build_request(Command::Calculate {
+            operation: Operation::Add,
+            a: 1.0.into(),
+            b: 2.0.into(),
+            precision: None,
+        });
+        match form_response(req, state).await {
+            Response::Ok { response, .. } => assert_eq!(response, json!({"result": 3.0})),
+            Response::Error { .. } => panic!("Expected OK response"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_command_concurrency_reject_busy() {
+        let state = Arc::new(ServerState::new(Config {
+            command_concurrency: HashMap::from([(CommandKind::Calculate, 1)]),
+            reject_busy: true,
+            ..Default::default()
+        }));
+        let permit = state
+            .concurrency_limits
+            .acquire(CommandKind::Calculate)
+            .await
+            .unwrap();
+        let calculate = || {
+            build_request(Command::Calculate {
+                operation: Operation::Add,
+                a: 1.0.into(),
+                b: 2.0.into(),
+                precision: None,
+            })
+        };
+        match form_response(calculate(), state.clone()).await {
+            Response::Error { code, .. } => assert_eq!(code, ErrorCode::Busy),
+            Response::Ok { .. } => panic!("Expected error response"),
+        }
+        // other commands aren't limited
+        match form_response(build_request(Command::Ping), state.clone()).await {
+            Response::Ok { response, .. } => assert_eq!(response, json!("pong")),
+            Response::Error { .. } => panic!("Expected OK response"),
+        }
+
+        drop(permit);
+        match form_response(calculate(), state).await {
+            Response::Ok { response, .. } => assert_eq!(response, json!({"result": 3.0})),
+            Response::Error { .. } => panic!("Expected OK response"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_command_queuedepth() {
+        let state = Arc::new(ServerState::new(Config {
+            command_concurrency: HashMap::from([
+                (CommandKind::Calculate, 1),
+                (CommandKind::Vector, 2),
+            ]),
+            ..Default::default()
+        }));
+        let queue_depth =
+            async || match form_response(build_request(Command::QueueDepth), state.clone()).await {
+                Response::Ok { response, .. } => response,
+                Response::Error { .. } => panic!("Expected OK response"),
+            };
+        assert_eq!(queue_depth().await, json!({"calculate": 0, "vector": 0}));
+
+        let permit = state.concurrency_limits.acquire(CommandKind::Calculate);
+        let permit = permit.await.unwrap();
+        let waiting: Vec<_> = (0..3)
+            .map(|_| {
+                let req = build_request(Command::Calculate {
+                    operation: Operation::Add,
+                    a: 1.0.into(),
+                    b: 2.0.into(),
+                    precision: None,
+                });
+                tokio::spawn(form_response(req, state.clone()))
+            })
+            .collect();
+        while state.concurrency_limits.queue_depth(CommandKind::Calculate) != Some(3) {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        assert_eq!(queue_depth().await, json!({"calculate": 3, "vector": 0}));
+        let stats = form_response(build_request(Command::Stats), state.clone()).await;
+        let stats = serde_json::to_value(&stats).unwrap();
+        assert_eq!(
+            stats["response"]["queue_depth"],
+            json!({"calculate": 3, "vector": 0})
+        );
+
+        // a request dropped while waiting is no longer queued
+        waiting[0].abort();
+        while state.concurrency_limits.queue_depth(CommandKind::Calculate) != Some(2) {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+
+        drop(permit);
+        for task in waiting.into_iter().skip(1) {
+            match task.await.unwrap() {
+                Response::Ok { response, .. } => assert_eq!(response, json!({"result": 3.0})),
+                Response::Error { .. } => panic!("Expected OK response"),
+            }
+        }
+        assert_eq!(queue_depth().await, json!({"calculate": 0, "vector": 0}));
+    }
+
+    #[tokio::test]
+    async fn test_busy_retry_after() {
+        let busy_state = || {
+            Arc::new(ServerState::new(Config {
+                command_concurrency: HashMap::from([(CommandKind::Calculate, 1)]),
+                reject_busy: true,
+                ..Default::default()
+            }))
+        };
+        let calculate = || {
+            build_request(Command::Calculate {
+                operation: Operation::Divide,
+                a: 1.0.into(),
+                b: 0.0.into(),
+                precision: None,
+            })
+        };
+
+        // the command holding the slot is expected to finish in 500 - 200 ms
+        let state = busy_state();
+        let permit = state.concurrency_limits.acquire(CommandKind::Calculate);
+        let permit = permit.await.unwrap();
+        state.lock_metrics().update(CommandKind::Calculate, 500.0);
+        state.active_requests.lock().unwrap().insert(
+            Uuid::new_v4(),
+            ActiveRequest {
+                command_kind: CommandKind::Calculate,
+                started: Instant::now() - Duration::from_millis(200),
+            },
+        );
+        match form_response(calculate(), state.clone()).await {
+            Response::Error {
+                code,
+                retry_after_ms,
+                ..
+            } => {
+                assert_eq!(code, ErrorCode::Busy);
+                let retry_after_ms = retry_after_ms.unwrap();
+                assert!((250..=300).contains(&retry_after_ms), "{retry_after_ms}");
+            }
+            Response::Ok { .. } => panic!("Expected error response"),
+        }
+
+        // other errors aren't temporary
+        drop(permit);
+        let response = form_response(calculate(), state).await;
+        let json = serde_json::to_value(&response).unwrap();
+        assert_eq!(json["code"], "division_by_zero");
+        assert!(json.get("retry_after_ms").is_none(), "{json}");
+
+        // without the average processing time, the shortest delay is suggested
+        let state = busy_state();
+        let permit = state.concurrency_limits.acquire(CommandKind::Calculate);
+        let _permit = permit.await.unwrap();
+        let response = form_response(calculate(), state).await;
+        let json = serde_json::to_value(&response).unwrap();
+        assert_eq!(json["code"], "busy");
+        assert_eq!(json["retry_after_ms"], MIN_RETRY_AFTER_MS);
+    }
+
+    #[tokio::test]
+    async fn test_replay() {
+        let state = build_state();
+        let req = build_request(Command::Calculate {
+            operation: Operation::Divide,
+            a: 1.0.into(),
+            b: 3.0.into(),
+            precision: Some(4),
+        });
+        let uuid = req.request_id;
+        let original = match form_response(req, state.clone()).await {
+            Response::Ok { response, .. } => response,
+            Response::Error { .. } => panic!("Expected OK response"),
+        };
+
+        let replay = build_request(Command::Replay { request_id: uuid });
+        match form_admin_response(replay, state.clone()).await {
+            Response::Ok { response, .. } => assert_eq!(response, original),
+            Response::Error { .. } => panic!("Expected OK response"),
+        }
+        // the replayed command is counted like the original one
+        let count = state.metrics.lock().unwrap().command_counts[&CommandKind::Calculate];
+        assert_eq!(count, 2);
+
+        let unknown = build_request(Command::Replay {
+            request_id: Uuid::new_v4(),
+        });
+        match form_admin_response(unknown, state.clone()).await {
+            Response::Error { code, .. } => assert_eq!(code, ErrorCode::NotFound),
+            Response::Ok { .. } => panic!("Expected error response"),
+        }
+
+        let unauthorized = build_request(Command::Replay { request_id: uuid });
+        match form_response(unauthorized, state).await {
+            Response::Error { code, .. } => assert_eq!(code, ErrorCode::Unauthorized),
+            Response::Ok { .. } => panic!("Expected error response"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_replay_without_metrics() {
+        let state = Arc::new(ServerState::new(Config {
+            replay_metrics: false,
+            ..Default::default()
+        }));
+        let req = build_request(Command::ForceError {
+            code: ErrorCode::Overflow,
+            message: None,
+        });
+        let uuid = req.request_id;
+        form_admin_response(req, state.clone()).await;
+
+        // the errors are replayed as well
+        let replay = build_request(Command::Replay { request_id: uuid });
+        match form_admin_response(replay, state.clone()).await {
+            Response::Error { code, .. } => assert_eq!(code, ErrorCode::Overflow),
+            Response::Ok { .. } => panic!("Expected error response"),
+        }
+        let count = state.metrics.lock().unwrap().command_counts[&CommandKind::ForceError];
+        assert_eq!(count, 1);
+    }
+
+    #[tokio::test]
+    async fn test_command_matrixmul() {
+        let state = build_state();
+        // large enough to be multiplied on the blocking thread pool
+        let size = 100;
+        let identity: Vec<Vec<f64>> = (0..size)
+            .map(|i| (0..size).map(|j| if i == j { 1.0 } else { 0.0 }).collect())
+            .collect();
+        let matrix: Vec<Vec<f64>> = (0..size)
+            .map(|i| (0..size).map(|j| (i * size + j) as f64).collect())
+            .collect();
+        let req = build_request(Command::MatrixMul {
+            a: matrix.clone(),
+            b: identity,
+        });
+        match form_response(req, state.clone()).await {
+            Response::Ok { response, .. } => assert_eq!(response, json!({"result": matrix})),
+            Response::Error { .. } => panic!("Expected OK response"),
+        }
+
+        let req = build_request(Command::MatrixMul {
+            a: vec![vec![1.0, 2.0]],
+            b: vec![vec![1.0, 2.0]],
+        });
+        match form_response(req, state).await {
+            Response::Error { code, .. } => assert_eq!(code, ErrorCode::DimensionMismatch),
+            Response::Ok { .. } => panic!("Expected error response"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_calculate_overflow() {
+        let state = build_state();
+        for (a, b) in [(1e308, 10.0), (f64::MAX, f64::MAX)] {
+            let req = build_request(Command::Calculate {
+                operation: Operation::Multiply,
+                a: a.into(),
+                b: b.into(),
+                precision: None,
+            });
+            match form_response(req, state.clone()).await {
+                Response::Ok { response, .. } => assert_eq!(response, json!({"result": null})),
+                Response::Error { .. } => panic!("Expected OK response"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_metrics_snapshots() {
+        let state = build_state();
+        let snapshot = |state: Arc<ServerState>| async move {
+            match form_response(build_request(Command::MetricsSnapshot), state).await {
+                Response::Ok { response, .. } => response,
+                Response::Error { .. } => panic!("Expected OK response"),
+            }
+        };
+        form_response(build_request(Command::Ping), state.clone()).await;
+        let first = snapshot(state.clone()).await;
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        for _ in 0..3 {
+            form_response(build_request(Command::Time), state.clone()).await;
+        }
+        let second = snapshot(state.clone()).await;
+
+        assert!(second["timestamp_ms"].as_u64() > first["timestamp_ms"].as_u64());
+        assert!(second["total"].as_u64() >= first["total"].as_u64());
+        for (name, stats) in first["commands"].as_object().unwrap() {
+            let count = second["commands"][name]["count"].as_u64().unwrap();
+            assert!(count >= stats["count"].as_u64().unwrap(), "{name}");
+        }
+        assert_eq!(first["commands"]["ping"]["count"], 1);
+        assert_eq!(second["commands"]["time"]["count"], 3);
+
+        // the series returns the most recent snapshots, oldest first
+        for timestamp_ms in 1..=4 {
+            let snapshot = state.metrics.lock().unwrap().snapshot(timestamp_ms);
+            state.metrics_history.lock().unwrap().push(snapshot);
+        }
+        let req = build_request(Command::MetricsSeries { points: 2 });
+        match form_response(req, state).await {
+            Response::Ok { response, .. } => {
+                let snapshots = response["snapshots"].as_array().unwrap();
+                let timestamps: Vec<_> = snapshots.iter().map(|s| &s["timestamp_ms"]).collect();
+                assert_eq!(timestamps, [3, 4]);
+            }
+            Response::Error { .. } => panic!("Expected OK response"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_command_statssince() {
+        let state = build_state();
+        let stats_since = |since: DateTime<Utc>| build_request(Command::StatsSince { since });
+        form_response(build_request(Command::Ping), state.clone()).await;
+        let before = Utc::now();
+        tokio::time::sleep(Duration::from_millis(5)).await;
+
+        // the checkpoint is the snapshot the server takes periodically
+        let snapshot = state.lock_metrics().snapshot(unix_millis());
+        state.metrics_history.lock().unwrap().push(snapshot);
+        let checkpoint = Utc::now();
+        for _ in 0..3 {
+            form_response(build_request(Command::Time), state.clone()).await;
+        }
+        form_response(build_request(Command::Ping), state.clone()).await;
+
+        match form_response(stats_since(checkpoint), state.clone()).await {
+            Response::Ok { response, .. } => {
+                assert_eq!(response["total"], 4);
+                let commands = response["commands"].as_object().unwrap();
+                assert_eq!(commands.len(), 2, "{commands:?}");
+                assert_eq!(commands["time"]["count"], 3);
+                assert_eq!(commands["ping"]["count"], 1);
+                assert!(commands["ping"]["avg"].as_f64().unwrap() >= 0.0);
+            }
+            Response::Error { .. } => panic!("Expected OK response"),
+        }
+
+        // there's no checkpoint before the first snapshot
+        match form_response(stats_since(before), state.clone()).await {
+            Response::Error { code, .. } => assert_eq!(code, ErrorCode::NotFound),
+            Response::Ok { .. } => panic!("Expected error response"),
+        }
+        let future = Utc::now() + chrono::Duration::minutes(1);
+        match form_response(stats_since(future), state).await {
+            Response::Error { code, .. } => assert_eq!(code, ErrorCode::InvalidArgument),
+            Response::Ok { .. } => panic!("Expected error response"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_command_subscribemetrics() {
+        let state = build_state();
+        form_response(build_request(Command::Ping), state.clone()).await;
+        // nested into a batch, the subscription returns a single snapshot
+        let subscribe = build_request(Command::SubscribeMetrics { interval_ms: 100 });
+        let batch = build_request(Command::Batch(BatchPayload::Requests(vec![subscribe])));
+        match form_response(batch, state.clone()).await {
+            Response::Ok { response, .. } => {
+                let snapshot = &response[0]["response"];
+                assert_eq!(snapshot["commands"]["ping"]["count"], 1);
+            }
+            Response::Error { .. } => panic!("Expected OK response"),
+        }
+
+        let (min, max) = (MIN_SUBSCRIPTION_INTERVAL_MS, MAX_SUBSCRIPTION_INTERVAL_MS);
+        for interval_ms in [0, min - 1, max + 1] {
+            let req = build_request(Command::SubscribeMetrics { interval_ms });
+            match form_response(req, state.clone()).await {
+                Response::Error { code, .. } => assert_eq!(code, ErrorCode::InvalidArgument),
+                Response::Ok { .. } => panic!("Expected error response for {interval_ms}"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_content_hash() {
+        let state = Arc::new(ServerState::new(Config {
+            content_hash: true,
+            ..Default::default()
+        }));
+        let echo = || build_request(Command::Echo(json!({"cached": true})));
+        let etag = match form_response(echo(), state.clone()).await {
+            Response::Ok { response, etag, .. } => {
+                assert_eq!(etag, Some(content_hash(&response)));
+                etag.unwrap()
+            }
+            Response::Error { .. } => panic!("Expected OK response"),
+        };
+
+        let mut repeated = echo();
+        repeated.if_none_match = Some(etag.clone());
+        match form_response(repeated, state.clone()).await {
+            Response::Error { code, .. } => assert_eq!(code, ErrorCode::NotModified),
+            Response::Ok { .. } => panic!("Expected error response"),
+        }
+
+        // a different result is sent in full
+        let mut changed = build_request(Command::Echo(json!({"cached": false})));
+        changed.if_none_match = Some(etag.clone());
+        match form_response(changed, state).await {
+            Response::Ok {
+                response,
+                etag: new,
+                ..
+            } => {
+                assert_eq!(response, json!({"cached": false}));
+                assert_ne!(new, Some(etag.clone()));
+            }
+            Response::Error { .. } => panic!("Expected OK response"),
+        }
+
+        // without the hashing, there are no etags to match
+        let mut unhashed = echo();
+        unhashed.if_none_match = Some(content_hash(&json!({"cached": true})));
+        match form_response(unhashed, build_state()).await {
+            Response::Ok { etag, .. } => assert_eq!(etag, None),
+            Response::Error { .. } => panic!("Expected OK response"),
+        }
+
+        // a repeated request answered from the cache is checked against its own if_none_match
+        let state = Arc::new(ServerState::new(Config {
+            content_hash: true,
+            idempotency_ttl_secs: 60,
+            ..Default::default()
+        }));
+        let mut matching = echo();
+        matching.if_none_match = Some(etag.clone());
+        let mut plain = matching.clone();
+        plain.if_none_match = None;
+        for req in [matching.clone(), plain, matching] {
+            let has_etag = req.if_none_match.is_some();
+            match form_response(req, state.clone()).await {
+                Response::Error { code, .. } if has_etag => {
+                    assert_eq!(code, ErrorCode::NotModified)
+                }
+                Response::Ok { response, .. } if !has_etag => {
+                    assert_eq!(response, json!({"cached": true}))
+                }
+                _ => panic!("Unexpected response"),
+            }
+        }
+        let count = state.metrics.lock().unwrap().command_counts[&CommandKind::Echo];
+        assert_eq!(count, 1);
+    }
+
+    #[tokio::test]
+    async fn test_recent_requests() {
+        let state = build_state();
+        let divide = |b: f64| {
+            build_request(Command::Calculate {
+                operation: Operation::Divide,
+                a: 1.0.into(),
+                b: b.into(),
+                precision: None,
+            })
+        };
+        let requests = [build_request(Command::Ping), divide(0.0), divide(2.0)];
+        let uuids: Vec<Uuid> = requests.iter().map(|r| r.request_id).collect();
+        for req in requests {
+            form_response(req, state.clone()).await;
+        }
+
+        let req = build_request(Command::RecentRequests { limit: 10 });
+        let records = match form_admin_response(req, state.clone()).await {
+            Response::Ok { response, .. } => response["requests"].clone(),
+            Response::Error { .. } => panic!("Expected OK response"),
+        };
+        let records = records.as_array().unwrap();
+        assert_eq!(records.len(), 3);
+        let expected = [
+            (uuids[2], "calculate", true, None),
+            (uuids[1], "calculate", false, Some("division_by_zero")),
+            (uuids[0], "ping", true, None),
+        ];
+        for (record, (uuid, command, ok, code)) in records.iter().zip(expected) {
+            assert_eq!(record["request_id"], uuid.to_string());
+            assert_eq!(record["command"], command);
+            assert_eq!(record["ok"], ok);
+            assert_eq!(record["code"].as_str(), code);
+            assert!(record["duration_ms"].as_f64().unwrap() >= 0.0);
+        }
+
+        // the listing itself is recorded as well
+        let req = build_request(Command::RecentRequests { limit: 2 });
+        match form_admin_response(req, state.clone()).await {
+            Response::Ok { response, .. } => {
+                let records = response["requests"].as_array().unwrap();
+                assert_eq!(records.len(), 2);
+                assert_eq!(records[0]["command"], "recentrequests");
+                assert_eq!(records[1]["request_id"], uuids[2].to_string());
+            }
+            Response::Error { .. } => panic!("Expected OK response"),
+        }
+
+        let req = build_request(Command::RecentRequests { limit: 1 });
+        match form_response(req, state).await {
+            Response::Error { code, .. } => assert_eq!(code, ErrorCode::Unauthorized),
+            Response::Ok { .. } => panic!("Expected error response"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_calculate_precision() {
+        let state = build_state();
+        for (precision, expected) in [(Some(2), 0.3), (None, 0.1 + 0.2)] {
+            let req = build_request(Command::Calculate {
+                operation: Operation::Add,
+                a: 0.1.into(),
+                b: 0.2.into(),
+                precision,
+            });
+            match form_response(req, state.clone()).await {
+                Response::Ok { response, .. } => {
+                    assert_eq!(response, json!({"result": expected}));
+                }
+                Response::Error { .. } => panic!("Expected OK response"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_command_forceerror() {
+        let state = build_state();
+        let req = build_request(Command::ForceError {
+            code: ErrorCode::DivisionByZero,
+            message: Some("custom message".to_string()),
+        });
+        match form_admin_response(req, state.clone()).await {
+            Response::Error { code, error, .. } => {
+                assert_eq!(code, ErrorCode::DivisionByZero);
+                assert_eq!(error, "custom message");
+            }
+            Response::Ok { .. } => panic!("Expected error response"),
+        }
+
+        let req = build_request(Command::ForceError {
+            code: ErrorCode::Internal,
+            message: None,
+        });
+        match form_admin_response(req, state.clone()).await {
+            Response::Error { code, error, .. } => {
+                assert_eq!(code, ErrorCode::Internal);
+                assert_eq!(error, "forced `internal` error");
+            }
+            Response::Ok { .. } => panic!("Expected error response"),
+        }
+
+        // the command requires the administrative token
+        let req = build_request(Command::ForceError {
+            code: ErrorCode::Internal,
+            message: None,
+        });
+        match form_response(req, state).await {
+            Response::Error { code, .. } => assert_eq!(code, ErrorCode::Unauthorized),
+            Response::Ok { .. } => panic!("Expected error response"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_command_pad() {
+        let state = Arc::new(ServerState::new(Config {
+            max_response_bytes: Some(10_000),
+            ..Default::default()
+        }));
+        for size in [0, 1, 27, 1500, 10_000] {
+            match form_response(build_request(Command::Pad { size }), state.clone()).await {
+                Response::Ok { response, .. } => {
+                    let data = response["data"].as_str().unwrap();
+                    assert_eq!(data.len(), size);
+                    // the pattern makes every byte predictable
+                    for (i, b) in data.bytes().enumerate() {
+                        assert_eq!(b, b'a' + (i % 26) as u8, "{i}");
+                    }
+                }
+                Response::Error { .. } => panic!("Expected OK response for {size}"),
+            }
+        }
+
+        let req = build_request(Command::Pad { size: 10_001 });
+        match form_response(req, state).await {
+            Response::Error { code, .. } => assert_eq!(code, ErrorCode::InvalidArgument),
+            Response::Ok { .. } => panic!("Expected error response"),
+        }
+        let req = build_request(Command::Pad {
+            size: MAX_PAD_BYTES + 1,
+        });
+        match form_response(req, build_state()).await {
+            Response::Error { code, .. } => assert_eq!(code, ErrorCode::InvalidArgument),
+            Response::Ok { .. } => panic!("Expected error response"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_command_normalizerequest() {
+        let state = build_state();
+        let uuid = Uuid::new_v4();
+        let request: Request = serde_json::from_value(json!({
+            "request_id": uuid,
+            "command": "batch",
+            "payload": [
+                {
+                    "request_id": uuid,
+                    "command": "latencyhistogram",
+                    "payload": {"command": "ping"},
+                },
+                {
+                    "request_id": uuid,
+                    "command": "forceerror",
+                    "payload": {"code": "internal"},
+                    "auth": "secret",
+                },
+                {
+                    "request_id": uuid,
+                    "command": "calculate",
+                    "payload": {"operation": "add", "a": 1, "b": 2},
+                },
+            ],
+        }))
+        .unwrap();
+        let req = build_request(Command::NormalizeRequest {
+            request: Box::new(request),
+        });
+        match form_response(req, state).await {
+            Response::Ok { response, .. } => {
+                assert_eq!(response["request_id"], uuid.to_string());
+                let requests = &response["payload"];
+                assert_eq!(
+                    requests[0]["payload"],
+                    json!({"command": "ping", "buckets": Histogram::BUCKETS})
+                );
+                assert_eq!(
+                    requests[1]["payload"],
+                    json!({"code": "internal", "message": "forced `internal` error"})
+                );
+                // the fields without a substitute stay absent, and the token isn't returned
+                assert_eq!(requests[1].get("auth"), None);
+                assert_eq!(requests[2]["payload"].get("precision"), None);
+            }
+            Response::Error { .. } => panic!("Expected OK response"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_command_latencyhistogram() {
+        let state = build_state();
+        for _ in 0..3 {
+            form_response(build_request(Command::Ping), state.clone()).await;
+        }
+
+        let histogram =
+            |command, buckets| build_request(Command::LatencyHistogram { command, buckets });
+        match form_response(histogram(CommandKind::Ping, Some(4)), state.clone()).await {
+            Response::Ok { response, .. } => {
+                assert_eq!(response["count"], 3);
+                let buckets = response["buckets"].as_array().unwrap();
+                assert_eq!(buckets.len(), 4);
+                let sum: u64 = buckets.iter().map(|b| b["count"].as_u64().unwrap()).sum();
+                assert_eq!(sum, 3);
+                assert!(buckets[3]["le_ms"].is_null());
+            }
+            Response::Error { .. } => panic!("Expected OK response"),
+        }
+
+        match form_response(histogram(CommandKind::Eval, None), state).await {
+            Response::Ok { response, .. } => {
+                assert_eq!(response["count"], 0);
+                let buckets = response["buckets"].as_array().unwrap();
+                assert_eq!(buckets.len(), Histogram::BUCKETS);
+                assert!(buckets.iter().all(|b| b["count"] == 0));
+            }
+            Response::Error { .. } => panic!("Expected OK response"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_command_macros() {
+        let state = build_state();
+        let define = build_request(Command::DefineMacro {
+            name: "twice".to_string(),
+            steps: vec![Command::Ping, Command::Ping],
+        });
+        match form_response(define, state.clone()).await {
+            Response::Ok { response, .. } => {
+                assert_eq!(response, json!({"name": "twice", "steps": 2}));
+            }
+            Response::Error { .. } => panic!("Expected OK response"),
+        }
+
+        let run = |name: &str| {
+            build_request(Command::RunMacro {
+                name: name.to_string(),
+            })
+        };
+        match form_response(run("twice"), state.clone()).await {
+            Response::Ok { response, .. } => {
+                let items: Vec<Response> = serde_json::from_value(response).unwrap();
+                assert_eq!(items.len(), 2);
+                for item in items {
+                    match item {
+                        Response::Ok { response, .. } => assert_eq!(response, json!("pong")),
+                        Response::Error { .. } => panic!("Expected OK response"),
+                    }
+                }
+            }
+            Response::Error { .. } => panic!("Expected OK response"),
+        }
+
+        match form_response(run("missing"), state.clone()).await {
+            Response::Error { code, .. } => assert_eq!(code, ErrorCode::NotFound),
+            Response::Ok { .. } => panic!("Expected error response"),
+        }
+
+        // a macro can't run macros, so it can't run itself forever
+        let define = build_request(Command::DefineMacro {
+            name: "loop".to_string(),
+            steps: vec![Command::RunMacro {
+                name: "loop".to_string(),
+            }],
+        });
+        form_response(define, state.clone()).await;
+        match form_response(run("loop"), state.clone()).await {
+            Response::Ok { response, .. } => assert_eq!(response[0]["code"], "invalid_argument"),
+            Response::Error { .. } => panic!("Expected OK response"),
+        }
+
+        // the macro of an administrator can't be replaced by a client without its token
+        let admin = ClientIdentity::new("127.0.0.1:50000".parse().unwrap(), Some("admin".into()));
+        let as_admin = |req| with_client(admin.clone(), form_admin_response(req, state.clone()));
+        let define = |steps| {
+            build_request(Command::DefineMacro {
+                name: "report".to_string(),
+                steps,
+            })
+        };
+        as_admin(define(vec![Command::Noop])).await;
+        form_response(define(vec![Command::Ping, Command::Ping]), state.clone()).await;
+        match as_admin(run("report")).await {
+            Response::Ok { response, .. } => {
+                assert_eq!(response.as_array().unwrap().len(), 1);
+                assert_eq!(response[0]["response"], json!({}));
+            }
+            Response::Error { .. } => panic!("Expected OK response"),
+        }
+        match form_response(run("report"), state.clone()).await {
+            Response::Ok { response, .. } => assert_eq!(response.as_array().unwrap().len(), 2),
+            Response::Error { .. } => panic!("Expected OK response"),
+        }
+
+        // a macro defined under one token can't be run under another one
+        let client =
+            |auth: &str| ClientIdentity::new("127.0.0.1:50000".parse().unwrap(), Some(auth.into()));
+        let define = build_request(Command::DefineMacro {
+            name: "private".to_string(),
+            steps: vec![Command::Ping],
+        });
+        with_client(client("alice"), form_response(define, state.clone())).await;
+        match with_client(client("bob"), form_response(run("private"), state.clone())).await {
+            Response::Error { code, .. } => assert_eq!(code, ErrorCode::NotFound),
+            Response::Ok { .. } => panic!("Expected error response"),
+        }
+        match with_client(client("alice"), form_response(run("private"), state)).await {
+            Response::Ok { response, .. } => assert_eq!(response[0]["response"], "pong"),
+            Response::Error { .. } => panic!("Expected OK response"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_command_describe() {
+        let state = build_state();
+        let values = vec![3.0, 1.0, 2.0, 6.0];
+        let req = build_request(Command::Describe { values });
+        match form_response(req, state.clone()).await {
+            Response::Ok { response, .. } => {
+                assert_eq!(response["mean"], 3.0);
+                assert_eq!(response["median"], 2.5);
+                assert_eq!(response["min"], 1.0);
+                assert_eq!(response["max"], 6.0);
+                assert!(response["stddev"].is_f64());
+            }
+            Response::Error { .. } => panic!("Expected OK response"),
+        }
+
+        let req = build_request(Command::Describe { values: Vec::new() });
+        match form_response(req, state).await {
+            Response::Error { code, .. } => assert_eq!(code, ErrorCode::InvalidArgument),
+            Response::Ok { .. } => panic!("Expected error response"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_batch_stop_on_error() {
+        let state = build_state();
+        let calculate = |b: f64| {
+            build_request(Command::Calculate {
+                operation: Operation::Divide,
+                a: 1.0.into(),
+                b: b.into(),
+                precision: None,
+            })
+        };
+        let batch = |stop_on_error| {
+            build_request(Command::Batch(BatchPayload::WithOptions(BatchOptions {
+                requests: vec![calculate(2.0), calculate(0.0), calculate(4.0)],
+                stop_on_error,
+                checksum: None,
+            })))
+        };
+
+        match form_response(batch(true), state.clone()).await {
+            Response::Ok { response, .. } => {
+                assert_eq!(response["truncated"], true);
+                let responses = response["responses"].as_array().unwrap();
+                assert_eq!(responses.len(), 2);
+                assert_eq!(responses[0]["response"], json!({"result": 0.5}));
+                assert_eq!(responses[1]["code"], "division_by_zero");
+            }
+            Response::Error { .. } => panic!("Expected OK response"),
+        }
+
+        match form_response(batch(false), state).await {
+            Response::Ok { response, .. } => {
+                assert_eq!(response["truncated"], false);
+                assert_eq!(response["responses"].as_array().unwrap().len(), 3);
+            }
+            Response::Error { .. } => panic!("Expected OK response"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_batch_checksum() {
+        let state = build_state();
+        let requests = vec![
+            build_request(Command::Ping),
+            build_request(Command::Echo(json!({"b": 1, "a": 2}))),
+        ];
+        let batch = |checksum| {
+            build_request(Command::Batch(BatchPayload::WithOptions(BatchOptions {
+                requests: requests.clone(),
+                stop_on_error: false,
+                checksum: Some(checksum),
+            })))
+        };
+
+        let canonical = format!(
+            r#"[{{"command":"ping","request_id":"{}"}},{{"command":"echo","payload":{{"a":2,"b":1}},"request_id":"{}"}}]"#,
+            requests[0].request_id, requests[1].request_id
+        );
+        let checksum = format!("{:x}", Sha256::digest(canonical));
+        assert_eq!(batch_checksum(&requests), checksum);
+
+        match form_response(batch(checksum.to_uppercase()), state.clone()).await {
+            Response::Ok { response, .. } => {
+                assert_eq!(response["responses"].as_array().unwrap().len(), 2);
+            }
+            Response::Error { .. } => panic!("Expected OK response"),
+        }
+
+        match form_response(batch("0".repeat(64)), state).await {
+            Response::Error { code, error, .. } => {
+                assert!(matches!(code, ErrorCode::ChecksumMismatch));
+                assert!(error.contains(&checksum));
+            }
+            Response::Ok { .. } => panic!("Expected error response"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_batch_truncated_by_shutdown() {
+        for with_options in [false, true] {
+            let state = Arc::new(ServerState::new(Config {
+                command_concurrency: HashMap::from([(CommandKind::Calculate, 1)]),
+                ..Default::default()
+            }));
+            // the batch waits at its third request until the shutdown starts
+            let permit = state
+                .concurrency_limits
+                .acquire(CommandKind::Calculate)
+                .await
+                .unwrap();
+            let mut requests: Vec<_> = (0..100).map(|_| build_request(Command::Ping)).collect();
+            requests[2] = build_request(Command::Calculate {
+                operation: Operation::Add,
+                a: 1.0.into(),
+                b: 2.0.into(),
+                precision: None,
+            });
+            let payload = match with_options {
+                false => BatchPayload::Requests(requests),
+                true => BatchPayload::WithOptions(BatchOptions {
+                    requests,
+                    stop_on_error: false,
+                    checksum: None,
+                }),
+            };
+            let batch = build_request(Command::Batch(payload));
+            let processing = tokio::spawn(form_response(batch, state.clone()));
+            let waiting = async {
+                let is_calculating = || {
+                    let active = state.active_requests.lock().unwrap();
+                    let mut kinds = active.values().map(|r| r.command_kind);
+                    kinds.any(|kind| kind == CommandKind::Calculate)
+                };
+                while !is_calculating() {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            };
+            tokio::time::timeout(Duration::from_secs(5), waiting)
+                .await
+                .unwrap();
+
+            state.draining.store(true, Ordering::Relaxed);
+            drop(permit);
+            let response = match processing.await.unwrap() {
+                Response::Ok { response, .. } => response,
+                Response::Error { .. } => panic!("Expected OK response"),
+            };
+            // a plain batch still gets an array, and the one with options is marked
+            let responses = match with_options {
+                false => response.as_array().unwrap(),
+                true => {
+                    assert_eq!(response["truncated"], true);
+                    assert_eq!(response["truncated_by_shutdown"], true);
+                    response["responses"].as_array().unwrap()
+                }
+            };
+            // the request in progress is completed, and the rest aren't processed
+            assert_eq!(responses.len(), 3);
+            assert_eq!(responses[2]["response"], json!({"result": 3.0}));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_command_streamrange() {
+        let state = build_state();
+        let req = build_request(Command::StreamRange { start: -2, end: 2 });
+        match form_response(req, state.clone()).await {
+            Response::Ok { response, .. } => assert_eq!(response, json!([-2, -1, 0, 1])),
+            Response::Error { .. } => panic!("Expected OK response"),
+        }
+
+        let too_long = MAX_STREAM_RANGE as i64 + 1;
+        for (start, end) in [(1, 0), (0, too_long), (i64::MIN, i64::MAX)] {
+            let req = build_request(Command::StreamRange { start, end });
+            match form_response(req, state.clone()).await {
+                Response::Error { code, .. } => assert_eq!(code, ErrorCode::InvalidArgument),
+                Response::Ok { .. } => panic!("Expected error response"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_command_slowecho() {
+        let state = build_state();
+        let slowecho = |payload: Value, bytes_per_sec| {
+            build_request(Command::SlowEcho {
+                payload,
+                bytes_per_sec,
+            })
+        };
+        match form_response(slowecho(json!([1, 2]), MIN_SLOWECHO_RATE), state.clone()).await {
+            Response::Ok { response, .. } => assert_eq!(response, json!([1, 2])),
+            Response::Error { .. } => panic!("Expected OK response"),
+        }
+
+        // the serialized string is 2 bytes longer because of the quotes
+        let limit = (MIN_SLOWECHO_RATE * MAX_SLOWECHO_SECS) as usize;
+        let longest = json!("x".repeat(limit - 2));
+        match form_response(slowecho(longest.clone(), MIN_SLOWECHO_RATE), state.clone()).await {
+            Response::Ok { response, .. } => assert_eq!(response, longest),
+            Response::Error { .. } => panic!("Expected OK response"),
+        }
+
+        // too long to send at the given rate, even by a single byte
+        for (payload, bytes_per_sec) in [
+            (json!([1, 2]), MIN_SLOWECHO_RATE - 1),
+            (json!([1, 2]), 0),
+            (json!("x".repeat(limit - 1)), MIN_SLOWECHO_RATE),
+            (json!("x".repeat(limit * 2)), MIN_SLOWECHO_RATE),
+        ] {
+            match form_response(slowecho(payload, bytes_per_sec), state.clone()).await {
+                Response::Error { code, .. } => assert_eq!(code, ErrorCode::InvalidArgument),
+                Response::Ok { .. } => panic!("Expected error response"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_command_seededrandom() {
+        let state = build_state();
+        let values = async |seed, count| {
+            let req = build_request(Command::SeededRandom { seed, count });
+            match form_response(req, state.clone()).await {
+                Response::Ok { response, .. } => response["values"].clone(),
+                Response::Error { .. } => panic!("Expected OK response"),
+            }
+        };
+        let first = values(42, 100).await;
+        assert_eq!(first.as_array().unwrap().len(), 100);
+        assert_eq!(values(42, 100).await, first);
+        assert_ne!(values(43, 100).await, first);
+        // a shorter sequence is the start of the longer one
+        assert_eq!(values(42, 10).await, json!(first.as_array().unwrap()[..10]));
+        assert_eq!(values(42, 0).await, json!([]));
+
+        let req = build_request(Command::SeededRandom {
+            seed: 42,
+            count: numbers::MAX_RANDOM_COUNT + 1,
+        });
+        match form_response(req, state.clone()).await {
+            Response::Error { code, .. } => assert_eq!(code, ErrorCode::InvalidArgument),
+            Response::Ok { .. } => panic!("Expected error response"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_command_sample() {
+        let state = build_state();
+        let values = vec![json!("a"), json!({"b": 1}), json!([2])];
+        for (n, replacement) in [(3, false), (2, false), (10, true)] {
+            let req = build_request(Command::Sample {
+                values: values.clone(),
+                n,
+                replacement,
+                seed: None,
+            });
+            let picked = match form_response(req, state.clone()).await {
+                Response::Ok { response, .. } => response["values"].clone(),
+                Response::Error { .. } => panic!("Expected OK response"),
+            };
+            let picked = picked.as_array().unwrap();
+            assert_eq!(picked.len(), n);
+            assert!(picked.iter().all(|v| values.contains(v)), "{picked:?}");
+            if !replacement {
+                let distinct: HashSet<String> = picked.iter().map(|v| v.to_string()).collect();
+                assert_eq!(distinct.len(), n);
+            }
+        }
+
+        let req = build_request(Command::Sample {
+            values,
+            n: 4,
+            replacement: false,
+            seed: Some(42),
+        });
+        match form_response(req, state).await {
+            Response::Error { code, .. } => assert_eq!(code, ErrorCode::InvalidArgument),
+            Response::Ok { .. } => panic!("Expected error response"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_command_crc32() {
+        let state = build_state();
+        for (data, expected) in [
+            // the standard check value of CRC-32
+            ("123456789", 0xCBF43926_u32),
+            ("", 0),
+            ("The quick brown fox jumps over the lazy dog", 0x414FA339),
+            ("привет", 0x2E763E4E),
+        ] {
+            let req = build_request(Command::Crc32 {
+                data: data.to_string(),
+            });
+            match form_response(req, state.clone()).await {
+                Response::Ok { response, .. } => assert_eq!(response, json!({"crc32": expected})),
+                Response::Error { .. } => panic!("Expected OK response"),
+            }
+        }
+        let metrics = state.lock_metrics();
+        assert_eq!(metrics.command_counts.get(&CommandKind::Crc32), Some(&4));
+    }
+
+    #[tokio::test]
+    async fn test_command_errorcodes() {
+        let req = build_request(Command::ErrorCodes);
+        match form_response(req, build_state()).await {
+            Response::Ok { response, .. } => {
+                let codes = response["codes"].as_array().unwrap();
+                assert_eq!(codes.len(), ErrorCode::ALL.len());
+                let division = codes
+                    .iter()
+                    .find(|code| code["code"] == "division_by_zero")
+                    .expect("division_by_zero is listed");
+                assert_eq!(
+                    division["description"],
+                    "The command has attempted to divide by zero."
+                );
+                // the descriptions spanning several lines of a doc comment are joined
+                let memory = codes
+                    .iter()
+                    .find(|code| code["code"] == "memory_exhausted")
+                    .unwrap();
+                assert!(
+                    memory["description"]
+                        .as_str()
+                        .unwrap()
+                        .starts_with("The server has exceeded its memory budget and doesn't")
+                );
+                for code in codes {
+                    assert!(!code["description"].as_str().unwrap().is_empty());
+                }
+            }
+            Response::Error { .. } => panic!("Expected OK response"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_command_encrypt_decrypt() {
+        let state = build_state();
+        let encrypt = async |data: Value| {
+            let req = build_request(Command::Encrypt { data });
+            match form_response(req, state.clone()).await {
+                Response::Ok { response, .. } => response["data"].as_str().unwrap().to_string(),
+                Response::Error { .. } => panic!("Expected OK response"),
+            }
+        };
+        let decrypt = async |data: &str| {
+            let req = build_request(Command::Decrypt {
+                data: data.to_string(),
+            });
+            match form_response(req, state.clone()).await {
+                Response::Ok { response, .. } => Ok(response["data"].clone()),
+                Response::Error { code, .. } => Err(code),
+            }
+        };
+        let data = json!({"card": "4111 1111 1111 1111", "amounts": [1, 2.5]});
+
+        let ciphertext = with_new_session(async {
+            let ciphertext = encrypt(data.clone()).await;
+            assert!(!ciphertext.contains("4111"));
+            assert_eq!(decrypt(&ciphertext).await, Ok(data.clone()));
+            // the nonce is random, so the same value is encrypted differently every time
+            let other = encrypt(data.clone()).await;
+            assert_ne!(other, ciphertext);
+            assert_eq!(decrypt(&other).await, Ok(data.clone()));
+
+            // the result of encrypt is the payload of decrypt in a pipe
+            let req = build_request(Command::Pipe {
+                stages: vec![
+                    Command::Encrypt { data: data.clone() },
+                    Command::Decrypt {
+                        data: String::new(),
+                    },
+                ],
+            });
+            match form_response(req, state.clone()).await {
+                Response::Ok { response, .. } => assert_eq!(response, json!({"data": data})),
+                Response::Error { .. } => panic!("Expected OK response"),
+            }
+            ciphertext
+        })
+        .await;
+
+        // another connection has its own key
+        let code = with_new_session(decrypt(&ciphertext)).await;
+        assert_eq!(code, Err(ErrorCode::DecryptionFailed));
+    }
+
+    #[tokio::test]
+    async fn test_command_decrypt_tampered() {
+        let state = build_state();
+        let exchange = async |command: Command| {
+            let req = build_request(command);
+            match form_response(req, state.clone()).await {
+                Response::Ok { response, .. } => Ok(response["data"].clone()),
+                Response::Error { code, .. } => Err(code),
+            }
+        };
+        with_new_session(async {
+            let ciphertext = exchange(Command::Encrypt { data: json!(42) }).await;
+            let ciphertext = ciphertext.unwrap();
+            let ciphertext = ciphertext.as_str().unwrap();
+            // flips a bit of the last byte of the ciphertext, which is a part of its tag
+            let (head, last) = ciphertext.split_at(ciphertext.len() - 2);
+            let last = u8::from_str_radix(last, 16).unwrap() ^ 1;
+            let tampered = format!("{head}{last:02x}");
+            // and of the first byte of the encrypted data, which follows the nonce
+            let mut bytes = ciphertext.as_bytes().to_vec();
+            bytes[24] = if bytes[24] == b'0' { b'1' } else { b'0' };
+            let tampered_data = String::from_utf8(bytes).unwrap();
+
+            for (data, expected) in [
+                (tampered, ErrorCode::DecryptionFailed),
+                (tampered_data, ErrorCode::DecryptionFailed),
+                (ciphertext[..20].to_string(), ErrorCode::DecryptionFailed),
+                ("not hex".to_string(), ErrorCode::InvalidArgument),
+                (format!("{ciphertext}0"), ErrorCode::InvalidArgument),
+            ] {
+                let result = exchange(Command::Decrypt { data }).await;
+                assert_eq!(result, Err(expected));
+            }
+            let data = ciphertext.to_string();
+            assert_eq!(exchange(Command::Decrypt { data }).await, Ok(json!(42)));
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_command_mapcalculate() {
+        let req = build_request(Command::MapCalculate {
+            operation: Operation::Divide,
+            pairs: vec![(6.0, 3.0), (1.0, 0.0), (-5.0, 2.0), (0.0, 0.0)],
+        });
+        match form_response(req, build_state()).await {
+            Response::Ok { response, .. } => assert_eq!(
+                response,
+                json!({"results": [
+                    {"result": 2.0},
+                    {"code": "division_by_zero", "error": "division by zero"},
+                    {"result": -2.5},
+                    {"code": "division_by_zero", "error": "division by zero"},
+                ]})
+            ),
+            Response::Error { .. } => panic!("Expected OK response"),
+        }
+
+        let req = build_request(Command::MapCalculate {
+            operation: Operation::Multiply,
+            pairs: vec![],
+        });
+        match form_response(req, build_state()).await {
+            Response::Ok { response, .. } => assert_eq!(response, json!({"results": []})),
+            Response::Error { .. } => panic!("Expected OK response"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_command_typeof() {
+        let state = build_state();
+        for (data, expected) in [
+            (json!({"a": [1, 2]}), json!({"type": "object", "size": 1})),
+            (json!({}), json!({"type": "object", "size": 0})),
+            (json!([1, "two", null]), json!({"type": "array", "size": 3})),
+            (json!([]), json!({"type": "array", "size": 0})),
+            // the characters are counted rather than the bytes
+            (json!("привет"), json!({"type": "string", "size": 6})),
+            (json!(""), json!({"type": "string", "size": 0})),
+            (json!(-1.5), json!({"type": "number"})),
+            (json!(42), json!({"type": "number"})),
+            (json!(true), json!({"type": "bool"})),
+            (json!(null), json!({"type": "null"})),
+        ] {
+            let req = build_request(Command::Typeof { data });
+            match form_response(req, state.clone()).await {
+                Response::Ok { response, .. } => assert_eq!(response, expected),
+                Response::Error { .. } => panic!("Expected OK response"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_command_vector() {
+        let state = build_state();
+        let vector = async |operation: VectorOp, b: Option<Vec<f64>>| {
+            let req = build_request(Command::Vector {
+                operation,
+                a: vec![3.0, 4.0],
+                b,
+            });
+            match form_response(req, state.clone()).await {
+                Response::Ok { response, .. } => Ok(response),
+                Response::Error { code, .. } => Err(code),
+            }
+        };
+        let result = vector(VectorOp::Dot, Some(vec![2.0, -1.0])).await;
+        assert_eq!(result, Ok(json!({"result": 2.0})));
+        // b is ignored by the operations on a single vector
+        let result = vector(VectorOp::Norm, Some(vec![1.0])).await;
+        assert_eq!(result, Ok(json!({"result": 5.0})));
+        let result = vector(VectorOp::Normalize, None).await;
+        assert_eq!(result, Ok(json!({"result": [0.6, 0.8]})));
+
+        let result = vector(VectorOp::Dot, Some(vec![1.0, 2.0, 3.0])).await;
+        assert_eq!(result, Err(ErrorCode::DimensionMismatch));
+        let result = vector(VectorOp::Dot, None).await;
+        assert_eq!(result, Err(ErrorCode::InvalidArgument));
+    }
+
+    #[tokio::test]
+    async fn test_command_uptime() {
+        let state = build_state();
+        let uptime = async || {
+            let req = build_request(Command::Uptime);
+            match form_response(req, state.clone()).await {
+                Response::Ok { response, .. } => response,
+                Response::Error { .. } => panic!("Expected OK response"),
+            }
+        };
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let first = uptime().await;
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let second = uptime().await;
+
+        let (first_ms, second_ms) = (first["uptime_ms"].as_u64(), second["uptime_ms"].as_u64());
+        assert!(first_ms.unwrap() >= 20, "{first}");
+        assert!(
+            second_ms.unwrap() >= first_ms.unwrap() + 20,
+            "{first} {second}"
+        );
+        assert_eq!(first["started_at"], second["started_at"]);
+        let started_at = first["started_at"].as_str().unwrap();
+        let started_at = DateTime::parse_from_rfc3339(started_at).unwrap();
+        assert!(started_at < Utc::now());
+    }
+
+    #[tokio::test]
+    async fn test_command_buildinfo() {
+        let req = build_request(Command::BuildInfo);
+        match form_response(req, build_state()).await {
+            Response::Ok { response, .. } => {
+                assert_eq!(response["version"], env!("CARGO_PKG_VERSION"));
+                let profile = response["profile"].as_str().unwrap();
+                assert!(["debug", "release"].contains(&profile), "{profile}");
+                assert!(!response["target"].as_str().unwrap().is_empty());
+                match &response["git_commit"] {
+                    Value::Null => {}
+                    Value::String(commit) => {
+                        assert!(commit.len() >= 40, "{commit}");
+                        assert!(commit.chars().all(|c| c.is_ascii_hexdigit()), "{commit}");
+                    }
+                    other => panic!("Unexpected git_commit: {other}"),
+                }
+            }
+            Response::Error { .. } => panic!("Expected OK response"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_command_protocolversion() {
+        let req = build_request(Command::ProtocolVersion);
+        match form_response(req, build_state()).await {
+            Response::Ok { response, .. } => {
+                assert_eq!(response, json!({"version": PROTOCOL_VERSION}));
+            }
+            Response::Error { .. } => panic!("Expected OK response"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_apidescribe() {
+        let req = build_request(Command::ApiDescribe);
+        match form_response(req, build_state()).await {
+            Response::Ok { response, .. } => {
+                let text = serde_json::to_string(&response).unwrap();
+                let document: Value = serde_json::from_str(&text).unwrap();
+                assert_eq!(document["protocol_version"], PROTOCOL_VERSION);
+
+                let methods = document["methods"].as_array().unwrap();
+                assert_eq!(methods.len(), CommandKind::ALL.len());
+                let calculate = methods
+                    .iter()
+                    .find(|m| m["name"] == "calculate")
+                    .expect("Expected an entry for calculate");
+                let params = calculate["params"].as_array().unwrap();
+                for name in ["operation", "a", "b"] {
+                    let param = params.iter().find(|p| p["name"] == name).unwrap();
+                    assert_eq!(param["required"], true);
+                }
+                assert!(params[0]["type"].as_str().unwrap().contains("\"divide\""));
+
+                let procinfo = methods.iter().find(|m| m["name"] == "procinfo").unwrap();
+                assert_eq!(procinfo["admin"], true);
+                for (method, kind) in methods.iter().zip(CommandKind::ALL) {
+                    assert_eq!(method["admin"], kind.is_admin(), "{}", kind.name());
+                }
+            }
+            Response::Error { .. } => panic!("Expected OK response"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_poisoned_metrics() {
+        let state = build_state();
+        let poisoner = state.clone();
+        let result = std::thread::spawn(move || {
+            let _guard = poisoner.metrics.lock().unwrap();
+            panic!("poisoning the metrics lock");
+        })
+        .join();
+        assert!(result.is_err());
+        assert!(state.metrics.is_poisoned());
+
+        for command in [Command::Ping, Command::Stats] {
+            match form_response(build_request(command), state.clone()).await {
+                Response::Ok { .. } => {}
+                Response::Error { .. } => panic!("Expected OK response"),
+            }
+        }
+        assert!(!state.metrics.is_poisoned());
+        assert_eq!(state.lock_metrics().command_counts[&CommandKind::Ping], 1);
+    }
 }
@@ -0,0 +1,183 @@
+use crate::types::{CommandError, ErrorCode};
+
+/// The maximum nesting depth of parentheses and unary operators in an expression.
+///
+/// This protects the recursive descent parser from overflowing the stack.
+const MAX_DEPTH: usize = 256;
+
+/// Evaluates an arithmetic expression.
+///
+/// The supported grammar consists of decimal numbers, binary operators `+`, `-`, `*`, `/`
+/// with the usual precedence and left associativity, unary `+` and `-`, and parentheses.
+/// Whitespace between tokens is ignored.
+///
+/// # Errors
+/// - `ErrorCode::ParseError` if the expression is malformed;
+/// - `ErrorCode::DivisionByZero` if the expression divides by zero.
+pub fn evaluate(expression: &str) -> Result<f64, CommandError> {
+    let mut parser = Parser {
+        input: expression.as_bytes(),
+        pos: 0,
+        depth: 0,
+    };
+    let result = parser.expr()?;
+    parser.skip_whitespace();
+    if let Some(c) = parser.peek() {
+        return Err(parser.error(&format!("unexpected character `{}`", c as char)));
+    }
+    Ok(result)
+}
+
+struct Parser<'a> {
+    input: &'a [u8],
+    pos: usize,
+    depth: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<u8> {
+        self.input.get(self.pos).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().is_some_and(|c| c.is_ascii_whitespace()) {
+            self.pos += 1;
+        }
+    }
+
+    /// Skips the whitespace and consumes the next character if it's one of the `expected` ones.
+    fn next_of(&mut self, expected: &[u8]) -> Option<u8> {
+        self.skip_whitespace();
+        let c = self.peek().filter(|c| expected.contains(c))?;
+        self.pos += 1;
+        Some(c)
+    }
+
+    fn error(&self, message: &str) -> CommandError {
+        CommandError::new(
+            ErrorCode::ParseError,
+            format!("invalid expression at position {}: {message}", self.pos),
+        )
+    }
+
+    // expr := term (('+' | '-') term)*
+    fn expr(&mut self) -> Result<f64, CommandError> {
+        let mut result = self.term()?;
+        while let Some(op) = self.next_of(b"+-") {
+            let rhs = self.term()?;
+            match op {
+                b'+' => result += rhs,
+                _ => result -= rhs,
+            }
+        }
+        Ok(result)
+    }
+
+    // term := factor (('*' | '/') factor)*
+    fn term(&mut self) -> Result<f64, CommandError> {
+        let mut result = self.factor()?;
+        while let Some(op) = self.next_of(b"*/") {
+            let rhs = self.factor()?;
+            match op {
+                b'*' => result *= rhs,
+                _ => {
+                    if rhs == 0.0 {
+                        return Err(CommandError::new(
+                            ErrorCode::DivisionByZero,
+                            "division by zero",
+                        ));
+                    }
+                    result /= rhs;
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    // factor := ('+' | '-') factor | '(' expr ')' | number
+    fn factor(&mut self) -> Result<f64, CommandError> {
+        if self.depth == MAX_DEPTH {
+            return Err(self.error("expression is nested too deeply"));
+        }
+        self.depth += 1;
+        let result = if let Some(op) = self.next_of(b"+-") {
+            let value = self.factor()?;
+            if op == b'-' { -value } else { value }
+        } else if self.next_of(b"(").is_some() {
+            let value = self.expr()?;
+            if self.next_of(b")").is_none() {
+                return Err(self.error("expected `)`"));
+            }
+            value
+        } else {
+            self.number()?
+        };
+        self.depth -= 1;
+        Ok(result)
+    }
+
+    fn number(&mut self) -> Result<f64, CommandError> {
+        self.skip_whitespace();
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_digit() || c == b'.') {
+            self.pos += 1;
+        }
+        if start == self.pos {
+            return Err(match self.peek() {
+                Some(c) => self.error(&format!("expected a number, found `{}`", c as char)),
+                None => self.error("unexpected end of expression"),
+            });
+        }
+        // the slice consists of ASCII digits and dots only, so it's always valid UTF-8
+        let input = self.input;
+        let text = std::str::from_utf8(&input[start..self.pos]).unwrap();
+        match text.parse::<f64>() {
+            Ok(v) => Ok(v),
+            Err(_) => {
+                self.pos = start;
+                Err(self.error(&format!("invalid number `{text}`")))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_precedence() {
+        assert_eq!(evaluate("2 + 3 * 4").unwrap(), 14.0);
+        assert_eq!(evaluate("10 - 4 - 3").unwrap(), 3.0);
+        assert_eq!(evaluate("16 / 4 / 2").unwrap(), 2.0);
+        assert_eq!(evaluate("1.5 * 2 - 8 / 4").unwrap(), 1.0);
+        assert_eq!(evaluate("-3 * -2").unwrap(), 6.0);
+        assert_eq!(evaluate("42").unwrap(), 42.0);
+    }
+
+    #[test]
+    fn test_parentheses() {
+        assert_eq!(evaluate("2 * (3 + 4)").unwrap(), 14.0);
+        assert_eq!(evaluate("((1 + 2) * (3 + 4)) / 7").unwrap(), 3.0);
+        assert_eq!(evaluate("-(2 - 5)").unwrap(), 3.0);
+    }
+
+    #[test]
+    fn test_malformed() {
+        for expr in [
+            "", "2 +", "(1 + 2", "1 + 2)", "2 ** 3", "1.2.3", "abc", "3 4",
+        ] {
+            let err = evaluate(expr).unwrap_err();
+            assert_eq!(err.code, ErrorCode::ParseError, "expression: {expr}");
+        }
+
+        let deep = "(".repeat(MAX_DEPTH + 1) + "1" + &")".repeat(MAX_DEPTH + 1);
+        assert_eq!(evaluate(&deep).unwrap_err().code, ErrorCode::ParseError);
+    }
+
+    #[test]
+    fn test_division_by_zero() {
+        let err = evaluate("1 / (2 - 2)").unwrap_err();
+        assert_eq!(err.code, ErrorCode::DivisionByZero);
+    }
+}
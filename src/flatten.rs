@@ -0,0 +1,304 @@
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::iter::Peekable;
+use std::str::Chars;
+
+use crate::types::{CommandError, ErrorCode};
+
+/// Converts a nested object into a single-level one, whose keys are the paths
+/// to the values of the original one, e.g. `{"a": {"b": [1, 2]}}` into
+/// `{"a.b[0]": 1, "a.b[1]": 2}`.
+///
+/// The keys of the nested objects are separated with dots, and the indices of the arrays
+/// are enclosed in brackets. The dots, brackets and backslashes within the keys are escaped
+/// with backslashes, e.g. `{"a.b": 1}` becomes `{"a\\.b": 1}`, so that `unflatten` restores
+/// the original object. Empty objects and arrays are kept as the values.
+///
+/// # Errors
+/// Fails with `ErrorCode::InvalidArgument` if the data is not an object.
+pub fn flatten(data: Value) -> Result<Value, CommandError> {
+    let Value::Object(object) = data else {
+        return Err(CommandError::new(
+            ErrorCode::InvalidArgument,
+            "data must be an object",
+        ));
+    };
+    let mut flat = Map::new();
+    for (key, value) in object {
+        flatten_into(escape(&key), value, &mut flat);
+    }
+    Ok(Value::Object(flat))
+}
+
+fn flatten_into(path: String, value: Value, flat: &mut Map<String, Value>) {
+    match value {
+        Value::Object(object) if !object.is_empty() => {
+            for (key, value) in object {
+                flatten_into(format!("{path}.{}", escape(&key)), value, flat);
+            }
+        }
+        Value::Array(values) if !values.is_empty() => {
+            for (i, value) in values.into_iter().enumerate() {
+                flatten_into(format!("{path}[{i}]"), value, flat);
+            }
+        }
+        value => {
+            flat.insert(path, value);
+        }
+    }
+}
+
+fn escape(key: &str) -> String {
+    let mut escaped = String::with_capacity(key.len());
+    for c in key.chars() {
+        if matches!(c, '.' | '[' | ']' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Converts a single-level object with the keys in the notation of `flatten` back into
+/// a nested one. The values are taken as is.
+///
+/// # Errors
+/// Fails with `ErrorCode::InvalidArgument` if the data is not an object, if a key is malformed
+/// or nests deeper than `max_depth`, if two keys refer to the same value, e.g. `a` and `a.b`,
+/// or if an array lacks an element, e.g. there's `a[1]`, but no `a[0]`.
+pub fn unflatten(data: Value, max_depth: usize) -> Result<Value, CommandError> {
+    let Value::Object(flat) = data else {
+        return Err(CommandError::new(
+            ErrorCode::InvalidArgument,
+            "data must be an object",
+        ));
+    };
+    let mut root = Node::Object(BTreeMap::new());
+    for (path, value) in flat {
+        let segments = parse_path(&path)?;
+        if segments.len() > max_depth {
+            return Err(CommandError::new(
+                ErrorCode::InvalidArgument,
+                format!("key `{path}` nests deeper than the limit of {max_depth}"),
+            ));
+        }
+        root.insert(&segments, value).ok_or_else(|| {
+            CommandError::new(
+                ErrorCode::InvalidArgument,
+                format!("key `{path}` conflicts with another key"),
+            )
+        })?;
+    }
+    let Node::Object(entries) = root else {
+        unreachable!("the root is always an object");
+    };
+    let mut object = Map::new();
+    for (key, node) in entries {
+        let path = escape(&key);
+        object.insert(key, node.into_value(path)?);
+    }
+    Ok(Value::Object(object))
+}
+
+/// A step of a path in the notation of `flatten`.
+enum Segment {
+    Key(String),
+    Index(usize),
+}
+
+fn parse_path(path: &str) -> Result<Vec<Segment>, CommandError> {
+    let malformed = || {
+        CommandError::new(
+            ErrorCode::InvalidArgument,
+            format!("key `{path}` is malformed"),
+        )
+    };
+    let mut chars = path.chars().peekable();
+    // the path always starts with a key, which may be empty, e.g. in `[0]`
+    let mut segments = vec![Segment::Key(parse_key(&mut chars).ok_or_else(malformed)?)];
+    while let Some(c) = chars.next() {
+        let segment = match c {
+            '.' => Segment::Key(parse_key(&mut chars).ok_or_else(malformed)?),
+            '[' => {
+                let mut digits = String::new();
+                loop {
+                    match chars.next() {
+                        Some(']') => break,
+                        Some(c) => digits.push(c),
+                        None => return Err(malformed()),
+                    }
+                }
+                Segment::Index(digits.parse().map_err(|_| malformed())?)
+            }
+            _ => return Err(malformed()),
+        };
+        segments.push(segment);
+    }
+    Ok(segments)
+}
+
+/// Reads a key up to the next unescaped dot or opening bracket, or returns `None`
+/// if the key has an invalid escape or an unescaped closing bracket.
+fn parse_key(chars: &mut Peekable<Chars>) -> Option<String> {
+    let mut key = String::new();
+    while let Some(&c) = chars.peek() {
+        match c {
+            '.' | '[' => break,
+            ']' => return None,
+            '\\' => {
+                chars.next();
+                match chars.next() {
+                    Some(c @ ('.' | '[' | ']' | '\\')) => key.push(c),
+                    _ => return None,
+                }
+            }
+            c => {
+                chars.next();
+                key.push(c);
+            }
+        }
+    }
+    Some(key)
+}
+
+/// A value being restored by `unflatten`, whose arrays may be filled in any order.
+enum Node {
+    Leaf(Value),
+    Object(BTreeMap<String, Node>),
+    Array(BTreeMap<usize, Node>),
+}
+
+impl Node {
+    /// Inserts the value at the path, creating the objects and arrays on it, or returns `None`
+    /// if the path conflicts with the values inserted before.
+    fn insert(&mut self, segments: &[Segment], value: Value) -> Option<()> {
+        let (last, parents) = segments.split_last()?;
+        let mut node = self;
+        for (segment, next) in parents.iter().zip(&segments[1..]) {
+            let empty = match next {
+                Segment::Key(_) => Node::Object(BTreeMap::new()),
+                Segment::Index(_) => Node::Array(BTreeMap::new()),
+            };
+            node = match (node, segment) {
+                (Node::Object(map), Segment::Key(key)) => map.entry(key.clone()).or_insert(empty),
+                (Node::Array(map), Segment::Index(index)) => map.entry(*index).or_insert(empty),
+                _ => return None,
+            };
+        }
+        match (node, last) {
+            (Node::Object(map), Segment::Key(key)) if !map.contains_key(key) => {
+                map.insert(key.clone(), Node::Leaf(value));
+            }
+            (Node::Array(map), Segment::Index(index)) if !map.contains_key(index) => {
+                map.insert(*index, Node::Leaf(value));
+            }
+            _ => return None,
+        }
+        Some(())
+    }
+
+    fn into_value(self, path: String) -> Result<Value, CommandError> {
+        match self {
+            Node::Leaf(value) => Ok(value),
+            Node::Object(entries) => {
+                let mut object = Map::new();
+                for (key, node) in entries {
+                    let value = node.into_value(format!("{path}.{}", escape(&key)))?;
+                    object.insert(key, value);
+                }
+                Ok(Value::Object(object))
+            }
+            Node::Array(items) => {
+                let mut values = Vec::with_capacity(items.len());
+                for (i, (index, node)) in items.into_iter().enumerate() {
+                    if index != i {
+                        return Err(CommandError::new(
+                            ErrorCode::InvalidArgument,
+                            format!("array `{path}` lacks the element {i}"),
+                        ));
+                    }
+                    values.push(node.into_value(format!("{path}[{i}]"))?);
+                }
+                Ok(Value::Array(values))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_flatten() {
+        let data = json!({
+            "a": {"b": 1, "c": [true, {"d": null}]},
+            "e": "text",
+            "empty": {"object": {}, "array": []},
+        });
+        let flat = json!({
+            "a.b": 1,
+            "a.c[0]": true,
+            "a.c[1].d": null,
+            "e": "text",
+            "empty.object": {},
+            "empty.array": [],
+        });
+        assert_eq!(flatten(data.clone()).unwrap(), flat);
+        assert_eq!(unflatten(flat, 64).unwrap(), data);
+
+        // the arrays are restored in order, though the keys are sorted as strings
+        let items: Vec<u32> = (0..12).collect();
+        let data = json!({"items": items});
+        let flat = flatten(data.clone()).unwrap();
+        assert_eq!(flat["items[11]"], 11);
+        assert_eq!(unflatten(flat, 64).unwrap(), data);
+    }
+
+    #[test]
+    fn test_flatten_escaped_keys() {
+        let data = json!({
+            "a.b": {"c": 1},
+            "a": {"b.c": 2, "[0]": 3},
+            "back\\slash": 4,
+            "": {"": [5]},
+        });
+        let flat = flatten(data.clone()).unwrap();
+        assert_eq!(
+            flat,
+            json!({
+                "a\\.b.c": 1,
+                "a.b\\.c": 2,
+                "a.\\[0\\]": 3,
+                "back\\\\slash": 4,
+                ".[0]": 5,
+            })
+        );
+        assert_eq!(unflatten(flat, 64).unwrap(), data);
+    }
+
+    #[test]
+    fn test_unflatten_rejected() {
+        for data in [
+            json!({"a": 1, "a.b": 2}),
+            json!({"a[0]": 1, "a.b": 2}),
+            json!({"a[1]": 1}),
+            json!({"a[x]": 1}),
+            json!({"a]": 1}),
+            json!({"a[0]b": 1}),
+            json!({"a\\b": 1}),
+            json!({"a[0": 1}),
+            json!([1]),
+        ] {
+            let err = unflatten(data.clone(), 64).unwrap_err();
+            assert_eq!(err.code, ErrorCode::InvalidArgument, "{data}");
+        }
+        let err = unflatten(json!({"a.b.c": 1}), 2).unwrap_err();
+        assert_eq!(err.code, ErrorCode::InvalidArgument);
+        assert!(unflatten(json!({"a.b": 1}), 2).is_ok());
+
+        let err = flatten(json!("a.b")).unwrap_err();
+        assert_eq!(err.code, ErrorCode::InvalidArgument);
+    }
+}
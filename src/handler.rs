@@ -1,63 +1,987 @@
-use log::{debug, error};
-use serde::Serialize;
-use serde_json::Value;
-use std::sync::{Arc, Mutex};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use async_compression::tokio::bufread::{GzipDecoder, GzipEncoder};
+use log::{debug, error, info, warn};
+use serde::de::IgnoredAny;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use std::any::Any;
+use std::collections::VecDeque;
+use std::io::{self, Cursor};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader, Interest};
 use tokio::net::TcpStream;
+use tokio::sync::{Notify, mpsc};
+use tokio::task::AbortHandle;
+use tokio::time::MissedTickBehavior;
+use uuid::Uuid;
 
+use crate::batchstream::{self, BatchHead, BatchSplitter};
 use crate::commands::*;
+use crate::lenient;
 use crate::types::*;
 
 /// Handles the TCP connection by processing an incoming request and sending a response.
 ///
 /// This function is called after a new client connection is accepted.
-/// It performs the following steps:
-/// 1. Reads the data from the TCP stream;
-/// 2. Attempts to deserialize it into a `Request`;
-/// 3. Calls `form_response` to process the request and generate a `Response`;
-/// 4. Serializes the response and writes it back to the same stream.
+/// If `Config::protocol_version_header` is set, it first sends the protocol version frame,
+/// `{"protocol_version": <u32>}`. Then it performs the following steps:
+/// 1. Reads the data from the TCP stream; if the client starts with a hello frame, the server
+///    replies to it and decompresses the request if the compression has been negotiated
+///    (see `read_request`);
+/// 2. Attempts to deserialize it into a `Request`, after checking that it's a valid UTF-8;
+///    if `Config::auth_token` is set, a request without a matching `auth` field is rejected
+///    with `ErrorCode::Unauthorized`;
+/// 3. Calls `form_response` in a separate task to process the request and generate a `Response`,
+///    chaining the requests of the connection if negotiated (see `RequestChain`);
+///    if processing panics, an `ErrorCode::Internal` response is generated instead;
+/// 4. Serializes the response and writes it back to the same stream, compressed if negotiated.
+///
+/// If `Config::stream_batches` is set, a batch is processed while it's still being received:
+/// each of its requests is passed to the batch as soon as it arrives (see `feed_batch`),
+/// and the response is sent once the batch ends.
+///
+/// If `Config::max_memory_bytes` is set, the request and the response are accounted
+/// in `ServerState::memory` while they're buffered. Once the budget is exceeded,
+/// the connection is rejected with `ErrorCode::MemoryExhausted` before reading its request.
+///
+/// If the data consists of several JSON values concatenated, e.g. `{...}{...}`, each of them
+/// is processed as a request of its own, and the responses are written one after another
+/// in the same order (see `respond_concatenated`).
+///
+/// A `Command::SubscribeMetrics` request keeps the connection open, the server sending
+/// the snapshots of the metrics while watching for the client to unsubscribe
+/// (see `push_metrics`).
+///
+/// The connection is registered in `ServerState::connections` while it's open,
+/// and is closed at any step once `Command::CloseConnection` requests it.
 ///
 /// # Parameters
 /// - `stream`: The TCP stream representing the client connection.
-/// - `metrics`: A shared thread-safe pointer to the global `Metrics` instance.
+/// - `addr`: The address of the client, used for logging and as the key of the registry.
+/// - `state`: A shared pointer to the global `ServerState` instance.
 ///   This is passed to the `form_response` function without modification.
-pub async fn handle_connection(mut stream: TcpStream, metrics: Arc<Mutex<Metrics>>) {
+pub async fn handle_connection(stream: TcpStream, addr: SocketAddr, state: Arc<ServerState>) {
+    let connection = ConnectionGuard::new(state.clone(), addr);
+    let close = connection.close.clone();
+    // boxed to keep the future of the connection task small
+    let serving = Box::pin(serve_connection(
+        stream,
+        addr,
+        state,
+        connection.requests.clone(),
+    ));
+    tokio::select! {
+        _ = serving => {}
+        _ = close.notified() => info!("Connection from {addr} was closed on request."),
+    }
+}
+
+/// Registers a connection in `ServerState::connections` for as long as it's alive.
+struct ConnectionGuard {
+    state: Arc<ServerState>,
+    addr: SocketAddr,
+    requests: Arc<AtomicUsize>,
+    close: Arc<Notify>,
+}
+
+impl ConnectionGuard {
+    fn new(state: Arc<ServerState>, addr: SocketAddr) -> Self {
+        let connection = ActiveConnection {
+            started: Instant::now(),
+            requests: Arc::default(),
+            close: Arc::default(),
+        };
+        let requests = connection.requests.clone();
+        let close = connection.close.clone();
+        state.connections.lock().unwrap().insert(addr, connection);
+        ConnectionGuard {
+            state,
+            addr,
+            requests,
+            close,
+        }
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        // panicking while already unwinding would abort the whole server
+        if let Ok(mut connections) = self.state.connections.lock() {
+            connections.remove(&self.addr);
+        }
+    }
+}
+
+/// Serves the connection as described in `handle_connection`, counting the requests
+/// processed on it in `requests`.
+async fn serve_connection(
+    mut stream: TcpStream,
+    addr: SocketAddr,
+    state: Arc<ServerState>,
+    requests: Arc<AtomicUsize>,
+) {
+    if state.config.protocol_version_header {
+        let frame = json!({"protocol_version": PROTOCOL_VERSION});
+        let data = serde_json::to_vec(&frame).expect("version frames are serializable");
+        if let Err(e) = stream.write_all(&data).await {
+            error!("Failed to send the protocol version: {e}");
+            return;
+        }
+    }
+    if state.memory.is_exceeded() {
+        warn!("Memory budget is exceeded, rejecting the connection from {addr}.");
+        // the request is discarded without buffering for the client to receive the error
+        if let Err(e) = tokio::io::copy(&mut stream, &mut tokio::io::sink()).await {
+            error!("Failed to receive data: {e}");
+            return;
+        }
+        let response = Response::Error {
+            request_id: None,
+            code: ErrorCode::MemoryExhausted,
+            error: "server is out of memory, try again later".to_string(),
+            retry_after_ms: None,
+            metadata: None,
+            prev_hash: None,
+        };
+        send_response(stream, &state, &Encoder::default(), response).await;
+        return;
+    }
     let mut buf = Vec::new();
-    if let Err(e) = stream.read_to_end(&mut buf).await {
-        error!("Failed to receive data: {e}");
+    let mut memory = state.memory.guard();
+    let max = state.config.max_request_bytes;
+    let (hello, mut input) = match open_request(&mut stream, &mut buf).await {
+        Ok(v) => v,
+        Err(e) => {
+            error!("Failed to receive data: {e}");
+            return;
+        }
+    };
+    let encoder = Encoder::new(hello.compression);
+    // a batch may be processed while it's still being received, instead of being buffered whole
+    let streamed = match state.config.stream_batches && !state.config.lenient_json {
+        true => {
+            let max_depth = state.config.max_json_depth;
+            read_batch_head(&mut input, &mut buf, &mut memory, max, max_depth).await
+        }
+        false => Ok(None),
+    };
+    let streamed = match streamed {
+        Ok(v) => v,
+        Err(e) => {
+            error!("Failed to receive data: {e}");
+            return;
+        }
+    };
+    if let Some((request, splitter)) = streamed {
+        debug!(
+            "Received streamed batch request: {}",
+            serde_json::to_string(&request).unwrap()
+        );
+        let Some(admin) = authenticate(&request, &state.config) else {
+            warn!("Request from {addr} has no valid authentication token, closing the connection.");
+            if let Err(e) = tokio::io::copy(&mut input, &mut tokio::io::sink()).await {
+                error!("Failed to receive data: {e}");
+                return;
+            }
+            drop(input);
+            send_response(stream, &state, &encoder, unauthorized(request)).await;
+            return;
+        };
+
+        let uuid = request.request_id;
+        let metadata = request.metadata.clone();
+        let client = ClientIdentity::new(addr, request.auth.clone());
+        let (sender, receiver) = mpsc::channel(STREAMED_BATCH_QUEUE);
+        let processing = Box::pin(form_response(request, state.clone()));
+        let processing = with_connection_compression(encoder.stats.clone(), processing);
+        let processing = with_streamed_batch(receiver, processing);
+        let processing = with_admin(admin, processing);
+        let processing = with_client(client, processing);
+        let processing = with_request_chain(hello.chain, processing);
+        let processing = with_last_error(processing);
+        let processing = with_new_session(processing);
+        let processing = with_request_count(requests.clone(), processing);
+        let processing = form_response_isolated(processing, uuid, metadata.clone(), addr);
+        let processing = tokio::spawn(processing);
+        let _guard = AbortOnDrop(processing.abort_handle());
+        let response = match feed_batch(input, splitter, sender, buf.len(), max).await {
+            Ok(Ok(())) => {
+                let processing = async {
+                    processing
+                        .await
+                        .expect("the processing is isolated from panics")
+                };
+                let Some(response) = await_unless_disconnected(&stream, processing).await else {
+                    info!(
+                        "Client {addr} disconnected before request {uuid} was processed, abandoning it."
+                    );
+                    return;
+                };
+                response
+            }
+            Ok(Err(e)) => {
+                debug!("Received streamed batch is invalid: {e}");
+                Response::Error {
+                    request_id: Some(uuid),
+                    code: e.code,
+                    error: e.message,
+                    retry_after_ms: None,
+                    metadata,
+                    prev_hash: None,
+                }
+            }
+            Err(e) => {
+                error!("Failed to receive data: {e}");
+                return;
+            }
+        };
+        send_response(stream, &state, &encoder, response).await;
+        return;
+    }
+    let eager = state.config.eager_requests;
+    let fits = match read_limited(&mut input, &mut buf, &mut memory, max, eager).await {
+        Ok(v) => v,
+        Err(e) => {
+            error!("Failed to receive data: {e}");
+            return;
+        }
+    };
+    drop(input);
+    if !fits {
+        let max = max.unwrap_or_default();
+        warn!("Request from {addr} exceeds the limit of {max} bytes, sending an error.");
+        send_response(
+            stream,
+            &state,
+            &encoder,
+            Response::Error {
+                request_id: None,
+                code: ErrorCode::RequestTooLarge,
+                error: format!("request exceeds the limit of {max} bytes"),
+                retry_after_ms: None,
+                metadata: None,
+                prev_hash: None,
+            },
+        )
+        .await;
         return;
     }
 
-    // first, check if the input is a valid JSON
-    let json_data = match serde_json::from_slice::<Value>(&buf) {
+    // first, check if the input is a text at all, which JSON parsing would report obscurely
+    if let Err(e) = std::str::from_utf8(&buf) {
+        debug!("Received data is not a valid UTF-8: {e}");
+        send_response(
+            stream,
+            &state,
+            &encoder,
+            Response::Error {
+                request_id: None,
+                code: ErrorCode::InvalidEncoding,
+                error: format!(
+                    "request is not a valid UTF-8, the first invalid sequence is at byte {}",
+                    e.valid_up_to()
+                ),
+                retry_after_ms: None,
+                metadata: None,
+                prev_hash: None,
+            },
+        )
+        .await;
+        return;
+    }
+    // then check the nesting before parsing, which recurses into each level of it
+    let depth = lenient::nesting_depth(&buf, state.config.lenient_json);
+    if depth > state.config.max_json_depth {
+        let max_depth = state.config.max_json_depth;
+        debug!("Received request is nested {depth} levels deep, above the limit of {max_depth}");
+        send_response(
+            stream,
+            &state,
+            &encoder,
+            Response::Error {
+                request_id: None,
+                code: ErrorCode::NestingTooDeep,
+                error: format!("request is nested deeper than {max_depth} levels"),
+                retry_after_ms: None,
+                metadata: None,
+                prev_hash: None,
+            },
+        )
+        .await;
+        return;
+    }
+    // several requests may be concatenated, each of them getting its own response
+    if !state.config.lenient_json
+        && let Some(split) = split_concatenated(&buf)
+    {
+        debug!("Received {} concatenated requests", split.values.len());
+        respond_concatenated(stream, split, hello, &encoder, addr, &state, requests).await;
+        return;
+    }
+    // then check if the input is a valid JSON
+    let json_data = match lenient::parse(&buf, state.config.lenient_json) {
         Ok(v) => v,
         Err(e) => {
             debug!("Received data is not a valid JSON: {e}");
             send_response(
                 stream,
+                &state,
+                &encoder,
                 Response::Error {
                     request_id: None,
+                    code: ErrorCode::ParseError,
                     error: "request is not a valid JSON".to_string(),
+                    retry_after_ms: None,
+                    metadata: None,
+                    prev_hash: None,
                 },
             )
             .await;
             return;
         }
     };
+    let (request, admin) = match prepare(&json_data, addr, &state.config) {
+        Ok(v) => v,
+        Err(response) => {
+            send_response(stream, &state, &encoder, *response).await;
+            return;
+        }
+    };
+
+    let uuid = request.request_id;
+    let metadata = request.metadata.clone();
+    let streamed = matches!(request.command, Command::StreamRange { .. });
+    let subscription = match request.command {
+        Command::SubscribeMetrics { interval_ms } => Some(interval_ms),
+        _ => None,
+    };
+    let pace = match request.command {
+        Command::SlowEcho { bytes_per_sec, .. } => Some(bytes_per_sec),
+        _ => None,
+    };
+    let client = ClientIdentity::new(addr, request.auth.clone());
+    let compression_name =
+        serde_plain::to_string(&encoder.compression).expect("compressions are serializable");
+    let raw = RawRequest::new(json_data, buf.len(), compression_name);
+    let processing = form_response(request, state.clone());
+    let processing = with_connection_compression(encoder.stats.clone(), processing);
+    // boxed to keep the future of the connection small
+    let processing = with_raw_request(raw, Box::pin(processing));
+    let processing = with_admin(admin, processing);
+    let processing = with_client(client, processing);
+    let processing = with_request_chain(hello.chain, processing);
+    let processing = with_last_error(processing);
+    let processing = with_new_session(processing);
+    let processing = with_request_count(requests.clone(), processing);
+    let processing = form_response_isolated(processing, uuid, metadata, addr);
+    let Some(response) = await_unless_disconnected(&stream, processing).await else {
+        info!("Client {addr} disconnected before request {uuid} was processed, abandoning it.");
+        return;
+    };
+    // the integers of a streamed range are sent one by one, followed by the final response
+    let response = match response {
+        Response::Ok {
+            request_id,
+            response: Value::Array(values),
+            metadata,
+            prev_hash,
+            etag,
+        } if streamed => {
+            let frames = send_frames(&mut stream, &state, &encoder, request_id, values);
+            if let Err(e) = frames.await {
+                error!("Sending failed: {e}");
+                return;
+            }
+            Response::Ok {
+                request_id,
+                response: json!({"done": true}),
+                metadata,
+                prev_hash,
+                etag,
+            }
+        }
+        response => response,
+    };
+    // the first snapshot of a subscription is followed by the periodic ones
+    let response = match (response, subscription) {
+        (
+            Response::Ok {
+                request_id,
+                response,
+                metadata,
+                prev_hash,
+                etag,
+            },
+            Some(interval_ms),
+        ) => {
+            let interval = Duration::from_millis(interval_ms);
+            let pushing = push_metrics(
+                &mut stream,
+                &state,
+                &encoder,
+                request_id,
+                response,
+                interval,
+            );
+            if let Err(e) = pushing.await {
+                info!("Metrics subscription of {addr} has ended abruptly: {e}");
+                return;
+            }
+            Response::Ok {
+                request_id,
+                response: json!({"done": true}),
+                metadata,
+                prev_hash,
+                etag,
+            }
+        }
+        (response, _) => response,
+    };
+    // an error isn't worth slowing down
+    let pace = pace.filter(|_| matches!(response, Response::Ok { .. }));
+    write_response(&mut stream, &state, &encoder, &response, pace).await;
+}
+
+/// The largest hello frame the server looks for at the start of a connection.
+const MAX_HELLO_BYTES: usize = 1024;
+
+/// Starts reading the request from the stream, and returns the options of the connection
+/// and the input to read the rest of the request from. The data read from the stream
+/// that belongs to the request is left in `buf`.
+///
+/// If the client starts the connection with a hello frame alone (see `parse_hello`),
+/// the server replies to it with the options it agrees to, and the request that follows
+/// is read through the negotiated compression. Otherwise, the defaults are used.
+async fn open_request<'a>(
+    stream: &'a mut TcpStream,
+    buf: &mut Vec<u8>,
+) -> io::Result<(Hello, Box<dyn AsyncRead + Unpin + Send + 'a>)> {
+    let mut chunk = [0; MAX_HELLO_BYTES];
+    // the hello is only looked for while the received data may still be the start of one
+    while buf.len() <= MAX_HELLO_BYTES {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Ok((Hello::default(), Box::new(stream)));
+        }
+        buf.extend_from_slice(&chunk[..n]);
+
+        let mut values = serde_json::Deserializer::from_slice(buf).into_iter::<Value>();
+        let hello = match values.next() {
+            Some(Ok(value)) => match parse_hello(&value) {
+                Some(v) => v,
+                None => break,
+            },
+            // the first value is incomplete yet
+            None => continue,
+            Some(Err(e)) if e.is_eof() => continue,
+            Some(Err(_)) => break,
+        };
+        let offset = values.byte_offset();
+        let rest: Vec<u8> = buf
+            .drain(..)
+            .skip(offset)
+            .skip_while(u8::is_ascii_whitespace)
+            .collect();
+
+        let reply = json!({ "hello": hello });
+        let data = serde_json::to_vec(&reply).expect("hello frames are serializable");
+        stream.write_all(&data).await?;
+        // the client may have started sending the request before receiving the reply
+        let input = hello.compression.decode(Cursor::new(rest).chain(stream));
+        return Ok((hello, input));
+    }
+    Ok((Hello::default(), Box::new(stream)))
+}
+
+/// The number of the received requests of a streamed batch that may wait for processing.
+///
+/// Once it's reached, the rest of the batch isn't read until the processing catches up.
+const STREAMED_BATCH_QUEUE: usize = 16;
+
+/// Reads the start of the request into `buf` until it's clear whether it's a batch that can be
+/// processed while it's still being received (see `batchstream::find_head`).
+///
+/// Returns the batch request without its requests and the splitter of the rest of them,
+/// or `None` if the request is to be read whole, e.g. since it already exceeds `max` bytes.
+async fn read_batch_head(
+    input: &mut (impl AsyncRead + Unpin),
+    buf: &mut Vec<u8>,
+    memory: &mut MemoryGuard<'_>,
+    max: Option<usize>,
+    max_depth: usize,
+) -> io::Result<Option<(Request, BatchSplitter)>> {
+    let mut chunk = [0; 8192];
+    loop {
+        let fits = max.is_none_or(|max| buf.len() <= max);
+        match batchstream::find_head(buf) {
+            BatchHead::Found(request, offset) if fits => {
+                let splitter = BatchSplitter::new(buf[offset..].to_vec(), max_depth);
+                return Ok(Some((request, splitter)));
+            }
+            BatchHead::Incomplete if fits && buf.len() <= batchstream::MAX_HEAD_BYTES => {}
+            _ => return Ok(None),
+        }
+        memory.wait_for_room().await;
+        let n = input.read(&mut chunk).await?;
+        if n == 0 {
+            return Ok(None);
+        }
+        buf.extend_from_slice(&chunk[..n]);
+        memory.set(buf.len());
+    }
+}
+
+/// Reads the rest of a streamed batch from the input, sending each of its requests
+/// to `requests` as soon as it's received, until the end of the request object.
+///
+/// The `received` bytes of the request have been read already. If the batch is invalid,
+/// or if it exceeds `max` bytes, the rest of the input is discarded, and the error is returned.
+async fn feed_batch(
+    mut input: impl AsyncRead + Unpin,
+    mut splitter: BatchSplitter,
+    requests: mpsc::Sender<Request>,
+    mut received: usize,
+    max: Option<usize>,
+) -> io::Result<Result<(), CommandError>> {
+    let mut chunk = [0; 8192];
+    let error = loop {
+        match splitter.next_item() {
+            Ok(Some(request)) => {
+                // the batch may have failed early, e.g. by exceeding its limit, but the rest
+                // of it is still read for the client to receive the response
+                let _ = requests.send(request).await;
+                continue;
+            }
+            Ok(None) if splitter.is_finished() => {
+                debug!(
+                    "Streamed batch received, at most {} bytes of it were buffered at once.",
+                    splitter.peak_buffered()
+                );
+                return Ok(Ok(()));
+            }
+            Ok(None) => {}
+            Err(e) => break e,
+        }
+        let n = input.read(&mut chunk).await?;
+        if n == 0 {
+            break CommandError::new(ErrorCode::ParseError, "request is not a valid JSON");
+        }
+        received += n;
+        if let Some(max) = max
+            && received > max
+        {
+            break CommandError::new(
+                ErrorCode::RequestTooLarge,
+                format!("request exceeds the limit of {max} bytes"),
+            );
+        }
+        splitter.push(&chunk[..n]);
+    };
+    tokio::io::copy(&mut input, &mut tokio::io::sink()).await?;
+    Ok(Err(error))
+}
+
+/// The options of a connection, negotiated by the hello frame the client sends
+/// before its request (see `read_request`).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+struct Hello {
+    compression: Compression,
+    /// Whether the responses carry the hashes of the previous requests (see `RequestChain`).
+    ///
+    /// The chaining is opt-in: it costs a canonical serialization and a SHA-256 per request,
+    /// which is wasted on the clients that don't verify the chain, and it would add a field
+    /// to the responses the existing clients don't expect.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    chain: bool,
+}
+
+/// The compression of the data sent over a connection, negotiated by the hello frame
+/// the client sends before its request (see `read_request`).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Compression {
+    #[default]
+    None,
+    Gzip,
+}
+
+impl Compression {
+    /// Wraps the reading half of the connection, decompressing the data read from it.
+    fn decode<'a>(
+        self,
+        reader: impl AsyncRead + Unpin + Send + 'a,
+    ) -> Box<dyn AsyncRead + Unpin + Send + 'a> {
+        match self {
+            Compression::None => Box::new(reader),
+            Compression::Gzip => {
+                let mut decoder = GzipDecoder::new(BufReader::new(reader));
+                // like the uncompressed data, the request ends with the end of the stream
+                decoder.multiple_members(true);
+                Box::new(decoder)
+            }
+        }
+    }
+
+    /// Compresses the data to be sent over the connection.
+    async fn encode(self, data: Vec<u8>) -> Vec<u8> {
+        match self {
+            Compression::None => data,
+            Compression::Gzip => {
+                let mut compressed = Vec::new();
+                GzipEncoder::new(data.as_slice())
+                    .read_to_end(&mut compressed)
+                    .await
+                    .expect("compressing the data in memory doesn't fail");
+                compressed
+            }
+        }
+    }
+}
+
+/// The compression negotiated for a connection, which counts the data it compresses
+/// in the `CompressionStats` of the connection, reported by `Command::Stats`
+/// (see `with_connection_compression`), and of the server.
+#[derive(Clone, Debug, Default)]
+struct Encoder {
+    compression: Compression,
+    stats: Arc<CompressionStats>,
+}
+
+impl Encoder {
+    fn new(compression: Compression) -> Self {
+        Encoder {
+            compression,
+            stats: Arc::default(),
+        }
+    }
+
+    /// Compresses the data to be sent over the connection (see `Compression::encode`).
+    async fn encode(&self, data: Vec<u8>, state: &ServerState) -> Vec<u8> {
+        if self.compression == Compression::None {
+            return data;
+        }
+        let uncompressed = data.len();
+        let data = self.compression.encode(data).await;
+        self.stats.record(uncompressed, data.len());
+        state.compression.record(uncompressed, data.len());
+        data
+    }
+}
+
+/// Returns the options requested by the value, if it's a hello frame,
+/// `{"hello": {"compression": <string>, "chain": <bool>}}` with both fields optional,
+/// an unsupported compression being declined.
+fn parse_hello(value: &Value) -> Option<Hello> {
+    let hello = value.as_object().filter(|v| v.len() == 1)?.get("hello")?;
+    let compression = match hello.get("compression").and_then(Value::as_str) {
+        Some("gzip") => Compression::Gzip,
+        _ => Compression::None,
+    };
+    let chain = hello.get("chain").and_then(Value::as_bool).unwrap_or(false);
+    Some(Hello { compression, chain })
+}
+
+/// Checks the authentication token in the `auth` field of the request.
+///
+/// Returns whether the request may use the administrative commands, or `None` if
+/// `Config::auth_token` is set, and the request doesn't contain it or the administrative token.
+fn authenticate(request: &Request, config: &Config) -> Option<bool> {
+    let matches = |token: &Option<String>| {
+        let (token, auth) = (token.as_ref(), request.auth.as_ref());
+        token
+            .zip(auth)
+            .is_some_and(|(token, auth)| constant_time_eq(auth.as_bytes(), token.as_bytes()))
+    };
+    let admin = matches(&config.admin_token);
+    // the administrative token authenticates the request as well
+    if config.auth_token.is_some() && !matches(&config.auth_token) && !admin {
+        return None;
+    }
+    Some(admin)
+}
+
+/// Forms the response to a request without a valid authentication token.
+fn unauthorized(request: Request) -> Response {
+    Response::Error {
+        request_id: Some(request.request_id),
+        code: ErrorCode::Unauthorized,
+        error: "the request requires a valid authentication token".to_string(),
+        retry_after_ms: None,
+        metadata: request.metadata,
+        prev_hash: None,
+    }
+}
+
+/// Compares the byte strings in a time that doesn't depend on their contents,
+/// so that the response time doesn't reveal how much of a guessed token is correct.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    // the length of the token is not a secret worth protecting
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Awaits the given future, unless the client disconnects first.
+///
+/// Returns `None` if the client has disconnected, in which case the future is dropped.
+/// Only an abrupt disconnect (a connection reset) can be detected: a graceful one
+/// is indistinguishable from the client closing its write half to mark the end of the request.
+async fn await_unless_disconnected<F>(stream: &TcpStream, future: F) -> Option<Response>
+where
+    F: Future<Output = Response>,
+{
+    tokio::select! {
+        resp = future => Some(resp),
+        _ = stream.ready(Interest::ERROR) => None,
+    }
+}
+
+/// Aborts the task when dropped, so that it doesn't outlive the future awaiting it.
+struct AbortOnDrop(AbortHandle);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Runs the given response-forming future in a separate task, so that a panic
+/// during command processing doesn't leave the client without a response.
+///
+/// If the future panics, the panic is logged and an `ErrorCode::Internal` response
+/// with the given `request_id` and `metadata` is returned.
+/// If the returned future is dropped, the task is aborted.
+async fn form_response_isolated<F>(
+    future: F,
+    request_id: Uuid,
+    metadata: Option<Value>,
+    addr: SocketAddr,
+) -> Response
+where
+    F: Future<Output = Response> + Send + 'static,
+{
+    let mut handle = tokio::spawn(future);
+    let _guard = AbortOnDrop(handle.abort_handle());
+    match (&mut handle).await {
+        Ok(v) => v,
+        Err(e) => {
+            let reason = match e.try_into_panic() {
+                Ok(payload) => panic_message(payload.as_ref()),
+                Err(e) => e.to_string(),
+            };
+            error!("Processing of request {request_id} from {addr} panicked: {reason}");
+            Response::Error {
+                request_id: Some(request_id),
+                code: ErrorCode::Internal,
+                error: "internal server error".to_string(),
+                retry_after_ms: None,
+                metadata,
+                prev_hash: None,
+            }
+        }
+    }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "unknown panic payload".to_string()
+    }
+}
+
+/// Reads the rest of a request from the input, keeping at most `max` bytes of it
+/// in `buf`, including the ones already there, if limited. The buffered bytes are accounted
+/// in `memory`, and the reading pauses while the memory budget is exceeded.
+///
+/// The request ends with EOF. If `eager` is set, it also ends once the data in `buf`
+/// is complete JSON objects (see `ObjectScanner`) and no more data arrives
+/// for `REQUEST_IDLE_TIMEOUT`, since some clients never close their write half.
+/// Waiting for the idle timeout lets the requests concatenated to the first one
+/// arrive in later writes.
+///
+/// Returns whether the request fits into the limit. The rest of a larger request
+/// is read and discarded without buffering, so that the client gets the error response
+/// instead of a connection reset caused by closing the socket with unread data.
+async fn read_limited(
+    input: &mut (impl AsyncRead + Unpin),
+    buf: &mut Vec<u8>,
+    memory: &mut MemoryGuard<'_>,
+    max: Option<usize>,
+    eager: bool,
+) -> io::Result<bool> {
+    let mut scanner = ObjectScanner::default();
+    let mut chunk = [0; 8192];
+    memory.set(buf.len());
+    while max.is_none_or(|max| buf.len() <= max) {
+        memory.wait_for_room().await;
+        let n = match eager && scanner.is_complete(buf) {
+            true => {
+                let reading = input.read(&mut chunk);
+                match tokio::time::timeout(REQUEST_IDLE_TIMEOUT, reading).await {
+                    Ok(n) => n?,
+                    Err(_) => return Ok(true),
+                }
+            }
+            false => input.read(&mut chunk).await?,
+        };
+        if n == 0 {
+            return Ok(true);
+        }
+        buf.extend_from_slice(&chunk[..n]);
+        memory.set(buf.len());
+    }
+    tokio::io::copy(input, &mut tokio::io::sink()).await?;
+    Ok(false)
+}
+
+/// How long the server waits for more data after receiving complete JSON objects
+/// without EOF, before processing them as the whole request (see `read_limited`).
+const REQUEST_IDLE_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Tells whether the data received so far is one or more complete JSON objects, optionally
+/// separated and surrounded by whitespace, scanning each received byte once.
+///
+/// Anything else, such as a request with comments, which only the lenient parser accepts,
+/// or an invalid one, is considered incomplete until EOF, to be reported as before.
+#[derive(Default)]
+struct ObjectScanner {
+    /// The number of bytes scanned so far.
+    scanned: usize,
+    /// The offset of the object being scanned.
+    start: usize,
+    /// The nesting depth of the objects and arrays at the scanned position.
+    depth: usize,
+    in_string: bool,
+    escaped: bool,
+    /// Whether the scanned data is valid objects, possibly followed by whitespace.
+    complete: bool,
+    /// Whether the scanned data can't be objects, so the request only ends at EOF.
+    invalid: bool,
+}
+
+impl ObjectScanner {
+    /// Scans the bytes of `data` added since the previous call, and returns whether
+    /// `data` is complete JSON objects.
+    fn is_complete(&mut self, data: &[u8]) -> bool {
+        while !self.invalid && self.scanned < data.len() {
+            let c = data[self.scanned];
+            self.scanned += 1;
+            if self.depth == 0 {
+                // only whitespace may separate and surround the objects
+                if c == b'{' {
+                    self.start = self.scanned - 1;
+                    self.depth = 1;
+                    self.complete = false;
+                } else if !c.is_ascii_whitespace() {
+                    self.invalid = true;
+                }
+            } else if self.in_string {
+                match c {
+                    _ if self.escaped => self.escaped = false,
+                    b'\\' => self.escaped = true,
+                    b'"' => self.in_string = false,
+                    _ => {}
+                }
+            } else {
+                match c {
+                    b'"' => self.in_string = true,
+                    b'{' | b'[' => self.depth += 1,
+                    b'}' | b']' => self.depth -= 1,
+                    _ => {}
+                }
+                // each object is only parsed once, when its braces are balanced
+                if self.depth == 0 {
+                    let object = &data[self.start..self.scanned];
+                    self.complete = serde_json::from_slice::<IgnoredAny>(object).is_ok();
+                    self.invalid = !self.complete;
+                }
+            }
+        }
+        self.complete && !self.invalid
+    }
+}
+
+/// Splits the data into the JSON values concatenated in it, such as `{...}{...}`,
+/// optionally separated by whitespace, along with the sizes of their parts of the data.
+/// If the values are followed by anything else, the error of parsing it is returned too.
+///
+/// Returns `None` unless the data consists of at least two values, or of at least one
+/// followed by something else, so a single request, as well as an invalid JSON,
+/// is parsed as usual.
+fn split_concatenated(data: &[u8]) -> Option<Concatenated> {
+    let mut values = Vec::new();
+    let mut stream = serde_json::Deserializer::from_slice(data).into_iter::<Value>();
+    let mut start = 0;
+    let mut trailing = None;
+    while let Some(value) = stream.next() {
+        let end = stream.byte_offset();
+        match value {
+            Ok(value) => values.push((value, end - start)),
+            Err(e) => {
+                trailing = Some(e);
+                break;
+            }
+        }
+        start = end;
+    }
+    match (values.len(), &trailing) {
+        (0, _) | (1, None) => None,
+        _ => Some(Concatenated { values, trailing }),
+    }
+}
+
+/// The JSON values concatenated in the data (see `split_concatenated`).
+struct Concatenated {
+    /// The values along with the sizes of their parts of the data.
+    values: Vec<(Value, usize)>,
+    /// The error of parsing the data following the values, if any.
+    trailing: Option<serde_json::Error>,
+}
+
+/// Checks that the parsed data is a request of a known command, and that it's authenticated
+/// (see `authenticate`).
+///
+/// Returns the request and whether it's an administrative one, or the error response.
+fn prepare(
+    json_data: &Value,
+    addr: SocketAddr,
+    config: &Config,
+) -> Result<(Request, bool), Box<Response>> {
+    // first, check that the command is known to give a clean error if it's not
+    if let Some(name) = json_data.get("command").and_then(Value::as_str)
+        && let Err(e) = check_command_name(name)
+    {
+        debug!("Received request with an unknown command: {name}");
+        return Err(Box::new(Response::Error {
+            request_id: json_data
+                .get("request_id")
+                .and_then(|v| Uuid::deserialize(v).ok()),
+            code: e.code,
+            error: e.message,
+            retry_after_ms: None,
+            metadata: json_data.get("metadata").cloned(),
+            prev_hash: None,
+        }));
+    }
     // then try deserializing it into Request
-    let request = match serde_json::from_value::<Request>(json_data) {
+    let request = match Request::deserialize(json_data) {
         Ok(v) => v,
         Err(e) => {
             debug!("Received data is not a valid request: {e}");
-            send_response(
-                stream,
-                Response::Error {
-                    request_id: None,
-                    error: e.to_string(),
-                },
-            )
-            .await;
-            return;
+            return Err(Box::new(Response::Error {
+                request_id: None,
+                code: ErrorCode::InvalidRequest,
+                error: e.to_string(),
+                retry_after_ms: None,
+                metadata: json_data.get("metadata").cloned(),
+                prev_hash: None,
+            }));
         }
     };
     debug!(
@@ -65,24 +989,1469 @@ pub async fn handle_connection(mut stream: TcpStream, metrics: Arc<Mutex<Metrics
         serde_json::to_string(&request).unwrap()
     );
 
-    send_response(stream, form_response(request, metrics).await).await;
+    let Some(admin) = authenticate(&request, config) else {
+        warn!("Request from {addr} has no valid authentication token, rejecting it.");
+        return Err(Box::new(unauthorized(request)));
+    };
+    Ok((request, admin))
 }
 
-async fn send_response<T: Serialize>(mut stream: TcpStream, resp: T) {
-    let data = match serde_json::to_vec(&resp) {
+/// Processes the requests concatenated in one payload (see `split_concatenated`) one after
+/// another, writing the response to each of them to the stream as soon as it's formed.
+///
+/// The requests share the state of the connection, such as the `RequestChain` and the session,
+/// like the requests of a batch do. A `Command::StreamRange` among them isn't streamed,
+/// but returns all of its integers in one response, like it does in a batch.
+/// If the requests are followed by an invalid JSON, an `ErrorCode::ParseError` error
+/// is sent for it after the responses to them.
+/// If the processing panics, each of the remaining requests gets an `ErrorCode::Internal` error.
+async fn respond_concatenated(
+    mut stream: TcpStream,
+    concatenated: Concatenated,
+    hello: Hello,
+    encoder: &Encoder,
+    addr: SocketAddr,
+    state: &Arc<ServerState>,
+    request_count: Arc<AtomicUsize>,
+) {
+    let compression_name =
+        serde_plain::to_string(&encoder.compression).expect("compressions are serializable");
+    let mut pending = VecDeque::new();
+    let mut requests = Vec::new();
+    for (json_data, bytes) in concatenated.values {
+        let request = prepare(&json_data, addr, &state.config).map(|(request, admin)| {
+            let raw = RawRequest::new(json_data.clone(), bytes, compression_name.clone());
+            (request, admin, raw)
+        });
+        let request_id = json_data
+            .get("request_id")
+            .and_then(|v| Uuid::deserialize(v).ok());
+        pending.push_back((request_id, json_data.get("metadata").cloned()));
+        requests.push(request);
+    }
+    if let Some(e) = concatenated.trailing {
+        debug!("Received concatenated requests are followed by an invalid JSON: {e}");
+        pending.push_back((None, None));
+        requests.push(Err(Box::new(Response::Error {
+            request_id: None,
+            code: ErrorCode::ParseError,
+            error: "data after the last request is not a valid JSON".to_string(),
+            retry_after_ms: None,
+            metadata: None,
+            prev_hash: None,
+        })));
+    }
+
+    let (sender, mut responses) = mpsc::unbounded_channel();
+    let processing = {
+        let state = state.clone();
+        async move {
+            for request in requests {
+                let response = match request {
+                    Ok((request, admin, raw)) => {
+                        let client = ClientIdentity::new(addr, request.auth.clone());
+                        let processing = Box::pin(form_response(request, state.clone()));
+                        let processing = with_client(client, with_raw_request(raw, processing));
+                        with_admin(admin, processing).await
+                    }
+                    Err(response) => *response,
+                };
+                if sender.send(response).is_err() {
+                    break;
+                }
+            }
+        }
+    };
+    let processing = with_connection_compression(encoder.stats.clone(), processing);
+    let processing = with_request_chain(hello.chain, processing);
+    let processing = with_last_error(processing);
+    let processing = with_new_session(processing);
+    let processing = with_request_count(request_count, processing);
+    let processing = tokio::spawn(processing);
+    let _guard = AbortOnDrop(processing.abort_handle());
+    while let Some(response) = responses.recv().await {
+        pending.pop_front();
+        if !write_response(&mut stream, state, encoder, &response, None).await {
+            return;
+        }
+    }
+    if pending.is_empty() {
+        return;
+    }
+
+    let reason = match processing.await {
+        Err(e) if e.is_panic() => panic_message(e.into_panic().as_ref()),
+        Err(e) => e.to_string(),
+        Ok(()) => "the processing has stopped".to_string(),
+    };
+    error!("Processing of concatenated requests from {addr} panicked: {reason}");
+    for (request_id, metadata) in pending {
+        let response = Response::Error {
+            request_id,
+            code: ErrorCode::Internal,
+            error: "internal server error".to_string(),
+            retry_after_ms: None,
+            metadata,
+            prev_hash: None,
+        };
+        if !write_response(&mut stream, state, encoder, &response, None).await {
+            return;
+        }
+    }
+}
+
+/// Writes each of the values to the stream as a separate `Response` to the request
+/// with the given ID, compressed with the compression negotiated for the connection.
+async fn send_frames(
+    stream: &mut TcpStream,
+    state: &ServerState,
+    encoder: &Encoder,
+    request_id: Uuid,
+    values: Vec<Value>,
+) -> io::Result<()> {
+    for value in values {
+        let frame = Response::Ok {
+            request_id,
+            response: value,
+            metadata: None,
+            prev_hash: None,
+            etag: None,
+        };
+        let data = serialize(&state.config, &frame).expect("responses are serializable");
+        let data = encoder.encode(data, state).await;
+        write_timed(stream, &data, &state.config).await?;
+    }
+    Ok(())
+}
+
+/// The largest data the client may send at once during a metrics subscription,
+/// which is only expected to unsubscribe.
+const MAX_CONTROL_BYTES: usize = 1024;
+
+/// Sends the snapshots of the metrics to the client of a `Command::SubscribeMetrics` request
+/// as separate `Response`s with its ID (see `send_frames`), the first one being `first`
+/// and the next one taken every `interval`.
+///
+/// Returns once the client sends the unsubscribe frame (see `take_unsubscribe`), closes
+/// its write half, or sends anything but JSON values, or once the server starts shutting down,
+/// which is checked before each snapshot. The frames the client sends aren't compressed,
+/// whatever compression has been negotiated for the connection.
+async fn push_metrics(
+    stream: &mut TcpStream,
+    state: &ServerState,
+    encoder: &Encoder,
+    request_id: Uuid,
+    first: Value,
+    interval: Duration,
+) -> io::Result<()> {
+    let mut ticks = tokio::time::interval(interval);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    // the first tick completes right away, the snapshot for which is taken already
+    ticks.tick().await;
+    send_frames(stream, state, encoder, request_id, vec![first]).await?;
+    let mut control = Vec::new();
+    let mut chunk = [0; MAX_CONTROL_BYTES];
+    loop {
+        tokio::select! {
+            _ = ticks.tick() => {
+                if state.draining.load(Ordering::Relaxed) {
+                    debug!("Server is shutting down, ending the metrics subscription.");
+                    return Ok(());
+                }
+                let snapshot = json!(state.lock_metrics().snapshot(unix_millis()));
+                send_frames(stream, state, encoder, request_id, vec![snapshot]).await?;
+            }
+            n = stream.read(&mut chunk) => {
+                let n = n?;
+                if n == 0 {
+                    return Ok(());
+                }
+                control.extend_from_slice(&chunk[..n]);
+                if take_unsubscribe(&mut control) {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+/// Removes the complete JSON values from the start of the data the client has sent
+/// during a metrics subscription, and returns whether the subscription is to end:
+/// if one of them is the unsubscribe frame, `{"unsubscribe": true}`, or if the data
+/// can't be a JSON value, or is too large for one (see `MAX_CONTROL_BYTES`).
+///
+/// The other values are ignored.
+fn take_unsubscribe(data: &mut Vec<u8>) -> bool {
+    let mut values = serde_json::Deserializer::from_slice(data).into_iter::<Value>();
+    let mut end = 0;
+    let unsubscribed = loop {
+        match values.next() {
+            Some(Ok(value)) => {
+                end = values.byte_offset();
+                if value == json!({"unsubscribe": true}) {
+                    break true;
+                }
+            }
+            // the rest of the value is yet to be received
+            Some(Err(e)) if e.is_eof() => break data.len() - end > MAX_CONTROL_BYTES,
+            Some(Err(_)) => break true,
+            None => break false,
+        }
+    };
+    data.drain(..end);
+    unsubscribed
+}
+
+/// Writes the data to the stream, failing with `io::ErrorKind::TimedOut` once the write
+/// has waited for the client to receive the data for `config.write_timeout_secs`.
+async fn write_timed(stream: &mut TcpStream, data: &[u8], config: &Config) -> io::Result<()> {
+    let secs = config.write_timeout_secs;
+    if secs == 0 {
+        return stream.write_all(data).await;
+    }
+    match tokio::time::timeout(Duration::from_secs(secs), stream.write_all(data)).await {
+        Ok(written) => written,
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("client hasn't received the data for {secs} seconds"),
+        )),
+    }
+}
+
+/// Sends the response and closes the connection (see `write_response`).
+async fn send_response(
+    mut stream: TcpStream,
+    state: &ServerState,
+    encoder: &Encoder,
+    resp: Response,
+) {
+    write_response(&mut stream, state, encoder, &resp, None).await;
+}
+
+/// Serializes the response and writes it to the stream.
+///
+/// The serialized response is accounted in `state.memory` until it's written.
+/// If the serialized response is larger than `config.max_response_bytes`,
+/// an `ErrorCode::ResponseTooLarge` error is sent instead.
+/// If `config.artificial_delay_ms` is set, the server waits for that long before sending,
+/// which is a debugging aid simulating a slow network.
+/// The serialized response is compressed with the compression negotiated for the connection,
+/// the limit applying to the uncompressed size, which is the pretty-printed one
+/// if `config.pretty_json` is set.
+/// If `pace` is set, the response is written at that many bytes per second,
+/// after the compression (see `write_paced`).
+///
+/// Returns whether the response has been sent; if it hasn't, e.g. since the client
+/// has stopped receiving it (see `write_timed`), the connection is to be abandoned.
+async fn write_response(
+    stream: &mut TcpStream,
+    state: &ServerState,
+    encoder: &Encoder,
+    resp: &Response,
+    pace: Option<u64>,
+) -> bool {
+    let config = &state.config;
+    if config.artificial_delay_ms > 0 {
+        tokio::time::sleep(Duration::from_millis(config.artificial_delay_ms)).await;
+    }
+    let mut data = match serialize(config, resp) {
         Ok(v) => v,
         Err(e) => {
-            error!("Sending failed - couldn't serialize the provided response (how?): {e}");
-            return;
+            // a `Value` holds no numbers JSON can't represent (see `warn_non_finite`)
+            // and no non-string keys, so this takes a broken `Serialize` implementation
+            error!("Sending failed - couldn't serialize the provided response: {e}");
+            return false;
         }
     };
-    debug!(
-        "Sending response: {}",
-        serde_json::to_string(&resp).unwrap()
-    );
-    if let Err(e) = stream.write_all(&data).await {
+    if let Some(max) = config.max_response_bytes
+        && data.len() > max
+    {
+        warn!(
+            "Response of {} bytes exceeds the limit of {max} bytes, sending an error instead.",
+            data.len()
+        );
+        let error = Response::Error {
+            request_id: resp.request_id(),
+            code: ErrorCode::ResponseTooLarge,
+            error: format!(
+                "response size of {} bytes exceeds the limit of {max} bytes",
+                data.len()
+            ),
+            retry_after_ms: None,
+            metadata: resp.metadata().cloned(),
+            prev_hash: resp.prev_hash().cloned(),
+        };
+        data = serialize(config, &error).expect("error responses are always serializable");
+    }
+    debug!("Sending response: {}", String::from_utf8_lossy(&data));
+    let mut memory = state.memory.guard();
+    memory.set(data.len());
+    let data = encoder.encode(data, state).await;
+    memory.set(data.len());
+    let sent = match pace {
+        Some(bytes_per_sec) => write_paced(stream, &data, bytes_per_sec, config).await,
+        None => write_timed(stream, &data, config).await,
+    };
+    if let Err(e) = sent {
         error!("Sending failed: {e}");
-        return;
+        return false;
     };
-    debug!("Response sent.")
+    debug!("Response sent.");
+    true
+}
+
+/// How many chunks a paced response is written in per second.
+const PACE_CHUNKS_PER_SEC: u64 = 10;
+
+/// Writes the data to the stream in chunks, sleeping between them,
+/// so that it's sent at about the given number of bytes per second.
+/// The write timeout applies to each of the chunks (see `write_timed`).
+async fn write_paced(
+    stream: &mut TcpStream,
+    data: &[u8],
+    bytes_per_sec: u64,
+    config: &Config,
+) -> io::Result<()> {
+    let chunk = (bytes_per_sec / PACE_CHUNKS_PER_SEC).max(1) as usize;
+    let start = tokio::time::Instant::now();
+    for (i, part) in data.chunks(chunk).enumerate() {
+        // the chunks are scheduled from the start, so that the delays don't accumulate
+        let offset = (i * chunk) as f64 / bytes_per_sec as f64;
+        tokio::time::sleep_until(start + Duration::from_secs_f64(offset)).await;
+        write_timed(stream, part, config).await?;
+    }
+    Ok(())
+}
+
+/// Serializes the response, pretty-printed if `config.pretty_json` is set.
+fn serialize(config: &Config, resp: &Response) -> serde_json::Result<Vec<u8>> {
+    match config.pretty_json {
+        true => serde_json::to_vec_pretty(resp),
+        false => serde_json::to_vec(resp),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use sha2::{Digest, Sha256};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::Instant;
+    use tokio::net::{TcpListener, TcpSocket};
+    use tokio::time::timeout;
+
+    /// Sets the flag when dropped.
+    struct DropFlag(Arc<AtomicBool>);
+
+    impl Drop for DropFlag {
+        fn drop(&mut self) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    async fn never_responding(flag: DropFlag) -> Response {
+        let _flag = flag;
+        std::future::pending().await
+    }
+
+    async fn panicking_response() -> Response {
+        panic!("boom")
+    }
+
+    #[tokio::test]
+    async fn test_panic_isolation() {
+        let uuid = Uuid::new_v4();
+        let addr: SocketAddr = "127.0.0.1:12345".parse().unwrap();
+
+        let resp = form_response_isolated(panicking_response(), uuid, None, addr).await;
+        match resp {
+            Response::Error {
+                request_id, code, ..
+            } => {
+                assert_eq!(request_id, Some(uuid));
+                assert_eq!(code, ErrorCode::Internal);
+            }
+            Response::Ok { .. } => panic!("Expected Error response"),
+        }
+
+        // the runtime keeps working after the panic
+        let state = Arc::new(ServerState::default());
+        let req = Request {
+            request_id: uuid,
+            command: Command::Ping,
+            metadata: None,
+            auth: None,
+            transform: None,
+            if_none_match: None,
+        };
+        let resp = form_response_isolated(form_response(req, state), uuid, None, addr).await;
+        assert!(matches!(resp, Response::Ok { .. }));
+    }
+
+    #[tokio::test]
+    async fn test_disconnect_abandons_processing() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, addr) = listener.accept().await.unwrap();
+
+        let dropped = Arc::new(AtomicBool::new(false));
+        let processing = form_response_isolated(
+            never_responding(DropFlag(dropped.clone())),
+            Uuid::new_v4(),
+            None,
+            addr,
+        );
+
+        // closing with zero linger resets the connection
+        client.set_linger(Some(Duration::ZERO)).unwrap();
+        drop(client);
+
+        let resp = timeout(
+            Duration::from_secs(5),
+            await_unless_disconnected(&stream, processing),
+        )
+        .await
+        .expect("Disconnect wasn't detected");
+        assert!(resp.is_none());
+
+        // the aborted task is dropped asynchronously
+        for _ in 0..100 {
+            if dropped.load(Ordering::SeqCst) {
+                return;
+            }
+            tokio::task::yield_now().await;
+        }
+        panic!("Processing wasn't aborted");
+    }
+
+    async fn connected_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        (client, stream)
+    }
+
+    async fn receive(mut client: TcpStream) -> Value {
+        let mut buf = Vec::new();
+        client.read_to_end(&mut buf).await.unwrap();
+        serde_json::from_slice(&buf).unwrap()
+    }
+
+    /// Sends the data on a new connection to the server, closing the write half after it,
+    /// and returns everything the server sends back until it closes the connection.
+    async fn exchange(state: &Arc<ServerState>, data: &[u8]) -> Vec<u8> {
+        let (mut client, stream) = connected_pair().await;
+        let addr = client.local_addr().unwrap();
+        let handler = tokio::spawn(handle_connection(stream, addr, state.clone()));
+        client.write_all(data).await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut buf = Vec::new();
+        client.read_to_end(&mut buf).await.unwrap();
+        handler.await.unwrap();
+        buf
+    }
+
+    /// Sends the request on a new connection to the server and returns the response to it.
+    async fn send(state: &Arc<ServerState>, request: &Value) -> Value {
+        let data = exchange(state, &serde_json::to_vec(request).unwrap()).await;
+        serde_json::from_slice(&data).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_artificial_delay() {
+        let (client, stream) = connected_pair().await;
+        let state = ServerState::new(Config {
+            artificial_delay_ms: 200,
+            ..Default::default()
+        });
+
+        let uuid = Uuid::new_v4();
+        let start = Instant::now();
+        send_response(
+            stream,
+            &state,
+            &Encoder::default(),
+            Response::Ok {
+                request_id: uuid,
+                response: Value::from("pong"),
+                metadata: None,
+                prev_hash: None,
+                etag: None,
+            },
+        )
+        .await;
+
+        let resp = receive(client).await;
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        assert_eq!(resp["request_id"], uuid.to_string());
+        assert_eq!(resp["response"], "pong");
+    }
+
+    #[tokio::test]
+    async fn test_idempotency_scope() {
+        let state = Arc::new(ServerState::new(Config {
+            admin_token: Some("admin".to_string()),
+            idempotency_ttl_secs: 60,
+            ..Default::default()
+        }));
+        let uuid = Uuid::new_v4();
+        let active = json!({"request_id": uuid, "command": "activerequests", "auth": "admin"});
+        assert_eq!(send(&state, &active).await["status"], "ok");
+
+        // another connection reusing the ID gets neither the administrative response
+        let active = json!({"request_id": uuid, "command": "activerequests"});
+        assert_eq!(send(&state, &active).await["code"], "unauthorized");
+        // nor the response to the command of the original request
+        let ping = json!({"request_id": uuid, "command": "ping"});
+        assert_eq!(send(&state, &ping).await["response"], "pong");
+
+        // the token is a part of the identity of the client
+        let echo =
+            |auth| json!({"request_id": uuid, "command": "echo", "payload": 1, "auth": auth});
+        for auth in [Some("admin"), Some("admin"), None] {
+            assert_eq!(send(&state, &echo(auth)).await["response"], 1);
+        }
+        let metrics = state.metrics.lock().unwrap();
+        assert_eq!(metrics.command_counts[&CommandKind::Echo], 2);
+    }
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secrets"));
+        assert!(constant_time_eq(b"", b""));
+    }
+
+    #[tokio::test]
+    async fn test_max_response_bytes() {
+        let state = ServerState::new(Config {
+            max_response_bytes: Some(1000),
+            ..Default::default()
+        });
+        let echo = |uuid: Uuid, size: usize| Response::Ok {
+            request_id: uuid,
+            response: Value::from("x".repeat(size)),
+            metadata: Some(json!({"trace": 7})),
+            prev_hash: Some("abc".to_string()),
+            etag: None,
+        };
+
+        let (client, stream) = connected_pair().await;
+        let uuid = Uuid::new_v4();
+        send_response(stream, &state, &Encoder::default(), echo(uuid, 2000)).await;
+        let resp = receive(client).await;
+        assert_eq!(resp["request_id"], uuid.to_string());
+        assert_eq!(resp["status"], "error");
+        assert_eq!(resp["code"], "response_too_large");
+        // the metadata of the request and the chain are still returned to the client
+        assert_eq!(resp["metadata"], json!({"trace": 7}));
+        assert_eq!(resp["prev_hash"], "abc");
+
+        let (client, stream) = connected_pair().await;
+        send_response(stream, &state, &Encoder::default(), echo(uuid, 500)).await;
+        let resp = receive(client).await;
+        assert_eq!(resp["status"], "ok");
+    }
+
+    /// Reads a frame sent before the response, such as the reply to a hello frame,
+    /// which is not followed by the end of the stream.
+    async fn receive_hello(client: &mut TcpStream) -> Value {
+        let mut buf = Vec::new();
+        let mut chunk = [0; 256];
+        loop {
+            let n = client.read(&mut chunk).await.unwrap();
+            assert!(n > 0, "Connection closed before the hello reply");
+            buf.extend_from_slice(&chunk[..n]);
+            if let Ok(v) = serde_json::from_slice(&buf) {
+                return v;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_compression_handshake() {
+        let state = Arc::new(ServerState::default());
+        let hello = |compression: &str| {
+            let hello = json!({"hello": {"compression": compression}});
+            serde_json::to_vec(&hello).unwrap()
+        };
+        let ping = serde_json::to_vec(&json!({"request_id": Uuid::new_v4(), "command": "ping"}));
+        let ping = ping.unwrap();
+
+        let (mut client, stream) = connected_pair().await;
+        let addr = client.local_addr().unwrap();
+        let handler = tokio::spawn(handle_connection(stream, addr, state.clone()));
+        client.write_all(&hello("gzip")).await.unwrap();
+        let reply = receive_hello(&mut client).await;
+        assert_eq!(reply, json!({"hello": {"compression": "gzip"}}));
+
+        // the request and the response are compressed after the hello
+        let compressed = Compression::Gzip.encode(ping.clone()).await;
+        assert_ne!(compressed, ping);
+        client.write_all(&compressed).await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut data = Vec::new();
+        client.read_to_end(&mut data).await.unwrap();
+        handler.await.unwrap();
+        let mut decompressed = Vec::new();
+        GzipDecoder::new(data.as_slice())
+            .read_to_end(&mut decompressed)
+            .await
+            .unwrap();
+        let resp: Value = serde_json::from_slice(&decompressed).unwrap();
+        assert_eq!(resp["response"], "pong");
+
+        // an unsupported compression is declined, and the connection stays uncompressed
+        let (mut client, stream) = connected_pair().await;
+        let addr = client.local_addr().unwrap();
+        let handler = tokio::spawn(handle_connection(stream, addr, state.clone()));
+        client.write_all(&hello("zstd")).await.unwrap();
+        let reply = receive_hello(&mut client).await;
+        assert_eq!(reply, json!({"hello": {"compression": "none"}}));
+        client.write_all(&ping).await.unwrap();
+        client.shutdown().await.unwrap();
+        let resp = receive(client).await;
+        handler.await.unwrap();
+        assert_eq!(resp["response"], "pong");
+
+        // without a hello, the connection is uncompressed
+        let resp: Value = serde_json::from_slice(&exchange(&state, &ping).await).unwrap();
+        assert_eq!(resp["response"], "pong");
+    }
+
+    #[tokio::test]
+    async fn test_compression_ratio() {
+        let state = Arc::new(ServerState::default());
+        let stats = json!({"request_id": Uuid::new_v4(), "command": "stats"});
+        let ratio = |resp: &Value, of: &str| resp["response"]["compression_ratio"][of].clone();
+
+        // nothing has been compressed yet
+        let resp = send(&state, &stats).await;
+        assert_eq!(ratio(&resp, "server"), Value::Null);
+        assert_eq!(ratio(&resp, "connection"), Value::Null);
+
+        let echo = json!({
+            "request_id": Uuid::new_v4(),
+            "command": "echo",
+            "payload": "a".repeat(100_000),
+        });
+        let (mut client, stream) = connected_pair().await;
+        let addr = client.local_addr().unwrap();
+        let handler = tokio::spawn(handle_connection(stream, addr, state.clone()));
+        client
+            .write_all(br#"{"hello": {"compression": "gzip"}}"#)
+            .await
+            .unwrap();
+        receive_hello(&mut client).await;
+        let echo = serde_json::to_vec(&echo).unwrap();
+        client
+            .write_all(&Compression::Gzip.encode(echo).await)
+            .await
+            .unwrap();
+        client.shutdown().await.unwrap();
+        let mut data = Vec::new();
+        client.read_to_end(&mut data).await.unwrap();
+        handler.await.unwrap();
+        assert!(data.len() < 100_000 / 10, "{}", data.len());
+
+        // the large echo compresses well, unlike the responses of this connection
+        let resp = send(&state, &stats).await;
+        let server = ratio(&resp, "server").as_f64().unwrap();
+        assert!(server > 10.0, "{server}");
+        assert_eq!(ratio(&resp, "connection"), Value::Null);
+    }
+
+    #[tokio::test]
+    async fn test_request_chain() {
+        let state = Arc::new(ServerState::default());
+        let echo =
+            json!({"request_id": Uuid::new_v4(), "command": "echo", "payload": {"b": 1, "a": 2}});
+        let batch = json!({
+            "request_id": Uuid::new_v4(),
+            "command": "batch",
+            "payload": [echo, {"request_id": Uuid::new_v4(), "command": "ping"}],
+        });
+        let batch = serde_json::to_vec(&batch).unwrap();
+
+        let (mut client, stream) = connected_pair().await;
+        let addr = client.local_addr().unwrap();
+        let handler = tokio::spawn(handle_connection(stream, addr, state.clone()));
+        client
+            .write_all(br#"{"hello": {"chain": true}}"#)
+            .await
+            .unwrap();
+        let reply = receive_hello(&mut client).await;
+        assert_eq!(
+            reply,
+            json!({"hello": {"compression": "none", "chain": true}})
+        );
+        client.write_all(&batch).await.unwrap();
+        client.shutdown().await.unwrap();
+        let resp = receive(client).await;
+        handler.await.unwrap();
+
+        // the nested requests follow the batch containing them
+        let sha256 = |data: String| format!("{:x}", Sha256::digest(data.as_bytes()));
+        let request: Request = serde_json::from_slice(&batch).unwrap();
+        let first = sha256(serde_json::to_value(&request).unwrap().to_string());
+        let canonical = r#"{"command":"echo","payload":{"a":2,"b":1},"request_id":"#;
+        let second = sha256(format!("{first}{canonical}{}}}", echo["request_id"]));
+        assert!(resp.get("prev_hash").is_none());
+        assert_eq!(resp["response"][0]["prev_hash"], first);
+        assert_eq!(resp["response"][1]["prev_hash"], second);
+
+        // without a hello, the responses are not chained
+        let resp: Value = serde_json::from_slice(&exchange(&state, &batch).await).unwrap();
+        assert!(resp["response"][1].get("prev_hash").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_max_request_bytes() {
+        let state = Arc::new(ServerState::new(Config {
+            max_request_bytes: Some(100),
+            ..Default::default()
+        }));
+        let request = |payload: &str| {
+            json!({
+                "request_id": Uuid::new_v4(),
+                "command": "echo",
+                "payload": payload,
+            })
+        };
+
+        let resp = send(&state, &request(&"x".repeat(100_000))).await;
+        assert_eq!(resp["status"], "error");
+        assert_eq!(resp["code"], "request_too_large");
+
+        // the limit doesn't affect the following requests
+        let resp = send(&state, &request("ok")).await;
+        assert_eq!(resp["status"], "ok");
+        assert_eq!(resp["response"], "ok");
+    }
+
+    #[tokio::test]
+    async fn test_max_memory_bytes() {
+        let state = Arc::new(ServerState::new(Config {
+            max_memory_bytes: Some(50_000),
+            ..Default::default()
+        }));
+        let request = json!({
+            "request_id": Uuid::new_v4(),
+            "command": "echo",
+            "payload": "x".repeat(100_000),
+        });
+
+        // the first request is held in memory until it ends
+        let (mut first, stream) = connected_pair().await;
+        let addr = first.local_addr().unwrap();
+        let handler = tokio::spawn(handle_connection(stream, addr, state.clone()));
+        let data = serde_json::to_vec(&request).unwrap();
+        let (head, tail) = data.split_at(data.len() - 1);
+        first.write_all(head).await.unwrap();
+        let exceeded = async {
+            while !state.memory.is_exceeded() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        timeout(Duration::from_secs(5), exceeded).await.unwrap();
+
+        // meanwhile, the new requests are rejected
+        let resp = send(&state, &request).await;
+        assert_eq!(resp["status"], "error");
+        assert_eq!(resp["code"], "memory_exhausted");
+
+        first.write_all(tail).await.unwrap();
+        first.shutdown().await.unwrap();
+        let resp = receive(first).await;
+        assert_eq!(resp["status"], "ok");
+        handler.await.unwrap();
+        assert_eq!(state.memory.used(), 0);
+
+        // and once the memory is freed, they're accepted again
+        let resp = send(&state, &request).await;
+        assert_eq!(resp["status"], "ok");
+    }
+
+    #[tokio::test]
+    async fn test_session_stats_resume() {
+        let dir = std::env::temp_dir().join(format!("testrust-{}", Uuid::new_v4()));
+        let state = Arc::new(ServerState::new(Config {
+            session_stats_dir: Some(dir.clone()),
+            ..Default::default()
+        }));
+        let session_stats = async |values: Value| {
+            let request = json!({
+                "request_id": Uuid::new_v4(),
+                "command": "sessionstats",
+                "payload": {"values": values, "session_id": "client-1"},
+            });
+            let resp = send(&state, &request).await;
+            assert_eq!(resp["status"], "ok", "{resp}");
+            resp["response"].clone()
+        };
+
+        let stats = session_stats(json!([2, 4, 4, 4])).await;
+        assert_eq!(stats, json!({"count": 4, "mean": 3.5, "variance": 0.75}));
+        assert!(dir.join("client-1.json").exists());
+
+        // the next connection with the same ID resumes the statistics
+        let stats = session_stats(json!([5, 5, 7, 9])).await;
+        assert_eq!(stats, json!({"count": 8, "mean": 5.0, "variance": 4.0}));
+        let stats = session_stats(json!([])).await;
+        assert_eq!(stats["count"], 8);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_streamed_batch() {
+        let state = Arc::new(ServerState::new(Config {
+            stream_batches: true,
+            ..Default::default()
+        }));
+        let item = |i: usize| {
+            format!(
+                r#"{{"request_id": "{}", "command": "echo", "payload": {i}}}"#,
+                Uuid::new_v4()
+            )
+        };
+        let items: Vec<String> = (0..2000).map(item).collect();
+
+        let (mut client, stream) = connected_pair().await;
+        let addr = client.local_addr().unwrap();
+        tokio::spawn(handle_connection(stream, addr, state.clone()));
+        let head = format!(
+            r#"{{"request_id": "{}", "command": "batch", "payload": ["#,
+            Uuid::new_v4()
+        );
+        let first = format!("{head}{},", items[..50].join(","));
+        client.write_all(first.as_bytes()).await.unwrap();
+        // the received requests are processed before the rest of the batch is sent
+        let processed = || {
+            let metrics = state.metrics.lock().unwrap();
+            metrics
+                .command_counts
+                .get(&CommandKind::Echo)
+                .copied()
+                .unwrap_or(0)
+        };
+        timeout(Duration::from_secs(5), async {
+            while processed() < 50 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("Expected the first requests to be processed");
+
+        let rest = format!("{}]}}", items[50..].join(","));
+        client.write_all(rest.as_bytes()).await.unwrap();
+        let resp = receive(client).await;
+        assert_eq!(resp["status"], "ok");
+        let responses = resp["response"].as_array().unwrap();
+        assert_eq!(responses.len(), 2000);
+        for (i, response) in responses.iter().enumerate() {
+            assert_eq!(response["response"], i);
+        }
+
+        // the payload can't be followed by other fields, which would arrive too late
+        let request = format!(r#"{head}{}], "metadata": 1}}"#, item(0));
+        let resp: Value =
+            serde_json::from_slice(&exchange(&state, request.as_bytes()).await).unwrap();
+        assert_eq!(resp["status"], "error");
+        assert_eq!(resp["code"], "invalid_request");
+    }
+
+    #[tokio::test]
+    async fn test_streamrange_frames() {
+        let state = Arc::new(ServerState::default());
+        let uuid = Uuid::new_v4();
+        let request = json!({
+            "request_id": uuid,
+            "command": "streamrange",
+            "payload": {"start": 5, "end": 8},
+        });
+
+        let data = exchange(&state, &serde_json::to_vec(&request).unwrap()).await;
+        let frames: Vec<Value> = serde_json::Deserializer::from_slice(&data)
+            .into_iter()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(frames.len(), 8 - 5 + 1);
+        assert!(frames.iter().all(|f| f["request_id"] == uuid.to_string()));
+        let values: Vec<&Value> = frames[..3].iter().map(|f| &f["response"]).collect();
+        assert_eq!(values, [5, 6, 7]);
+        assert_eq!(frames[3]["response"], json!({"done": true}));
+    }
+
+    #[tokio::test]
+    async fn test_metrics_subscription() {
+        let state = Arc::new(ServerState::new(Config {
+            eager_requests: true,
+            ..Default::default()
+        }));
+        let uuid = Uuid::new_v4();
+        let request = json!({
+            "request_id": uuid,
+            "command": "subscribemetrics",
+            "payload": {"interval_ms": MIN_SUBSCRIPTION_INTERVAL_MS},
+        });
+
+        let (mut client, stream) = connected_pair().await;
+        let addr = client.local_addr().unwrap();
+        let handler = tokio::spawn(handle_connection(stream, addr, state));
+        // the write half is kept open, closing it would end the subscription
+        client
+            .write_all(&serde_json::to_vec(&request).unwrap())
+            .await
+            .unwrap();
+        let parse = |data: &[u8]| -> Vec<Value> {
+            serde_json::Deserializer::from_slice(data)
+                .into_iter()
+                .map_while(Result::ok)
+                .collect()
+        };
+        let mut data = Vec::new();
+        let mut chunk = [0; 4096];
+        let frames = loop {
+            let n = client.read(&mut chunk).await.unwrap();
+            assert!(n > 0, "Connection closed during the subscription");
+            data.extend_from_slice(&chunk[..n]);
+            let frames = parse(&data);
+            if frames.len() >= 3 {
+                break frames;
+            }
+        };
+        assert!(frames.iter().all(|f| f["request_id"] == uuid.to_string()));
+        let timestamps: Vec<u64> = frames
+            .iter()
+            .map(|f| f["response"]["timestamp_ms"].as_u64().unwrap())
+            .collect();
+        assert!(timestamps.is_sorted(), "{timestamps:?}");
+        assert!(timestamps[0] < timestamps[2], "{timestamps:?}");
+
+        client.write_all(br#"{"unsubscribe": true}"#).await.unwrap();
+        client.read_to_end(&mut data).await.unwrap();
+        handler.await.unwrap();
+        let frames = parse(&data);
+        let (done, snapshots) = frames.split_last().unwrap();
+        assert_eq!(done["response"], json!({"done": true}));
+        for frame in snapshots {
+            assert!(frame["response"]["timestamp_ms"].is_u64(), "{frame}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_protocol_version_header() {
+        let state = Arc::new(ServerState::new(Config {
+            protocol_version_header: true,
+            ..Default::default()
+        }));
+        let (mut client, stream) = connected_pair().await;
+        let addr = client.local_addr().unwrap();
+        let handler = tokio::spawn(handle_connection(stream, addr, state));
+
+        // the frame is sent before the client sends anything
+        let frame = receive_hello(&mut client).await;
+        assert_eq!(frame, json!({"protocol_version": PROTOCOL_VERSION}));
+        let ping = json!({"request_id": Uuid::new_v4(), "command": "ping"});
+        client
+            .write_all(&serde_json::to_vec(&ping).unwrap())
+            .await
+            .unwrap();
+        client.shutdown().await.unwrap();
+        let resp = receive(client).await;
+        handler.await.unwrap();
+        assert_eq!(resp["response"], "pong");
+    }
+
+    #[tokio::test]
+    async fn test_last_error() {
+        let state = Arc::new(ServerState::default());
+        let failing_id = Uuid::new_v4();
+        let batch = json!({
+            "request_id": Uuid::new_v4(),
+            "command": "batch",
+            "payload": [
+                {"request_id": Uuid::new_v4(), "command": "lasterror"},
+                {
+                    "request_id": failing_id,
+                    "command": "calculate",
+                    "payload": {"operation": "divide", "a": 1, "b": 0},
+                },
+                {"request_id": Uuid::new_v4(), "command": "lasterror"},
+            ],
+        });
+
+        let resp = send(&state, &batch).await;
+
+        assert_eq!(resp["response"][0]["response"], json!({"last_error": null}));
+        assert_eq!(
+            resp["response"][2]["response"],
+            json!({"last_error": {
+                "request_id": failing_id,
+                "command": "calculate",
+                "code": "division_by_zero",
+                "error": "division by zero",
+            }})
+        );
+
+        // the errors of one connection are not visible on another
+        let request = json!({"request_id": Uuid::new_v4(), "command": "lasterror"});
+        let resp = send(&state, &request).await;
+        assert_eq!(resp["response"], json!({"last_error": null}));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_encoding() {
+        let state = Arc::new(ServerState::default());
+        let mut request = br#"{"command": "echo", "payload": "caf"#.to_vec();
+        let offset = request.len();
+        // Latin-1 instead of UTF-8
+        request.extend_from_slice(b"\xe9\"}");
+
+        let resp: Value = serde_json::from_slice(&exchange(&state, &request).await).unwrap();
+
+        assert_eq!(resp["status"], "error");
+        assert_eq!(resp["code"], "invalid_encoding");
+        let error = resp["error"].as_str().unwrap();
+        assert!(error.ends_with(&format!("at byte {offset}")), "{error}");
+    }
+
+    #[tokio::test]
+    async fn test_pretty_json() {
+        let state = ServerState::new(Config {
+            pretty_json: true,
+            ..Default::default()
+        });
+        let resp = Response::Ok {
+            request_id: Uuid::new_v4(),
+            response: json!({"a": [1, 2], "b": null}),
+            metadata: None,
+            prev_hash: None,
+            etag: None,
+        };
+
+        let (mut client, stream) = connected_pair().await;
+        send_response(stream, &state, &Encoder::default(), resp.clone()).await;
+        let mut data = Vec::new();
+        client.read_to_end(&mut data).await.unwrap();
+        assert!(data.contains(&b'\n'));
+        let value: Value = serde_json::from_slice(&data).unwrap();
+        assert_eq!(value, serde_json::to_value(&resp).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_auth_token() {
+        let state = Arc::new(ServerState::new(Config {
+            auth_token: Some("secret".to_string()),
+            admin_token: Some("admin".to_string()),
+            ..Default::default()
+        }));
+        for (auth, expected) in [
+            (Some("secret"), "ok"),
+            (Some("admin"), "ok"),
+            (Some("wrong"), "error"),
+            (None, "error"),
+        ] {
+            let uuid = Uuid::new_v4();
+            let mut request = json!({"request_id": uuid, "command": "ping"});
+            if let Some(auth) = auth {
+                request["auth"] = json!(auth);
+            }
+
+            let resp = send(&state, &request).await;
+
+            assert_eq!(resp["request_id"], uuid.to_string());
+            assert_eq!(resp["status"], expected, "{auth:?}");
+            if expected == "error" {
+                assert_eq!(resp["code"], "unauthorized");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_inspect() {
+        let state = Arc::new(ServerState::default());
+        let uuid = Uuid::new_v4();
+        let request = json!({
+            "request_id": uuid,
+            "command": "inspect",
+            "metadata": {"trace": 1},
+            "auth": "secret",
+        });
+        let data = serde_json::to_vec_pretty(&request).unwrap();
+
+        let resp: Value = serde_json::from_slice(&exchange(&state, &data).await).unwrap();
+        assert_eq!(resp["status"], "ok");
+        let raw = &resp["response"];
+        assert_eq!(raw["request"]["command"], "inspect");
+        assert_eq!(raw["request"]["request_id"], uuid.to_string());
+        assert_eq!(raw["request"]["metadata"], json!({"trace": 1}));
+        assert_eq!(raw["request"]["auth"], "<redacted>");
+        assert_eq!(raw["bytes"], data.len());
+        assert_eq!(raw["encoding"], "utf-8");
+        assert_eq!(raw["compression"], "none");
+    }
+
+    #[tokio::test]
+    async fn test_session() {
+        let state = Arc::new(ServerState::default());
+        let get = json!({
+            "request_id": Uuid::new_v4(),
+            "command": "sessionget",
+            "payload": {"key": "user"},
+        });
+        let batch = json!({
+            "request_id": Uuid::new_v4(),
+            "command": "batch",
+            "payload": [
+                {
+                    "request_id": Uuid::new_v4(),
+                    "command": "sessionset",
+                    "payload": {"key": "user", "value": {"name": "alice"}},
+                },
+                get,
+            ],
+        });
+
+        let resp = send(&state, &batch).await;
+        assert_eq!(resp["status"], "ok");
+        let responses = resp["response"].as_array().unwrap();
+        assert_eq!(
+            responses[0]["response"],
+            json!({"key": "user", "entries": 1})
+        );
+        assert_eq!(responses[1]["response"], json!({"name": "alice"}));
+
+        // another connection has its own session
+        let resp = send(&state, &get).await;
+        assert_eq!(resp["status"], "error");
+        assert_eq!(resp["code"], "not_found");
+    }
+
+    #[tokio::test]
+    async fn test_max_requests_per_connection() {
+        let state = Arc::new(ServerState::new(Config {
+            max_requests_per_connection: 3,
+            ..Default::default()
+        }));
+        let ping = || json!({"request_id": Uuid::new_v4(), "command": "ping"});
+        let batch = json!({
+            "request_id": Uuid::new_v4(),
+            "command": "batch",
+            "payload": [ping(), ping(), ping(), ping()],
+        });
+
+        // the batch itself is the first request, so its third one exceeds the quota
+        let resp = send(&state, &batch).await;
+        assert_eq!(resp["status"], "ok");
+        let responses = resp["response"].as_array().unwrap();
+        assert_eq!(responses.len(), 3);
+        assert_eq!(responses[1]["response"], "pong");
+        assert_eq!(responses[2]["status"], "error");
+        assert_eq!(responses[2]["code"], "connection_quota_exceeded");
+
+        // another connection has its own quota
+        let resp = send(&state, &ping()).await;
+        assert_eq!(resp["response"], "pong");
+    }
+
+    #[tokio::test]
+    async fn test_concatenated_requests() {
+        let state = Arc::new(ServerState::default());
+        let send_all = async |data: String| {
+            serde_json::Deserializer::from_slice(&exchange(&state, data.as_bytes()).await)
+                .into_iter::<Value>()
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap()
+        };
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+        let pings = format!(
+            r#"{{"request_id": "{first}", "command": "ping"}}
+            {{"request_id": "{second}", "command": "ping"}}"#
+        );
+
+        let responses = send_all(pings.clone()).await;
+        assert_eq!(responses.len(), 2);
+        for (resp, uuid) in responses.iter().zip([first, second]) {
+            assert_eq!(resp["status"], "ok");
+            assert_eq!(resp["request_id"], uuid.to_string());
+            assert_eq!(resp["response"], "pong");
+        }
+
+        // anything but JSON after the requests gets an error after their responses
+        let responses = send_all(format!("{pings} garbage")).await;
+        assert_eq!(responses.len(), 3);
+        for (resp, uuid) in responses.iter().zip([first, second]) {
+            assert_eq!(resp["request_id"], uuid.to_string());
+            assert_eq!(resp["response"], "pong");
+        }
+        assert_eq!(responses[2]["code"], "parse_error");
+        assert_eq!(responses[2]["request_id"], Value::Null);
+    }
+
+    #[tokio::test]
+    async fn test_concatenated_requests_without_eof() {
+        let state = Arc::new(ServerState::new(Config {
+            eager_requests: true,
+            ..Default::default()
+        }));
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+        let (mut client, stream) = connected_pair().await;
+        let addr = client.local_addr().unwrap();
+        let handler = tokio::spawn(handle_connection(stream, addr, state));
+        // the second request arrives in a later write, and the write half stays open
+        for uuid in [first, second] {
+            let request = json!({"request_id": uuid, "command": "ping"});
+            client
+                .write_all(&serde_json::to_vec(&request).unwrap())
+                .await
+                .unwrap();
+            tokio::time::sleep(REQUEST_IDLE_TIMEOUT / 4).await;
+        }
+        let mut buf = Vec::new();
+        timeout(Duration::from_secs(5), client.read_to_end(&mut buf))
+            .await
+            .expect("Responses weren't sent without EOF")
+            .unwrap();
+        handler.await.unwrap();
+
+        let responses = serde_json::Deserializer::from_slice(&buf)
+            .into_iter::<Value>()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(responses.len(), 2);
+        for (resp, uuid) in responses.iter().zip([first, second]) {
+            assert_eq!(resp["request_id"], uuid.to_string());
+            assert_eq!(resp["response"], "pong");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_max_json_depth() {
+        let nested = async |config: Config, depth: usize| {
+            let state = Arc::new(ServerState::new(config));
+            // the request object itself is the first level
+            let payload = "[".repeat(depth - 1) + &"]".repeat(depth - 1);
+            let request = format!(
+                r#"{{"request_id": "{}", "command": "echo", "payload": {payload}}}"#,
+                Uuid::new_v4()
+            );
+            let data = exchange(&state, request.as_bytes()).await;
+            serde_json::from_slice::<Value>(&data).unwrap()
+        };
+        let config = || Config {
+            max_json_depth: 8,
+            ..Default::default()
+        };
+
+        let resp = nested(config(), 8).await;
+        assert_eq!(resp["status"], "ok");
+        let resp = nested(config(), 9).await;
+        assert_eq!(resp["code"], "nesting_too_deep");
+
+        // the nesting the parser can't handle gets the same clean error by default
+        let resp = nested(Config::default(), MAX_JSON_DEPTH + 1).await;
+        assert_eq!(resp["code"], "nesting_too_deep");
+        let resp = nested(Config::default(), 100_000).await;
+        assert_eq!(resp["code"], "nesting_too_deep");
+    }
+
+    #[tokio::test]
+    async fn test_slowecho() {
+        let state = Arc::new(ServerState::default());
+        let payload = json!({"text": "slow", "numbers": [1, 2, 3]});
+        let request = json!({
+            "request_id": Uuid::new_v4(),
+            "command": "slowecho",
+            "payload": {"payload": payload, "bytes_per_sec": 200},
+        });
+        let request = serde_json::to_vec(&request).unwrap();
+        let start = Instant::now();
+        let buf = exchange(&state, &request).await;
+        let elapsed = start.elapsed().as_secs_f64();
+
+        let resp: Value = serde_json::from_slice(&buf).unwrap();
+        assert_eq!(resp["response"], payload);
+        // the last chunk of 20 bytes is sent without waiting after it
+        let expected = (buf.len() - 20) as f64 / 200.0;
+        assert!(
+            elapsed >= expected * 0.9 && elapsed < expected + 0.5,
+            "{elapsed}s, expected about {expected}s"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_infinite_result() {
+        let state = Arc::new(ServerState::default());
+        let uuid = Uuid::new_v4();
+        let request = json!({
+            "request_id": uuid,
+            "command": "calculate",
+            "payload": {"operation": "multiply", "a": 1e308, "b": 10}
+        });
+
+        // the connection isn't dropped, and the response is a valid JSON
+        let resp = send(&state, &request).await;
+
+        assert_eq!(resp["request_id"], uuid.to_string());
+        assert_eq!(resp["status"], "ok");
+        assert_eq!(resp["response"], json!({"result": null}));
+    }
+
+    #[tokio::test]
+    async fn test_request_without_eof() {
+        let state = Arc::new(ServerState::new(Config {
+            eager_requests: true,
+            ..Default::default()
+        }));
+        let uuid = Uuid::new_v4();
+        let request = json!({"request_id": uuid, "command": "ping"});
+        let request = serde_json::to_vec(&request).unwrap();
+
+        let (mut client, stream) = connected_pair().await;
+        let addr = client.local_addr().unwrap();
+        let handler = tokio::spawn(handle_connection(stream, addr, state));
+        // the request arrives in parts, and the write half stays open
+        let (head, tail) = request.split_at(10);
+        client.write_all(head).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        client.write_all(tail).await.unwrap();
+        let resp = timeout(Duration::from_secs(5), receive(client))
+            .await
+            .expect("Response wasn't sent without EOF");
+        handler.await.unwrap();
+
+        assert_eq!(resp["request_id"], uuid.to_string());
+        assert_eq!(resp["response"], "pong");
+    }
+
+    #[test]
+    fn test_object_scanner() {
+        let complete = |data: &[u8]| ObjectScanner::default().is_complete(data);
+        assert!(complete(br#" {"command": "echo", "payload": "}{\""} "#));
+        assert!(!complete(br#"{"command": "ping", "payload": {}"#));
+        assert!(complete(br#"{"command": "ping"} {}"#));
+        assert!(!complete(br#"{"command": "ping"} {"#));
+        assert!(!complete(br#"{"command": "ping"} []"#));
+        assert!(!complete(br#"{"command": "ping", }"#));
+        assert!(!complete(b"[]"));
+        assert!(!complete(b""));
+
+        // the data arriving in chunks is scanned as a whole
+        let data = br#"{"command": "echo", "payload": [{}, "]"]}"#;
+        let mut scanner = ObjectScanner::default();
+        for end in 1..data.len() {
+            assert!(!scanner.is_complete(&data[..end]));
+        }
+        assert!(scanner.is_complete(data));
+        assert_eq!(scanner.scanned, data.len());
+    }
+
+    #[tokio::test]
+    async fn test_connections() {
+        let state = Arc::new(ServerState::new(Config {
+            admin_token: Some("admin".to_string()),
+            ..Default::default()
+        }));
+        let mut idle = Vec::new();
+        for _ in 0..2 {
+            let (client, stream) = connected_pair().await;
+            let addr = client.local_addr().unwrap();
+            let handler = tokio::spawn(handle_connection(stream, addr, state.clone()));
+            idle.push((client, addr, handler));
+        }
+        while state.connections.lock().unwrap().len() < 2 {
+            tokio::task::yield_now().await;
+        }
+
+        let query = async |mut request: Value| {
+            request["request_id"] = json!(Uuid::new_v4());
+            request["auth"] = json!("admin");
+            send(&state, &request).await
+        };
+
+        let resp = query(json!({"command": "connections"})).await;
+        assert_eq!(resp["status"], "ok", "{resp}");
+        let connections = resp["response"].as_array().unwrap();
+        let peers: Vec<&str> = connections
+            .iter()
+            .map(|c| c["peer"].as_str().unwrap())
+            .collect();
+        // the last one is the connection of the query itself
+        let expected = [idle[0].1, idle[1].1].map(|addr| addr.to_string());
+        assert_eq!(peers.len(), 3);
+        assert_eq!(peers[..2], expected);
+        for connection in &connections[..2] {
+            assert_eq!(connection["requests"], 0);
+            assert!(connection["duration_ms"].as_f64().unwrap() >= 0.0);
+        }
+        assert_eq!(connections[2]["requests"], 1);
+
+        let (mut client, peer, handler) = idle.remove(0);
+        let command = json!({"command": "closeconnection", "payload": {"peer": peer}});
+        let resp = query(command).await;
+        assert_eq!(resp["response"], json!({"closed": peer.to_string()}));
+        let mut buf = Vec::new();
+        let read = timeout(Duration::from_secs(5), client.read_to_end(&mut buf)).await;
+        assert_eq!(read.unwrap().unwrap(), 0);
+        handler.await.unwrap();
+
+        let resp = query(json!({"command": "connections"})).await;
+        let peers: Vec<&Value> = resp["response"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|c| &c["peer"])
+            .collect();
+        assert_eq!(peers.len(), 2);
+        assert_eq!(peers[0], &json!(idle[0].1.to_string()));
+
+        let command = json!({"command": "closeconnection", "payload": {"peer": peer}});
+        let resp = query(command).await;
+        assert_eq!(resp["code"], "not_found");
+    }
+
+    #[tokio::test]
+    async fn test_write_timeout() {
+        let state = Arc::new(ServerState::new(Config {
+            write_timeout_secs: 1,
+            ..Default::default()
+        }));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        // a small receive buffer makes the window of the client fill up sooner
+        let socket = TcpSocket::new_v4().unwrap();
+        socket.set_recv_buffer_size(4096).unwrap();
+        let mut client = socket
+            .connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, addr) = listener.accept().await.unwrap();
+        let handler = tokio::spawn(handle_connection(stream, addr, state.clone()));
+
+        let request = json!({
+            "request_id": Uuid::new_v4(),
+            "command": "pad",
+            "payload": {"size": MAX_PAD_BYTES},
+        });
+        client
+            .write_all(&serde_json::to_vec(&request).unwrap())
+            .await
+            .unwrap();
+        client.shutdown().await.unwrap();
+        // the client never reads the response, but keeps the connection open
+        let start = Instant::now();
+        timeout(Duration::from_secs(10), handler)
+            .await
+            .expect("the write should time out")
+            .unwrap();
+        assert!(start.elapsed() >= Duration::from_secs(1));
+        assert!(state.connections.lock().unwrap().is_empty());
+        drop(client);
+    }
 }
@@ -0,0 +1,170 @@
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+/// The differences between two JSON values, keyed by the JSON Pointers to the values
+/// that differ, e.g. `/servers/0/port`, `""` being the pointer to the whole value.
+#[derive(Serialize, Debug, PartialEq, Default)]
+pub struct JsonDiff {
+    /// The values that only the right one has.
+    pub added: BTreeMap<String, Value>,
+
+    /// The values that only the left one has.
+    pub removed: BTreeMap<String, Value>,
+
+    /// The values that the both have, but that differ and can't be compared deeper,
+    /// e.g. the scalars or an object and an array.
+    pub changed: BTreeMap<String, Change>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Change {
+    pub from: Value,
+    pub to: Value,
+}
+
+/// Compares the values recursively: the objects by their keys, and the arrays by the indices
+/// of their elements, so that inserting an element into the middle of an array changes
+/// the ones after it. The numbers are compared as they're written, so `1` and `1.0` differ.
+pub fn diff(left: Value, right: Value) -> JsonDiff {
+    let mut diff = JsonDiff::default();
+    diff.compare(String::new(), left, right);
+    diff
+}
+
+impl JsonDiff {
+    fn compare(&mut self, path: String, left: Value, right: Value) {
+        match (left, right) {
+            (Value::Object(mut left), Value::Object(right)) => {
+                for (key, right) in right {
+                    let path = format!("{path}/{}", escape(&key));
+                    match left.remove(&key) {
+                        Some(left) => self.compare(path, left, right),
+                        None => {
+                            self.added.insert(path, right);
+                        }
+                    }
+                }
+                for (key, left) in left {
+                    self.removed
+                        .insert(format!("{path}/{}", escape(&key)), left);
+                }
+            }
+            (Value::Array(left), Value::Array(right)) => {
+                let (mut left, mut right) = (left.into_iter(), right.into_iter());
+                for i in 0.. {
+                    let path = format!("{path}/{i}");
+                    match (left.next(), right.next()) {
+                        (Some(left), Some(right)) => self.compare(path, left, right),
+                        (Some(left), None) => {
+                            self.removed.insert(path, left);
+                        }
+                        (None, Some(right)) => {
+                            self.added.insert(path, right);
+                        }
+                        (None, None) => break,
+                    }
+                }
+            }
+            (left, right) if left != right => {
+                self.changed.insert(
+                    path,
+                    Change {
+                        from: left,
+                        to: right,
+                    },
+                );
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Escapes the key as a reference token of a JSON Pointer.
+fn escape(key: &str) -> String {
+    key.replace('~', "~0").replace('/', "~1")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn config() -> Value {
+        json!({
+            "name": "server",
+            "port": 8080,
+            "tls": {"enabled": false},
+            "hosts": ["a", "b"],
+        })
+    }
+
+    #[test]
+    fn test_diff_identical() {
+        assert_eq!(diff(config(), config()), JsonDiff::default());
+        assert_eq!(diff(json!(null), json!(null)), JsonDiff::default());
+        assert_eq!(
+            json!(JsonDiff::default()),
+            json!({"added": {}, "removed": {}, "changed": {}})
+        );
+    }
+
+    #[test]
+    fn test_diff_added_and_removed() {
+        let mut right = config();
+        right["tls"]["cert"] = json!("/etc/cert.pem");
+        right["hosts"] = json!(["a", "b", "c"]);
+        right.as_object_mut().unwrap().remove("name");
+
+        let result = diff(config(), right);
+        assert_eq!(
+            result.added,
+            BTreeMap::from([
+                ("/hosts/2".to_string(), json!("c")),
+                ("/tls/cert".to_string(), json!("/etc/cert.pem")),
+            ])
+        );
+        assert_eq!(
+            result.removed,
+            BTreeMap::from([("/name".to_string(), json!("server"))])
+        );
+        assert!(result.changed.is_empty());
+
+        // the other way around, the additions become the removals
+        let mut left = config();
+        left["hosts"] = json!(["a", "b", "c"]);
+        let result = diff(left, config());
+        assert_eq!(result.removed.keys().collect::<Vec<_>>(), ["/hosts/2"]);
+        assert!(result.added.is_empty());
+    }
+
+    #[test]
+    fn test_diff_changed() {
+        let mut right = config();
+        right["port"] = json!(8443);
+        right["tls"]["enabled"] = json!(true);
+        right["hosts"] = json!({"a": 1});
+
+        let result = diff(config(), right);
+        assert!(result.added.is_empty() && result.removed.is_empty());
+        assert_eq!(
+            json!(result.changed),
+            json!({
+                "/hosts": {"from": ["a", "b"], "to": {"a": 1}},
+                "/port": {"from": 8080, "to": 8443},
+                "/tls/enabled": {"from": false, "to": true},
+            })
+        );
+
+        // the whole value is changed under the empty pointer
+        let result = diff(json!(1), json!("1"));
+        assert_eq!(result.changed.keys().collect::<Vec<_>>(), [""]);
+    }
+
+    #[test]
+    fn test_diff_escaped_keys() {
+        let result = diff(json!({"a/b": 1, "c~d": 2}), json!({"a/b": 3}));
+        assert_eq!(result.changed.keys().collect::<Vec<_>>(), ["/a~1b"]);
+        assert_eq!(result.removed.keys().collect::<Vec<_>>(), ["/c~0d"]);
+    }
+}
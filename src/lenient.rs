@@ -0,0 +1,161 @@
+use serde_json::Value;
+
+/// Parses the input as JSON.
+///
+/// If `lenient` is set, the input may also contain comments (`// ...` and `/* ... */`)
+/// and trailing commas in objects and arrays, as allowed by JSON5.
+pub fn parse(input: &[u8], lenient: bool) -> serde_json::Result<Value> {
+    if !lenient {
+        return serde_json::from_slice(input);
+    }
+    serde_json::from_slice(&strip_trailing_commas(&strip_comments(input)))
+}
+
+/// Returns the deepest nesting of the arrays and objects in the input, e.g. 2 for `{"a": []}`,
+/// without parsing it. The brackets inside string literals, as well as inside comments
+/// if `lenient` is set, are ignored.
+pub fn nesting_depth(input: &[u8], lenient: bool) -> usize {
+    let stripped;
+    let input = match lenient {
+        true => {
+            stripped = strip_comments(input);
+            &stripped
+        }
+        false => input,
+    };
+    let mut tracker = StringTracker::default();
+    let (mut depth, mut max) = (0usize, 0);
+    for &c in input {
+        if tracker.next(c) {
+            continue;
+        }
+        match c {
+            b'{' | b'[' => {
+                depth += 1;
+                max = max.max(depth);
+            }
+            b'}' | b']' => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+    max
+}
+
+/// Tracks whether the scanned bytes are inside a string literal.
+#[derive(Default)]
+struct StringTracker {
+    in_string: bool,
+    escaped: bool,
+}
+
+impl StringTracker {
+    /// Consumes the next byte and tells whether it's a part of a string literal,
+    /// including the surrounding quotes.
+    fn next(&mut self, c: u8) -> bool {
+        if !self.in_string {
+            self.in_string = c == b'"';
+            return self.in_string;
+        }
+        if self.escaped {
+            self.escaped = false;
+        } else if c == b'\\' {
+            self.escaped = true;
+        } else if c == b'"' {
+            self.in_string = false;
+        }
+        true
+    }
+}
+
+fn strip_comments(input: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(input.len());
+    let mut tracker = StringTracker::default();
+    let mut i = 0;
+    while i < input.len() {
+        let rest = &input[i..];
+        if !tracker.in_string && rest.starts_with(b"//") {
+            // the newline is kept in the output
+            i += rest.iter().position(|&c| c == b'\n').unwrap_or(rest.len());
+            continue;
+        }
+        if !tracker.in_string && rest.starts_with(b"/*") {
+            // an unterminated comment spans till the end of the input
+            i += rest[2..]
+                .windows(2)
+                .position(|w| w == b"*/")
+                .map_or(rest.len(), |p| p + 4);
+            // a comment separates tokens like whitespace does
+            output.push(b' ');
+            continue;
+        }
+        tracker.next(input[i]);
+        output.push(input[i]);
+        i += 1;
+    }
+    output
+}
+
+fn strip_trailing_commas(input: &[u8]) -> Vec<u8> {
+    let mut output: Vec<u8> = Vec::with_capacity(input.len());
+    let mut tracker = StringTracker::default();
+    for (i, &c) in input.iter().enumerate() {
+        if !tracker.next(c) && c == b',' {
+            // only a comma following a value is trailing, so e.g. `[,]` stays invalid
+            let prev = output.iter().rev().find(|c| !c.is_ascii_whitespace());
+            let follows_value = !matches!(prev, Some(b'[' | b'{' | b',' | b':'));
+            let next = input[i + 1..].iter().find(|c| !c.is_ascii_whitespace());
+            if follows_value && matches!(next, Some(b'}' | b']')) {
+                continue;
+            }
+        }
+        output.push(c);
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_trailing_commas() {
+        let input = br#"{"command": "echo", "payload": [1, 2, ], }"#;
+        assert!(parse(input, false).is_err());
+        assert_eq!(
+            parse(input, true).unwrap(),
+            json!({"command": "echo", "payload": [1, 2]})
+        );
+    }
+
+    #[test]
+    fn test_comments() {
+        let input = br#"{
+            // a line comment
+            "a": "not // a comment", /* a block
+            comment */ "b": "/* neither */ \"quoted\"",
+            "c": 1/* "quoted" */
+        }"#;
+        assert!(parse(input, false).is_err());
+        assert_eq!(
+            parse(input, true).unwrap(),
+            json!({"a": "not // a comment", "b": "/* neither */ \"quoted\"", "c": 1})
+        );
+    }
+
+    #[test]
+    fn test_nesting_depth() {
+        assert_eq!(nesting_depth(b"1", false), 0);
+        assert_eq!(nesting_depth(br#"{"a": [], "b": {"c": [1]}}"#, false), 3);
+        assert_eq!(nesting_depth(br#"["[[{", "\"[["]"#, false), 1);
+        assert_eq!(nesting_depth(b"[[]] /* [[[ */", false), 3);
+        assert_eq!(nesting_depth(b"[[]] /* [[[ */", true), 2);
+    }
+
+    #[test]
+    fn test_invalid_stays_invalid() {
+        assert!(parse(b"{\"a\": 1 /* unterminated", true).is_err());
+        assert!(parse(b"[,]", true).is_err());
+        assert!(parse(b"{\"a\": ,}", true).is_err());
+    }
+}
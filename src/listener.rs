@@ -0,0 +1,221 @@
+use std::io;
+use tokio::net::{TcpListener, TcpSocket, lookup_host};
+
+/// Binds a TCP listener to the given address with the specified socket options.
+///
+/// The address is resolved first, and the listener is bound to the first resolved
+/// socket address that accepts binding.
+///
+/// # Parameters
+/// - `addr`: The address to listen on, e.g. `"localhost:7878"`.
+/// - `reuseaddr`: Whether to set the `SO_REUSEADDR` option, which allows to rebind the address
+///   right after a restart while connections of the previous process are in `TIME_WAIT` state.
+/// - `reuseport`: Whether to set the `SO_REUSEPORT` option, which allows several processes
+///   to listen on the same address, e.g. during a rolling restart. On Linux, the kernel
+///   distributes the incoming connections between them by the hash of the connection's
+///   addresses and ports. The option is only supported on Unix.
+/// - `backlog`: The maximum number of pending connections waiting to be accepted.
+pub async fn bind(
+    addr: &str,
+    reuseaddr: bool,
+    reuseport: bool,
+    backlog: u32,
+) -> io::Result<TcpListener> {
+    if reuseport && !cfg!(unix) {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "SO_REUSEPORT is only supported on Unix",
+        ));
+    }
+    let mut last_error = None;
+    for socket_addr in lookup_host(addr).await? {
+        let socket = if socket_addr.is_ipv4() {
+            TcpSocket::new_v4()?
+        } else {
+            TcpSocket::new_v6()?
+        };
+        socket.set_reuseaddr(reuseaddr)?;
+        #[cfg(unix)]
+        socket.set_reuseport(reuseport)?;
+        if let Err(e) = socket.bind(socket_addr) {
+            last_error = Some(e);
+            continue;
+        }
+        return socket.listen(backlog);
+    }
+    Err(last_error.unwrap_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::AddrNotAvailable,
+            format!("{addr} could not be resolved"),
+        )
+    }))
+}
+
+/// The first file descriptor passed by systemd socket activation, `SD_LISTEN_FDS_START`.
+#[cfg(unix)]
+const SD_LISTEN_FDS_START: std::os::fd::RawFd = 3;
+
+/// Adopts the listening socket passed by systemd socket activation instead of binding
+/// a new one, so that systemd can keep accepting connections while the server restarts.
+///
+/// The socket is configured by the `.socket` unit, so the socket options and the backlog
+/// of `bind` don't apply to it. Only Unix is supported.
+///
+/// # Errors
+/// Fails if `LISTEN_PID` or `LISTEN_FDS` is absent or malformed, if `LISTEN_PID` is not
+/// the ID of this process, if not exactly one socket is passed, or if it's not a socket.
+pub fn from_systemd() -> io::Result<TcpListener> {
+    #[cfg(unix)]
+    {
+        let listen_pid = std::env::var("LISTEN_PID").ok();
+        let listen_fds = std::env::var("LISTEN_FDS").ok();
+        // SAFETY: the descriptors passed by systemd are owned by nothing else in the process
+        unsafe {
+            adopt(
+                listen_pid.as_deref(),
+                listen_fds.as_deref(),
+                SD_LISTEN_FDS_START,
+            )
+        }
+    }
+    #[cfg(not(unix))]
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "systemd socket activation is only supported on Unix",
+    ))
+}
+
+/// Validates the variables of systemd socket activation and adopts the descriptor `fd`
+/// as the listener.
+///
+/// # Safety
+/// If the variables are valid, `fd` must be an open descriptor that nothing else owns,
+/// since the listener closes it once dropped.
+#[cfg(unix)]
+unsafe fn adopt(
+    listen_pid: Option<&str>,
+    listen_fds: Option<&str>,
+    fd: std::os::fd::RawFd,
+) -> io::Result<TcpListener> {
+    use std::os::fd::FromRawFd;
+
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidInput, message);
+    let (Some(listen_pid), Some(listen_fds)) = (listen_pid, listen_fds) else {
+        return Err(invalid(
+            "LISTEN_PID and LISTEN_FDS are not set, the server must be started \
+             by systemd socket activation"
+                .to_string(),
+        ));
+    };
+    let pid = std::process::id();
+    if listen_pid.parse() != Ok(pid) {
+        return Err(invalid(format!(
+            "LISTEN_PID is {listen_pid}, but the ID of the server process is {pid}"
+        )));
+    }
+    if listen_fds.parse() != Ok(1) {
+        return Err(invalid(format!(
+            "LISTEN_FDS is {listen_fds}, but the server accepts exactly one socket"
+        )));
+    }
+    // SAFETY: guaranteed by the caller
+    let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+    // fails if the descriptor is not a socket
+    listener.local_addr().map_err(|e| {
+        invalid(format!(
+            "the descriptor {fd} passed by systemd is not a socket: {e}"
+        ))
+    })?;
+    listener.set_nonblocking(true)?;
+    TcpListener::from_std(listener)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpStream;
+
+    #[tokio::test]
+    async fn test_rebind_with_reuseaddr() {
+        let listener = bind("127.0.0.1:0", true, false, 16).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // closing an accepted connection on the server side leaves it in TIME_WAIT
+        let client = TcpStream::connect(addr).await.unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        drop(stream);
+        drop(client);
+        drop(listener);
+
+        let listener = bind(&addr.to_string(), true, false, 16).await.unwrap();
+        assert_eq!(listener.local_addr().unwrap(), addr);
+    }
+
+    // other kernels may not distribute the connections between the listeners
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_reuseport_shares_connections() {
+        let first = bind("127.0.0.1:0", false, true, 16).await.unwrap();
+        let addr = first.local_addr().unwrap();
+        let second = bind(&addr.to_string(), false, true, 16).await.unwrap();
+
+        // the connections are distributed by the hash of the client port
+        let mut accepted = [0; 2];
+        let mut clients = Vec::new();
+        while accepted.contains(&0) && clients.len() < 100 {
+            clients.push(TcpStream::connect(addr).await.unwrap());
+            tokio::select! {
+                _ = first.accept() => accepted[0] += 1,
+                _ = second.accept() => accepted[1] += 1,
+            }
+        }
+        assert!(!accepted.contains(&0), "accepted: {accepted:?}");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_adopt_inherited_socket() {
+        use std::os::fd::IntoRawFd;
+
+        // the descriptor is inherited from the test itself instead of systemd
+        let inherited = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = inherited.local_addr().unwrap();
+        let fd = inherited.into_raw_fd();
+        let pid = std::process::id().to_string();
+        let listener = unsafe { adopt(Some(&pid), Some("1"), fd) }.unwrap();
+        assert_eq!(listener.local_addr().unwrap(), addr);
+
+        let client = TcpStream::connect(addr).await.unwrap();
+        let (_, peer) = listener.accept().await.unwrap();
+        assert_eq!(peer, client.local_addr().unwrap());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_adopt_rejected() {
+        use std::os::fd::IntoRawFd;
+
+        let pid = std::process::id().to_string();
+        let other_pid = (std::process::id() + 1).to_string();
+        // the descriptor is only adopted once the variables are valid,
+        // so it's never touched here
+        for (listen_pid, listen_fds) in [
+            (None, None),
+            (Some(pid.as_str()), None),
+            (None, Some("1")),
+            (Some(other_pid.as_str()), Some("1")),
+            (Some("systemd"), Some("1")),
+            (Some(pid.as_str()), Some("0")),
+            (Some(pid.as_str()), Some("2")),
+        ] {
+            let err = unsafe { adopt(listen_pid, listen_fds, -1) }.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        }
+
+        // a file is not a socket
+        let file = std::fs::File::open("Cargo.toml").unwrap();
+        let fd = file.into_raw_fd();
+        let err = unsafe { adopt(Some(&pid), Some("1"), fd) }.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
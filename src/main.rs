@@ -1,13 +1,31 @@
-use clap::Parser;
+use clap::{ArgAction, Parser};
 use ftail::Ftail;
-use log::{LevelFilter, debug, error, info};
-use std::path::PathBuf;
-use std::sync::{Arc, Mutex};
-use tokio::net::TcpListener;
+use log::{LevelFilter, Log, Metadata, Record, debug, error, info, warn};
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+use tokio::runtime::Runtime;
 use tokio::task::JoinSet;
 
+mod apidoc;
+mod batchstream;
+mod cipher;
 mod commands;
+mod expression;
+mod flatten;
 mod handler;
+mod jsondiff;
+mod lenient;
+mod listener;
+mod numbers;
+mod procinfo;
+mod schema;
+mod text;
 mod types;
 
 #[derive(Parser)]
@@ -20,49 +38,358 @@ struct Cli {
     /// Sets a custom log file
     #[arg(short, long, value_name = "FILE", default_value = "default.log")]
     log_file: PathBuf,
+
+    /// Logs to stderr if the log file can't be opened, instead of refusing to start
+    #[arg(long, value_name = "BOOL", action = ArgAction::Set, default_value_t = true)]
+    log_fallback_stderr: bool,
+
+    /// Prints a throughput report on shutdown
+    #[arg(long)]
+    bench_report: bool,
+
+    /// Sets the length of the window for the recent metrics in seconds
+    #[arg(long, value_name = "SECONDS", default_value_t = types::DEFAULT_METRICS_WINDOW_SECS)]
+    metrics_window: u64,
+
+    /// Takes a snapshot of the metrics every given number of seconds for `metricsseries`,
+    /// zero disables the snapshots
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value_t = types::DEFAULT_METRICS_SNAPSHOT_INTERVAL_SECS
+    )]
+    metrics_snapshot_interval: u64,
+
+    /// Sets the number of the most recent metrics snapshots kept
+    #[arg(long, value_name = "N", default_value_t = types::DEFAULT_METRICS_HISTORY_SIZE)]
+    metrics_history_size: usize,
+
+    /// Delays each response by the given number of milliseconds to simulate a slow network
+    #[arg(long, value_name = "MS", default_value_t = 0)]
+    artificial_delay: u64,
+
+    /// Sets the SO_REUSEADDR option on the listening socket
+    #[arg(long, value_name = "BOOL", action = ArgAction::Set, default_value_t = cfg!(unix))]
+    so_reuseaddr: bool,
+
+    /// Sets the SO_REUSEPORT option on the listening socket, letting several servers share the port
+    /// (Unix only)
+    #[arg(long)]
+    reuse_port: bool,
+
+    /// Sets the maximum number of pending connections waiting to be accepted
+    #[arg(long, value_name = "N", default_value_t = 1024)]
+    backlog: u32,
+
+    /// Listens on the socket passed by systemd socket activation (`LISTEN_FDS`)
+    /// instead of binding a new one (Unix only)
+    #[arg(long)]
+    systemd_socket: bool,
+
+    /// Allows the requests containing the given token in their `auth` field to use
+    /// the administrative commands, such as `procinfo`
+    #[arg(long, value_name = "TOKEN")]
+    admin_token: Option<String>,
+
+    /// Requires every request to contain the given token in its `auth` field,
+    /// rejecting the ones that don't
+    #[arg(long, value_name = "TOKEN")]
+    auth_token: Option<String>,
+
+    /// Caches responses for the given number of seconds to deduplicate requests with the same ID
+    #[arg(long, value_name = "SECONDS", default_value_t = 0)]
+    idempotency_ttl: u64,
+
+    /// Limits the size of a response, sending an error instead of larger ones
+    #[arg(long, value_name = "BYTES")]
+    max_response_bytes: Option<usize>,
+
+    /// Limits the size of a request, rejecting larger ones with an error
+    #[arg(long, value_name = "BYTES")]
+    max_request_bytes: Option<usize>,
+
+    /// Accepts requests with comments and trailing commas
+    #[arg(long)]
+    lenient_json: bool,
+
+    /// Processes a request once a complete JSON object is received and no more data arrives
+    /// for 100 ms, for the clients that never close their write half
+    #[arg(long)]
+    eager_requests: bool,
+
+    /// Pretty-prints the responses for reading them by a human
+    #[arg(long)]
+    pretty: bool,
+
+    /// Limits the number of requests in a batch, rejecting larger batches
+    #[arg(long, value_name = "N")]
+    max_batch_size: Option<usize>,
+
+    /// Limits the number of the requests nested into batches that are executed concurrently
+    /// across all of the connections, queueing the ones over the limit
+    #[arg(long, value_name = "N")]
+    max_total_subrequests: Option<NonZeroUsize>,
+
+    /// Processes the requests of a batch while the rest of it is still being received
+    #[arg(long)]
+    stream_batches: bool,
+
+    /// Disables the given command, can be repeated to disable several ones
+    #[arg(long = "disable-command", value_name = "COMMAND")]
+    disabled_commands: Vec<types::CommandKind>,
+
+    /// Limits the number of concurrently executed commands of a kind, e.g. `calculate=4`,
+    /// can be repeated to limit several ones
+    #[arg(long = "command-concurrency", value_name = "COMMAND=N")]
+    command_concurrency: Vec<types::CommandConcurrency>,
+
+    /// Rejects the commands that have reached their concurrency limit instead of queueing them
+    #[arg(long)]
+    reject_busy: bool,
+
+    /// Counts the commands executed by `replay` in the metrics
+    #[arg(long, value_name = "BOOL", action = ArgAction::Set, default_value_t = true)]
+    replay_metrics: bool,
+
+    /// Adds the hash of the result to each successful response as its `etag`
+    #[arg(long)]
+    content_hash: bool,
+
+    /// Limits the number of requests processed on a connection, including the ones nested
+    /// into a batch, zero means no limit
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    max_requests_per_connection: usize,
+
+    /// Limits the nesting depth of the arrays and objects in a request, rejecting deeper ones
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = types::MAX_JSON_DEPTH,
+        value_parser = parse_json_depth
+    )]
+    max_json_depth: usize,
+
+    /// Limits the memory buffered for the requests and responses, rejecting new connections
+    /// and pausing the reading of the requests while it's exceeded
+    #[arg(long, value_name = "BYTES")]
+    max_memory_bytes: Option<usize>,
+
+    /// Persists the statistics of `sessionstats` to the given directory by their session IDs,
+    /// which allows the clients to resume them on another connection
+    #[arg(long, value_name = "DIR")]
+    session_stats_dir: Option<PathBuf>,
+
+    /// Abandons a connection once writing a response to it has waited for the client
+    /// to receive it for the given number of seconds, zero means no limit
+    #[arg(long, value_name = "SECONDS", default_value_t = types::DEFAULT_WRITE_TIMEOUT_SECS)]
+    write_timeout: u64,
+
+    /// Sends the protocol version frame to each client as soon as its connection is accepted
+    #[arg(long)]
+    protocol_version_header: bool,
+
+    /// Writes the listening address to the given file once the server is ready to accept connections
+    #[arg(long, value_name = "FILE")]
+    ready_file: Option<PathBuf>,
+
+    /// Sets the number of the worker threads of the runtime, zero means one per CPU core
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    worker_threads: usize,
+
+    /// Limits the number of the threads running the blocking tasks, such as large `matrixmul`s
+    #[arg(long, value_name = "N", default_value_t = DEFAULT_MAX_BLOCKING_THREADS)]
+    max_blocking_threads: NonZeroUsize,
 }
 
-#[tokio::main]
-async fn main() {
+/// The default limit of the blocking threads, which is the one of Tokio.
+const DEFAULT_MAX_BLOCKING_THREADS: NonZeroUsize = NonZeroUsize::new(512).unwrap();
+
+/// Parses the nesting depth limit, which can't exceed the one of the JSON parser.
+fn parse_json_depth(value: &str) -> Result<usize, String> {
+    match value.parse() {
+        Ok(depth @ 1..=types::MAX_JSON_DEPTH) => Ok(depth),
+        _ => Err(format!(
+            "must be an integer from 1 to {}",
+            types::MAX_JSON_DEPTH
+        )),
+    }
+}
+
+fn main() {
     // parsing arguments
     let cli = Cli::parse();
+
+    // setting up the runtime
+    let runtime = match build_runtime(cli.worker_threads, cli.max_blocking_threads) {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("Couldn't start the runtime: {e}");
+            return;
+        }
+    };
+    runtime.block_on(run(cli));
+}
+
+async fn run(cli: Cli) {
     let loglevel = match cli.debug {
         true => LevelFilter::Debug,
         false => LevelFilter::Info,
     };
     let logfile = cli.log_file.as_path();
 
-    // setting up the logger
+    // setting up the logger, which lets every record through, leaving the filtering
+    // to the global level that the `loglevel` command changes at runtime
+    let mut log_target = logfile.display().to_string();
+    // logged once the level is set, since nothing is logged before that
+    let mut fallback_warning = None;
     if let Err(e) = Ftail::new()
-        .single_file(logfile, true, loglevel)
+        .single_file(logfile, true, LevelFilter::Trace)
         .timezone(ftail::Tz::UTC)
         .init()
     {
-        eprintln!("Couldn't initialize the logger: {e}");
+        if !cli.log_fallback_stderr {
+            eprintln!("Couldn't initialize the logger: {e}");
+            return;
+        }
+        eprintln!(
+            "WARNING: couldn't open the log file {log_target}: {e}, logging to stderr instead!"
+        );
+        if let Err(e) = log::set_logger(&StderrLogger) {
+            eprintln!("Couldn't initialize the logger: {e}");
+            return;
+        }
+        fallback_warning = Some(format!(
+            "Couldn't open the log file {log_target}: {e}, logging to stderr instead."
+        ));
+        log_target = "stderr".to_string();
+    }
+    log::set_max_level(loglevel);
+    if let Some(warning) = fallback_warning {
+        warn!("{warning}");
+    }
+    info!(
+        "Runtime started with {} worker threads and at most {} blocking threads.",
+        tokio::runtime::Handle::current().metrics().num_workers(),
+        cli.max_blocking_threads
+    );
+
+    // resolving the effective configuration
+    let config = types::Config {
+        address: "localhost:7878".to_string(),
+        debug: cli.debug,
+        metrics_window_secs: cli.metrics_window,
+        artificial_delay_ms: cli.artificial_delay,
+        so_reuseaddr: cli.so_reuseaddr,
+        so_reuseport: cli.reuse_port,
+        backlog: cli.backlog,
+        systemd_socket: cli.systemd_socket,
+        admin_token: cli.admin_token,
+        auth_token: cli.auth_token,
+        idempotency_ttl_secs: cli.idempotency_ttl,
+        max_response_bytes: cli.max_response_bytes,
+        max_request_bytes: cli.max_request_bytes,
+        lenient_json: cli.lenient_json,
+        eager_requests: cli.eager_requests,
+        pretty_json: cli.pretty,
+        max_batch_size: cli.max_batch_size,
+        max_total_subrequests: cli.max_total_subrequests.map(NonZeroUsize::get),
+        stream_batches: cli.stream_batches,
+        disabled_commands: cli.disabled_commands.into_iter().collect(),
+        protocol_version_header: cli.protocol_version_header,
+        command_concurrency: cli
+            .command_concurrency
+            .into_iter()
+            .map(|c| (c.kind, c.limit))
+            .collect(),
+        reject_busy: cli.reject_busy,
+        replay_metrics: cli.replay_metrics,
+        metrics_snapshot_interval_secs: cli.metrics_snapshot_interval,
+        metrics_history_size: cli.metrics_history_size,
+        content_hash: cli.content_hash,
+        max_requests_per_connection: cli.max_requests_per_connection,
+        max_json_depth: cli.max_json_depth,
+        max_memory_bytes: cli.max_memory_bytes,
+        session_stats_dir: cli.session_stats_dir,
+        write_timeout_secs: cli.write_timeout,
+    };
+    if config.artificial_delay_ms > 0 {
+        let delay = config.artificial_delay_ms;
+        warn!("Artificial delay of {delay}ms is enabled, all responses will be sent late!");
+        eprintln!(
+            "WARNING: artificial delay of {delay}ms is enabled, all responses will be sent late!"
+        );
+    }
+
+    // a stale file from a previous run must not signal readiness
+    if let Some(path) = &cli.ready_file
+        && let Err(e) = fs::remove_file(path)
+        && e.kind() != io::ErrorKind::NotFound
+    {
+        error!(
+            "Couldn't remove the stale ready file {}: {e}",
+            path.display()
+        );
         return;
     }
 
     // setting up the listener
-    let server_addr = "localhost:7878";
-    let listener = match TcpListener::bind(server_addr).await {
+    let listener = if config.systemd_socket {
+        listener::from_systemd()
+    } else {
+        listener::bind(
+            &config.address,
+            config.so_reuseaddr,
+            config.so_reuseport,
+            config.backlog,
+        )
+        .await
+    };
+    let listener = match listener {
         Ok(v) => v,
         Err(e) => {
             error!("Couldn't start the server: {e}");
             return;
         }
     };
+    let server_addr = if config.systemd_socket {
+        info!("Listening on the socket passed by systemd.");
+        listener.local_addr().map_or_else(
+            |_| "the systemd socket".to_string(),
+            |addr| addr.to_string(),
+        )
+    } else {
+        info!(
+            "Listening socket options: SO_REUSEADDR = {}, SO_REUSEPORT = {}, backlog = {}.",
+            config.so_reuseaddr, config.so_reuseport, config.backlog
+        );
+        config.address.clone()
+    };
     let mut tasks = JoinSet::new();
+    let start = Instant::now();
 
     info!("Server started on {server_addr}, ready to accept connections.");
-    println!(
-        "Server started on {server_addr}. Logs are available at {}",
-        logfile.display()
-    );
+    println!("Server started on {server_addr}. Logs are available at {log_target}");
 
-    // setting up metrics
-    let metrics = Arc::new(Mutex::new(types::Metrics::default()));
+    // setting up the shared state
+    let state = Arc::new(types::ServerState::new(config));
+    if state.config.metrics_snapshot_interval_secs > 0 {
+        tokio::spawn(take_metrics_snapshots(state.clone()));
+    }
+
+    if let Some(path) = &cli.ready_file {
+        let result = listener
+            .local_addr()
+            .and_then(|addr| write_ready_file(path, addr));
+        if let Err(e) = result {
+            error!("Couldn't write the ready file {}: {e}", path.display());
+            return;
+        }
+        debug!("Readiness signaled via {}.", path.display());
+    }
 
     // accepting connections
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
     loop {
         tokio::select! {
             conn = listener.accept() => {
@@ -74,20 +401,26 @@ async fn main() {
                     }
                 };
                 debug!("Accepted incoming connection from {addr}.");
-                let m_clone = metrics.clone();
+                let s_clone = state.clone();
                 tasks.spawn(async move {
-                    handler::handle_connection(socket, m_clone).await;
+                    // a separate task isolates the handler panics from the server
+                    let handle = tokio::spawn(handler::handle_connection(socket, addr, s_clone));
+                    if let Err(e) = handle.await {
+                        error!("Connection handler for {addr} failed: {e}");
+                    }
                 });
             }
-            sigint = tokio::signal::ctrl_c() => {
-                if let Err(e) = sigint {
-                    error!("Failed to set up Ctrl+C signal handler: {e}");
-                    error!("Shutting down the server now since we are unable to receive stop signals properly.");
-                    eprintln!("Unexpected error occurred: unable to listen for Ctrl+C signal. Stopping the server...");
-                }
-                else {
-                    info!("Shutdown signal received, stopping acceptance of new connections.");
-                    println!("Stopping the server...");
+            signal = &mut shutdown => {
+                match signal {
+                    Ok(name) => {
+                        info!("{name} received, stopping acceptance of new connections.");
+                        println!("Stopping the server...");
+                    }
+                    Err(e) => {
+                        error!("Failed to set up the shutdown signal handlers: {e}");
+                        error!("Shutting down the server now since we are unable to receive stop signals properly.");
+                        eprintln!("Unexpected error occurred: unable to listen for stop signals. Stopping the server...");
+                    }
                 }
                 break;
             }
@@ -95,7 +428,165 @@ async fn main() {
     }
 
     info!("Waiting for existing connections to finish...");
+    state.draining.store(true, Ordering::Relaxed);
     tasks.join_all().await;
+
+    if cli.bench_report {
+        let report = state.lock_metrics().bench_report(start.elapsed());
+        info!("Throughput report:\n{report}");
+        println!("{report}");
+    }
+
     info!("Server shut down.");
     println!("Server stopped.");
 }
+
+/// Builds the multi-threaded runtime the server runs on.
+///
+/// Zero `worker_threads` leaves the choice to Tokio, which starts one per CPU core.
+fn build_runtime(worker_threads: usize, max_blocking_threads: NonZeroUsize) -> io::Result<Runtime> {
+    let mut builder = tokio::runtime::Builder::new_multi_thread();
+    builder
+        .enable_all()
+        .max_blocking_threads(max_blocking_threads.get());
+    if worker_threads > 0 {
+        builder.worker_threads(worker_threads);
+    }
+    builder.build()
+}
+
+/// Waits for a signal requesting the server to shut down, and returns its name.
+///
+/// This is Ctrl+C (`SIGINT`) on all platforms, and also `SIGTERM` on Unix,
+/// which is what most process managers send to stop a service.
+async fn shutdown_signal() -> io::Result<&'static str> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+        let mut sigterm = signal(SignalKind::terminate())?;
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result.map(|_| "SIGINT"),
+            _ = sigterm.recv() => Ok("SIGTERM"),
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        tokio::signal::ctrl_c().await.map(|_| "Ctrl+C")
+    }
+}
+
+/// Takes a snapshot of the metrics every `Config::metrics_snapshot_interval_secs`
+/// and appends it to `ServerState::metrics_history`, for as long as the server is running.
+async fn take_metrics_snapshots(state: Arc<types::ServerState>) {
+    let interval = Duration::from_secs(state.config.metrics_snapshot_interval_secs);
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        let snapshot = state.lock_metrics().snapshot(types::unix_millis());
+        state.metrics_history.lock().unwrap().push(snapshot);
+    }
+}
+
+/// Writes the address to the ready file.
+///
+/// The address is written to a temporary file first, which is then renamed, so that
+/// whoever watches for the ready file never sees it incomplete.
+fn write_ready_file(path: &Path, addr: SocketAddr) -> io::Result<()> {
+    let mut tmp_name = path.as_os_str().to_owned();
+    tmp_name.push(".tmp");
+    fs::write(&tmp_name, addr.to_string())?;
+    fs::rename(&tmp_name, path)
+}
+
+/// A logger writing to stderr in the same format as the log file,
+/// used when the log file can't be opened.
+struct StderrLogger;
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!(
+                "{} {} {} {}",
+                chrono::Utc::now().format("%Y-%m-%d %H:%M:%S"),
+                record.level(),
+                record.target(),
+                record.args()
+            );
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn test_runtime_options() {
+        let cli = Cli::try_parse_from(["testrust"]).unwrap();
+        assert_eq!(cli.worker_threads, 0);
+        assert_eq!(cli.max_blocking_threads, DEFAULT_MAX_BLOCKING_THREADS);
+
+        let args = [
+            "testrust",
+            "--worker-threads",
+            "3",
+            "--max-blocking-threads",
+            "1",
+        ];
+        let cli = Cli::try_parse_from(args).unwrap();
+        assert_eq!(cli.worker_threads, 3);
+        assert_eq!(cli.max_blocking_threads.get(), 1);
+
+        assert!(Cli::try_parse_from(["testrust", "--max-blocking-threads", "0"]).is_err());
+    }
+
+    #[test]
+    fn test_max_json_depth() {
+        let cli = Cli::try_parse_from(["testrust"]).unwrap();
+        assert_eq!(cli.max_json_depth, types::MAX_JSON_DEPTH);
+
+        let cli = Cli::try_parse_from(["testrust", "--max-json-depth", "8"]).unwrap();
+        assert_eq!(cli.max_json_depth, 8);
+
+        for invalid in ["0", "128", "deep"] {
+            assert!(Cli::try_parse_from(["testrust", "--max-json-depth", invalid]).is_err());
+        }
+    }
+
+    #[test]
+    fn test_build_runtime() {
+        let runtime = build_runtime(0, DEFAULT_MAX_BLOCKING_THREADS).unwrap();
+        assert!(runtime.metrics().num_workers() > 0);
+
+        let runtime = build_runtime(3, NonZeroUsize::MIN).unwrap();
+        assert_eq!(runtime.metrics().num_workers(), 3);
+
+        // a single blocking thread runs the blocking tasks one by one
+        let running = Arc::new(AtomicUsize::new(0));
+        let max_running = Arc::new(AtomicUsize::new(0));
+        runtime.block_on(async {
+            let tasks: Vec<_> = (0..3)
+                .map(|_| {
+                    let (running, max_running) = (running.clone(), max_running.clone());
+                    tokio::task::spawn_blocking(move || {
+                        let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                        max_running.fetch_max(now, Ordering::SeqCst);
+                        std::thread::sleep(Duration::from_millis(20));
+                        running.fetch_sub(1, Ordering::SeqCst);
+                    })
+                })
+                .collect();
+            for task in tasks {
+                task.await.unwrap();
+            }
+        });
+        assert_eq!(max_running.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,639 @@
+use rand::rngs::StdRng;
+use rand::seq::index;
+use rand::{Rng, SeedableRng};
+use serde::Serialize;
+
+use crate::types::{CommandError, ErrorCode, MathFn};
+
+/// The maximum length of a number accepted by `convert_base`, in digits.
+///
+/// The conversion takes quadratic time, so this keeps it cheap.
+const MAX_DIGITS: usize = 4096;
+
+/// Converts an integer of arbitrary size from one base to another.
+///
+/// The `value` may have a leading `-` sign, and its digits are case-insensitive:
+/// digits above 9 are letters `a` to `z`. The result is written in lowercase without leading zeros.
+/// Both bases must be in the range 2 to 36.
+///
+/// # Errors
+/// Fails with `ErrorCode::InvalidArgument` if a base is out of range, or if the `value`
+/// is empty, too long or contains a digit invalid for `from_base`.
+pub fn convert_base(value: &str, from_base: u32, to_base: u32) -> Result<String, CommandError> {
+    for base in [from_base, to_base] {
+        if !(2..=36).contains(&base) {
+            return Err(invalid(format!(
+                "base {base} is not supported, it must be from 2 to 36"
+            )));
+        }
+    }
+
+    let (negative, digits) = match value.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, value),
+    };
+    if digits.is_empty() {
+        return Err(invalid("value must contain at least one digit".to_string()));
+    }
+    if digits.len() > MAX_DIGITS {
+        return Err(invalid(format!(
+            "value must not be longer than {MAX_DIGITS} digits"
+        )));
+    }
+
+    // most significant digit first
+    let mut number = digits
+        .chars()
+        .map(|c| {
+            c.to_digit(from_base)
+                .ok_or_else(|| invalid(format!("`{c}` is not a valid digit in base {from_base}")))
+        })
+        .collect::<Result<Vec<u32>, _>>()?;
+
+    // repeatedly dividing the number by the target base yields its digits, least significant first
+    let mut result = Vec::new();
+    while !number.is_empty() {
+        let mut remainder = 0;
+        let mut quotient = Vec::with_capacity(number.len());
+        for digit in number {
+            let current = remainder * from_base + digit;
+            let q = current / to_base;
+            remainder = current % to_base;
+            if q != 0 || !quotient.is_empty() {
+                quotient.push(q);
+            }
+        }
+        result.push(char::from_digit(remainder, to_base).unwrap());
+        number = quotient;
+    }
+
+    // a zero number yields a single zero digit, which has no sign
+    if negative && result != ['0'] {
+        result.push('-');
+    }
+    Ok(result.iter().rev().collect())
+}
+
+/// The maximum number of decimal places accepted by `round_decimal`.
+pub const MAX_PRECISION: u32 = 20;
+
+/// Rounds a number to the given number of decimal places.
+///
+/// The rounding is done on the exact decimal expansion of the number, so e.g.
+/// `0.1 + 0.2` rounded to two places is exactly the same `f64` as the literal `0.3`.
+///
+/// # Errors
+/// Fails with `ErrorCode::InvalidArgument` if `precision` is greater than `MAX_PRECISION`.
+pub fn round_decimal(value: f64, precision: u32) -> Result<f64, CommandError> {
+    if precision > MAX_PRECISION {
+        return Err(invalid(format!(
+            "precision must not be greater than {MAX_PRECISION}"
+        )));
+    }
+    if !value.is_finite() {
+        return Ok(value);
+    }
+    let rounded = format!("{value:.0$}", precision as usize);
+    Ok(rounded
+        .parse()
+        .expect("formatted finite numbers are always valid"))
+}
+
+/// Computes the greatest common divisor of two integers using the Euclidean algorithm.
+///
+/// The divisor of zero and any `b` is `b`, so `gcd(0, 0)` is zero.
+pub fn gcd(mut a: u64, mut b: u64) -> u64 {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a
+}
+
+/// Computes the least common multiple of two integers.
+///
+/// The multiple of zero and any `b` is zero.
+///
+/// # Errors
+/// Fails with `ErrorCode::Overflow` if the result doesn't fit into `u64`.
+pub fn lcm(a: u64, b: u64) -> Result<u64, CommandError> {
+    if a == 0 || b == 0 {
+        return Ok(0);
+    }
+    // dividing first keeps the intermediate value no larger than the result
+    (a / gcd(a, b)).checked_mul(b).ok_or_else(|| {
+        CommandError::new(
+            ErrorCode::Overflow,
+            format!("the least common multiple of {a} and {b} doesn't fit into 64 bits"),
+        )
+    })
+}
+
+/// The maximum number of values generated by `seeded_random`.
+pub const MAX_RANDOM_COUNT: u32 = 10_000;
+
+/// Generates `count` pseudo-random integers from the seed.
+///
+/// The same seed always yields the same sequence, as long as the generator, `StdRng`,
+/// stays the same, which may change with the version of the `rand` crate.
+///
+/// # Errors
+/// Fails with `ErrorCode::InvalidArgument` if `count` exceeds `MAX_RANDOM_COUNT`.
+pub fn seeded_random(seed: u64, count: u32) -> Result<Vec<u64>, CommandError> {
+    if count > MAX_RANDOM_COUNT {
+        return Err(invalid(format!("count must be at most {MAX_RANDOM_COUNT}")));
+    }
+    let mut rng = StdRng::seed_from_u64(seed);
+    Ok((0..count).map(|_| rng.random()).collect())
+}
+
+/// The maximum number of values picked by `sample`.
+pub const MAX_SAMPLE_SIZE: usize = 10_000;
+
+/// Picks `n` random values, with or without replacement, in the order they're picked.
+///
+/// With a `seed`, the same values always yield the same sample, like the sequences
+/// of `seeded_random` do; otherwise, the generator is seeded from the thread-local one.
+///
+/// # Errors
+/// Fails with `ErrorCode::InvalidArgument` if `n` exceeds `MAX_SAMPLE_SIZE`,
+/// or if there are fewer than `n` values without replacement, or no values with it.
+pub fn sample<T: Clone>(
+    values: &[T],
+    n: usize,
+    replacement: bool,
+    seed: Option<u64>,
+) -> Result<Vec<T>, CommandError> {
+    if n > MAX_SAMPLE_SIZE {
+        return Err(invalid(format!("n must be at most {MAX_SAMPLE_SIZE}")));
+    }
+    if !replacement && n > values.len() {
+        return Err(invalid(format!(
+            "n must be at most the number of values, {}, without replacement",
+            values.len()
+        )));
+    }
+    if replacement && n > 0 && values.is_empty() {
+        return Err(invalid("values must not be empty".to_string()));
+    }
+    let mut rng = match seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_rng(&mut rand::rng()),
+    };
+    let picked = match replacement {
+        true => (0..n)
+            .map(|_| values[rng.random_range(0..values.len())].clone())
+            .collect(),
+        false => index::sample(&mut rng, values.len(), n)
+            .into_iter()
+            .map(|i| values[i].clone())
+            .collect(),
+    };
+    Ok(picked)
+}
+
+/// The descriptive statistics of an array of numbers, as computed by `describe`.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Description {
+    pub mean: f64,
+    pub median: f64,
+    /// The population standard deviation.
+    pub stddev: f64,
+    pub min: f64,
+    pub max: f64,
+}
+
+/// Computes the mean, median, standard deviation, minimum and maximum of the values.
+///
+/// The median of an even number of values is the mean of the two middle ones.
+///
+/// # Errors
+/// Fails with `ErrorCode::InvalidArgument` if there are no values.
+pub fn describe(values: &[f64]) -> Result<Description, CommandError> {
+    if values.is_empty() {
+        return Err(invalid("values must not be empty".to_string()));
+    }
+    let mut sorted = values.to_vec();
+    sorted.sort_by(f64::total_cmp);
+
+    let len = sorted.len();
+    let median = match len % 2 {
+        0 => (sorted[len / 2 - 1] + sorted[len / 2]) / 2.0,
+        _ => sorted[len / 2],
+    };
+    let mean = sorted.iter().sum::<f64>() / len as f64;
+    let variance = sorted.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / len as f64;
+    Ok(Description {
+        mean,
+        median,
+        stddev: variance.sqrt(),
+        min: sorted[0],
+        max: sorted[len - 1],
+    })
+}
+
+/// Computes the `q`-th percentile of the values, from 0 to 100.
+///
+/// The percentile is interpolated linearly between the closest ranks, so the 0th one
+/// is the minimum, the 50th one is the median, and the 100th one is the maximum.
+///
+/// # Errors
+/// Fails with `ErrorCode::InvalidArgument` if there are no values, or if `q` is out of range.
+pub fn percentile(values: &[f64], q: f64) -> Result<f64, CommandError> {
+    if values.is_empty() {
+        return Err(invalid("values must not be empty".to_string()));
+    }
+    if !(0.0..=100.0).contains(&q) {
+        return Err(invalid(format!("q must be from 0 to 100, got {q}")));
+    }
+    let mut sorted = values.to_vec();
+    sorted.sort_by(f64::total_cmp);
+
+    let rank = q / 100.0 * (sorted.len() - 1) as f64;
+    let (lower, upper) = (rank.floor() as usize, rank.ceil() as usize);
+    let fraction = rank - lower as f64;
+    Ok(sorted[lower] + (sorted[upper] - sorted[lower]) * fraction)
+}
+
+/// Applies the mathematical function to `x`.
+///
+/// # Errors
+/// Fails with `ErrorCode::DomainError` if `x` is outside of the domain of the function:
+/// negative for `MathFn::Sqrt`, or non-positive for `MathFn::Ln` and `MathFn::Log10`.
+pub fn math(function: MathFn, x: f64) -> Result<f64, CommandError> {
+    let domain_error = |domain: &str| {
+        let name = serde_plain::to_string(&function).expect("functions are always serializable");
+        CommandError::new(
+            ErrorCode::DomainError,
+            format!("{name} is only defined for {domain} numbers, got {x}"),
+        )
+    };
+    match function {
+        MathFn::Sin => Ok(x.sin()),
+        MathFn::Cos => Ok(x.cos()),
+        MathFn::Tan => Ok(x.tan()),
+        MathFn::Sqrt if x < 0.0 => Err(domain_error("non-negative")),
+        MathFn::Sqrt => Ok(x.sqrt()),
+        MathFn::Ln | MathFn::Log10 if x <= 0.0 => Err(domain_error("positive")),
+        MathFn::Ln => Ok(x.ln()),
+        MathFn::Log10 => Ok(x.log10()),
+        MathFn::Exp => Ok(x.exp()),
+    }
+}
+
+/// The maximum number of elements in each of the matrices multiplied by `matrix_mul`,
+/// as well as in their product.
+pub const MAX_MATRIX_ELEMENTS: usize = 250_000;
+
+/// Multiplies two matrices, given as arrays of their rows.
+///
+/// # Errors
+/// Fails with `ErrorCode::DimensionMismatch` if a matrix is not rectangular, or if the number
+/// of columns of `a` doesn't equal the number of rows of `b`, and with
+/// `ErrorCode::InvalidArgument` if a matrix is empty or has too many elements.
+pub fn matrix_mul(a: &[Vec<f64>], b: &[Vec<f64>]) -> Result<Vec<Vec<f64>>, CommandError> {
+    let (rows, inner) = matrix_dimensions(a, "a")?;
+    let (b_rows, cols) = matrix_dimensions(b, "b")?;
+    if inner != b_rows {
+        return Err(mismatch(format!(
+            "matrix a has {inner} columns, but matrix b has {b_rows} rows"
+        )));
+    }
+    if rows.saturating_mul(cols) > MAX_MATRIX_ELEMENTS {
+        return Err(invalid(format!(
+            "the product must not have more than {MAX_MATRIX_ELEMENTS} elements"
+        )));
+    }
+
+    let mut result = vec![vec![0.0; cols]; rows];
+    for (a_row, result_row) in a.iter().zip(&mut result) {
+        // iterating over the rows of b keeps the memory access sequential
+        for (a_value, b_row) in a_row.iter().zip(b) {
+            for (r, b_value) in result_row.iter_mut().zip(b_row) {
+                *r += a_value * b_value;
+            }
+        }
+    }
+    Ok(result)
+}
+
+/// Returns the numbers of rows and columns of the matrix, checking that it's rectangular.
+fn matrix_dimensions(matrix: &[Vec<f64>], name: &str) -> Result<(usize, usize), CommandError> {
+    let cols = matrix.first().map_or(0, Vec::len);
+    if cols == 0 {
+        return Err(invalid(format!("matrix {name} must not be empty")));
+    }
+    if let Some(i) = matrix.iter().position(|row| row.len() != cols) {
+        return Err(mismatch(format!(
+            "row {i} of matrix {name} has {} elements, but row 0 has {cols}",
+            matrix[i].len()
+        )));
+    }
+    if matrix.len().saturating_mul(cols) > MAX_MATRIX_ELEMENTS {
+        return Err(invalid(format!(
+            "matrix {name} must not have more than {MAX_MATRIX_ELEMENTS} elements"
+        )));
+    }
+    Ok((matrix.len(), cols))
+}
+
+/// Computes the dot product of two vectors.
+///
+/// # Errors
+/// Fails with `ErrorCode::DimensionMismatch` if the vectors differ in length.
+pub fn dot(a: &[f64], b: &[f64]) -> Result<f64, CommandError> {
+    if a.len() != b.len() {
+        return Err(mismatch(format!(
+            "vector a has {} elements, but vector b has {}",
+            a.len(),
+            b.len()
+        )));
+    }
+    Ok(a.iter().zip(b).map(|(a, b)| a * b).sum())
+}
+
+/// Computes the Euclidean (L2) norm of a vector, which is zero for an empty one.
+pub fn norm(a: &[f64]) -> f64 {
+    // unlike the square root of the sum of the squares, this doesn't overflow
+    a.iter().fold(0.0, |norm, x| norm.hypot(*x))
+}
+
+/// Divides the vector by its norm, returning the unit vector with the same direction.
+///
+/// # Errors
+/// Fails with `ErrorCode::DomainError` if the vector is zero or empty.
+pub fn normalize(a: &[f64]) -> Result<Vec<f64>, CommandError> {
+    let norm = norm(a);
+    if norm == 0.0 {
+        return Err(CommandError::new(
+            ErrorCode::DomainError,
+            "a zero vector can't be normalized",
+        ));
+    }
+    Ok(a.iter().map(|x| x / norm).collect())
+}
+
+fn mismatch(message: String) -> CommandError {
+    CommandError::new(ErrorCode::DimensionMismatch, message)
+}
+
+fn invalid(message: String) -> CommandError {
+    CommandError::new(ErrorCode::InvalidArgument, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_binary_hex() {
+        assert_eq!(convert_base("11111111", 2, 16).unwrap(), "ff");
+        assert_eq!(
+            convert_base("DeadBeef", 16, 2).unwrap(),
+            "11011110101011011011111011101111"
+        );
+        assert_eq!(convert_base("-1010", 2, 16).unwrap(), "-a");
+        assert_eq!(convert_base("0000", 2, 16).unwrap(), "0");
+        assert_eq!(convert_base("-0", 16, 10).unwrap(), "0");
+    }
+
+    #[test]
+    fn test_decimal_base36() {
+        assert_eq!(convert_base("35", 10, 36).unwrap(), "z");
+        assert_eq!(convert_base("1295", 10, 36).unwrap(), "zz");
+        assert_eq!(convert_base("zz", 36, 10).unwrap(), "1295");
+        assert_eq!(convert_base("007", 10, 10).unwrap(), "7");
+    }
+
+    #[test]
+    fn test_large_numbers() {
+        let max_u256 = "f".repeat(64);
+        let decimal = convert_base(&max_u256, 16, 10).unwrap();
+        assert_eq!(
+            decimal,
+            "115792089237316195423570985008687907853269984665640564039457584007913129639935"
+        );
+        assert_eq!(convert_base(&decimal, 10, 16).unwrap(), max_u256);
+    }
+
+    #[test]
+    fn test_invalid() {
+        for (value, from, to) in [
+            ("102", 2, 10),
+            ("g", 16, 10),
+            ("", 10, 2),
+            ("-", 10, 2),
+            ("1", 1, 10),
+            ("1", 10, 37),
+        ] {
+            let err = convert_base(value, from, to).unwrap_err();
+            assert_eq!(err.code, ErrorCode::InvalidArgument);
+        }
+    }
+
+    #[test]
+    fn test_gcd() {
+        assert_eq!(gcd(12, 18), 6);
+        assert_eq!(gcd(18, 12), 6);
+        assert_eq!(gcd(17, 31), 1);
+        assert_eq!(gcd(0, 5), 5);
+        assert_eq!(gcd(5, 0), 5);
+        assert_eq!(gcd(0, 0), 0);
+    }
+
+    #[test]
+    fn test_lcm() {
+        assert_eq!(lcm(4, 6).unwrap(), 12);
+        assert_eq!(lcm(17, 31).unwrap(), 527);
+        assert_eq!(lcm(0, 5).unwrap(), 0);
+        // the product overflows, but the result doesn't
+        assert_eq!(lcm(u64::MAX, u64::MAX).unwrap(), u64::MAX);
+        assert_eq!(
+            lcm(u64::MAX, u64::MAX - 1).unwrap_err().code,
+            ErrorCode::Overflow
+        );
+    }
+
+    #[test]
+    fn test_round_decimal() {
+        assert_eq!(round_decimal(0.1 + 0.2, 2).unwrap(), 0.3);
+        assert_eq!(round_decimal(2.0 / 3.0, 3).unwrap(), 0.667);
+        assert_eq!(round_decimal(-1.25e10 + 0.4, 0).unwrap(), -1.25e10);
+        assert_eq!(round_decimal(0.1 + 0.2, MAX_PRECISION).unwrap(), 0.1 + 0.2);
+        assert_eq!(
+            round_decimal(1.0, MAX_PRECISION + 1).unwrap_err().code,
+            ErrorCode::InvalidArgument
+        );
+    }
+
+    #[test]
+    fn test_sample() {
+        let values: Vec<u32> = (0..100).collect();
+        let picked = sample(&values, 100, false, None).unwrap();
+        let mut sorted = picked.clone();
+        sorted.sort();
+        assert_eq!(sorted, values);
+        // a shuffle of 100 values is all but certain to change their order
+        assert_ne!(picked, values);
+
+        let picked = sample(&values[..3], 50, true, None).unwrap();
+        assert_eq!(picked.len(), 50);
+        assert!(picked.iter().all(|v| values[..3].contains(v)));
+
+        let seeded = sample(&values, 10, false, Some(42)).unwrap();
+        assert_eq!(sample(&values, 10, false, Some(42)).unwrap(), seeded);
+        assert!(sample(&values, 0, true, None).unwrap().is_empty());
+        assert!(sample::<u32>(&[], 0, true, None).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_sample_invalid() {
+        let values = [1, 2, 3];
+        for (n, replacement) in [(4, false), (MAX_SAMPLE_SIZE + 1, true)] {
+            let err = sample(&values, n, replacement, None).unwrap_err();
+            assert_eq!(err.code, ErrorCode::InvalidArgument, "{n}");
+        }
+        let err = sample::<u32>(&[], 1, true, None).unwrap_err();
+        assert_eq!(err.code, ErrorCode::InvalidArgument);
+    }
+
+    #[test]
+    fn test_describe() {
+        assert_eq!(
+            describe(&[4.0, 1.0, 3.0, 2.0, 5.0]).unwrap(),
+            Description {
+                mean: 3.0,
+                median: 3.0,
+                stddev: 2.0_f64.sqrt(),
+                min: 1.0,
+                max: 5.0,
+            }
+        );
+        // the median of an even number of values is between the middle ones
+        let even = describe(&[10.0, -2.0, 4.0, 1.0]).unwrap();
+        assert_eq!(even.median, 2.5);
+        assert_eq!(even.min, -2.0);
+        assert_eq!(even.max, 10.0);
+
+        let single = describe(&[7.5]).unwrap();
+        assert_eq!((single.mean, single.median, single.stddev), (7.5, 7.5, 0.0));
+
+        assert_eq!(describe(&[]).unwrap_err().code, ErrorCode::InvalidArgument);
+    }
+
+    #[test]
+    fn test_percentile() {
+        let values = [15.0, 20.0, 35.0, 40.0, 50.0];
+        assert_eq!(percentile(&values, 50.0).unwrap(), 35.0);
+        assert_eq!(percentile(&values, 0.0).unwrap(), 15.0);
+        assert_eq!(percentile(&values, 100.0).unwrap(), 50.0);
+        // the rank 1.6 is between 20 and 35
+        assert_eq!(percentile(&values, 40.0).unwrap(), 29.0);
+
+        // the order of the values doesn't matter
+        let unsorted = [40.0, 15.0, 50.0, 35.0, 20.0];
+        assert_eq!(percentile(&unsorted, 50.0).unwrap(), 35.0);
+        assert_eq!(unsorted, [40.0, 15.0, 50.0, 35.0, 20.0]);
+        assert_eq!(percentile(&[1.0, 2.0, 3.0, 4.0], 50.0).unwrap(), 2.5);
+        assert_eq!(percentile(&[7.5], 90.0).unwrap(), 7.5);
+    }
+
+    #[test]
+    fn test_percentile_invalid() {
+        for q in [-0.1, 100.1, f64::NAN] {
+            let err = percentile(&[1.0, 2.0], q).unwrap_err();
+            assert_eq!(err.code, ErrorCode::InvalidArgument);
+        }
+        let err = percentile(&[], 50.0).unwrap_err();
+        assert_eq!(err.code, ErrorCode::InvalidArgument);
+    }
+
+    #[test]
+    fn test_matrix_mul() {
+        let a = vec![vec![1.0, 2.0], vec![3.0, 4.0]];
+        let b = vec![vec![5.0, 6.0], vec![7.0, 8.0]];
+        assert_eq!(
+            matrix_mul(&a, &b).unwrap(),
+            vec![vec![19.0, 22.0], vec![43.0, 50.0]]
+        );
+        // a row times a column
+        let row = vec![vec![1.0, 2.0, 3.0]];
+        let column = vec![vec![4.0], vec![5.0], vec![6.0]];
+        assert_eq!(matrix_mul(&row, &column).unwrap(), vec![vec![32.0]]);
+    }
+
+    #[test]
+    fn test_matrix_mul_invalid() {
+        let square = vec![vec![1.0, 2.0], vec![3.0, 4.0]];
+        let row = vec![vec![1.0, 2.0, 3.0]];
+        let ragged = vec![vec![1.0, 2.0], vec![3.0]];
+        let err = matrix_mul(&square, &row).unwrap_err();
+        assert_eq!(err.code, ErrorCode::DimensionMismatch);
+        let err = matrix_mul(&ragged, &square).unwrap_err();
+        assert_eq!(err.code, ErrorCode::DimensionMismatch);
+        let err = matrix_mul(&square, &ragged).unwrap_err();
+        assert_eq!(err.code, ErrorCode::DimensionMismatch);
+        let err = matrix_mul(&[], &square).unwrap_err();
+        assert_eq!(err.code, ErrorCode::InvalidArgument);
+
+        // the product may be larger than the matrices themselves
+        let column = vec![vec![1.0]; 1000];
+        let row = vec![vec![1.0; 1000]];
+        let err = matrix_mul(&column, &row).unwrap_err();
+        assert_eq!(err.code, ErrorCode::InvalidArgument);
+    }
+
+    #[test]
+    fn test_dot() {
+        assert_eq!(dot(&[1.0, 2.0, 3.0], &[4.0, -5.0, 6.0]).unwrap(), 12.0);
+        assert_eq!(dot(&[1.0, 0.0], &[0.0, 1.0]).unwrap(), 0.0);
+        assert_eq!(dot(&[], &[]).unwrap(), 0.0);
+
+        let err = dot(&[1.0, 2.0, 3.0], &[1.0, 2.0]).unwrap_err();
+        assert_eq!(err.code, ErrorCode::DimensionMismatch);
+        let err = dot(&[], &[1.0]).unwrap_err();
+        assert_eq!(err.code, ErrorCode::DimensionMismatch);
+    }
+
+    #[test]
+    fn test_norm() {
+        assert_eq!(norm(&[3.0, 4.0]), 5.0);
+        assert_eq!(norm(&[-3.0, 0.0, 4.0]), 5.0);
+        assert_eq!(norm(&[2.0]), 2.0);
+        assert_eq!(norm(&[]), 0.0);
+        // the squares would overflow
+        assert!((norm(&[3e200, 4e200]) / 5e200 - 1.0).abs() < 1e-12);
+
+        assert_eq!(normalize(&[3.0, -4.0]).unwrap(), vec![0.6, -0.8]);
+        assert_eq!(normalize(&[0.0, 2.5]).unwrap(), vec![0.0, 1.0]);
+        for zero in [&[0.0, 0.0][..], &[]] {
+            let err = normalize(zero).unwrap_err();
+            assert_eq!(err.code, ErrorCode::DomainError);
+        }
+    }
+
+    #[test]
+    fn test_math() {
+        assert_eq!(math(MathFn::Sin, 0.0).unwrap(), 0.0);
+        assert_eq!(math(MathFn::Cos, 0.0).unwrap(), 1.0);
+        assert!((math(MathFn::Tan, std::f64::consts::FRAC_PI_4).unwrap() - 1.0).abs() < 1e-12);
+        assert_eq!(math(MathFn::Sqrt, 4.0).unwrap(), 2.0);
+        assert_eq!(math(MathFn::Sqrt, 0.0).unwrap(), 0.0);
+        assert_eq!(math(MathFn::Ln, 1.0).unwrap(), 0.0);
+        assert_eq!(math(MathFn::Log10, 1000.0).unwrap(), 3.0);
+        assert_eq!(math(MathFn::Exp, 0.0).unwrap(), 1.0);
+    }
+
+    #[test]
+    fn test_math_domain_error() {
+        for (function, x) in [
+            (MathFn::Sqrt, -1.0),
+            (MathFn::Ln, 0.0),
+            (MathFn::Ln, -2.5),
+            (MathFn::Log10, 0.0),
+            (MathFn::Log10, -10.0),
+        ] {
+            let err = math(function, x).unwrap_err();
+            assert_eq!(err.code, ErrorCode::DomainError, "{function:?}({x})");
+        }
+    }
+}
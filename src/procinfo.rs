@@ -0,0 +1,79 @@
+use serde::Serialize;
+use std::fs;
+
+/// The number of clock ticks per second used by the kernel to report CPU times.
+///
+/// This is `sysconf(_SC_CLK_TCK)`, which is 100 on all mainstream Linux platforms.
+const CLOCK_TICKS_PER_SEC: u64 = 100;
+
+/// Metrics of the server process and the host it runs on.
+///
+/// Each metric is `None` if it's unavailable on the current platform.
+/// Currently, the metrics are only collected on Linux, from the `/proc` filesystem.
+#[derive(Serialize)]
+pub struct ProcInfo {
+    /// The resident set size of the process in bytes.
+    pub rss_bytes: Option<u64>,
+
+    /// The CPU time (user and system) consumed by the process in milliseconds.
+    pub cpu_time_ms: Option<u64>,
+
+    /// The number of threads in the process.
+    pub threads: Option<u64>,
+
+    /// The system load averages over the last 1, 5 and 15 minutes.
+    pub load_average: Option<[f64; 3]>,
+}
+
+impl ProcInfo {
+    /// Collects the metrics available on the current platform.
+    pub fn collect() -> Self {
+        let status = fs::read_to_string("/proc/self/status").ok();
+        let status_field = |name: &str| -> Option<u64> {
+            let line = status.as_deref()?.lines().find(|l| l.starts_with(name))?;
+            line[name.len()..].split_whitespace().next()?.parse().ok()
+        };
+
+        ProcInfo {
+            // reported in kB
+            rss_bytes: status_field("VmRSS:").map(|kb| kb * 1024),
+            cpu_time_ms: cpu_time_ms(),
+            threads: status_field("Threads:"),
+            load_average: load_average(),
+        }
+    }
+}
+
+fn cpu_time_ms() -> Option<u64> {
+    let stat = fs::read_to_string("/proc/self/stat").ok()?;
+    // the second field is the executable name in parentheses, which may contain spaces,
+    // so the fields are counted from the closing parenthesis; `utime` and `stime` are
+    // the 14th and 15th fields, i.e. the 12th and 13th after the name
+    let mut fields = stat
+        .get(stat.rfind(')')? + 1..)?
+        .split_whitespace()
+        .skip(11);
+    let utime: u64 = fields.next()?.parse().ok()?;
+    let stime: u64 = fields.next()?.parse().ok()?;
+    Some((utime + stime) * 1000 / CLOCK_TICKS_PER_SEC)
+}
+
+fn load_average() -> Option<[f64; 3]> {
+    let loadavg = fs::read_to_string("/proc/loadavg").ok()?;
+    let mut fields = loadavg.split_whitespace().map(|f| f.parse::<f64>().ok());
+    Some([fields.next()??, fields.next()??, fields.next()??])
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_collect() {
+        let info = ProcInfo::collect();
+        assert!(info.rss_bytes.unwrap() > 0);
+        assert!(info.threads.unwrap() > 0);
+        assert!(info.cpu_time_ms.is_some());
+        assert!(info.load_average.is_some());
+    }
+}
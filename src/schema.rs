@@ -0,0 +1,139 @@
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::types::{CommandError, ErrorCode};
+
+/// The maximum size of the schema, as well as of the instance, validated by `validate`,
+/// in bytes of their compact serialization.
+pub const MAX_SCHEMA_BYTES: usize = 64 * 1024;
+
+/// The result of validating an instance against a JSON Schema.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct SchemaValidation {
+    pub valid: bool,
+
+    /// The errors found in the instance, empty if it's valid.
+    pub errors: Vec<SchemaError>,
+}
+
+/// A single violation of a JSON Schema by an instance.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct SchemaError {
+    /// The JSON Pointer to the invalid part of the instance, `""` for the instance itself.
+    pub instance_path: String,
+
+    /// The JSON Pointer to the keyword of the schema that the instance violates.
+    pub schema_path: String,
+
+    pub message: String,
+}
+
+/// Validates the instance against the JSON Schema, the draft of which is detected
+/// from its `$schema` keyword, the latest one being the default.
+///
+/// Only the references within the schema itself are resolved, since fetching remote ones
+/// would make the server send requests or read files on the client's behalf.
+///
+/// # Errors
+/// Fails with `ErrorCode::InputTooLarge` if the schema or the instance exceeds
+/// `MAX_SCHEMA_BYTES`, and with `ErrorCode::InvalidArgument` if the schema is invalid.
+pub fn validate(schema: &Value, instance: &Value) -> Result<SchemaValidation, CommandError> {
+    for (name, value) in [("schema", schema), ("instance", instance)] {
+        let size = serde_json::to_vec(value)
+            .expect("values are serializable")
+            .len();
+        if size > MAX_SCHEMA_BYTES {
+            return Err(CommandError::new(
+                ErrorCode::InputTooLarge,
+                format!("{name} of {size} bytes exceeds the limit of {MAX_SCHEMA_BYTES} bytes"),
+            ));
+        }
+    }
+    let validator = jsonschema::validator_for(schema).map_err(|e| {
+        CommandError::new(
+            ErrorCode::InvalidArgument,
+            format!("schema is invalid: {e}"),
+        )
+    })?;
+
+    let errors: Vec<SchemaError> = validator
+        .iter_errors(instance)
+        .map(|e| SchemaError {
+            instance_path: e.instance_path().to_string(),
+            schema_path: e.schema_path().to_string(),
+            message: e.to_string(),
+        })
+        .collect();
+    Ok(SchemaValidation {
+        valid: errors.is_empty(),
+        errors,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn person_schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "name": {"type": "string"},
+                "age": {"type": "integer", "minimum": 0},
+                "tags": {"type": "array", "items": {"$ref": "#/$defs/tag"}},
+            },
+            "required": ["name"],
+            "$defs": {"tag": {"type": "string", "maxLength": 8}},
+        })
+    }
+
+    #[test]
+    fn test_validate_valid() {
+        let instance = json!({"name": "Alice", "age": 30, "tags": ["admin"]});
+        let result = validate(&person_schema(), &instance).unwrap();
+        assert_eq!(
+            result,
+            SchemaValidation {
+                valid: true,
+                errors: vec![]
+            }
+        );
+        // an empty schema allows anything
+        let result = validate(&json!({}), &json!([1, "two", null])).unwrap();
+        assert!(result.valid);
+    }
+
+    #[test]
+    fn test_validate_invalid() {
+        let instance = json!({"age": -1, "tags": ["administrator"]});
+        let result = validate(&person_schema(), &instance).unwrap();
+        assert!(!result.valid);
+        let mut paths: Vec<&str> = result
+            .errors
+            .iter()
+            .map(|e| e.instance_path.as_str())
+            .collect();
+        paths.sort();
+        assert_eq!(paths, ["", "/age", "/tags/0"]);
+        let missing = result
+            .errors
+            .iter()
+            .find(|e| e.instance_path.is_empty())
+            .unwrap();
+        assert_eq!(missing.schema_path, "/required");
+        assert!(missing.message.contains("name"), "{}", missing.message);
+    }
+
+    #[test]
+    fn test_validate_rejected() {
+        let err = validate(&json!({"type": "nope"}), &json!(1)).unwrap_err();
+        assert_eq!(err.code, ErrorCode::InvalidArgument);
+
+        let large = json!("a".repeat(MAX_SCHEMA_BYTES));
+        let err = validate(&json!({}), &large).unwrap_err();
+        assert_eq!(err.code, ErrorCode::InputTooLarge);
+        let err = validate(&json!({"const": large}), &json!(1)).unwrap_err();
+        assert_eq!(err.code, ErrorCode::InputTooLarge);
+    }
+}
@@ -0,0 +1,204 @@
+use chrono::format::{Item, Parsed, StrftimeItems};
+use std::fmt::Write;
+
+use crate::types::{CommandError, ErrorCode};
+
+/// The maximum product of the lengths of the strings compared by `levenshtein`.
+///
+/// The distance takes time proportional to the product, so this keeps it cheap.
+const MAX_DISTANCE_CELLS: usize = 10_000_000;
+
+/// Computes the Levenshtein distance between two strings: the minimum number of insertions,
+/// deletions and substitutions of characters (Unicode scalar values) that turn `a` into `b`.
+///
+/// # Errors
+/// Fails with `ErrorCode::InputTooLarge` if the product of the lengths of the strings
+/// exceeds `MAX_DISTANCE_CELLS`.
+pub fn levenshtein(a: &str, b: &str) -> Result<usize, CommandError> {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    if a.len().saturating_mul(b.len()) > MAX_DISTANCE_CELLS {
+        return Err(CommandError::new(
+            ErrorCode::InputTooLarge,
+            format!(
+                "product of the lengths of the strings, {} and {}, exceeds {MAX_DISTANCE_CELLS}",
+                a.len(),
+                b.len()
+            ),
+        ));
+    }
+
+    // only the previous row of the table is needed to compute the next one
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    let mut current = vec![0; b.len() + 1];
+    for (i, x) in a.iter().enumerate() {
+        current[0] = i + 1;
+        for (j, y) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(x != y);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        std::mem::swap(&mut previous, &mut current);
+    }
+    Ok(previous[b.len()])
+}
+
+/// Parses the date in `input` with the strftime pattern `from`, and formats it with `to`.
+///
+/// The input may contain a date, a time of day, or both, with or without an offset,
+/// and is formatted as the most complete of them that its fields make up.
+///
+/// # Errors
+/// Fails with `ErrorCode::ParseError` if the input doesn't match `from`, or if it contains
+/// neither a complete date nor a time, and with `ErrorCode::InvalidArgument` if either pattern
+/// is invalid, or if `to` requires the fields the input lacks, e.g. the offset.
+pub fn reformat_date(input: &str, from: &str, to: &str) -> Result<String, CommandError> {
+    let from = strftime_pattern(from, "from")?;
+    let to = strftime_pattern(to, "to")?;
+    let mut parsed = Parsed::new();
+    chrono::format::parse(&mut parsed, input, from.iter()).map_err(|e| {
+        CommandError::new(
+            ErrorCode::ParseError,
+            format!("input doesn't match the pattern: {e}"),
+        )
+    })?;
+
+    let formatted = if let Ok(datetime) = parsed.to_datetime() {
+        datetime.format_with_items(to.iter())
+    } else if let Ok(datetime) = parsed.to_naive_datetime_with_offset(0) {
+        datetime.format_with_items(to.iter())
+    } else if let Ok(date) = parsed.to_naive_date() {
+        date.format_with_items(to.iter())
+    } else if let Ok(time) = parsed.to_naive_time() {
+        time.format_with_items(to.iter())
+    } else {
+        return Err(CommandError::new(
+            ErrorCode::ParseError,
+            "input contains neither a complete date nor a time",
+        ));
+    };
+    // formatting fails rather than panics only when written explicitly
+    let mut result = String::new();
+    write!(result, "{formatted}").map_err(|_| {
+        CommandError::new(
+            ErrorCode::InvalidArgument,
+            "pattern to requires the fields that the input lacks",
+        )
+    })?;
+    Ok(result)
+}
+
+/// Parses a strftime pattern, the `name` of which is reported if it's invalid.
+fn strftime_pattern<'a>(pattern: &'a str, name: &str) -> Result<Vec<Item<'a>>, CommandError> {
+    StrftimeItems::new(pattern).parse().map_err(|_| {
+        CommandError::new(
+            ErrorCode::InvalidArgument,
+            format!("pattern {name} is not a valid strftime pattern"),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_levenshtein() {
+        for (a, b, distance) in [
+            ("", "", 0),
+            ("", "abc", 3),
+            ("abc", "", 3),
+            ("same", "same", 0),
+            ("kitten", "sitting", 3),
+            ("flaw", "lawn", 2),
+            ("gumbo", "gambol", 2),
+            // characters rather than bytes are counted
+            ("café", "cafe", 1),
+            ("ёжик", "ежик", 1),
+            ("🦀", "", 1),
+        ] {
+            assert_eq!(levenshtein(a, b).unwrap(), distance, "{a:?} -> {b:?}");
+            assert_eq!(levenshtein(b, a).unwrap(), distance, "{b:?} -> {a:?}");
+        }
+    }
+
+    #[test]
+    fn test_levenshtein_too_large() {
+        let long = "a".repeat(MAX_DISTANCE_CELLS / 1000 + 1);
+        let err = levenshtein(&long, &"b".repeat(1000)).unwrap_err();
+        assert_eq!(err.code, ErrorCode::InputTooLarge);
+        // an empty string is compared with anything at no cost
+        assert_eq!(levenshtein(&long, "").unwrap(), long.len());
+    }
+
+    #[test]
+    fn test_reformat_date() {
+        for (input, from, to, expected) in [
+            ("16.10.2026", "%d.%m.%Y", "%Y-%m-%d", "2026-10-16"),
+            (
+                "2026-10-16 07:05",
+                "%Y-%m-%d %H:%M",
+                "%d/%m/%y %I:%M %p",
+                "16/10/26 07:05 AM",
+            ),
+            ("23:59:01", "%H:%M:%S", "%Hh%Mm%Ss", "23h59m01s"),
+            (
+                "2026-10-16T12:00:00+03:00",
+                "%Y-%m-%dT%H:%M:%S%:z",
+                "%a, %d %b %Y %H:%M:%S %z",
+                "Fri, 16 Oct 2026 12:00:00 +0300",
+            ),
+        ] {
+            assert_eq!(
+                reformat_date(input, from, to).unwrap(),
+                expected,
+                "{input:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_reformat_date_invalid() {
+        for (input, from, to, code) in [
+            // the input doesn't match the pattern
+            ("2026/10/16", "%Y-%m-%d", "%d.%m.%Y", ErrorCode::ParseError),
+            ("2026-13-01", "%Y-%m-%d", "%d.%m.%Y", ErrorCode::ParseError),
+            (
+                "2026-10-16 extra",
+                "%Y-%m-%d",
+                "%d.%m.%Y",
+                ErrorCode::ParseError,
+            ),
+            // a year alone is neither a date nor a time
+            ("2026", "%Y", "%Y", ErrorCode::ParseError),
+            // the patterns are invalid
+            (
+                "2026-10-16",
+                "%Y-%m-%Q",
+                "%d.%m.%Y",
+                ErrorCode::InvalidArgument,
+            ),
+            (
+                "2026-10-16",
+                "%Y-%m-%d",
+                "%d.%m.%",
+                ErrorCode::InvalidArgument,
+            ),
+            // a date has no time, nor an offset
+            (
+                "2026-10-16",
+                "%Y-%m-%d",
+                "%H:%M",
+                ErrorCode::InvalidArgument,
+            ),
+            (
+                "2026-10-16 12:00",
+                "%Y-%m-%d %H:%M",
+                "%z",
+                ErrorCode::InvalidArgument,
+            ),
+        ] {
+            let err = reformat_date(input, from, to).unwrap_err();
+            assert_eq!(err.code, code, "{input:?} {from:?} {to:?}");
+        }
+    }
+}
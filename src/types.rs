@@ -1,8 +1,21 @@
+use chrono::{DateTime, Utc};
+use log::warn;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::collections::HashMap;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::{Notify, OwnedSemaphorePermit, Semaphore};
 use uuid::Uuid;
 
+use crate::cipher::SessionCipher;
+
 /// A structure representing a valid request to the server.
 ///
 /// The server expects JSON requests that can be deserialized into this structure.
@@ -14,6 +27,15 @@ use uuid::Uuid;
 ///
 /// Optionally, the request may also contain a `payload` field.
 /// This field is required for some commands and may be omitted for others.
+///
+/// The request may also contain a `metadata` field with arbitrary content,
+/// which the server copies into the response without inspecting it.
+///
+/// If `Config::auth_token` is set, every request must contain it in the `auth` field.
+/// The administrative commands, such as `Command::ProcInfo`, additionally require
+/// the configured administrative token there, which is accepted in place of the former.
+///
+/// The `echo` requests may also contain a `transform` field, see `EchoTransform`.
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Request {
     /// A unique request identifier.
@@ -23,6 +45,26 @@ pub struct Request {
     /// This also determines the structure of the `payload` field.
     #[serde(flatten)]
     pub command: Command,
+
+    /// Arbitrary client data, which is ignored by the server
+    /// and copied verbatim into the corresponding `Response`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<Value>,
+
+    /// The token authenticating the request, checked against `Config::auth_token`
+    /// and `Config::admin_token`.
+    /// It's never serialized so as not to leak into the logs.
+    #[serde(default, skip_serializing)]
+    pub auth: Option<String>,
+
+    /// The transform to apply to the echoed value; only allowed with `Command::Echo`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transform: Option<EchoTransform>,
+    /// The `etag` of a previous response, if the client has it cached. If the response
+    /// to this request has the same one, `ErrorCode::NotModified` is returned instead
+    /// (see `Config::content_hash`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub if_none_match: Option<String>,
 }
 
 /// An enumeration of supported arithmetic operations.
@@ -40,17 +82,446 @@ pub enum Operation {
     Divide,
 }
 
+/// An enumeration of number theory operations.
+///
+/// This enum represents the possible values of the `operation` field
+/// in `Command::NumberTheory`'s payload.
+///
+/// The operation values are (de)serialized in lowercase, e.g., `"gcd"`.
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum NumOp {
+    /// The greatest common divisor.
+    Gcd,
+
+    /// The least common multiple.
+    Lcm,
+}
+
+/// An enumeration of elementary mathematical functions.
+///
+/// This enum represents the possible values of the `function` field
+/// in `Command::Math`'s payload.
+///
+/// The functions are (de)serialized in lowercase, e.g., `"sin"`.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum MathFn {
+    /// The sine of an angle in radians.
+    Sin,
+
+    /// The cosine of an angle in radians.
+    Cos,
+
+    /// The tangent of an angle in radians.
+    Tan,
+
+    /// The square root, defined for non-negative numbers.
+    Sqrt,
+
+    /// The natural logarithm, defined for positive numbers.
+    Ln,
+
+    /// The base 10 logarithm, defined for positive numbers.
+    Log10,
+
+    /// The exponential function, `e` raised to the given power.
+    Exp,
+}
+
+/// An enumeration of vector operations.
+///
+/// This enum represents the possible values of the `operation` field
+/// in `Command::Vector`'s payload.
+///
+/// The operations are (de)serialized in lowercase, e.g., `"dot"`.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum VectorOp {
+    /// The dot product of two vectors of the same length.
+    Dot,
+
+    /// The Euclidean (L2) norm of a vector.
+    Norm,
+
+    /// The unit vector with the same direction, defined for non-zero vectors.
+    Normalize,
+}
+
+/// An enumeration of units of time.
+///
+/// This enum represents the possible values of the `unit` field
+/// in `Command::TimeDiff`'s payload.
+///
+/// The units are (de)serialized in lowercase, e.g., `"seconds"`.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TimeUnit {
+    Millis,
+    Seconds,
+    Minutes,
+    Hours,
+    Days,
+}
+
+impl TimeUnit {
+    /// Returns the signed difference `to - from` in this unit.
+    pub fn between(self, from: DateTime<Utc>, to: DateTime<Utc>) -> f64 {
+        let delta = to - from;
+        let nanos = delta.num_seconds() as f64 * 1e9 + delta.subsec_nanos() as f64;
+        let nanos_per_unit = match self {
+            TimeUnit::Millis => 1e6,
+            TimeUnit::Seconds => 1e9,
+            TimeUnit::Minutes => 60e9,
+            TimeUnit::Hours => 3600e9,
+            TimeUnit::Days => 86400e9,
+        };
+        nanos / nanos_per_unit
+    }
+}
+
+/// A numeric operand of `Command::Calculate`.
+///
+/// Operands are accepted either as JSON numbers, e.g. `3.5`, or as strings containing
+/// a finite decimal number, optionally in exponential notation, e.g. `"3.5"`, `"-2"` or `"1e3"`.
+/// Surrounding whitespace in strings is ignored.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum Operand {
+    Number(f64),
+    String(String),
+}
+
+impl Operand {
+    /// Returns the numeric value of the operand.
+    ///
+    /// Fails with `ErrorCode::InvalidArgument` if the operand is a non-numeric string.
+    /// The `name` of the operand is used in the error message.
+    pub fn value(&self, name: &str) -> Result<f64, CommandError> {
+        match self {
+            Operand::Number(v) => Ok(*v),
+            Operand::String(s) => s
+                .trim()
+                .parse::<f64>()
+                .ok()
+                .filter(|v| v.is_finite())
+                .ok_or_else(|| {
+                    CommandError::new(
+                        ErrorCode::InvalidArgument,
+                        format!("operand `{name}` is not a number: {s:?}"),
+                    )
+                }),
+        }
+    }
+}
+
+impl From<f64> for Operand {
+    fn from(value: f64) -> Self {
+        Operand::Number(value)
+    }
+}
+
+/// The payload of `Command::Batch`.
+///
+/// A payload that is an array of requests is processed entirely, and the server returns
+/// the array of their responses. A payload that is an object with the `requests` field
+/// may also contain options; the server returns an object with the array of `responses`
+/// and the `truncated` flag, which is set if some of the requests haven't been processed.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum BatchPayload {
+    Requests(Vec<Request>),
+    WithOptions(BatchOptions),
+}
+
+impl BatchPayload {
+    /// Returns the requests of the batch.
+    pub fn requests(&self) -> &[Request] {
+        match self {
+            BatchPayload::Requests(requests) => requests,
+            BatchPayload::WithOptions(options) => &options.requests,
+        }
+    }
+}
+
+impl From<Vec<Request>> for BatchPayload {
+    fn from(requests: Vec<Request>) -> Self {
+        BatchPayload::Requests(requests)
+    }
+}
+
+/// The requests of a batch with the options of their processing.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct BatchOptions {
+    pub requests: Vec<Request>,
+
+    /// Whether the processing stops after the first request that fails,
+    /// leaving the rest of the requests unprocessed.
+    #[serde(default)]
+    pub stop_on_error: bool,
+
+    /// The expected `batch_checksum` of the requests. If it's set and differs, the batch
+    /// fails with `ErrorCode::ChecksumMismatch` without processing any of them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<String>,
+}
+
+/// Computes the checksum of the requests of a batch: the hex-encoded SHA-256 of their array
+/// serialized without whitespace, with the keys of its objects sorted and without the `auth`
+/// fields, like in the `RequestChain`.
+pub fn batch_checksum(requests: &[Request]) -> String {
+    let serialized = serde_json::to_value(requests).expect("requests are serializable");
+    format!("{:x}", Sha256::digest(serialized.to_string()))
+}
+
+/// An enumeration of transforms that `Command::Echo` can apply to the echoed value.
+///
+/// The transforms are (de)serialized in lowercase, e.g. `"keys"`.
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum EchoTransform {
+    /// Returns the value serialized into a JSON string.
+    Stringify,
+
+    /// Returns the array of the top-level keys of the value, which must be an object.
+    Keys,
+
+    /// Returns the name of the JSON type of the value, e.g. `"object"`.
+    Type,
+}
 
 /// A simplified enum representing the type of command, excluding payload details.
 /// 
 /// This is used, for example, for performance metrics where only the kind of command matters.
-#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+///
+/// The kinds are (de)serialized the same way as the commands, e.g. `"ping"`.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Hash, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
 pub enum CommandKind {
     Ping,
     Echo,
     Time,
     Calculate,
     Batch,
+    ServerInfo,
+    Pipe,
+    Eval,
+    Stats,
+    ActiveRequests,
+    ProcInfo,
+    BaseConvert,
+    Noop,
+    SelfTest,
+    NumericLimits,
+    NumberTheory,
+    ForceError,
+    LatencyHistogram,
+    DefineMacro,
+    RunMacro,
+    Describe,
+    StreamRange,
+    ProtocolVersion,
+    LastError,
+    Replay,
+    MatrixMul,
+    MetricsSnapshot,
+    MetricsSeries,
+    RecentRequests,
+    Percentile,
+    ApiDescribe,
+    Inspect,
+    Math,
+    SessionSet,
+    SessionGet,
+    TimeDiff,
+    Canonicalize,
+    SlowEcho,
+    CommandStatus,
+    StringDistance,
+    SeededRandom,
+    DateFormat,
+    LogLevel,
+    SessionStats,
+    TimeZone,
+    StatsSince,
+    SubscribeMetrics,
+    SchemaValidate,
+    NormalizeRequest,
+    Pad,
+    Connections,
+    CloseConnection,
+    Flatten,
+    Unflatten,
+    Sample,
+    Crc32,
+    JsonDiff,
+    ErrorCodes,
+    Encrypt,
+    Decrypt,
+    BuildInfo,
+    MapCalculate,
+    Uptime,
+    Vector,
+    Typeof,
+    QueueDepth,
+}
+
+impl CommandKind {
+    /// All of the command kinds supported by the server.
+    pub const ALL: [CommandKind; 66] = [
+        CommandKind::Ping,
+        CommandKind::Echo,
+        CommandKind::Time,
+        CommandKind::Calculate,
+        CommandKind::Batch,
+        CommandKind::ServerInfo,
+        CommandKind::Pipe,
+        CommandKind::Eval,
+        CommandKind::Stats,
+        CommandKind::ActiveRequests,
+        CommandKind::ProcInfo,
+        CommandKind::BaseConvert,
+        CommandKind::Noop,
+        CommandKind::SelfTest,
+        CommandKind::NumericLimits,
+        CommandKind::NumberTheory,
+        CommandKind::ForceError,
+        CommandKind::LatencyHistogram,
+        CommandKind::DefineMacro,
+        CommandKind::RunMacro,
+        CommandKind::Describe,
+        CommandKind::StreamRange,
+        CommandKind::ProtocolVersion,
+        CommandKind::LastError,
+        CommandKind::Replay,
+        CommandKind::MatrixMul,
+        CommandKind::MetricsSnapshot,
+        CommandKind::MetricsSeries,
+        CommandKind::RecentRequests,
+        CommandKind::Percentile,
+        CommandKind::ApiDescribe,
+        CommandKind::Inspect,
+        CommandKind::Math,
+        CommandKind::SessionSet,
+        CommandKind::SessionGet,
+        CommandKind::TimeDiff,
+        CommandKind::Canonicalize,
+        CommandKind::SlowEcho,
+        CommandKind::CommandStatus,
+        CommandKind::StringDistance,
+        CommandKind::SeededRandom,
+        CommandKind::DateFormat,
+        CommandKind::LogLevel,
+        CommandKind::SessionStats,
+        CommandKind::TimeZone,
+        CommandKind::StatsSince,
+        CommandKind::SubscribeMetrics,
+        CommandKind::SchemaValidate,
+        CommandKind::NormalizeRequest,
+        CommandKind::Pad,
+        CommandKind::Connections,
+        CommandKind::CloseConnection,
+        CommandKind::Flatten,
+        CommandKind::Unflatten,
+        CommandKind::Sample,
+        CommandKind::Crc32,
+        CommandKind::JsonDiff,
+        CommandKind::ErrorCodes,
+        CommandKind::Encrypt,
+        CommandKind::Decrypt,
+        CommandKind::BuildInfo,
+        CommandKind::MapCalculate,
+        CommandKind::Uptime,
+        CommandKind::Vector,
+        CommandKind::Typeof,
+        CommandKind::QueueDepth,
+    ];
+
+    /// Returns the name of the command as it appears in the `command` field of a request.
+    pub fn name(&self) -> String {
+        serde_plain::to_string(self).expect("command kinds are always serializable")
+    }
+
+    /// Whether the command is administrative, i.e. requires `Config::admin_token`.
+    pub fn is_admin(self) -> bool {
+        matches!(
+            self,
+            CommandKind::ActiveRequests
+                | CommandKind::ProcInfo
+                | CommandKind::SelfTest
+                | CommandKind::ForceError
+                | CommandKind::Replay
+                | CommandKind::RecentRequests
+                | CommandKind::LogLevel
+                | CommandKind::Connections
+                | CommandKind::CloseConnection
+        )
+    }
+
+    /// Whether the command contains other commands, which are executed as a part of it.
+    pub fn contains_requests(self) -> bool {
+        matches!(
+            self,
+            CommandKind::Batch | CommandKind::Pipe | CommandKind::SelfTest | CommandKind::RunMacro
+        )
+    }
+}
+
+impl FromStr for CommandKind {
+    type Err = CommandError;
+
+    /// Parses the name of a command as it appears in the `command` field of a request.
+    ///
+    /// Fails with `ErrorCode::UnknownCommand` listing the valid names if the command is unknown.
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        serde_plain::from_str(name).map_err(|_| {
+            let valid: Vec<String> = CommandKind::ALL.iter().map(|k| k.name()).collect();
+            CommandError::new(
+                ErrorCode::UnknownCommand,
+                format!(
+                    "unknown command `{name}`, valid commands are: {}",
+                    valid.join(", ")
+                ),
+            )
+        })
+    }
+}
+
+/// The maximum number of concurrently executed commands of a kind.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CommandConcurrency {
+    pub kind: CommandKind,
+    pub limit: usize,
+}
+
+impl FromStr for CommandConcurrency {
+    type Err = CommandError;
+
+    /// Parses the limit in the format `<command>=<limit>`, e.g. `calculate=4`.
+    ///
+    /// Fails with `ErrorCode::InvalidArgument` if the format is wrong, the limit is zero,
+    /// or the command contains other commands, such as `batch`: those would wait for
+    /// the slots held by themselves when nested.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |message: String| CommandError::new(ErrorCode::InvalidArgument, message);
+        let (name, limit) = s
+            .split_once('=')
+            .ok_or_else(|| invalid(format!("`{s}` is not in the format <command>=<limit>")))?;
+        let kind: CommandKind = name.trim().parse()?;
+        let limit = limit
+            .trim()
+            .parse()
+            .ok()
+            .filter(|&limit| limit > 0)
+            .ok_or_else(|| invalid(format!("`{limit}` is not a positive number")))?;
+        if kind.contains_requests() {
+            return Err(invalid(format!(
+                "the concurrency of `{name}` can't be limited, since it contains other commands"
+            )));
+        }
+        Ok(CommandConcurrency { kind, limit })
+    }
 }
 
 /// An enumeration of all of the commands supported by the server, each with its required payload.
@@ -60,6 +531,8 @@ pub enum CommandKind {
 ///
 /// The command values are (de)serialized in lowercase, e.g., `"ping"`.
 #[derive(Serialize, Deserialize, Clone)]
+// the variants are named after the commands, including `commandstatus`
+#[allow(clippy::enum_variant_names)]
 #[serde(rename_all = "lowercase")]
 #[serde(tag = "command", content = "payload")]
 pub enum Command {
@@ -67,7 +540,7 @@ pub enum Command {
     Ping,
 
     /// The server will return the content of the original request's `payload` field
-    /// without modifying it.
+    /// without modifying it, unless the request has the `transform` field (see `EchoTransform`).
     Echo(Value),
 
     /// Requires no payload. The server will return the current UTC time in RFC 3339 format.
@@ -75,24 +548,588 @@ pub enum Command {
 
     /// The `payload` field is expected to be an object with fields `operation`, `a`, and `b`.
     /// The server will perform the specified `operation` (which must be a variant of `Operation`)
-    /// on the operands `a` and `b`.
+    /// on the operands `a` and `b`, which may be either numbers or numeric strings (see `Operand`).
     ///
     /// The server will return a JSON object in the format `{"result": <f64>}`,
     /// where `<f64>` is the result of the calculation as a floating-point number.
+    /// If the optional `precision` field is set, the result is rounded to that many
+    /// decimal places; otherwise, it's returned with the full precision.
     Calculate {
         operation: Operation,
-        a: f64,
-        b: f64,
+        a: Operand,
+        b: Operand,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        precision: Option<u32>,
     },
 
     /// The `payload` field is expected to be an array of objects, each one of which
-    /// can be deserialized into a separate `Request`.
+    /// can be deserialized into a separate `Request`, or an object with such an array
+    /// in the `requests` field and options (see `BatchPayload`).
     ///
     /// The server will return an array of `Response` structures,
     /// one for each `Request` provided in the `payload`.
-    Batch(Vec<Request>),
+    ///
+    /// If the server starts shutting down while the batch is processed, the rest of its
+    /// requests are not, and an object with the `responses` to the processed ones is returned,
+    /// with the `truncated` and `truncated_by_shutdown` flags set (see `ServerState::draining`).
+    Batch(BatchPayload),
+
+    /// Requires no payload. The server will return its non-sensitive effective configuration.
+    ServerInfo,
+
+    /// The `payload` field is expected to be an object with the `stages` field containing
+    /// an array of commands, which are executed one after another. Each stage is an object
+    /// with the `command` and `payload` fields, just like in a request.
+    ///
+    /// Each stage after the first one receives the result of the previous stage:
+    /// if both the result and the stage `payload` are objects, the fields of the result are merged
+    /// into the `payload`; otherwise the result replaces the `payload` entirely.
+    /// If a stage cannot accept the previous result, the pipe fails with `ErrorCode::PipeTypeMismatch`.
+    ///
+    /// The server will return the result of the last stage.
+    Pipe { stages: Vec<Command> },
+
+    /// The `payload` field is expected to be an object with the `expression` field
+    /// containing an arithmetic expression, e.g. `"2 * (3 + 4)"`.
+    /// The expression may consist of numbers, operators `+`, `-`, `*`, `/` and parentheses.
+    ///
+    /// The server will return a JSON object in the format `{"result": <f64>}`.
+    Eval { expression: String },
+
+    /// Requires no payload. The server will return the performance metrics for each command,
+    /// both over the whole lifetime of the server and over the recent time window,
+    /// along with the `CompressionStats::ratio` of the server and of the current connection.
+    Stats,
+
+    /// Requires no payload. The server will return an array of the requests
+    /// that are currently being processed, including this one,
+    /// with their IDs, command names and elapsed processing times in milliseconds.
+    ///
+    /// This is an administrative command: unless the request contains `Config::admin_token`
+    /// in its `auth` field, the command fails with `ErrorCode::Unauthorized`.
+    ActiveRequests,
+
+    /// Requires no payload. The server will return the metrics of its process and host:
+    /// resident memory, CPU time, thread count and load averages.
+    /// The metrics unavailable on the current platform are returned as `null`.
+    ///
+    /// This is an administrative command: unless the request contains `Config::admin_token`
+    /// in its `auth` field, the command fails with `ErrorCode::Unauthorized`.
+    ProcInfo,
+
+    /// The `payload` field is expected to be an object with fields `value`, `from_base`
+    /// and `to_base`. The server will parse the integer `value` written in `from_base`
+    /// and write it in `to_base`. The bases must be from 2 to 36, and the value may be
+    /// of arbitrary size.
+    ///
+    /// The server will return a JSON object in the format `{"result": <string>}`.
+    BaseConvert {
+        value: String,
+        from_base: u32,
+        to_base: u32,
+    },
+
+    /// Requires no payload. The server will do nothing and return an empty object.
+    ///
+    /// Unlike `Ping`, this is meant for measuring the overhead of request processing,
+    /// which is why its metrics are tracked separately.
+    Noop,
+
+    /// The `payload` field is expected to be an object with fields `command`, containing
+    /// a command in the same format as in a request (e.g. `{"command": "ping"}`),
+    /// and `iterations`. The server will execute the inner command the given number of times
+    /// internally, which measures the cost of its execution without the network overhead.
+    ///
+    /// The server will return a JSON object with the number of iterations and the total, minimum,
+    /// average and maximum execution times in milliseconds.
+    /// If any of the iterations fails, the error is returned instead.
+    ///
+    /// This is an administrative command, like `Command::ProcInfo`.
+    SelfTest {
+        command: Box<Command>,
+        iterations: u32,
+    },
+
+    /// Requires no payload. The server will return the limits of the numeric types it uses,
+    /// such as the largest finite `f64` and the range of integers representable in it exactly.
+    NumericLimits,
+
+    /// The `payload` field is expected to be an object with fields `operation`, `a`, and `b`.
+    /// The server will perform the specified `operation` (which must be a variant of `NumOp`)
+    /// on the non-negative integers `a` and `b`.
+    ///
+    /// The server will return a JSON object in the format `{"result": <u64>}`.
+    /// If the result doesn't fit into `u64`, the command fails with `ErrorCode::Overflow`.
+    NumberTheory { operation: NumOp, a: u64, b: u64 },
+
+    /// The `payload` field is expected to be an object with the `code` field containing
+    /// an `ErrorCode`, and an optional `message` field.
+    ///
+    /// The server will always fail with the given code and message,
+    /// which allows clients to test their handling of any error.
+    ///
+    /// This is an administrative command, like `Command::ProcInfo`.
+    ForceError {
+        code: ErrorCode,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        message: Option<String>,
+    },
+
+    /// The `payload` field is expected to be an object with the `command` field containing
+    /// a command name, and an optional `buckets` field limiting the number of buckets.
+    ///
+    /// The server will return the histogram of the processing times of the command over
+    /// the lifetime of the server, as an array of buckets with their upper bounds and counts
+    /// (see `Histogram`). A command that hasn't been processed yet has all counts zero.
+    LatencyHistogram {
+        command: CommandKind,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        buckets: Option<u32>,
+    },
+
+    /// The `payload` field is expected to be an object with fields `name` and `steps`,
+    /// containing an array of commands in the same format as in a request
+    /// (e.g. `{"command": "ping"}`).
+    ///
+    /// The server will store the steps under the given name, replacing the previous macro
+    /// with the same name, if any, and return the name and the number of steps.
+    /// The macro can only be run by the requests with the same `auth` token, if any,
+    /// and the number of macros and their sizes are limited (see `Macros`).
+    DefineMacro { name: String, steps: Vec<Command> },
+
+    /// The `payload` field is expected to be an object with the `name` field containing
+    /// the name of a macro defined with `Command::DefineMacro`.
+    ///
+    /// The server will execute the steps of the macro like a batch and return an array
+    /// of `Response` structures, one for each step. If the macro is not defined,
+    /// the command fails with `ErrorCode::NotFound`.
+    RunMacro { name: String },
+
+    /// The `payload` field is expected to be an object with the `values` field
+    /// containing a non-empty array of numbers.
+    ///
+    /// The server will return a JSON object with the `mean`, `median`, `stddev` (population
+    /// standard deviation), `min` and `max` of the values.
+    Describe { values: Vec<f64> },
+
+    /// The `payload` field is expected to be an object with fields `start` and `end`.
+    /// The range from `start` inclusive to `end` exclusive must contain at most
+    /// `MAX_STREAM_RANGE` integers.
+    ///
+    /// The server will send each integer of the range as a separate `Response` with the ID
+    /// of the request, followed by the final one with `{"done": true}` as its result.
+    /// When the command is nested, e.g. into a batch, the integers are returned as an array.
+    StreamRange { start: i64, end: i64 },
+
+    /// Requires no payload. The server will return the version of its wire protocol
+    /// in the format `{"version": <u32>}` (see `PROTOCOL_VERSION`).
+    ProtocolVersion,
+
+    /// Requires no payload. The server will return the details of the last request
+    /// that has failed on the current connection, including the nested ones,
+    /// in the format `{"last_error": <LastError>}`, or `{"last_error": null}` if there is none.
+    LastError,
+
+    /// The `payload` field is expected to be an object with the `request_id` field containing
+    /// the ID of a recently processed request (see `RecentRequests`).
+    ///
+    /// The server will execute the command of that request again and return its result
+    /// or error, as if the request was sent once more. Unless `Config::replay_metrics`
+    /// is disabled, the replayed command is counted in the metrics like any other.
+    /// If the request is not cached, the command fails with `ErrorCode::NotFound`.
+    ///
+    /// This is an administrative command, like `Command::ProcInfo`.
+    Replay { request_id: Uuid },
+
+    /// The `payload` field is expected to be an object with fields `a` and `b`, each containing
+    /// a matrix as an array of its rows. The matrices must be rectangular, the number of
+    /// columns of `a` must equal the number of rows of `b`, and each of the matrices,
+    /// including the product, must have at most `numbers::MAX_MATRIX_ELEMENTS` elements.
+    ///
+    /// The server will return the product in the format `{"result": [[<f64>, ...], ...]}`.
+    /// If the dimensions are wrong, the command fails with `ErrorCode::DimensionMismatch`.
+    MatrixMul { a: Vec<Vec<f64>>, b: Vec<Vec<f64>> },
+
+    /// Requires no payload. The server will return a `MetricsSnapshot` of its current
+    /// lifetime metrics.
+    MetricsSnapshot,
+
+    /// The `payload` field is expected to be an object with the `points` field containing
+    /// the maximum number of snapshots to return.
+    ///
+    /// The server will return the most recent snapshots from `ServerState::metrics_history`,
+    /// oldest first, in the format `{"interval_secs": <u64>, "snapshots": [...]}`,
+    /// each snapshot being a `MetricsSnapshot`.
+    MetricsSeries { points: u32 },
+
+    /// The `payload` field is expected to be an object with the `limit` field containing
+    /// the maximum number of requests to return, which is capped at `RequestLog::CAPACITY`.
+    ///
+    /// The server will return the most recently processed requests, including the nested ones,
+    /// newest first, in the format `{"requests": [<RequestRecord>, ...]}`.
+    ///
+    /// This is an administrative command, like `Command::ProcInfo`.
+    RecentRequests { limit: u32 },
+
+    /// The `payload` field is expected to be an object with the `values` field
+    /// containing a non-empty array of numbers, and the percentile `q` from 0 to 100.
+    ///
+    /// The server will return the `q`-th percentile of the values, interpolated linearly
+    /// between the closest ranks, in the format `{"result": <f64>}`.
+    Percentile { values: Vec<f64>, q: f64 },
+
+    /// Requires no payload. The server will return the machine-readable description
+    /// of all of the commands it supports, with their payload fields and result shapes
+    /// (see `apidoc::ApiDescription`).
+    ///
+    /// The command is named `apidescribe` so as not to be confused with `Command::Describe`.
+    ApiDescribe,
+
+    /// Requires no payload. The server will return the request received on the connection
+    /// as it has parsed it, along with its size and encoding (see `RawRequest`).
+    /// When the command is nested, e.g. into a batch, that's the outermost request.
+    ///
+    /// If the request is not available, e.g. since it's a streamed batch
+    /// (see `Config::stream_batches`), the command fails with `ErrorCode::NotFound`.
+    Inspect,
+
+    /// The `payload` field is expected to be an object with fields `function`, which must be
+    /// a variant of `MathFn`, and `x`. The server will apply the function to `x`.
+    ///
+    /// The server will return a JSON object in the format `{"result": <f64>}`.
+    /// If `x` is outside of the domain of the function, e.g. negative for `MathFn::Sqrt`,
+    /// the command fails with `ErrorCode::DomainError`.
+    Math { function: MathFn, x: f64 },
+
+    /// The `payload` field is expected to be an object with fields `key` and `value`,
+    /// which may be any JSON value.
+    ///
+    /// The server will store the value under the given key for the rest of the connection,
+    /// replacing the previous value, if any, and return the key and the number of the stored
+    /// entries. The number and the size of the entries are limited (see `Session`).
+    SessionSet { key: String, value: Value },
+
+    /// The `payload` field is expected to be an object with the `key` field.
+    ///
+    /// The server will return the value stored under the key by `Command::SessionSet`
+    /// on the same connection as is. If there is no such value,
+    /// the command fails with `ErrorCode::NotFound`.
+    SessionGet { key: String },
+
+    /// The `payload` field is expected to be an object with fields `from` and `to`,
+    /// which must be timestamps in RFC 3339 format, and `unit`, which must be a variant
+    /// of `TimeUnit`.
+    ///
+    /// The server will return a JSON object in the format `{"result": <f64>}` with the signed
+    /// difference `to - from` in the given unit, which is negative if `to` precedes `from`.
+    TimeDiff {
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        unit: TimeUnit,
+    },
+
+    /// The `payload` field is expected to be an object with the `data` field,
+    /// which may be any JSON value.
+    ///
+    /// The server will return a JSON object in the format `{"result": <string>}`
+    /// with the value serialized in the canonical form (see `canonicalize`).
+    Canonicalize { data: Value },
+
+    /// The `payload` field is expected to be an object with fields `payload`, which may be
+    /// any JSON value, and `bytes_per_sec`, which must be at least `MIN_SLOWECHO_RATE`.
+    /// Sending the serialized `payload` at that rate must take at most `MAX_SLOWECHO_SECS`.
+    ///
+    /// The server will return the `payload` as is, sending the response at the given rate
+    /// to simulate a slow backend. When the command is nested, e.g. into a batch,
+    /// the response isn't slowed down.
+    SlowEcho { payload: Value, bytes_per_sec: u64 },
+
+    /// Requires no payload. The server will return a JSON object with an entry for each
+    /// of the commands it supports, in the format
+    /// `{"enabled": <bool>, "concurrency_limit": <usize | null>}`, reflecting
+    /// `Config::disabled_commands` and `Config::command_concurrency`.
+    CommandStatus,
+
+    /// The `payload` field is expected to be an object with the string fields `a` and `b`.
+    ///
+    /// The server will return a JSON object in the format `{"distance": <usize>}` with
+    /// the Levenshtein distance between the strings (see `text::levenshtein`).
+    StringDistance { a: String, b: String },
+
+    /// The `payload` field is expected to be an object with the fields `seed`, an unsigned
+    /// 64-bit integer, and `count`, the number of the values, at most `MAX_RANDOM_COUNT`.
+    ///
+    /// The server will return a JSON object in the format `{"values": [<u64>, ...]}` with
+    /// a pseudo-random sequence generated from the seed, e.g. for reproducible tests;
+    /// the same seed always yields the same sequence (see `numbers::seeded_random`).
+    SeededRandom { seed: u64, count: u32 },
+
+    /// The `payload` field is expected to be an object with the string fields `input`,
+    /// which is a date, a time or both, and `from` and `to`, which are strftime patterns.
+    ///
+    /// The server will return a JSON object in the format `{"result": <string>}` with
+    /// the input parsed with `from` and formatted with `to` (see `text::reformat_date`).
+    /// If the input doesn't match `from`, the command fails with `ErrorCode::ParseError`.
+    DateFormat {
+        input: String,
+        from: String,
+        to: String,
+    },
+
+    /// The `payload` field is expected to be an object with an optional `level` field,
+    /// one of `off`, `error`, `warn`, `info`, `debug` and `trace`, case-insensitive.
+    ///
+    /// The server will change the level of its log to the given one, if any, and return
+    /// the effective level in the format `{"level": <string>}`. The change lasts until
+    /// the server is restarted.
+    ///
+    /// This is an administrative command, like `Command::ProcInfo`.
+    LogLevel {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        level: Option<String>,
+    },
+
+    /// The `payload` field is expected to be an object with an optional array of numbers
+    /// `values`, and an optional `session_id`.
+    ///
+    /// The server will add the values to the statistics of the connection's session,
+    /// and return their count, mean and population variance in the format
+    /// `{"count": <u64>, "mean": <f64>, "variance": <f64>}`, the latter two being `null`
+    /// without values (see `RunningStats`).
+    ///
+    /// If `session_id` is given, the session resumes the statistics persisted under that ID
+    /// in `Config::session_stats_dir`, if any, and persists them there once it ends,
+    /// so that the client may continue on another connection. The ID can't be changed
+    /// for the rest of the session.
+    SessionStats {
+        #[serde(default)]
+        values: Vec<f64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        session_id: Option<String>,
+    },
+
+    /// The `payload` field is expected to be an object with the `tz` field containing
+    /// the name of an IANA time zone, e.g. `"America/New_York"`.
+    ///
+    /// The server will return the current time in that time zone in RFC 3339 format,
+    /// with its UTC offset, in the format `{"time": <string>, "tz": <string>}`.
+    /// If the time zone is unknown, the command fails with `ErrorCode::InvalidArgument`.
+    TimeZone { tz: String },
+
+    /// The `payload` field is expected to be an object with the `since` field containing
+    /// a timestamp in RFC 3339 format, which must not be in the future.
+    ///
+    /// The server will return the metrics accumulated since the latest snapshot taken
+    /// at or before that moment in `ServerState::metrics_history`, which serves as
+    /// the checkpoint, as a `MetricsDelta`. If there's no such snapshot, e.g. since it's been
+    /// evicted already, the command fails with `ErrorCode::NotFound`.
+    StatsSince { since: DateTime<Utc> },
+
+    /// The `payload` field is expected to be an object with the `interval_ms` field
+    /// from `MIN_SUBSCRIPTION_INTERVAL_MS` to `MAX_SUBSCRIPTION_INTERVAL_MS`.
+    ///
+    /// The server will send a `MetricsSnapshot` as a separate `Response` with the ID
+    /// of the request every `interval_ms` milliseconds, starting right away, until the client
+    /// sends `{"unsubscribe": true}`, closes its write half or disconnects, or the server
+    /// shuts down, followed by the final one with `{"done": true}` as its result.
+    /// Keeping the write half open needs `Config::eager_requests` for the request to be processed.
+    /// When the command is nested, e.g. into a batch, a single snapshot is returned.
+    SubscribeMetrics { interval_ms: u64 },
+
+    /// The `payload` field is expected to be an object with the `schema` field containing
+    /// a JSON Schema, and the `instance` field with any JSON value, each of them at most
+    /// `schema::MAX_SCHEMA_BYTES` large.
+    ///
+    /// The server will return a `SchemaValidation` in the format
+    /// `{"valid": <bool>, "errors": [{"instance_path": <string>, "schema_path": <string>,
+    /// "message": <string>}, ...]}`. If the schema is invalid, the command fails
+    /// with `ErrorCode::InvalidArgument`.
+    SchemaValidate { schema: Value, instance: Value },
+
+    /// The `payload` field is expected to be an object with the `request` field containing
+    /// a `Request`, which is parsed but not processed.
+    ///
+    /// The server will return the request as it interprets it: re-serialized, with the absent
+    /// optional fields that the server substitutes a value for set to that value, e.g.
+    /// the `buckets` of `Command::LatencyHistogram`, including the ones of the nested requests.
+    /// The optional fields that have no such value, e.g. the `metadata`, stay absent,
+    /// and the `auth` field is omitted.
+    NormalizeRequest { request: Box<Request> },
+
+    /// The `payload` field is expected to be an object with the `size` field, which must
+    /// not exceed `Config::max_response_bytes`, if it's set, nor `MAX_PAD_BYTES`.
+    ///
+    /// The server will return a JSON object in the format `{"data": <string>}` with a string
+    /// of exactly `size` bytes, the lowercase Latin alphabet repeated over and over.
+    Pad { size: usize },
+
+    /// Requires no payload. The server will return an array of the connections that are
+    /// currently open, including this one, oldest first, with their peer addresses,
+    /// the durations since they were accepted in milliseconds and the numbers of requests
+    /// processed on them, including the nested ones.
+    ///
+    /// This is an administrative command, like `Command::ProcInfo`.
+    Connections,
+
+    /// The `payload` field is expected to be an object with the `peer` field containing
+    /// the address of an open connection as returned by `Command::Connections`,
+    /// e.g. `"127.0.0.1:50000"`.
+    ///
+    /// The server will close the connection, abandoning the request being processed on it,
+    /// and return `{"closed": <string>}`. If there's no such connection, the command fails
+    /// with `ErrorCode::NotFound`.
+    ///
+    /// This is an administrative command, like `Command::ProcInfo`.
+    CloseConnection { peer: String },
+
+    /// The `payload` field is expected to be an object with the `data` field containing
+    /// an object.
+    ///
+    /// The server will return a JSON object in the format `{"result": <object>}`
+    /// with the data converted into a single-level object with dot-separated keys,
+    /// e.g. `{"a.b[0]": 1}` for `{"a": {"b": [1]}}` (see `flatten::flatten`).
+    Flatten { data: Value },
+
+    /// The `payload` field is expected to be an object with the `data` field containing
+    /// a single-level object, as returned by `Command::Flatten`.
+    ///
+    /// The server will return a JSON object in the format `{"result": <object>}`
+    /// with the data converted back into a nested object (see `flatten::unflatten`).
+    /// Keys nesting deeper than `Config::max_json_depth` are rejected.
+    Unflatten { data: Value },
+
+    /// The `payload` field is expected to be an object with the fields `values`, an array
+    /// of any JSON values, `n`, the number of the values to pick, at most
+    /// `numbers::MAX_SAMPLE_SIZE`, and optional `replacement`, `false` by default, and `seed`.
+    ///
+    /// The server will return a JSON object in the format `{"values": [<value>, ...]}` with
+    /// the values picked at random, with or without replacement (see `numbers::sample`).
+    Sample {
+        values: Vec<Value>,
+        n: usize,
+        #[serde(default)]
+        replacement: bool,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        seed: Option<u64>,
+    },
+
+    /// The `payload` field is expected to be an object with the `data` field containing
+    /// a string.
+    ///
+    /// The server will return a JSON object in the format `{"crc32": <u32>}` with
+    /// the CRC-32 (IEEE) checksum of the UTF-8 bytes of the string.
+    Crc32 { data: String },
+
+    /// The `payload` field is expected to be an object with the fields `left` and `right`,
+    /// which may be any JSON values.
+    ///
+    /// The server will return a JSON object in the format
+    /// `{"added": {...}, "removed": {...}, "changed": {<pointer>: {"from": ..., "to": ...}}}`
+    /// with the differences keyed by the JSON Pointers to them (see `jsondiff::diff`).
+    JsonDiff { left: Value, right: Value },
+
+    /// Requires no payload. The server will return a JSON object in the format
+    /// `{"codes": [{"code": "parse_error", "description": "..."}, ...]}`
+    /// listing every `ErrorCode` it may respond with, along with its description.
+    ErrorCodes,
+
+    /// The `payload` field is expected to be an object with the `data` field,
+    /// which may be any JSON value.
+    ///
+    /// The server will return a JSON object in the format `{"data": <string>}`
+    /// with the value encrypted with the key of the connection (see `SessionCipher`),
+    /// which only `Command::Decrypt` on the same connection can decrypt.
+    ///
+    /// The key isn't derived from a secret established by a handshake: the hello frame
+    /// carries no key exchange, and since the key never leaves the process, the client
+    /// has no use for it. So a random key generated for the connection serves the same end.
+    Encrypt { data: Value },
+
+    /// The `payload` field is expected to be an object with the `data` field,
+    /// a string returned by `Command::Encrypt`.
+    ///
+    /// The server will return a JSON object in the format `{"data": <value>}`
+    /// with the decrypted value. If the data has been modified or encrypted
+    /// on another connection, the command fails with `ErrorCode::DecryptionFailed`.
+    Decrypt { data: String },
+
+    /// Requires no payload. The server will return the metadata of its build
+    /// in the format of `BuildInfo`.
+    BuildInfo,
+
+    /// The `payload` field is expected to be an object with fields `operation`,
+    /// which must be a variant of `Operation`, and `pairs`, an array of `[a, b]` arrays
+    /// of numbers.
+    ///
+    /// The server will perform the operation on each pair like `Command::Calculate` does
+    /// and return a JSON object in the format `{"results": [...]}` with either
+    /// `{"result": <f64>}` or `{"code": <ErrorCode>, "error": <string>}` for each pair,
+    /// in the same order, so that an error, e.g. a division by zero, only fails its own pair.
+    MapCalculate {
+        operation: Operation,
+        pairs: Vec<(f64, f64)>,
+    },
+
+    /// Requires no payload. The server will return how long it has been running
+    /// in the format `{"uptime_ms": <u64>, "started_at": <string>}`, the start time
+    /// being in RFC 3339 format.
+    Uptime,
+
+    /// The `payload` field is expected to be an object with fields `operation`, which must be
+    /// a variant of `VectorOp`, `a`, an array of numbers, and `b`, another one, which is only
+    /// required by `VectorOp::Dot` and ignored by the other operations.
+    ///
+    /// The server will return the result in the format `{"result": <f64>}` for `VectorOp::Dot`
+    /// and `VectorOp::Norm`, and `{"result": [<f64>, ...]}` for `VectorOp::Normalize`.
+    /// If the vectors differ in length, the command fails with `ErrorCode::DimensionMismatch`,
+    /// and if a zero vector is normalized, with `ErrorCode::DomainError`.
+    Vector {
+        operation: VectorOp,
+        a: Vec<f64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        b: Option<Vec<f64>>,
+    },
+
+    /// The `payload` field is expected to be an object with the `data` field,
+    /// which may be any JSON value.
+    ///
+    /// The server will return a JSON object in the format `{"type": <string>, "size": <usize>}`
+    /// with the name of the JSON type of the value, as `EchoTransform::Type` returns it
+    /// except for `bool`, and the number of the elements of an array, the entries of an object or the characters
+    /// (Unicode scalar values) of a string. Other values have no `size`.
+    Typeof { data: Value },
+
+    /// The `payload` field is not required.
+    ///
+    /// The server will return a JSON object in the format `{"<command>": <usize>, ...}`
+    /// with the number of the requests waiting for a slot of each command whose concurrency
+    /// is limited by `Config::command_concurrency`.
+    QueueDepth,
 }
 
+/// The maximum number of integers sent by `Command::StreamRange`.
+pub const MAX_STREAM_RANGE: u64 = 10_000;
+
+/// The shortest interval between the snapshots sent by `Command::SubscribeMetrics`, in milliseconds.
+pub const MIN_SUBSCRIPTION_INTERVAL_MS: u64 = 100;
+
+/// The longest interval between the snapshots sent by `Command::SubscribeMetrics`,
+/// in milliseconds, which is also how long it may take the subscription to notice the shutdown.
+pub const MAX_SUBSCRIPTION_INTERVAL_MS: u64 = 60_000;
+
+/// The largest string returned by `Command::Pad`, in bytes.
+pub const MAX_PAD_BYTES: usize = 16 * 1024 * 1024;
+
+/// The lowest rate of sending the response to `Command::SlowEcho`, in bytes per second.
+pub const MIN_SLOWECHO_RATE: u64 = 16;
+
+/// The longest time of sending the payload of `Command::SlowEcho`, in seconds.
+pub const MAX_SLOWECHO_SECS: u64 = 60;
+
+/// The shortest delay before retrying a command rejected with `ErrorCode::Busy`
+/// suggested to the client, in milliseconds.
+pub const MIN_RETRY_AFTER_MS: u64 = 10;
+
 impl Command {
     /// Returns a simplified classification (`CommandKind`) of the given command, without payload.
     pub fn kind(&self) -> CommandKind {
@@ -102,35 +1139,385 @@ impl Command {
             Command::Time => CommandKind::Time,
             Command::Calculate { .. } => CommandKind::Calculate,
             Command::Batch(_) => CommandKind::Batch,
+            Command::ServerInfo => CommandKind::ServerInfo,
+            Command::Pipe { .. } => CommandKind::Pipe,
+            Command::Eval { .. } => CommandKind::Eval,
+            Command::Stats => CommandKind::Stats,
+            Command::ActiveRequests => CommandKind::ActiveRequests,
+            Command::ProcInfo => CommandKind::ProcInfo,
+            Command::BaseConvert { .. } => CommandKind::BaseConvert,
+            Command::Noop => CommandKind::Noop,
+            Command::SelfTest { .. } => CommandKind::SelfTest,
+            Command::NumericLimits => CommandKind::NumericLimits,
+            Command::NumberTheory { .. } => CommandKind::NumberTheory,
+            Command::ForceError { .. } => CommandKind::ForceError,
+            Command::LatencyHistogram { .. } => CommandKind::LatencyHistogram,
+            Command::DefineMacro { .. } => CommandKind::DefineMacro,
+            Command::RunMacro { .. } => CommandKind::RunMacro,
+            Command::Describe { .. } => CommandKind::Describe,
+            Command::StreamRange { .. } => CommandKind::StreamRange,
+            Command::ProtocolVersion => CommandKind::ProtocolVersion,
+            Command::LastError => CommandKind::LastError,
+            Command::Replay { .. } => CommandKind::Replay,
+            Command::MatrixMul { .. } => CommandKind::MatrixMul,
+            Command::MetricsSnapshot => CommandKind::MetricsSnapshot,
+            Command::MetricsSeries { .. } => CommandKind::MetricsSeries,
+            Command::RecentRequests { .. } => CommandKind::RecentRequests,
+            Command::Percentile { .. } => CommandKind::Percentile,
+            Command::ApiDescribe => CommandKind::ApiDescribe,
+            Command::Inspect => CommandKind::Inspect,
+            Command::Math { .. } => CommandKind::Math,
+            Command::SessionSet { .. } => CommandKind::SessionSet,
+            Command::SessionGet { .. } => CommandKind::SessionGet,
+            Command::TimeDiff { .. } => CommandKind::TimeDiff,
+            Command::Canonicalize { .. } => CommandKind::Canonicalize,
+            Command::SlowEcho { .. } => CommandKind::SlowEcho,
+            Command::CommandStatus => CommandKind::CommandStatus,
+            Command::StringDistance { .. } => CommandKind::StringDistance,
+            Command::SeededRandom { .. } => CommandKind::SeededRandom,
+            Command::DateFormat { .. } => CommandKind::DateFormat,
+            Command::LogLevel { .. } => CommandKind::LogLevel,
+            Command::SessionStats { .. } => CommandKind::SessionStats,
+            Command::TimeZone { .. } => CommandKind::TimeZone,
+            Command::StatsSince { .. } => CommandKind::StatsSince,
+            Command::SubscribeMetrics { .. } => CommandKind::SubscribeMetrics,
+            Command::SchemaValidate { .. } => CommandKind::SchemaValidate,
+            Command::NormalizeRequest { .. } => CommandKind::NormalizeRequest,
+            Command::Pad { .. } => CommandKind::Pad,
+            Command::Connections => CommandKind::Connections,
+            Command::CloseConnection { .. } => CommandKind::CloseConnection,
+            Command::Flatten { .. } => CommandKind::Flatten,
+            Command::Unflatten { .. } => CommandKind::Unflatten,
+            Command::Sample { .. } => CommandKind::Sample,
+            Command::Crc32 { .. } => CommandKind::Crc32,
+            Command::JsonDiff { .. } => CommandKind::JsonDiff,
+            Command::ErrorCodes => CommandKind::ErrorCodes,
+            Command::Encrypt { .. } => CommandKind::Encrypt,
+            Command::Decrypt { .. } => CommandKind::Decrypt,
+            Command::BuildInfo => CommandKind::BuildInfo,
+            Command::MapCalculate { .. } => CommandKind::MapCalculate,
+            Command::Uptime => CommandKind::Uptime,
+            Command::Vector { .. } => CommandKind::Vector,
+            Command::Typeof { .. } => CommandKind::Typeof,
+            Command::QueueDepth => CommandKind::QueueDepth,
         }
     }
 }
 
-/// An enumeration representing the possible server responses to a request.
-///
-/// This is an internally tagged enum; its variant is set in the `status` field,
-/// and the response structure depends on its value.
-/// 
-/// This enum can be either:
-/// - `Ok`, containing the UUID of the corresponding request and the result of the command execution
-///   in the `response` field;
-/// - `Error`, containing the optional UUID of the request (if it could be retrieved)
-///   and the description of the error in the `error` field.
-///
-/// The status is (de)serialized in lowercase, e.g. `{"status": "error"}`.
-#[derive(Serialize, Deserialize)]
-#[serde(rename_all = "lowercase")]
-#[serde(tag = "status")]
-pub enum Response {
-    Ok { request_id: Uuid, response: Value },
-    Error { request_id: Option<Uuid>, error: String },
-}
+/// Defines `ErrorCode` with the doc comments of its variants doubling as their descriptions
+/// reported by `Command::ErrorCodes`, so that the two can't diverge.
+macro_rules! error_codes {
+    ($($(#[doc = $line:literal])+ $code:ident,)+) => {
+        /// An enumeration of machine-readable error codes sent in the `code` field
+        /// of an error response.
+        ///
+        /// The codes are (de)serialized in snake_case, e.g. `"parse_error"`.
+        #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+        #[serde(rename_all = "snake_case")]
+        pub enum ErrorCode {
+            $($(#[doc = $line])+ $code,)+
+        }
+
+        impl ErrorCode {
+            /// All of the error codes the server may respond with.
+            pub const ALL: &[ErrorCode] = &[$(ErrorCode::$code),+];
+
+            /// Returns the human-readable description of the code.
+            pub fn description(self) -> &'static str {
+                let description = match self {
+                    $(ErrorCode::$code => concat!($($line),+),)+
+                };
+                // every line of a doc comment starts with a space
+                description.trim_start()
+            }
+        }
+    };
+}
+
+error_codes! {
+    /// The received data is not a valid JSON, or an expression or a date can't be parsed.
+    ParseError,
+
+    /// The received JSON is not a valid request.
+    InvalidRequest,
+
+    /// The requested command is not supported by the server.
+    UnknownCommand,
+
+    /// The command has failed for a reason not covered by a more specific code.
+    CommandFailed,
+
+    /// A stage of a pipe cannot accept the result of the previous stage.
+    PipeTypeMismatch,
+
+    /// An argument of the command has an invalid value.
+    InvalidArgument,
+
+    /// The command has attempted to divide by zero.
+    DivisionByZero,
+
+    /// The command requires the administrative token, which the request lacks or has wrong.
+    Unauthorized,
+
+    /// The serialized response exceeds the configured size limit.
+    ResponseTooLarge,
+
+    /// The batch contains more requests than the configured limit.
+    BatchTooLarge,
+
+    /// The result of the command doesn't fit into its numeric type.
+    Overflow,
+
+    /// The command is disabled in the server configuration.
+    CommandDisabled,
+
+    /// The received request exceeds the configured size limit.
+    RequestTooLarge,
+
+    /// The entity requested by the command, such as a macro, doesn't exist.
+    NotFound,
+
+    /// The received data is not a valid UTF-8 text.
+    InvalidEncoding,
+
+    /// The command has reached its concurrency limit, and the server doesn't wait for a slot.
+    Busy,
+
+    /// The dimensions of the matrices given to the command are incompatible or inconsistent.
+    DimensionMismatch,
+
+    /// The response is the same as the one the client has cached, according to its `etag`.
+    NotModified,
+
+    /// The argument of a mathematical function is outside of its domain.
+    DomainError,
+
+    /// The checksum of the requests of a batch differs from the expected one.
+    ChecksumMismatch,
+
+    /// The connection has processed as many requests as it's allowed to.
+    ConnectionQuotaExceeded,
+
+    /// The arrays and objects of the request are nested deeper than the configured limit.
+    NestingTooDeep,
+
+    /// The input of the command is too large for it to process.
+    InputTooLarge,
+
+    /// The server has exceeded its memory budget and doesn't accept new connections
+    /// until the memory is freed.
+    MemoryExhausted,
+
+    /// The data given to the command fails the authentication and can't be decrypted.
+    DecryptionFailed,
+
+    /// An unexpected failure inside the server, such as a panic during command processing.
+    Internal,
+}
+
+/// An error that occurred during command execution, carrying the code reported to the client.
+///
+/// Errors of other types are reported with `ErrorCode::CommandFailed`.
+#[derive(Debug)]
+pub struct CommandError {
+    pub code: ErrorCode,
+    pub message: String,
+    /// How long the client should wait before retrying the request, in milliseconds,
+    /// if the error is temporary, e.g. `ErrorCode::Busy`.
+    pub retry_after_ms: Option<u64>,
+}
+
+impl CommandError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        CommandError {
+            code,
+            message: message.into(),
+            retry_after_ms: None,
+        }
+    }
+
+    /// Returns the error suggesting the client to retry the request after the given delay.
+    pub fn with_retry_after(mut self, retry_after_ms: u64) -> Self {
+        self.retry_after_ms = Some(retry_after_ms);
+        self
+    }
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+/// An enumeration representing the possible server responses to a request.
+///
+/// This is an internally tagged enum; its variant is set in the `status` field,
+/// and the response structure depends on its value.
+/// 
+/// This enum can be either:
+/// - `Ok`, containing the UUID of the corresponding request and the result of the command execution
+///   in the `response` field;
+/// - `Error`, containing the optional UUID of the request (if it could be retrieved),
+///   the machine-readable `ErrorCode` in the `code` field
+///   and the description of the error in the `error` field. If the error is temporary,
+///   e.g. `ErrorCode::Busy`, the `retry_after_ms` field suggests when to retry the request.
+///
+/// Both variants also carry the `metadata` of the corresponding request, if it had any,
+/// and the hash of the previous request of the connection in `prev_hash`,
+/// if the client has enabled the chaining (see `RequestChain`).
+/// A successful response also carries the hash of its result in `etag`,
+/// if `Config::content_hash` is set (see `content_hash`).
+///
+/// The status is (de)serialized in lowercase, e.g. `{"status": "error"}`.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "lowercase")]
+#[serde(tag = "status")]
+pub enum Response {
+    Ok {
+        request_id: Uuid,
+        response: Value,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        metadata: Option<Value>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        prev_hash: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        etag: Option<String>,
+    },
+    Error {
+        request_id: Option<Uuid>,
+        code: ErrorCode,
+        error: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        retry_after_ms: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        metadata: Option<Value>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        prev_hash: Option<String>,
+    },
+}
+
+impl Response {
+    /// Returns the ID of the request this response corresponds to, if it's known.
+    pub fn request_id(&self) -> Option<Uuid> {
+        match self {
+            Response::Ok { request_id, .. } => Some(*request_id),
+            Response::Error { request_id, .. } => *request_id,
+        }
+    }
+
+    /// Returns the metadata copied from the request this response corresponds to.
+    pub fn metadata(&self) -> Option<&Value> {
+        match self {
+            Response::Ok { metadata, .. } | Response::Error { metadata, .. } => metadata.as_ref(),
+        }
+    }
+
+    /// Returns the hash of the previous request of the connection, if the chaining is enabled.
+    pub fn prev_hash(&self) -> Option<&String> {
+        match self {
+            Response::Ok { prev_hash, .. } | Response::Error { prev_hash, .. } => {
+                prev_hash.as_ref()
+            }
+        }
+    }
+
+    /// Returns the response with `prev_hash` set to the given hash.
+    pub fn with_prev_hash(mut self, hash: Option<String>) -> Self {
+        match &mut self {
+            Response::Ok { prev_hash, .. } | Response::Error { prev_hash, .. } => *prev_hash = hash,
+        }
+        self
+    }
+}
+
+/// Computes the `etag` of a result: the hex-encoded SHA-256 of the result serialized
+/// without whitespace.
+pub fn content_hash(response: &Value) -> String {
+    let data = serde_json::to_vec(response).expect("values are always serializable");
+    format!("{:x}", Sha256::digest(data))
+}
+
+/// Brings the value into the canonical form, whose serialization without whitespace is stable:
+/// the keys of the objects are sorted at every level, and the floating-point numbers
+/// that are integers, e.g. `2.0` or `1e2`, are replaced with the integers, as long as they're
+/// represented exactly.
+pub fn canonicalize(value: Value) -> Value {
+    let max_safe_integer = ((1_i64 << f64::MANTISSA_DIGITS) - 1) as f64;
+    match value {
+        Value::Object(object) => {
+            // the map only keeps the keys sorted without the `preserve_order` feature
+            let mut entries: Vec<_> = object.into_iter().collect();
+            entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+            Value::Object(
+                entries
+                    .into_iter()
+                    .map(|(key, value)| (key, canonicalize(value)))
+                    .collect(),
+            )
+        }
+        Value::Array(values) => Value::Array(values.into_iter().map(canonicalize).collect()),
+        Value::Number(number) => match number.as_f64() {
+            Some(x) if number.is_f64() && x.fract() == 0.0 && x.abs() <= max_safe_integer => {
+                Value::from(x as i64)
+            }
+            _ => Value::Number(number),
+        },
+        value => value,
+    }
+}
+
+/// The details of a failed request, as returned by `Command::LastError`.
+#[derive(Serialize, Clone, Debug)]
+pub struct LastError {
+    pub request_id: Uuid,
+    pub command: CommandKind,
+    pub code: ErrorCode,
+    pub error: String,
+}
+
+/// The request as it was received on the connection, returned by `Command::Inspect`.
+#[derive(Serialize, Clone, Debug)]
+pub struct RawRequest {
+    /// The parsed JSON of the request, with the `auth` field redacted.
+    pub request: Value,
+
+    /// The size of the request in bytes, after the decompression.
+    pub bytes: usize,
+
+    /// The text encoding of the request, which is always UTF-8, since others are rejected.
+    pub encoding: &'static str,
+
+    /// The compression of the connection negotiated by the hello frame, e.g. `"gzip"`.
+    pub compression: String,
+}
+
+impl RawRequest {
+    /// Creates the raw request, redacting its authentication token.
+    ///
+    /// The token is redacted, since the raw request is returned in the response,
+    /// which may be logged or cached (see `ResponseCache`).
+    pub fn new(mut request: Value, bytes: usize, compression: String) -> Self {
+        if let Some(auth) = request.get_mut("auth") {
+            *auth = Value::from("<redacted>");
+        }
+        RawRequest {
+            request,
+            bytes,
+            encoding: "utf-8",
+            compression,
+        }
+    }
+}
 
 /// A structure for collecting performance metrics per command.
 ///
 /// This structure tracks the number of times each command has been processed (`command_counts`),
 /// as well as the minimum (`processing_time_min`), maximum (`processing_time_max`)
 /// and average (`processing_time_avg`) processing times (in milliseconds) for each command.
+///
+/// The same statistics over the recent time window are tracked separately in `window`.
 #[derive(Default)]
 pub struct Metrics {
     /// The count of how many times each command has been processed.
@@ -144,6 +1531,12 @@ pub struct Metrics {
 
     /// The average processing time (in ms) observed for each command.
     pub processing_time_max: HashMap<CommandKind, f64>,
+
+    /// The statistics over the recent time window.
+    pub window: WindowedMetrics,
+
+    /// The distribution of processing times for each command.
+    pub histograms: HashMap<CommandKind, Histogram>,
 }
 
 impl Metrics {
@@ -173,5 +1566,1795 @@ impl Metrics {
             .entry(command_kind)
             .and_modify(|old| *old = (*old * (count - 1) as f64 + duration) / count as f64)
             .or_insert(duration);
+
+        self.window.record(command_kind, duration, unix_secs());
+        self.histograms
+            .entry(command_kind)
+            .or_default()
+            .record(duration);
+    }
+
+    /// Returns the total number of processed commands of all kinds.
+    pub fn total_count(&self) -> usize {
+        self.command_counts.values().sum()
+    }
+
+    /// Takes a snapshot of the lifetime metrics, timestamped with `timestamp_ms`.
+    pub fn snapshot(&self, timestamp_ms: u64) -> MetricsSnapshot {
+        let commands = self
+            .command_counts
+            .iter()
+            .map(|(kind, &count)| {
+                let stats = CommandSnapshot {
+                    count,
+                    min: self.processing_time_min[kind],
+                    avg: self.processing_time_avg[kind],
+                    max: self.processing_time_max[kind],
+                };
+                (kind.name(), stats)
+            })
+            .collect();
+        MetricsSnapshot {
+            timestamp_ms,
+            total: self.total_count(),
+            commands,
+        }
+    }
+
+    /// Formats a throughput report as a table with the number of processed commands
+    /// and the number of operations per second for each command kind and in total.
+    ///
+    /// # Parameters
+    /// - `uptime`: The wall-clock time the server has been running for.
+    pub fn bench_report(&self, uptime: Duration) -> String {
+        let mut report = format!("{:<12} {:>10} {:>12}\n", "command", "count", "ops/sec");
+        for kind in CommandKind::ALL {
+            if let Some(&count) = self.command_counts.get(&kind) {
+                report += &format!(
+                    "{:<12} {:>10} {:>12.2}\n",
+                    kind.name(),
+                    count,
+                    ops_per_sec(count, uptime)
+                );
+            }
+        }
+        let total = self.total_count();
+        report += &format!(
+            "{:<12} {:>10} {:>12.2}\n",
+            "total",
+            total,
+            ops_per_sec(total, uptime)
+        );
+        report += &format!("uptime: {:.3}s", uptime.as_secs_f64());
+        report
+    }
+}
+
+/// The lifetime metrics at a moment, as returned by `Metrics::snapshot`.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct MetricsSnapshot {
+    /// The moment the snapshot was taken at, in milliseconds since the Unix epoch.
+    pub timestamp_ms: u64,
+
+    /// The total number of processed commands of all kinds.
+    pub total: usize,
+
+    /// The statistics of each command kind processed at least once, keyed by its name.
+    pub commands: BTreeMap<String, CommandSnapshot>,
+}
+
+impl MetricsSnapshot {
+    /// Returns the metrics accumulated between the earlier snapshot and this one.
+    ///
+    /// The minimum and maximum processing times over the period can't be told from
+    /// the snapshots, unlike the average one. The command kinds that haven't been
+    /// processed over the period are omitted.
+    pub fn since(&self, checkpoint: &MetricsSnapshot) -> MetricsDelta {
+        let commands = self
+            .commands
+            .iter()
+            .filter_map(|(name, now)| {
+                let (count, sum) = checkpoint
+                    .commands
+                    .get(name)
+                    .map_or((0, 0.0), |then| (then.count, then.avg * then.count as f64));
+                let delta = now.count.checked_sub(count).filter(|&c| c > 0)?;
+                let avg = (now.avg * now.count as f64 - sum) / delta as f64;
+                Some((name.clone(), CommandDelta { count: delta, avg }))
+            })
+            .collect();
+        MetricsDelta {
+            since_ms: checkpoint.timestamp_ms,
+            until_ms: self.timestamp_ms,
+            total: self.total.saturating_sub(checkpoint.total),
+            commands,
+        }
+    }
+}
+
+/// The metrics accumulated between two moments, as returned by `MetricsSnapshot::since`.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct MetricsDelta {
+    /// The moment of the earlier snapshot, in milliseconds since the Unix epoch.
+    pub since_ms: u64,
+
+    /// The moment of the later snapshot, in milliseconds since the Unix epoch.
+    pub until_ms: u64,
+
+    /// The total number of commands of all kinds processed over the period.
+    pub total: usize,
+
+    /// The statistics of each command kind processed over the period, keyed by its name.
+    pub commands: BTreeMap<String, CommandDelta>,
+}
+
+/// The statistics of a single command kind in a `MetricsDelta`,
+/// with the average processing time in milliseconds.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+pub struct CommandDelta {
+    pub count: usize,
+    pub avg: f64,
+}
+
+/// The lifetime statistics of a single command kind in a `MetricsSnapshot`,
+/// with the processing times in milliseconds.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+pub struct CommandSnapshot {
+    pub count: usize,
+    pub min: f64,
+    pub avg: f64,
+    pub max: f64,
+}
+
+/// The most recent `MetricsSnapshot`s, taken periodically by the server.
+///
+/// Only the last `capacity` snapshots are kept, the older ones are evicted.
+pub struct MetricsHistory {
+    capacity: usize,
+    snapshots: VecDeque<MetricsSnapshot>,
+}
+
+impl MetricsHistory {
+    /// Creates an empty history keeping at most `capacity` snapshots.
+    pub fn new(capacity: usize) -> Self {
+        MetricsHistory {
+            capacity,
+            snapshots: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    /// Appends the snapshot, evicting the oldest one if the capacity is reached.
+    pub fn push(&mut self, snapshot: MetricsSnapshot) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.snapshots.len() == self.capacity {
+            self.snapshots.pop_front();
+        }
+        self.snapshots.push_back(snapshot);
+    }
+
+    /// Returns at most `points` most recent snapshots, oldest first.
+    pub fn recent(&self, points: usize) -> Vec<MetricsSnapshot> {
+        let skip = self.snapshots.len().saturating_sub(points);
+        self.snapshots.iter().skip(skip).cloned().collect()
+    }
+
+    /// Returns the latest snapshot taken at or before `timestamp_ms`, if it's still kept.
+    pub fn checkpoint(&self, timestamp_ms: u64) -> Option<&MetricsSnapshot> {
+        let taken = self
+            .snapshots
+            .partition_point(|s| s.timestamp_ms <= timestamp_ms);
+        taken.checked_sub(1).map(|i| &self.snapshots[i])
+    }
+}
+
+impl Default for MetricsHistory {
+    fn default() -> Self {
+        MetricsHistory::new(DEFAULT_METRICS_HISTORY_SIZE)
+    }
+}
+
+/// A histogram of processing times (in milliseconds) with fixed exponential buckets.
+///
+/// The upper bound of the bucket `i` is `2^i` microseconds, inclusive;
+/// the last bucket is unbounded and collects all of the larger times.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Histogram {
+    counts: [u64; Histogram::BUCKETS],
+}
+
+/// A single bucket of a `Histogram`, as returned by `Histogram::buckets`.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+pub struct HistogramBucket {
+    /// The inclusive upper bound of the bucket in milliseconds, or `None` if it's unbounded.
+    pub le_ms: Option<f64>,
+
+    /// The number of processing times that fell into the bucket.
+    pub count: u64,
+}
+
+impl Histogram {
+    /// The number of buckets in the histogram.
+    pub const BUCKETS: usize = 32;
+
+    /// The upper bound of the first bucket in milliseconds, i.e. one microsecond.
+    const BASE_MS: f64 = 0.001;
+
+    /// Records a processing time in milliseconds.
+    pub fn record(&mut self, duration: f64) {
+        // the saturating cast maps non-positive logarithms and NaN to the first bucket
+        let index = (duration / Self::BASE_MS).log2().ceil() as usize;
+        self.counts[index.min(Self::BUCKETS - 1)] += 1;
+    }
+
+    /// Returns the total number of recorded times.
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Returns the buckets of the histogram, merged into at most `max_buckets` ones.
+    ///
+    /// The adjacent buckets are merged in equal groups, so the bounds stay exponential.
+    /// A `max_buckets` of zero is treated as one.
+    pub fn buckets(&self, max_buckets: usize) -> Vec<HistogramBucket> {
+        let group = Self::BUCKETS.div_ceil(max_buckets.max(1));
+        self.counts
+            .chunks(group)
+            .enumerate()
+            .map(|(i, counts)| {
+                let last = i * group + counts.len() - 1;
+                HistogramBucket {
+                    le_ms: (last < Self::BUCKETS - 1)
+                        .then(|| Self::BASE_MS * 2_f64.powi(last as i32)),
+                    count: counts.iter().sum(),
+                }
+            })
+            .collect()
+    }
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram {
+            counts: [0; Histogram::BUCKETS],
+        }
+    }
+}
+
+/// The version of the wire protocol of the server.
+///
+/// It must be incremented whenever a change breaks the compatibility with the existing clients,
+/// such as a change to the structure of requests or responses, or to their framing.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// The metadata of the build of the server, returned by `Command::BuildInfo`,
+/// which is embedded by the build script.
+#[derive(Serialize)]
+pub struct BuildInfo {
+    /// The version of the crate.
+    pub version: &'static str,
+
+    /// The profile the server has been built with, `"debug"` or `"release"`.
+    pub profile: &'static str,
+
+    /// The target triple the server has been built for, e.g. `"x86_64-unknown-linux-gnu"`.
+    pub target: &'static str,
+
+    /// The hash of the git commit the server has been built from, if it's been built
+    /// in a git repository.
+    pub git_commit: Option<&'static str>,
+}
+
+impl BuildInfo {
+    /// The metadata of the running server.
+    pub const CURRENT: BuildInfo = BuildInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        profile: env!("BUILD_PROFILE"),
+        target: env!("BUILD_TARGET"),
+        git_commit: option_env!("BUILD_GIT_COMMIT"),
+    };
+}
+
+/// The default length of the `WindowedMetrics` window in seconds.
+pub const DEFAULT_METRICS_WINDOW_SECS: u64 = 60;
+
+/// The default interval between the snapshots in `MetricsHistory` in seconds.
+pub const DEFAULT_METRICS_SNAPSHOT_INTERVAL_SECS: u64 = 10;
+
+/// The default number of snapshots kept in `MetricsHistory`.
+pub const DEFAULT_METRICS_HISTORY_SIZE: usize = 60;
+
+/// The default for how long a response may wait for the client to receive it, in seconds.
+pub const DEFAULT_WRITE_TIMEOUT_SECS: u64 = 60;
+
+/// The deepest nesting of arrays and objects that the JSON parser accepts,
+/// which is also the highest `Config::max_json_depth`.
+pub const MAX_JSON_DEPTH: usize = 127;
+
+/// Aggregated processing time statistics (in milliseconds) of a single command kind.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TimeStats {
+    pub count: usize,
+    pub sum: f64,
+    pub min: f64,
+    pub max: f64,
+}
+
+impl TimeStats {
+    fn new(duration: f64) -> Self {
+        TimeStats {
+            count: 1,
+            sum: duration,
+            min: duration,
+            max: duration,
+        }
+    }
+
+    fn merge(&mut self, other: &TimeStats) {
+        self.count += other.count;
+        self.sum += other.sum;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+    }
+
+    /// Returns the average processing time.
+    pub fn avg(&self) -> f64 {
+        self.sum / self.count as f64
+    }
+}
+
+/// The statistics of all commands processed during a single second.
+#[derive(Default)]
+struct Bucket {
+    second: u64,
+    stats: HashMap<CommandKind, TimeStats>,
+}
+
+/// Per-command processing time statistics over a sliding time window.
+///
+/// The window is split into one-second buckets stored in a ring buffer;
+/// a bucket is reused once the second it belongs to leaves the window.
+/// Time is measured in whole seconds, provided by the caller.
+pub struct WindowedMetrics {
+    buckets: Vec<Bucket>,
+}
+
+impl WindowedMetrics {
+    /// Creates empty metrics with a window of the given length in seconds (at least 1).
+    pub fn new(window_secs: u64) -> Self {
+        let mut buckets = Vec::new();
+        buckets.resize_with(window_secs.max(1) as usize, Bucket::default);
+        WindowedMetrics { buckets }
+    }
+
+    /// Returns the length of the window in seconds.
+    pub fn window_secs(&self) -> u64 {
+        self.buckets.len() as u64
+    }
+
+    /// Records a new processing duration (in ms) of a given command at the given second.
+    pub fn record(&mut self, command_kind: CommandKind, duration: f64, now: u64) {
+        let len = self.window_secs();
+        let bucket = &mut self.buckets[(now % len) as usize];
+        if bucket.second != now {
+            bucket.second = now;
+            bucket.stats.clear();
+        }
+        bucket
+            .stats
+            .entry(command_kind)
+            .and_modify(|s| s.merge(&TimeStats::new(duration)))
+            .or_insert_with(|| TimeStats::new(duration));
+    }
+
+    /// Returns the statistics of each command processed within the window ending at `now`.
+    pub fn stats(&self, now: u64) -> HashMap<CommandKind, TimeStats> {
+        let mut result: HashMap<CommandKind, TimeStats> = HashMap::new();
+        let in_window = |second: u64| second <= now && now - second < self.window_secs();
+        for bucket in self.buckets.iter().filter(|b| in_window(b.second)) {
+            for (kind, stats) in &bucket.stats {
+                result
+                    .entry(*kind)
+                    .and_modify(|s| s.merge(stats))
+                    .or_insert(*stats);
+            }
+        }
+        result
+    }
+}
+
+impl Default for WindowedMetrics {
+    fn default() -> Self {
+        WindowedMetrics::new(DEFAULT_METRICS_WINDOW_SECS)
+    }
+}
+
+/// Returns the current UNIX time in whole seconds.
+pub fn unix_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// Returns the current time in milliseconds since the Unix epoch.
+pub fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+/// Computes the number of operations per second given their count and the elapsed time.
+///
+/// Returns 0 if no time has elapsed.
+pub fn ops_per_sec(count: usize, elapsed: Duration) -> f64 {
+    let secs = elapsed.as_secs_f64();
+    if secs == 0.0 {
+        return 0.0;
+    }
+    count as f64 / secs
+}
+
+/// The effective server configuration, resolved from the command line arguments at startup.
+#[derive(Serialize, Clone)]
+pub struct Config {
+    /// The address the server listens on.
+    pub address: String,
+
+    /// Whether debug logging is enabled.
+    pub debug: bool,
+
+    /// The length of the window for the windowed metrics in seconds.
+    pub metrics_window_secs: u64,
+
+    /// The delay before sending each response in milliseconds, used to simulate a slow network.
+    pub artificial_delay_ms: u64,
+
+    /// Whether the `SO_REUSEADDR` option is set on the listening socket.
+    pub so_reuseaddr: bool,
+
+    /// Whether the `SO_REUSEPORT` option is set on the listening socket.
+    pub so_reuseport: bool,
+
+    /// The maximum number of pending connections waiting to be accepted.
+    pub backlog: u32,
+
+    /// Whether the server listens on the socket passed by systemd socket activation
+    /// instead of binding `address` (see `listener::from_systemd`).
+    pub systemd_socket: bool,
+
+    /// The token that allows a request containing it in its `auth` field to use
+    /// the administrative commands, such as `Command::ProcInfo`.
+    #[serde(skip)]
+    pub admin_token: Option<String>,
+
+    /// The token that every request must contain in its `auth` field, if set.
+    #[serde(skip)]
+    pub auth_token: Option<String>,
+
+    /// For how long the responses are cached to deduplicate requests with the same ID,
+    /// in seconds. Zero disables the deduplication.
+    pub idempotency_ttl_secs: u64,
+
+    /// The maximum size of a serialized response in bytes, if limited.
+    pub max_response_bytes: Option<usize>,
+
+    /// The maximum size of a received request in bytes, if limited.
+    pub max_request_bytes: Option<usize>,
+
+    /// Whether the requests may contain comments and trailing commas.
+    pub lenient_json: bool,
+
+    /// Whether a request is processed once it's complete JSON objects and no more data
+    /// arrives for a short time, without waiting for the client to close its write half.
+    pub eager_requests: bool,
+
+    /// Whether the responses are pretty-printed instead of being compact.
+    pub pretty_json: bool,
+
+    /// The maximum number of requests in a batch, if limited.
+    pub max_batch_size: Option<usize>,
+
+    /// The maximum number of the requests nested into batches that are executed concurrently
+    /// across all of the connections, if limited (see `SubrequestLimit`).
+    pub max_total_subrequests: Option<usize>,
+
+    /// Whether the requests of a batch are processed while the rest of it is still being
+    /// received, instead of after buffering it whole. This only applies to the batches
+    /// whose `payload` is an array following the `request_id` and `command` fields
+    /// (see `batchstream::find_head`), and not to the lenient JSON.
+    pub stream_batches: bool,
+
+    /// The commands that the server refuses to execute.
+    pub disabled_commands: HashSet<CommandKind>,
+
+    /// Whether the server sends the protocol version frame as soon as it accepts a connection.
+    pub protocol_version_header: bool,
+
+    /// The maximum numbers of concurrently executed commands of the limited kinds.
+    pub command_concurrency: HashMap<CommandKind, usize>,
+
+    /// Whether a command that has reached its concurrency limit is rejected
+    /// with `ErrorCode::Busy` instead of waiting for a slot.
+    pub reject_busy: bool,
+
+    /// Whether the commands executed by `Command::Replay` are counted in the metrics.
+    pub replay_metrics: bool,
+
+    /// How often the metrics snapshots are taken for `Command::MetricsSeries`, in seconds.
+    /// Zero disables the snapshots.
+    pub metrics_snapshot_interval_secs: u64,
+
+    /// The maximum number of metrics snapshots kept.
+    pub metrics_history_size: usize,
+
+    /// Whether the successful responses carry the `etag` of their results,
+    /// which the clients may send back in `Request::if_none_match`.
+    pub content_hash: bool,
+
+    /// The maximum number of requests processed on a connection, including the nested ones,
+    /// such as the requests of a batch, after which the rest of them fail with
+    /// `ErrorCode::ConnectionQuotaExceeded`. Zero means no limit.
+    pub max_requests_per_connection: usize,
+
+    /// The maximum nesting depth of the arrays and objects in a request, which the deeper
+    /// requests fail with `ErrorCode::NestingTooDeep` before being parsed.
+    /// It's at most `MAX_JSON_DEPTH`.
+    pub max_json_depth: usize,
+
+    /// The soft limit of the memory buffered for the requests and responses of all of
+    /// the connections in bytes, if limited (see `MemoryBudget`).
+    pub max_memory_bytes: Option<usize>,
+
+    /// The directory where the statistics of `Command::SessionStats` are persisted
+    /// by the session IDs, if enabled.
+    pub session_stats_dir: Option<PathBuf>,
+
+    /// For how long writing a response may wait for the client to receive it, in seconds,
+    /// after which the connection is abandoned. Zero means no limit.
+    pub write_timeout_secs: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            address: "localhost:7878".to_string(),
+            debug: false,
+            metrics_window_secs: DEFAULT_METRICS_WINDOW_SECS,
+            artificial_delay_ms: 0,
+            so_reuseaddr: cfg!(unix),
+            so_reuseport: false,
+            backlog: 1024,
+            systemd_socket: false,
+            admin_token: None,
+            auth_token: None,
+            idempotency_ttl_secs: 0,
+            max_response_bytes: None,
+            max_request_bytes: None,
+            lenient_json: false,
+            eager_requests: false,
+            pretty_json: false,
+            max_batch_size: None,
+            max_total_subrequests: None,
+            stream_batches: false,
+            disabled_commands: HashSet::new(),
+            protocol_version_header: false,
+            command_concurrency: HashMap::new(),
+            reject_busy: false,
+            replay_metrics: true,
+            metrics_snapshot_interval_secs: DEFAULT_METRICS_SNAPSHOT_INTERVAL_SECS,
+            metrics_history_size: DEFAULT_METRICS_HISTORY_SIZE,
+            content_hash: false,
+            max_requests_per_connection: 0,
+            max_json_depth: MAX_JSON_DEPTH,
+            max_memory_bytes: None,
+            session_stats_dir: None,
+            write_timeout_secs: DEFAULT_WRITE_TIMEOUT_SECS,
+        }
+    }
+}
+
+/// The state shared between all connections handled by the server.
+#[derive(Default)]
+pub struct ServerState {
+    /// The effective server configuration.
+    pub config: Config,
+
+    /// The global performance metrics.
+    pub metrics: Mutex<Metrics>,
+
+    /// The requests that are currently being processed, keyed by their IDs.
+    pub active_requests: Mutex<HashMap<Uuid, ActiveRequest>>,
+
+    /// The connections that are currently open, keyed by their peer addresses.
+    pub connections: Mutex<HashMap<SocketAddr, ActiveConnection>>,
+
+    /// The recent responses, used to deduplicate repeated requests.
+    pub response_cache: Mutex<ResponseCache>,
+
+    /// The macros defined by the clients.
+    pub macros: Mutex<Macros>,
+
+    /// The limits of the concurrently executed commands.
+    pub concurrency_limits: ConcurrencyLimits,
+
+    /// The limit of the concurrently executed requests nested into batches.
+    pub subrequests: SubrequestLimit,
+
+    /// The recently processed requests, which can be executed again with `Command::Replay`.
+    pub recent_requests: Mutex<RecentRequests>,
+
+    /// The periodic snapshots of the metrics, returned by `Command::MetricsSeries`.
+    pub metrics_history: Mutex<MetricsHistory>,
+
+    /// The outcomes of the recently processed requests, returned by `Command::RecentRequests`.
+    pub request_log: Mutex<RequestLog>,
+
+    /// The memory buffered for the requests and responses of the connections.
+    pub memory: MemoryBudget,
+
+    /// Whether the server is shutting down, waiting for the connections to finish,
+    /// which makes the batches stop before their next requests.
+    pub draining: AtomicBool,
+
+    /// The statistics of the compression of the responses sent by the server.
+    pub compression: CompressionStats,
+
+    /// When the server has started, which `Command::Uptime` counts from.
+    pub started: StartTime,
+}
+
+/// The moment the server has started.
+pub struct StartTime {
+    /// The monotonic time, which the uptime is measured from.
+    pub instant: Instant,
+
+    /// The wall-clock time, which is reported to the clients.
+    pub time: DateTime<Utc>,
+}
+
+impl Default for StartTime {
+    fn default() -> Self {
+        StartTime {
+            instant: Instant::now(),
+            time: Utc::now(),
+        }
+    }
+}
+
+/// Limits the number of concurrently executed commands of each kind,
+/// according to `Config::command_concurrency`,
+/// and keeps track of the number of the commands waiting for a slot.
+#[derive(Default)]
+pub struct ConcurrencyLimits {
+    semaphores: HashMap<CommandKind, Arc<Semaphore>>,
+    queued: HashMap<CommandKind, AtomicUsize>,
+    reject_busy: bool,
+}
+
+/// Counts a command as queued for as long as it's alive, so that a command is no longer
+/// counted even if its request is dropped while it's waiting, e.g. on a timeout.
+struct QueuedGuard<'a>(&'a AtomicUsize);
+
+impl<'a> QueuedGuard<'a> {
+    fn new(queued: &'a AtomicUsize) -> Self {
+        queued.fetch_add(1, Ordering::SeqCst);
+        QueuedGuard(queued)
+    }
+}
+
+impl Drop for QueuedGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl ConcurrencyLimits {
+    /// Creates the limits from the maximum numbers of concurrent commands of each kind.
+    ///
+    /// If `reject_busy` is set, `acquire` fails instead of waiting for a slot.
+    pub fn new(limits: &HashMap<CommandKind, usize>, reject_busy: bool) -> Self {
+        let semaphores = limits
+            .iter()
+            .map(|(kind, limit)| (*kind, Arc::new(Semaphore::new(*limit))))
+            .collect();
+        let queued = limits
+            .keys()
+            .map(|kind| (*kind, AtomicUsize::new(0)))
+            .collect();
+        ConcurrencyLimits {
+            semaphores,
+            queued,
+            reject_busy,
+        }
+    }
+
+    /// Returns the number of the commands of the given kind waiting for a slot,
+    /// or `None` if the kind isn't limited.
+    ///
+    /// Always zero if the limits reject busy commands, since they never wait.
+    pub fn queue_depth(&self, kind: CommandKind) -> Option<usize> {
+        self.queued.get(&kind).map(|q| q.load(Ordering::SeqCst))
+    }
+
+    /// Takes a slot to execute a command of the given kind, which is released
+    /// when the returned permit is dropped. Unlimited commands need no permit.
+    ///
+    /// Waits for a slot if all of them are taken, unless the limits reject busy commands,
+    /// in which case this fails with `ErrorCode::Busy`.
+    pub async fn acquire(
+        &self,
+        kind: CommandKind,
+    ) -> Result<Option<OwnedSemaphorePermit>, CommandError> {
+        let Some(semaphore) = self.semaphores.get(&kind) else {
+            return Ok(None);
+        };
+        let permit = match self.reject_busy {
+            true => semaphore.clone().try_acquire_owned().map_err(|_| {
+                CommandError::new(
+                    ErrorCode::Busy,
+                    format!("too many `{}` commands are being executed", kind.name()),
+                )
+            })?,
+            false => match semaphore.clone().try_acquire_owned() {
+                Ok(permit) => permit,
+                Err(_) => {
+                    let _queued = QueuedGuard::new(&self.queued[&kind]);
+                    // the semaphores are never closed
+                    semaphore.clone().acquire_owned().await.unwrap()
+                }
+            },
+        };
+        Ok(Some(permit))
+    }
+}
+
+/// Limits the number of the requests nested into batches that are executed concurrently
+/// across the whole server, according to `Config::max_total_subrequests`,
+/// and keeps track of the peak number of them.
+///
+/// A nested request that contains other requests, such as a batch, takes no slot,
+/// since it would wait for the slots held by its own nested requests.
+#[derive(Default)]
+pub struct SubrequestLimit {
+    semaphore: Option<Arc<Semaphore>>,
+    in_flight: Arc<AtomicUsize>,
+    peak: AtomicUsize,
+}
+
+impl SubrequestLimit {
+    pub fn new(limit: Option<usize>) -> Self {
+        SubrequestLimit {
+            semaphore: limit.map(|limit| Arc::new(Semaphore::new(limit))),
+            ..Default::default()
+        }
+    }
+
+    /// Takes a slot to execute a nested request with a command of the given kind,
+    /// waiting for one if all of them are taken. The slot is released when the returned
+    /// permit is dropped. The requests containing others need no permit.
+    pub async fn acquire(&self, kind: CommandKind) -> Option<SubrequestPermit> {
+        // a replayed command may contain others as well
+        if kind.contains_requests() || kind == CommandKind::Replay {
+            return None;
+        }
+        let permit = match &self.semaphore {
+            // the semaphore is never closed
+            Some(semaphore) => Some(semaphore.clone().acquire_owned().await.unwrap()),
+            None => None,
+        };
+        let in_flight = self.in_flight.fetch_add(1, Ordering::Relaxed) + 1;
+        self.peak.fetch_max(in_flight, Ordering::Relaxed);
+        Some(SubrequestPermit {
+            _permit: permit,
+            in_flight: self.in_flight.clone(),
+        })
+    }
+
+    /// The number of the nested requests being executed.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    /// The largest number of the nested requests that have been executed at once.
+    pub fn peak(&self) -> usize {
+        self.peak.load(Ordering::Relaxed)
+    }
+}
+
+/// A slot taken from `SubrequestLimit`, released on drop.
+pub struct SubrequestPermit {
+    _permit: Option<OwnedSemaphorePermit>,
+    in_flight: Arc<AtomicUsize>,
+}
+
+impl Drop for SubrequestPermit {
+    fn drop(&mut self) {
+        self.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// For how long a connection waits for the memory to be freed by the others once the budget
+/// is exceeded, after which it proceeds anyway, since they may be waiting for it as well.
+const MAX_MEMORY_WAIT: Duration = Duration::from_secs(5);
+
+/// Accounts the memory buffered for the requests and responses of all of the connections
+/// against `Config::max_memory_bytes`.
+///
+/// The limit is a soft one: the memory is accounted after it's been allocated, and once
+/// the budget is exceeded, the new connections are rejected with `ErrorCode::MemoryExhausted`,
+/// while the ones in flight pause reading their requests until the memory is freed
+/// (see `MemoryGuard::wait_for_room`).
+#[derive(Default)]
+pub struct MemoryBudget {
+    max: Option<usize>,
+    used: AtomicUsize,
+    freed: Notify,
+}
+
+impl MemoryBudget {
+    /// Creates an empty budget of at most `max` bytes, if limited.
+    pub fn new(max: Option<usize>) -> Self {
+        MemoryBudget {
+            max,
+            ..Default::default()
+        }
+    }
+
+    /// Returns the number of bytes currently accounted.
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    /// Returns whether more memory is accounted than the budget allows.
+    pub fn is_exceeded(&self) -> bool {
+        self.max.is_some_and(|max| self.used() > max)
+    }
+
+    /// Returns a guard accounting no memory yet, which releases its memory when dropped.
+    pub fn guard(&self) -> MemoryGuard<'_> {
+        MemoryGuard {
+            budget: self,
+            bytes: 0,
+        }
+    }
+}
+
+/// The memory accounted in a `MemoryBudget` for a buffer, released when the guard is dropped.
+pub struct MemoryGuard<'a> {
+    budget: &'a MemoryBudget,
+    bytes: usize,
+}
+
+impl MemoryGuard<'_> {
+    /// Accounts `bytes` in total for the buffer, e.g. after it has grown.
+    pub fn set(&mut self, bytes: usize) {
+        let used = &self.budget.used;
+        if bytes >= self.bytes {
+            used.fetch_add(bytes - self.bytes, Ordering::Relaxed);
+        } else {
+            used.fetch_sub(self.bytes - bytes, Ordering::Relaxed);
+            self.budget.freed.notify_waiters();
+        }
+        self.bytes = bytes;
+    }
+
+    /// Waits until the budget isn't exceeded anymore, as long as the other guards hold
+    /// some of the memory, which only they can free. Gives up after `MAX_MEMORY_WAIT`.
+    pub async fn wait_for_room(&self) {
+        let deadline = tokio::time::Instant::now() + MAX_MEMORY_WAIT;
+        loop {
+            // the notification is subscribed to before checking, not to miss it in between
+            let freed = self.budget.freed.notified();
+            let mut freed = std::pin::pin!(freed);
+            freed.as_mut().enable();
+            if !self.budget.is_exceeded() || self.budget.used() <= self.bytes {
+                return;
+            }
+            if tokio::time::timeout_at(deadline, freed).await.is_err() {
+                warn!("Memory budget is still exceeded, proceeding anyway.");
+                return;
+            }
+        }
+    }
+}
+
+impl Drop for MemoryGuard<'_> {
+    fn drop(&mut self) {
+        self.set(0);
+    }
+}
+
+/// The total sizes of the responses compressed with the compression negotiated
+/// for their connections, before and after the compression, in bytes.
+#[derive(Default, Debug)]
+pub struct CompressionStats {
+    uncompressed: AtomicU64,
+    compressed: AtomicU64,
+}
+
+impl CompressionStats {
+    /// Counts the data compressed from `uncompressed` bytes into `compressed` ones.
+    pub fn record(&self, uncompressed: usize, compressed: usize) {
+        self.uncompressed
+            .fetch_add(uncompressed as u64, Ordering::Relaxed);
+        self.compressed
+            .fetch_add(compressed as u64, Ordering::Relaxed);
+    }
+
+    /// Returns the ratio of the uncompressed bytes to the compressed ones, which is greater
+    /// the more effective the compression is, or `None` if nothing has been compressed.
+    pub fn ratio(&self) -> Option<f64> {
+        let compressed = self.compressed.load(Ordering::Relaxed);
+        let uncompressed = self.uncompressed.load(Ordering::Relaxed);
+        (compressed > 0).then(|| uncompressed as f64 / compressed as f64)
+    }
+}
+
+/// A request that is currently being processed.
+pub struct ActiveRequest {
+    /// The kind of the command being processed.
+    pub command_kind: CommandKind,
+
+    /// The moment the processing started.
+    pub started: Instant,
+}
+
+/// A connection that is currently open.
+pub struct ActiveConnection {
+    /// The moment the connection was accepted.
+    pub started: Instant,
+
+    /// The number of requests processed on the connection, including the nested ones.
+    pub requests: Arc<AtomicUsize>,
+
+    /// Notified to close the connection (see `Command::CloseConnection`).
+    pub close: Arc<Notify>,
+}
+
+/// The client a request comes from, as far as the server can tell it across connections:
+/// the IP address of the client, without the port, which changes with every connection,
+/// and the token in the `auth` field of the request, if any.
+#[derive(Serialize, Default, Clone)]
+pub struct ClientIdentity {
+    ip: Option<IpAddr>,
+    auth: Option<String>,
+}
+
+impl ClientIdentity {
+    /// Creates the identity of the client with the given address sending the given token.
+    pub fn new(addr: SocketAddr, auth: Option<String>) -> Self {
+        ClientIdentity {
+            ip: Some(addr.ip()),
+            auth,
+        }
+    }
+
+    /// Returns the token in the `auth` field of the request, if any.
+    pub fn auth(&self) -> Option<&str> {
+        self.auth.as_deref()
+    }
+}
+
+/// The key of a response in `ResponseCache`.
+///
+/// Besides the request ID, which the client uses as the idempotency key, the key covers
+/// the client and the command with its transform, so that another client reusing the ID,
+/// e.g. one seen in a log, doesn't get the response, and neither does another command
+/// with the same ID.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    request_id: Uuid,
+    /// The SHA-256 of the serialized `ClientIdentity`, command and transform.
+    scope: [u8; 32],
+}
+
+impl CacheKey {
+    /// Creates the key of the response to the request with the given ID, command
+    /// and transform sent by the given client.
+    pub fn new(
+        request_id: Uuid,
+        client: &ClientIdentity,
+        command: &Command,
+        transform: Option<EchoTransform>,
+    ) -> Self {
+        let scope = (client, command, transform);
+        let scope = serde_json::to_vec(&scope).expect("commands are serializable");
+        CacheKey {
+            request_id,
+            scope: Sha256::digest(scope).into(),
+        }
+    }
+}
+
+/// A cache of responses keyed by `CacheKey`s, which are used as idempotency keys.
+///
+/// Each response is kept for the configured time-to-live after it's been inserted.
+/// A zero TTL disables the cache.
+#[derive(Default)]
+pub struct ResponseCache {
+    ttl: Duration,
+    responses: HashMap<CacheKey, (Instant, Response)>,
+    /// The insertion order of the responses, which is also their expiration order.
+    order: VecDeque<(Instant, CacheKey)>,
+}
+
+impl ResponseCache {
+    /// Creates an empty cache with the given time-to-live of the responses.
+    pub fn new(ttl: Duration) -> Self {
+        ResponseCache {
+            ttl,
+            ..Default::default()
+        }
+    }
+
+    /// Returns whether the cache is enabled.
+    pub fn is_enabled(&self) -> bool {
+        !self.ttl.is_zero()
+    }
+
+    /// Returns the cached response with the given key, unless it expired by `now`.
+    pub fn get(&self, key: &CacheKey, now: Instant) -> Option<&Response> {
+        self.responses
+            .get(key)
+            .filter(|(inserted, _)| now.duration_since(*inserted) < self.ttl)
+            .map(|(_, response)| response)
+    }
+
+    /// Caches the response with the given key at the moment `now`,
+    /// evicting the expired responses.
+    pub fn insert(&mut self, key: CacheKey, response: Response, now: Instant) {
+        if !self.is_enabled() {
+            return;
+        }
+        while let Some(&(inserted, key)) = self.order.front() {
+            if now.duration_since(inserted) < self.ttl {
+                break;
+            }
+            self.order.pop_front();
+            // the response might have been replaced by a newer one since
+            let replaced = self.responses.get(&key).is_none_or(|(i, _)| *i != inserted);
+            if !replaced {
+                self.responses.remove(&key);
+            }
+        }
+        self.responses.insert(key, (now, response));
+        self.order.push_back((now, key));
+    }
+}
+
+impl ServerState {
+    /// Creates a new state with the given configuration and empty metrics.
+    pub fn new(config: Config) -> Self {
+        let metrics = Metrics {
+            window: WindowedMetrics::new(config.metrics_window_secs),
+            ..Default::default()
+        };
+        let response_cache = ResponseCache::new(Duration::from_secs(config.idempotency_ttl_secs));
+        let concurrency_limits =
+            ConcurrencyLimits::new(&config.command_concurrency, config.reject_busy);
+        let metrics_history = MetricsHistory::new(config.metrics_history_size);
+        let max_memory = config.max_memory_bytes;
+        let max_subrequests = config.max_total_subrequests;
+        ServerState {
+            config,
+            metrics: Mutex::new(metrics),
+            active_requests: Mutex::default(),
+            connections: Mutex::default(),
+            response_cache: Mutex::new(response_cache),
+            macros: Mutex::default(),
+            concurrency_limits,
+            subrequests: SubrequestLimit::new(max_subrequests),
+            recent_requests: Mutex::default(),
+            metrics_history: Mutex::new(metrics_history),
+            request_log: Mutex::default(),
+            memory: MemoryBudget::new(max_memory),
+            draining: AtomicBool::new(false),
+            compression: CompressionStats::default(),
+            started: StartTime::default(),
+        }
+    }
+
+    /// Estimates how long a client should wait before retrying a command of the given kind
+    /// rejected with `ErrorCode::Busy`: until the command of that kind that has been running
+    /// the longest is expected to finish according to their average processing time,
+    /// but at least `MIN_RETRY_AFTER_MS`, which is also the estimate if none has finished yet.
+    pub fn busy_retry_after_ms(&self, kind: CommandKind) -> u64 {
+        let average_ms = self.lock_metrics().processing_time_avg.get(&kind).copied();
+        let longest = (self.active_requests.lock().unwrap().values())
+            .filter(|request| request.command_kind == kind)
+            .map(|request| request.started.elapsed())
+            .max()
+            .unwrap_or_default();
+        let remaining_ms =
+            average_ms.map_or(0.0, |average| average - longest.as_secs_f64() * 1000.0);
+        // a negative remaining time is saturated to zero
+        (remaining_ms.ceil() as u64).max(MIN_RETRY_AFTER_MS)
+    }
+
+    /// Locks the global performance metrics.
+    ///
+    /// If a thread has panicked while holding the lock, the metrics may be partially updated,
+    /// but they're used anyway, with a warning, rather than failing every following request.
+    pub fn lock_metrics(&self) -> MutexGuard<'_, Metrics> {
+        self.metrics.lock().unwrap_or_else(|e| {
+            warn!("The metrics lock has been poisoned by a panic, recovering it.");
+            self.metrics.clear_poison();
+            e.into_inner()
+        })
+    }
+}
+
+/// The macros defined with `Command::DefineMacro`, keyed by the `auth` tokens
+/// of the requests defining them and by their names.
+///
+/// A macro is only visible to the requests with the same token, which have the same rights,
+/// so that no client can replace the macro of another one, e.g. of an administrator,
+/// to have its own steps run with the rights of that client.
+///
+/// The macros are stored by the whole server, so both their number and their sizes
+/// are capped to keep a client from exhausting the server memory.
+#[derive(Default)]
+pub struct Macros {
+    macros: HashMap<(Option<String>, String), Vec<Command>>,
+}
+
+impl Macros {
+    /// The maximum number of macros stored at once.
+    pub const MAX_COUNT: usize = 64;
+
+    /// The maximum size of the steps of a macro, serialized into JSON, in bytes.
+    pub const MAX_BYTES: usize = 64 * 1024;
+
+    /// Stores the steps under the given name for the requests with the given token,
+    /// replacing their previous macro with that name.
+    ///
+    /// Fails with `ErrorCode::InvalidArgument` if the name is empty, there are no steps,
+    /// the steps are too large, or a new macro would exceed the maximum number of them.
+    pub fn define(
+        &mut self,
+        auth: Option<&str>,
+        name: String,
+        steps: Vec<Command>,
+    ) -> Result<(), CommandError> {
+        let invalid = |message: String| Err(CommandError::new(ErrorCode::InvalidArgument, message));
+        if name.is_empty() {
+            return invalid("macro name must not be empty".to_string());
+        }
+        if steps.is_empty() {
+            return invalid("macro must have at least one step".to_string());
+        }
+        let size = serde_json::to_vec(&steps)
+            .expect("commands are serializable")
+            .len();
+        if size > Self::MAX_BYTES {
+            return invalid(format!(
+                "macro of {size} bytes exceeds the limit of {} bytes",
+                Self::MAX_BYTES
+            ));
+        }
+        let key = (auth.map(str::to_string), name);
+        if !self.macros.contains_key(&key) && self.macros.len() >= Self::MAX_COUNT {
+            return invalid(format!(
+                "the limit of {} macros has been reached",
+                Self::MAX_COUNT
+            ));
+        }
+        self.macros.insert(key, steps);
+        Ok(())
+    }
+
+    /// Returns the steps of the macro with the given name defined with the given token.
+    ///
+    /// Fails with `ErrorCode::NotFound` if there is no such macro.
+    pub fn get(&self, auth: Option<&str>, name: &str) -> Result<&[Command], CommandError> {
+        let key = (auth.map(str::to_string), name.to_string());
+        self.macros.get(&key).map(Vec::as_slice).ok_or_else(|| {
+            CommandError::new(
+                ErrorCode::NotFound,
+                format!("macro `{name}` is not defined"),
+            )
+        })
+    }
+}
+
+/// The key-value pairs stored with `Command::SessionSet` on a connection.
+///
+/// Like the macros, the entries are capped both in number and in total size, since a client
+/// may keep its connection open for long (see `Macros`).
+#[derive(Default)]
+pub struct Session {
+    entries: HashMap<String, Value>,
+    /// The total size of the keys and the serialized values, in bytes.
+    bytes: usize,
+    /// The statistics of the values submitted with `Command::SessionStats`.
+    pub stats: RunningStats,
+    /// The file the statistics are persisted to once the session ends, if any.
+    pub stats_file: Option<PathBuf>,
+    /// The cipher of `Command::Encrypt`, created on its first use.
+    cipher: Option<SessionCipher>,
+}
+
+impl Session {
+    /// The maximum number of entries stored at once.
+    pub const MAX_ENTRIES: usize = 64;
+
+    /// The maximum total size of the keys and the values, serialized into JSON, in bytes.
+    pub const MAX_BYTES: usize = 64 * 1024;
+
+    /// Stores the value under the given key, replacing the previous one with that key.
+    ///
+    /// Fails with `ErrorCode::InvalidArgument` if the key is empty, or if the entry
+    /// would exceed the maximum number or total size of the entries.
+    pub fn set(&mut self, key: String, value: Value) -> Result<(), CommandError> {
+        let invalid = |message: String| Err(CommandError::new(ErrorCode::InvalidArgument, message));
+        if key.is_empty() {
+            return invalid("session key must not be empty".to_string());
+        }
+        let replaced = self
+            .entries
+            .get(&key)
+            .map_or(0, |previous| Self::entry_size(&key, previous));
+        let bytes = self.bytes - replaced + Self::entry_size(&key, &value);
+        if bytes > Self::MAX_BYTES {
+            return invalid(format!(
+                "session entries of {bytes} bytes would exceed the limit of {} bytes",
+                Self::MAX_BYTES
+            ));
+        }
+        if !self.entries.contains_key(&key) && self.entries.len() >= Self::MAX_ENTRIES {
+            return invalid(format!(
+                "the limit of {} session entries has been reached",
+                Self::MAX_ENTRIES
+            ));
+        }
+        self.entries.insert(key, value);
+        self.bytes = bytes;
+        Ok(())
+    }
+
+    /// Returns the value stored under the given key.
+    ///
+    /// Fails with `ErrorCode::NotFound` if there is no such value.
+    pub fn get(&self, key: &str) -> Result<&Value, CommandError> {
+        self.entries.get(key).ok_or_else(|| {
+            CommandError::new(
+                ErrorCode::NotFound,
+                format!("session key `{key}` is not set"),
+            )
+        })
+    }
+
+    /// Returns the number of the stored entries.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns the cipher of the connection, generating its key on the first call.
+    pub fn cipher(&mut self) -> &SessionCipher {
+        self.cipher.get_or_insert_with(SessionCipher::new)
+    }
+
+    fn entry_size(key: &str, value: &Value) -> usize {
+        let value = serde_json::to_vec(value).expect("values are serializable");
+        key.len() + value.len()
+    }
+}
+
+/// The count, mean and variance of a sequence of numbers, updated as each of them arrives
+/// with Welford's algorithm, which doesn't lose the precision over long sequences.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct RunningStats {
+    pub count: u64,
+    pub mean: f64,
+    /// The sum of the squared differences of the values from the mean.
+    m2: f64,
+}
+
+impl RunningStats {
+    /// Adds the value to the statistics.
+    pub fn push(&mut self, value: f64) {
+        self.count += 1;
+        let delta = value - self.mean;
+        self.mean += delta / self.count as f64;
+        self.m2 += delta * (value - self.mean);
+    }
+
+    /// Adds the values of other statistics, as if they were pushed one by one.
+    pub fn merge(&mut self, other: &RunningStats) {
+        let count = self.count + other.count;
+        if count == 0 {
+            return;
+        }
+        let delta = other.mean - self.mean;
+        let weight = self.count as f64 * other.count as f64 / count as f64;
+        self.mean += delta * other.count as f64 / count as f64;
+        self.m2 += other.m2 + delta * delta * weight;
+        self.count = count;
+    }
+
+    /// Returns the population variance, or `None` if there are no values.
+    pub fn variance(&self) -> Option<f64> {
+        (self.count > 0).then(|| self.m2 / self.count as f64)
+    }
+}
+
+/// The commands of the recently processed requests, keyed by the request IDs,
+/// for `Command::Replay`.
+///
+/// Only the last `RecentRequests::CAPACITY` requests are kept, the older ones are evicted.
+#[derive(Default)]
+pub struct RecentRequests {
+    commands: HashMap<Uuid, Command>,
+    /// The insertion order of the requests, which is also their eviction order.
+    order: VecDeque<Uuid>,
+}
+
+impl RecentRequests {
+    /// The maximum number of requests kept at once.
+    pub const CAPACITY: usize = 256;
+
+    /// Remembers the command of the request with the given ID, evicting the oldest request
+    /// if the capacity is reached. A request with a repeated ID replaces the previous one.
+    pub fn insert(&mut self, request_id: Uuid, command: Command) {
+        if self.commands.insert(request_id, command).is_some() {
+            self.order.retain(|id| *id != request_id);
+        }
+        self.order.push_back(request_id);
+        if self.order.len() > Self::CAPACITY {
+            let evicted = self.order.pop_front().expect("the order is not empty");
+            self.commands.remove(&evicted);
+        }
+    }
+
+    /// Returns the command of the request with the given ID.
+    ///
+    /// Fails with `ErrorCode::NotFound` if the request is not cached.
+    pub fn get(&self, request_id: &Uuid) -> Result<&Command, CommandError> {
+        self.commands.get(request_id).ok_or_else(|| {
+            CommandError::new(
+                ErrorCode::NotFound,
+                format!("request {request_id} is not among the recent requests"),
+            )
+        })
+    }
+}
+
+/// The outcome of a processed request, as returned by `Command::RecentRequests`.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct RequestRecord {
+    pub request_id: Uuid,
+    pub command: CommandKind,
+
+    /// Whether the request has succeeded.
+    pub ok: bool,
+
+    /// The code of the error, if the request has failed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<ErrorCode>,
+
+    /// The processing time in milliseconds.
+    pub duration_ms: f64,
+}
+
+/// The outcomes of the recently processed requests, in the order they have been processed.
+///
+/// Only the last `RequestLog::CAPACITY` requests are kept, the older ones are evicted.
+#[derive(Default)]
+pub struct RequestLog {
+    records: VecDeque<RequestRecord>,
+}
+
+impl RequestLog {
+    /// The maximum number of requests kept at once.
+    pub const CAPACITY: usize = 256;
+
+    /// Appends the outcome of a request, evicting the oldest one if the capacity is reached.
+    pub fn push(&mut self, record: RequestRecord) {
+        if self.records.len() == Self::CAPACITY {
+            self.records.pop_front();
+        }
+        self.records.push_back(record);
+    }
+
+    /// Returns at most `limit` most recent outcomes, newest first.
+    pub fn recent(&self, limit: usize) -> Vec<RequestRecord> {
+        self.records.iter().rev().take(limit).cloned().collect()
+    }
+}
+
+/// The hash chain of the requests processed on a connection, which lets the client verify
+/// that the server has received all of its requests intact and in order.
+///
+/// The hash of a request is the hex-encoded SHA-256 of the hash of the previous request,
+/// if there is one, followed by the request as serialized by the server: without whitespace,
+/// with the keys of its objects sorted and without the `auth` field.
+/// The nested requests, such as the items of a batch, follow the request containing them.
+#[derive(Default)]
+pub struct RequestChain {
+    last: Option<String>,
+}
+
+impl RequestChain {
+    /// Appends the request to the chain, returning the hash of the previous request.
+    pub fn push(&mut self, request: &Request) -> Option<String> {
+        let mut hasher = Sha256::new();
+        if let Some(last) = &self.last {
+            hasher.update(last.as_bytes());
+        }
+        let serialized = serde_json::to_value(request).expect("requests are serializable");
+        hasher.update(serialized.to_string().as_bytes());
+        self.last.replace(format!("{:x}", hasher.finalize()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ops_per_sec() {
+        assert_eq!(ops_per_sec(500, Duration::from_secs(4)), 125.0);
+        assert_eq!(ops_per_sec(3, Duration::from_millis(500)), 6.0);
+        assert_eq!(ops_per_sec(10, Duration::ZERO), 0.0);
+    }
+
+    #[test]
+    fn test_bench_report() {
+        let mut metrics = Metrics::default();
+        metrics.update(CommandKind::Ping, 1.0);
+        metrics.update(CommandKind::Ping, 1.0);
+        metrics.update(CommandKind::Time, 1.0);
+
+        let report = metrics.bench_report(Duration::from_secs(2));
+        assert!(report.contains("ping"));
+        assert!(report.contains("1.00"));
+        assert!(report.contains("1.50"));
+        assert!(!report.contains("calculate"));
+        assert!(report.ends_with("uptime: 2.000s"));
+    }
+
+    #[test]
+    fn test_histogram() {
+        let mut histogram = Histogram::default();
+        let durations = [0.0, 0.0005, 0.001, 0.0015, 0.003, 1.0, 1.0, 250.0, 1e9];
+        for d in durations {
+            histogram.record(d);
+        }
+        assert_eq!(histogram.count(), durations.len() as u64);
+
+        let buckets = histogram.buckets(Histogram::BUCKETS);
+        assert_eq!(buckets.len(), Histogram::BUCKETS);
+        assert_eq!(
+            buckets.iter().map(|b| b.count).sum::<u64>(),
+            histogram.count()
+        );
+        // 0, 0.0005 and 0.001 are within 1us
+        assert_eq!(
+            buckets[0],
+            HistogramBucket {
+                le_ms: Some(0.001),
+                count: 3
+            }
+        );
+        assert_eq!(buckets[1].count, 1);
+        assert_eq!(buckets[2].count, 1);
+        // 1ms is within 1.024ms
+        assert_eq!(buckets[10].count, 2);
+        assert_eq!(
+            buckets[Histogram::BUCKETS - 1],
+            HistogramBucket {
+                le_ms: None,
+                count: 1
+            }
+        );
+
+        let merged = histogram.buckets(5);
+        assert_eq!(merged.len(), 5);
+        assert_eq!(
+            merged[0],
+            HistogramBucket {
+                le_ms: Some(0.064),
+                count: 5
+            }
+        );
+        assert_eq!(
+            merged.iter().map(|b| b.count).sum::<u64>(),
+            histogram.count()
+        );
+        assert_eq!(merged[4].le_ms, None);
+    }
+
+    #[test]
+    fn test_windowed_metrics() {
+        let mut window = WindowedMetrics::new(10);
+        window.record(CommandKind::Ping, 1.0, 100);
+        window.record(CommandKind::Ping, 3.0, 105);
+        window.record(CommandKind::Echo, 2.0, 105);
+
+        let stats = window.stats(105);
+        let ping = stats[&CommandKind::Ping];
+        assert_eq!(ping.count, 2);
+        assert_eq!(ping.min, 1.0);
+        assert_eq!(ping.max, 3.0);
+        assert_eq!(ping.avg(), 2.0);
+        assert_eq!(stats[&CommandKind::Echo].count, 1);
+
+        // the first sample ages out of the window
+        let stats = window.stats(110);
+        assert_eq!(stats[&CommandKind::Ping].count, 1);
+        assert_eq!(stats[&CommandKind::Ping].min, 3.0);
+
+        // a bucket reused by a later second drops its old samples
+        window.record(CommandKind::Time, 5.0, 115);
+        let stats = window.stats(115);
+        assert!(!stats.contains_key(&CommandKind::Ping));
+        assert!(!stats.contains_key(&CommandKind::Echo));
+        assert_eq!(stats[&CommandKind::Time].count, 1);
+
+        assert!(window.stats(200).is_empty());
+    }
+
+    #[test]
+    fn test_response_cache() {
+        let response = |n: u64| Response::Ok {
+            request_id: Uuid::nil(),
+            response: Value::from(n),
+            metadata: None,
+            prev_hash: None,
+            etag: None,
+        };
+        let cached = |cache: &ResponseCache, key: &CacheKey, now: Instant| match cache.get(key, now)
+        {
+            Some(Response::Ok { response, .. }) => response.as_u64(),
+            _ => None,
+        };
+        let client = ClientIdentity::default();
+        let key = |id| CacheKey::new(id, &client, &Command::Ping, None);
+
+        let start = Instant::now();
+        let mut cache = ResponseCache::new(Duration::from_secs(10));
+        let (id1, id2) = (Uuid::new_v4(), Uuid::new_v4());
+        cache.insert(key(id1), response(1), start);
+        cache.insert(key(id2), response(2), start + Duration::from_secs(5));
+
+        let secs = |n: u64| start + Duration::from_secs(n);
+        assert_eq!(cached(&cache, &key(id1), secs(9)), Some(1));
+        assert_eq!(cached(&cache, &key(id1), secs(10)), None);
+        assert_eq!(cached(&cache, &key(id2), secs(10)), Some(2));
+
+        // the same ID sent by another client or with another command has another key
+        let addr = "192.0.2.1:7878".parse().unwrap();
+        let token = Some("token".to_string());
+        for (client, command) in [
+            (ClientIdentity::new(addr, None), Command::Ping),
+            (ClientIdentity::new(addr, token), Command::Ping),
+            (client.clone(), Command::Stats),
+        ] {
+            let other = CacheKey::new(id2, &client, &command, None);
+            assert_eq!(cached(&cache, &other, secs(5)), None);
+        }
+
+        // the expired responses are evicted on insertion
+        cache.insert(key(Uuid::new_v4()), response(3), secs(12));
+        assert_eq!(cache.responses.len(), 2);
+
+        let mut disabled = ResponseCache::default();
+        disabled.insert(key(id1), response(1), start);
+        assert!(disabled.responses.is_empty());
+    }
+
+    #[test]
+    fn test_macros() {
+        let mut macros = Macros::default();
+        for i in 0..Macros::MAX_COUNT {
+            let name = format!("m{i}");
+            macros.define(None, name, vec![Command::Ping]).unwrap();
+        }
+        let err = macros.define(None, "extra".to_string(), vec![Command::Ping]);
+        assert_eq!(err.unwrap_err().code, ErrorCode::InvalidArgument);
+        // the limit is shared by all of the tokens
+        let err = macros.define(Some("token"), "extra".to_string(), vec![Command::Ping]);
+        assert_eq!(err.unwrap_err().code, ErrorCode::InvalidArgument);
+        // redefining an existing macro doesn't count against the limit
+        macros
+            .define(None, "m0".to_string(), vec![Command::Noop, Command::Noop])
+            .unwrap();
+        assert_eq!(macros.get(None, "m0").unwrap().len(), 2);
+
+        let echo = Command::Echo(Value::from("x".repeat(Macros::MAX_BYTES)));
+        for steps in [vec![echo], Vec::new()] {
+            let err = macros.define(None, "m1".to_string(), steps).unwrap_err();
+            assert_eq!(err.code, ErrorCode::InvalidArgument);
+        }
+
+        assert!(matches!(macros.get(None, "missing"), Err(e) if e.code == ErrorCode::NotFound));
+        // the macros are only visible with the token they've been defined with
+        let other = macros.get(Some("token"), "m0");
+        assert!(matches!(other, Err(e) if e.code == ErrorCode::NotFound));
+    }
+
+    #[test]
+    fn test_session() {
+        let mut session = Session::default();
+        for i in 0..Session::MAX_ENTRIES {
+            session.set(format!("k{i}"), Value::from(i)).unwrap();
+        }
+        let err = session.set("extra".to_string(), Value::Null).unwrap_err();
+        assert_eq!(err.code, ErrorCode::InvalidArgument);
+        // replacing an existing entry doesn't count against the limit
+        session.set("k0".to_string(), Value::from("zero")).unwrap();
+        assert_eq!(session.get("k0").unwrap(), "zero");
+        assert_eq!(session.len(), Session::MAX_ENTRIES);
+
+        let large = Value::from("x".repeat(Session::MAX_BYTES));
+        let err = session.set("k1".to_string(), large).unwrap_err();
+        assert_eq!(err.code, ErrorCode::InvalidArgument);
+        let err = session.set(String::new(), Value::Null).unwrap_err();
+        assert_eq!(err.code, ErrorCode::InvalidArgument);
+
+        // the size of a replaced value is freed
+        let mut session = Session::default();
+        let half = Value::from("x".repeat(Session::MAX_BYTES / 2));
+        session.set("a".to_string(), half.clone()).unwrap();
+        session.set("a".to_string(), half.clone()).unwrap();
+        assert!(session.set("b".to_string(), half).is_err());
+
+        assert!(matches!(session.get("missing"), Err(e) if e.code == ErrorCode::NotFound));
+    }
+
+    #[test]
+    fn test_parse_command_concurrency() {
+        assert_eq!(
+            "calculate=4".parse::<CommandConcurrency>().unwrap(),
+            CommandConcurrency {
+                kind: CommandKind::Calculate,
+                limit: 4
+            }
+        );
+        for invalid in [
+            "calculate",
+            "calculate=0",
+            "calculate=x",
+            "nope=1",
+            "batch=2",
+        ] {
+            assert!(invalid.parse::<CommandConcurrency>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_metrics_history() {
+        let mut history = MetricsHistory::new(3);
+        for timestamp_ms in 0..5 {
+            history.push(Metrics::default().snapshot(timestamp_ms));
+        }
+        let timestamps: Vec<u64> = history.recent(10).iter().map(|s| s.timestamp_ms).collect();
+        assert_eq!(timestamps, [2, 3, 4]);
+        assert!(history.recent(0).is_empty());
+
+        let mut disabled = MetricsHistory::new(0);
+        disabled.push(Metrics::default().snapshot(0));
+        assert!(disabled.recent(1).is_empty());
+    }
+
+    #[test]
+    fn test_request_log() {
+        let record = |n: u128| RequestRecord {
+            request_id: Uuid::from_u128(n),
+            command: CommandKind::Ping,
+            ok: true,
+            code: None,
+            duration_ms: 0.0,
+        };
+        let mut log = RequestLog::default();
+        for n in 0..RequestLog::CAPACITY as u128 + 2 {
+            log.push(record(n));
+        }
+        let recent = log.recent(usize::MAX);
+        assert_eq!(recent.len(), RequestLog::CAPACITY);
+        assert_eq!(recent[0], record(RequestLog::CAPACITY as u128 + 1));
+        // the oldest ones are evicted
+        assert_eq!(recent[RequestLog::CAPACITY - 1], record(2));
+        assert!(log.recent(0).is_empty());
+    }
+
+    #[test]
+    fn test_canonicalize() {
+        let value: Value = serde_json::from_str(
+            r#"{
+                "b": {"z": [{"y": 1, "x": 2.0}], "a": 1e2},
+                "a": [3.5, -0.0, 1e300, "text", null],
+                "c": true
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            canonicalize(value).to_string(),
+            r#"{"a":[3.5,0,1e300,"text",null],"b":{"a":100,"z":[{"x":2,"y":1}]},"c":true}"#
+        );
+    }
+
+    #[test]
+    fn test_time_unit() {
+        let from: DateTime<Utc> = "2025-01-01T00:00:00Z".parse().unwrap();
+        let to: DateTime<Utc> = "2025-01-02T12:30:00.250Z".parse().unwrap();
+        assert_eq!(TimeUnit::Millis.between(from, to), 131_400_250.0);
+        assert_eq!(TimeUnit::Seconds.between(from, to), 131_400.25);
+        assert_eq!(TimeUnit::Minutes.between(from, to), 2190.0041666666666);
+        assert_eq!(TimeUnit::Hours.between(from, to), 36.50006944444444);
+        assert_eq!(TimeUnit::Days.between(from, to), 1.5208362268518518);
+
+        // the difference is negative if `to` precedes `from`
+        assert_eq!(TimeUnit::Seconds.between(to, from), -131_400.25);
+        assert_eq!(TimeUnit::Days.between(to, from), -1.5208362268518518);
+        assert_eq!(TimeUnit::Hours.between(from, from), 0.0);
+    }
+
+    #[test]
+    fn test_running_stats() {
+        let values = [2.0, 4.0, 4.0, 4.0, 5.0, 5.0, 7.0, 9.0];
+        let mut stats = RunningStats::default();
+        assert_eq!(stats.variance(), None);
+        values.iter().for_each(|&v| stats.push(v));
+        assert_eq!(stats.count, 8);
+        assert_eq!(stats.mean, 5.0);
+        assert_eq!(stats.variance(), Some(4.0));
+
+        // merging the statistics of the parts gives the same as pushing the values one by one
+        let (mut first, mut second) = (RunningStats::default(), RunningStats::default());
+        values[..3].iter().for_each(|&v| first.push(v));
+        values[3..].iter().for_each(|&v| second.push(v));
+        first.merge(&second);
+        assert_eq!(first.count, 8);
+        assert!((first.mean - 5.0).abs() < 1e-12);
+        assert!((first.variance().unwrap() - 4.0).abs() < 1e-12);
+        first.merge(&RunningStats::default());
+        assert_eq!(first.count, 8);
+    }
+
+    #[tokio::test]
+    async fn test_memory_budget() {
+        let budget = MemoryBudget::new(Some(100));
+        let mut first = budget.guard();
+        first.set(80);
+        let mut second = budget.guard();
+        second.set(40);
+        assert_eq!(budget.used(), 120);
+        assert!(budget.is_exceeded());
+
+        // the reading waits for the other guard to free its memory
+        let waiting = second.wait_for_room();
+        let freeing = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            first.set(10);
+        };
+        let start = Instant::now();
+        tokio::join!(waiting, freeing);
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert_eq!(budget.used(), 50);
+        drop(first);
+
+        // there's nothing to wait for if the guard holds all of the memory itself
+        second.set(200);
+        tokio::time::timeout(Duration::from_secs(1), second.wait_for_room())
+            .await
+            .unwrap();
+        drop(second);
+        assert_eq!(budget.used(), 0);
+        assert!(!MemoryBudget::new(None).is_exceeded());
+    }
+
+    #[test]
+    fn test_compression_stats() {
+        let stats = CompressionStats::default();
+        assert_eq!(stats.ratio(), None);
+        stats.record(1000, 100);
+        stats.record(500, 400);
+        assert_eq!(stats.ratio(), Some(3.0));
+        // an incompressible response may even grow
+        let stats = CompressionStats::default();
+        stats.record(10, 30);
+        assert!(stats.ratio().unwrap() < 1.0);
     }
 }
@@ -1,31 +1,54 @@
 use std::io::{Read, Write};
-use std::net::TcpStream;
-use std::process::Command;
+use std::net::{SocketAddr, TcpStream};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::{Mutex, MutexGuard};
 use std::time::Duration;
 
 use serde_json::{Value, json};
 use uuid::Uuid;
 
-fn wait_for_server() {
-    for _ in 0..10 {
-        if TcpStream::connect("localhost:7878").is_ok() {
-            return;
+/// The server always listens on the same port, so the tests can't run it concurrently.
+static SERVER_LOCK: Mutex<()> = Mutex::new(());
+
+fn lock_server() -> MutexGuard<'static, ()> {
+    // a failed test must not fail the others
+    SERVER_LOCK.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Waits for the server to write its address to the ready file.
+fn wait_for_server(ready_file: &Path) -> SocketAddr {
+    // the first run has to build the server
+    for _ in 0..600 {
+        if let Ok(addr) = std::fs::read_to_string(ready_file) {
+            return addr
+                .parse()
+                .expect("The ready file doesn't contain an address");
         }
-        std::thread::sleep(Duration::from_millis(200));
+        std::thread::sleep(Duration::from_millis(100));
     }
     panic!("Server didn't start in time");
 }
 
 #[test]
 fn test_ping() {
+    let _lock = lock_server();
+    let id = Uuid::new_v4();
+    let ready_file = std::env::temp_dir().join(format!("testrust-{id}.ready"));
+    let log_file = std::env::temp_dir().join(format!("testrust-{id}.log"));
     let mut server = Command::new("cargo")
-        .args(["run", "--", "--debug", "--log-file", "test.log"])
+        .args(["run", "--", "--debug", "--log-file"])
+        .arg(&log_file)
+        .arg("--ready-file")
+        .arg(&ready_file)
         .spawn()
         .unwrap();
 
     // the server needs some time to start
-    wait_for_server();
-    let mut stream = TcpStream::connect("localhost:7878").expect("Couldn't connect to the server");
+    let addr = wait_for_server(&ready_file);
+    assert!(addr.ip().is_loopback());
+    assert_eq!(addr.port(), 7878);
+    let mut stream = TcpStream::connect(addr).expect("Couldn't connect to the server");
 
     let uuid = Uuid::new_v4();
     let request = json!({
@@ -52,4 +75,117 @@ fn test_ping() {
 
     // this is SIGKILL, so no graceful shutdown... oh well.
     server.kill().expect("This should never happen: couldn't kill the server");
+    let _ = std::fs::remove_file(&ready_file);
+    let _ = std::fs::remove_file(&log_file);
+}
+
+#[test]
+fn test_unwritable_log_file() {
+    let _lock = lock_server();
+    let id = Uuid::new_v4();
+    let ready_file = std::env::temp_dir().join(format!("testrust-{id}.ready"));
+    // a file can't contain the log file, whatever the permissions are
+    let not_a_dir = std::env::temp_dir().join(format!("testrust-{id}.file"));
+    std::fs::write(&not_a_dir, "").unwrap();
+    let mut server = Command::new(env!("CARGO_BIN_EXE_testrust"))
+        .arg("--log-file")
+        .arg(not_a_dir.join("test.log"))
+        .arg("--ready-file")
+        .arg(&ready_file)
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    // the server must start anyway
+    let addr = wait_for_server(&ready_file);
+    let mut stream = TcpStream::connect(addr).expect("Couldn't connect to the server");
+    let uuid = Uuid::new_v4();
+    let request = json!({"request_id": uuid, "command": "ping"});
+    stream
+        .write_all(&serde_json::to_vec(&request).unwrap())
+        .expect("Couldn't send the request");
+    stream
+        .shutdown(std::net::Shutdown::Write)
+        .expect("Couldn't shut down the write of the connection");
+    let mut buf = Vec::new();
+    stream
+        .read_to_end(&mut buf)
+        .expect("Couldn't read the response");
+    let response: Value =
+        serde_json::from_slice(&buf).expect("Couldn't deserialize the data received");
+    assert_eq!(response["request_id"], uuid.to_string());
+    assert_eq!(response["response"], "pong");
+
+    server.kill().expect("Couldn't kill the server");
+    server.wait().expect("Couldn't wait for the server");
+    let mut output = String::new();
+    server
+        .stderr
+        .take()
+        .unwrap()
+        .read_to_string(&mut output)
+        .unwrap();
+    assert!(output.contains("WARNING: couldn't open the log file"));
+    let _ = std::fs::remove_file(&ready_file);
+    let _ = std::fs::remove_file(&not_a_dir);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_sigterm_graceful_shutdown() {
+    let _lock = lock_server();
+    let id = Uuid::new_v4();
+    let ready_file = std::env::temp_dir().join(format!("testrust-{id}.ready"));
+    let log_file = std::env::temp_dir().join(format!("testrust-{id}.log"));
+    let mut server = Command::new(env!("CARGO_BIN_EXE_testrust"))
+        .arg("--log-file")
+        .arg(&log_file)
+        .arg("--ready-file")
+        .arg(&ready_file)
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let addr = wait_for_server(&ready_file);
+
+    // a request that is in flight when the signal arrives must still be answered
+    let mut stream = TcpStream::connect(addr).expect("Couldn't connect to the server");
+    let uuid = Uuid::new_v4();
+    let request = json!({"request_id": uuid, "command": "ping"});
+    stream
+        .write_all(&serde_json::to_vec(&request).unwrap())
+        .expect("Couldn't send the request");
+    // the server needs some time to accept the connection
+    std::thread::sleep(Duration::from_millis(200));
+
+    let kill = Command::new("kill")
+        .args(["-TERM", &server.id().to_string()])
+        .status()
+        .expect("Couldn't send SIGTERM");
+    assert!(kill.success());
+    std::thread::sleep(Duration::from_millis(200));
+
+    stream
+        .shutdown(std::net::Shutdown::Write)
+        .expect("Couldn't shut down the write of the connection");
+    let mut buf = Vec::new();
+    stream
+        .read_to_end(&mut buf)
+        .expect("Couldn't read the response");
+    let response: Value =
+        serde_json::from_slice(&buf).expect("Couldn't deserialize the data received");
+    assert_eq!(response["request_id"], uuid.to_string());
+    assert_eq!(response["response"], "pong");
+
+    let status = server.wait().expect("Couldn't wait for the server");
+    assert!(status.success());
+    let mut output = String::new();
+    server
+        .stdout
+        .take()
+        .unwrap()
+        .read_to_string(&mut output)
+        .unwrap();
+    assert!(output.contains("Server stopped."));
+    let _ = std::fs::remove_file(&ready_file);
+    let _ = std::fs::remove_file(&log_file);
 }